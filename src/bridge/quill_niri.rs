@@ -1,10 +1,20 @@
-use crate::ebc::{self, CommandSender};
+use super::sync::{BridgeSync, SyncWindow, WindowState};
+use crate::{
+    ebc::{self, CommandSender},
+    shared_state::{Shared, SharedState},
+};
 use anyhow::{Context, Result};
 use inotify::{Inotify, WatchMask};
-use log::{debug, error, info, warn};
-use niri_ipc::{Event, Request, Response, WindowGeometry, socket::Socket};
+use log::{Level, debug, error, info, log, warn};
+use niri_ipc::{Event, Output, Request, Response, WindowGeometry, socket::Socket};
 use nix::libc::pid_t;
-use pinenote_service::types::{Rect, rockchip_ebc::Hint};
+use pinenote_service::{
+    pixel_manager::PixelManager,
+    types::{
+        Rect, Rounding,
+        rockchip_ebc::{self, Hint},
+    },
+};
 use qoms_lib::find_session;
 use quill_data_provider_lib::{
     Dithering, DriverMode, EinkWindowSetting, PINENOTE_ENABLE_SOCKET, RedrawOptions,
@@ -17,116 +27,63 @@ use tokio::{
     net::UnixListener,
     sync::{
         Mutex,
-        mpsc::{self, Receiver, Sender},
-        oneshot,
+        mpsc::{self, Receiver},
     },
     time::sleep,
 };
 
-use std::{
-    collections::{HashMap, HashSet},
-    sync::OnceLock,
-    time::Duration,
-};
+use std::{sync::Once, time::Duration};
 
 const QUILL_NIRI_BRIDGE: &str = "Quill niri";
 
 pub struct QuillNiriBridge {
-    app_meta: HashMap<pid_t, (String, HashSet<i64>)>,
-    window_meta: HashMap<i64, (String, NiriWindows)>,
+    sync: BridgeSync<CoreWindow>,
     previous_windows: Vec<NiriWindows>,
     enabled_rx: Receiver<bool>,
     enabled: bool,
     is_overview: bool,
+    shared: Shared,
+    /// Rounding of the window areas scaled to physical pixels.
+    rounding: Rounding,
 }
 
-static SETTINGS: OnceLock<Mutex<Vec<EinkWindowSetting>>> = OnceLock::new();
+/// Global settings last applied for the focused window, and whether they were applied at all.
+type AppliedSettings = (ThresholdLevel, Dithering, RedrawOptions, DriverMode, bool);
+
+/// Bridge state shared with the settings watcher, see [SharedState].
+#[derive(Default)]
+pub struct SharedSettings {
+    /// Settings of the applications managed by the bridge, from the user configuration.
+    window_settings: Mutex<Vec<EinkWindowSetting>>,
+    applied: Mutex<AppliedSettings>,
+}
 
 impl QuillNiriBridge {
-    const OUTPUT_NAME: &str = "DPI-1";
+    const OUTPUT_NAME: &str = PixelManager::PANEL_OUTPUT;
 
-    pub async fn new(enabled_rx: Receiver<bool>) -> Result<Self> {
+    pub async fn new(
+        enabled_rx: Receiver<bool>,
+        shared: Shared,
+        rounding: Rounding,
+    ) -> Result<Self> {
         let bridge = Self {
-            app_meta: HashMap::new(),
-            window_meta: HashMap::new(),
+            sync: Default::default(),
             previous_windows: Vec::new(),
             enabled_rx,
             enabled: true,
             is_overview: false,
+            shared,
+            rounding,
         };
         Ok(bridge)
     }
 
+    /// Remove every application and window from the core.
     async fn remove_all(&mut self, tx: &mut ebc::CommandSender) -> Result<()> {
-        let app_keys: Vec<String> = self.app_meta.values().map(|(key, _)| key.clone()).collect();
-        for key in app_keys {
-            tx.send(ebc::command::Application::Remove(key)).await.ok();
-        }
-        self.app_meta.clear();
-        self.window_meta.clear();
-        Ok(())
-    }
-
-    async fn add_app(&mut self, pid: pid_t, tx: &mut ebc::CommandSender) -> Result<String> {
-        let (ret_tx, ret_rx) = oneshot::channel::<String>();
-        let app_key = tx
-            .with_reply(ebc::command::Application::Add(pid, ret_tx), ret_rx)
-            .await
-            .context(format!("Failed to add application with PID {}", pid))?;
-
-        self.app_meta
-            .insert(pid, (app_key.clone(), Default::default()));
-
-        Ok(app_key)
-    }
-
-    async fn add_window(
-        &mut self,
-        win: &NiriWindows,
-        app_key: String,
-        tx: &mut ebc::CommandSender,
-        scale: f64,
-        socket: &mut Socket,
-    ) -> Result<()> {
-        let (rtx, rx) = oneshot::channel::<String>();
-
-        let cmd = ebc::command::Window::Add {
-            app_key: app_key.clone(),
-            title: win.title.clone(),
-            area: Rect::from_xywh(
-                (win.geometry.x as f64 * scale) as i32,
-                (win.geometry.y as f64 * scale) as i32,
-                (win.geometry.width as f64 * scale) as i32,
-                (win.geometry.height as f64 * scale) as i32,
-            ),
-            hint: Some(setting_to_hint(&win.setting, win.focused, socket).await),
-            visible: true,
-            fullscreen: false,
-            z_index: 0,
-            reply: rtx,
-        };
-
-        let win_key = tx
-            .with_reply(cmd, rx)
-            .await
-            .with_context(|| format!("Failed to add window for app '{}'", win.app_id))?;
-
-        let win_id = win.geometry.id as i64;
-        let pid = win_id as pid_t;
-
-        self.window_meta.insert(win_id, (win_key, win.clone()));
-        if let Some(app) = self.app_meta.get_mut(&pid) {
-            app.1.insert(win_id);
-        }
-
-        Ok(())
+        self.sync.sync(Vec::new(), tx).await
     }
 
     pub async fn main_manage(&mut self, tx: &mut ebc::CommandSender) {
-        if let Err(e) = self.remove_all(tx).await {
-            error!("Failed to remove all apps/windows: {:?}", e);
-        }
-
         let mut socket = get_socket().await;
         debug!("Requesting windows");
 
@@ -143,9 +100,10 @@ impl QuillNiriBridge {
         }) else {
             return;
         };
-        let focused_workspace_id = workspaces
+        // Only the workspace shown on the e-ink panel matters, other outputs may have the focus.
+        let eink_workspace_id = workspaces
             .iter()
-            .find(|ws| ws.is_focused)
+            .find(|ws| ws.is_active && ws.output.as_deref() == Some(Self::OUTPUT_NAME))
             .map(|ws| ws.id)
             .unwrap_or(0);
 
@@ -155,7 +113,7 @@ impl QuillNiriBridge {
         }) else {
             return;
         };
-        let (screen_w, screen_h, scale) = {
+        let (screen_x, screen_y, screen_w, screen_h, scale) = {
             let output = match outputs.get(Self::OUTPUT_NAME) {
                 Some(o) => o,
                 None => {
@@ -164,67 +122,63 @@ impl QuillNiriBridge {
                 }
             };
 
-            match output.logical {
-                Some(logical_mode) => (
-                    logical_mode.width as i32,
-                    logical_mode.height as i32,
-                    logical_mode.scale,
-                ),
+            match output_layout(output) {
+                Some(layout) => layout,
                 None => {
-                    error!("No logical screen info");
+                    error!("No logical screen info, nor current mode");
                     return;
                 }
             }
         };
 
-        let Some(windows_geometries) =
+        // Only those with settings attached to them
+        let mut candidates = Vec::new();
+        {
+            let settings = self.shared.quill_niri.window_settings.lock().await;
+            for window in windows_regular {
+                let Some(app_id) = window.app_id.clone() else {
+                    continue;
+                };
+                // Make sure it's on the e-ink output, not sure for windows without workspace
+                if window.workspace_id != Some(eink_workspace_id) {
+                    continue;
+                }
+
+                if let Some(setting) = settings.iter().find(|s| s.app_id == app_id) {
+                    candidates.push((window, app_id, setting.clone()));
+                }
+            }
+        }
+
+        let wanted: Vec<u64> = candidates.iter().map(|(window, ..)| window.id).collect();
+        let fetch = || {
             try_fetch(&mut socket, Request::WindowGeometries, |r| match r {
                 Response::WindowGeometries(w) => Some(w),
                 _ => None,
             })
-        else {
+        };
+        let Some(windows_geometries) = fetch_geometries(fetch, &wanted).await else {
             return;
         };
 
         let mut new_niri_windows: Vec<NiriWindows> = Vec::new();
 
-        // Only those with settings attached to them
-        for window in windows_regular {
-            if let Some(app_id) = window.app_id {
-                let settings = SETTINGS.get_or_init(|| Mutex::new(Vec::new())).lock().await;
-                for setting in settings.iter() {
-                    if app_id == setting.app_id {
-                        // Make sure it's on the same workspace
-                        match window.workspace_id {
-                            Some(id) => {
-                                if id != focused_workspace_id {
-                                    continue;
-                                }
-                            }
-                            None => {
-                                // Not sure
-                                continue;
-                            }
-                        }
-                        // Find the geometry for it now
-                        for geometry in windows_geometries.iter() {
-                            if geometry.id == window.id {
-                                new_niri_windows.push(NiriWindows {
-                                    app_id: app_id.clone(),
-                                    title: window.title.clone().unwrap_or_default(),
-                                    focused: window.is_focused,
-                                    setting: setting.clone(),
-                                    geometry: OurWindowGeometry::from(geometry.clone()),
-                                });
-                            }
-                        }
-                    }
-                }
+        for (window, app_id, setting) in candidates {
+            // Find the geometry for it now
+            if let Some(geometry) = windows_geometries.iter().find(|g| g.id == window.id) {
+                new_niri_windows.push(NiriWindows {
+                    id: geometry.id,
+                    app_id,
+                    title: window.title.clone().unwrap_or_default(),
+                    focused: window.is_focused,
+                    setting,
+                    area: output_area(geometry, (screen_x, screen_y)),
+                });
             }
         }
 
         // TODO: remove floating windows from this, or maybe not???
-        new_niri_windows.sort_by_key(|w| w.geometry.x);
+        new_niri_windows.sort_by_key(|w| w.area.x1);
         windows_on_screen(&mut new_niri_windows, screen_w, screen_h);
 
         if self.previous_windows == new_niri_windows {
@@ -237,24 +191,42 @@ impl QuillNiriBridge {
 
         debug!("New niri windows are: {:#?}", new_niri_windows);
 
+        let applied = &self.shared.quill_niri.applied;
+        let mut windows = Vec::new();
         for win in &new_niri_windows {
-            let pid = win.geometry.id as pid_t;
-            match self.add_app(pid, tx).await {
-                Ok(app_key) => {
-                    if let Err(e) = self.add_window(win, app_key, tx, scale, &mut socket).await {
-                        error!("Failed to add window: {:?}", e);
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to add app: {:?}", e);
-                }
+            let area = win.area.scaled_with(scale, self.rounding);
+
+            // Windows scaled to nothing cover no pixel, don't spend a window on them
+            if area.area() == 0 {
+                debug!("Skipping window {}: empty area {area:?}", win.id);
+                continue;
             }
+
+            let hint = setting_to_hint(applied, &win.setting, win.focused, &mut socket, tx).await;
+            windows.push(CoreWindow {
+                id: win.id,
+                app_id: win.app_id.clone(),
+                state: WindowState {
+                    title: win.title.clone(),
+                    area,
+                    hint: Some(hint),
+                    visible: true,
+                    fullscreen: false,
+                    z_index: 0,
+                    focused: win.focused,
+                },
+            });
+        }
+
+        if let Err(e) = self.sync.sync(windows, tx).await {
+            error!("Failed to sync windows: {e:?}");
         }
 
         if !new_niri_windows.iter().any(|w| w.focused) {
             warn!("No window is focused, restoring defaults");
             set_global_things(
                 &mut socket,
+                tx,
                 &Default::default(),
                 &Default::default(),
                 &Default::default(),
@@ -263,21 +235,22 @@ impl QuillNiriBridge {
             .await;
 
             // Set it
-            let mut older_settings = get_global_settings().await;
+            let mut older_settings = self.shared.quill_niri.applied.lock().await;
             *older_settings = Default::default();
             older_settings.4 = true;
         }
         debug!("Main manage exit");
     }
 
-    pub async fn run(mut self, tx: Sender<ebc::Command>) -> Result<()> {
+    pub async fn run(mut self, mut tx: CommandSender) -> Result<()> {
         info!("Bridge niri started");
         let mut socket = get_socket().await;
-        let mut tx: CommandSender = tx.into();
 
         let reply = socket.send(Request::EventStream).unwrap();
         if matches!(reply, Ok(Response::Handled)) {
+            self.shared.set_bridge_connected(true);
             let (evt_tx, mut evt_rx) = mpsc::unbounded_channel();
+            let mut paused_rx = self.shared.watch_bridge_paused();
 
             tokio::task::spawn_blocking(move || {
                 let mut read_event = socket.read_events();
@@ -298,8 +271,18 @@ impl QuillNiriBridge {
                                         }
                                     }
 
+                                    Ok(()) = paused_rx.changed() => {
+                                        let paused = *paused_rx.borrow_and_update();
+                                        // Events were dropped while paused, rebuild from scratch
+                                        if !paused && self.enabled && !self.is_overview {
+                                            info!("Bridge resumed, resyncing windows");
+                                            self.reset_everything(&mut tx).await;
+                                            self.main_manage(&mut tx).await;
+                                        }
+                                    }
+
                                     Some(event) = evt_rx.recv() => {
-                                        if !self.enabled {
+                                        if !self.enabled || self.shared.bridge_paused() {
                                             continue;
                                         }
 
@@ -351,7 +334,7 @@ impl QuillNiriBridge {
             error!("Failed to remove all apps/windows: {:?}", e);
         }
         // Reset it so it applies next time
-        let mut older_settings = get_global_settings().await;
+        let mut older_settings = self.shared.quill_niri.applied.lock().await;
         *older_settings = Default::default();
         // Reset windows so it's fresh
         self.previous_windows.clear();
@@ -366,6 +349,7 @@ impl QuillNiriBridge {
             let driver_mode = DriverMode::Fast(Default::default());
             set_global_things(
                 &mut new_socket,
+                tx,
                 &Default::default(),
                 &Default::default(),
                 &Default::default(),
@@ -378,6 +362,7 @@ impl QuillNiriBridge {
             sleep(Duration::from_millis(300)).await;
             set_global_things(
                 &mut new_socket,
+                tx,
                 &Default::default(),
                 &Default::default(),
                 &Default::default(),
@@ -389,7 +374,7 @@ impl QuillNiriBridge {
     }
 }
 
-pub async fn load_settings_internal(username: String) {
+pub async fn load_settings_internal(shared: &SharedState, username: String, strict_hints: bool) {
     debug!("Reading settings...");
     let path = format!(
         "/home/{}{}{}",
@@ -397,11 +382,17 @@ pub async fn load_settings_internal(username: String) {
     );
     let settings = load_window_settings(path);
     debug!("Got window settings: {:?}", settings);
-    let mut guard = SETTINGS.get_or_init(|| Mutex::new(Vec::new())).lock().await;
+    report_wasteful_settings(&settings, strict_hints);
+    let mut guard = shared.quill_niri.window_settings.lock().await;
     *guard = settings;
 }
 
-pub async fn start(tx: mpsc::Sender<ebc::Command>) -> Result<String> {
+pub async fn start(
+    tx: CommandSender,
+    shared: Shared,
+    rounding: Rounding,
+    strict_hints: bool,
+) -> Result<String> {
     let initial_session = find_session().await;
     debug!("Initial session is: {:?}", initial_session);
     if initial_session.is_none() {
@@ -440,14 +431,15 @@ pub async fn start(tx: mpsc::Sender<ebc::Command>) -> Result<String> {
         }
     });
 
-    let quill_niri_bridge = QuillNiriBridge::new(enabled_rx)
+    let quill_niri_bridge = QuillNiriBridge::new(enabled_rx, shared.clone(), rounding)
         .await
         .context("While trying to start Quill niri bridge")?;
 
+    let watcher_shared = shared.clone();
     tokio::spawn(async move {
+        let shared = watcher_shared;
         debug!("Settings watcher init");
         const DELAY: Duration = Duration::from_secs(5);
-        SETTINGS.get_or_init(|| Mutex::new(Vec::new()));
         let mut username = "".to_string();
         let mut inotify = Inotify::init().expect("Failed to initialize inotify");
         let mut inotify_set = false;
@@ -458,7 +450,7 @@ pub async fn start(tx: mpsc::Sender<ebc::Command>) -> Result<String> {
                 if username != username2 || !inotify_set {
                     if initial_loop {
                         initial_loop = false;
-                        load_settings_internal(username2.clone()).await; // So it creates the dir, if it's missing
+                        load_settings_internal(&shared, username2.clone(), strict_hints).await; // So it creates the dir, if it's missing
                     }
                     let path = format!("/home/{}{}", username2, WINDOW_SETTINGS_HOME_CONFIG_DIR);
 
@@ -471,7 +463,7 @@ pub async fn start(tx: mpsc::Sender<ebc::Command>) -> Result<String> {
                             inotify_descriptors.push(descriptor);
                             inotify_set = true;
                             username = username2.clone();
-                            load_settings_internal(username2.clone()).await;
+                            load_settings_internal(&shared, username2.clone(), strict_hints).await;
                             info!("Inotify set!");
                         }
                         Err(err) => {
@@ -490,7 +482,8 @@ pub async fn start(tx: mpsc::Sender<ebc::Command>) -> Result<String> {
                     match inotify.read_events(&mut buffer) {
                         Ok(_) => {
                             if !readed_settings {
-                                load_settings_internal(username.clone()).await;
+                                load_settings_internal(&shared, username.clone(), strict_hints)
+                                    .await;
                                 readed_settings = true;
                             }
                         }
@@ -514,7 +507,11 @@ pub async fn start(tx: mpsc::Sender<ebc::Command>) -> Result<String> {
     sleep(Duration::from_secs(1)).await;
 
     tokio::spawn(async move {
-        let _ = quill_niri_bridge.run(tx).await;
+        let res = shared.until_shutdown(quill_niri_bridge.run(tx)).await;
+        if res.is_none() {
+            info!("Quill niri bridge stopped for shutdown");
+        }
+        shared.set_bridge_connected(false);
     });
 
     Ok(QUILL_NIRI_BRIDGE.into())
@@ -556,61 +553,144 @@ async fn get_socket() -> Socket {
     }
 }
 
+/// A niri window as told to the core, see [BridgeSync].
+#[derive(Clone, Debug)]
+struct CoreWindow {
+    /// niri window id.
+    id: u64,
+    app_id: String,
+    state: WindowState,
+}
+
+impl SyncWindow for CoreWindow {
+    fn id(&self) -> i64 {
+        self.id as i64
+    }
+
+    /// niri doesn't report pids, each window stands for its own application. The id is negated
+    /// so that the core knows there is no process behind it, and never sweeps it.
+    fn pid(&self) -> pid_t {
+        -(self.id.min(pid_t::MAX as u64) as pid_t)
+    }
+
+    fn app_id(&self) -> &str {
+        &self.app_id
+    }
+
+    fn state(&self) -> WindowState {
+        self.state.clone()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 struct NiriWindows {
+    /// niri window id.
+    id: u64,
     app_id: String,
     title: String,
     focused: bool,
     setting: EinkWindowSetting,
-    geometry: OurWindowGeometry,
+    /// Area in the output logical space, before scaling.
+    area: Rect,
 }
 
-// Because niri doesn't do partialeq defines
-#[derive(Clone, Debug, PartialEq)]
-pub struct OurWindowGeometry {
-    pub id: u64,
-    pub x: i32,
-    pub y: i32,
-    pub width: i32,
-    pub height: i32,
+/// Position, size and scale of `output` in the layout space.
+///
+/// Without logical info, the output is assumed unscaled at the origin, like the sway bridge does
+/// with transforms it doesn't know: hints roughly matching beat no hint at all.
+fn output_layout(output: &Output) -> Option<(i32, i32, i32, i32, f64)> {
+    if let Some(logical) = &output.logical {
+        return Some((
+            logical.x,
+            logical.y,
+            logical.width as i32,
+            logical.height as i32,
+            logical.scale,
+        ));
+    }
+
+    let mode = output.current_mode.and_then(|i| output.modes.get(i))?;
+
+    static NO_LOGICAL: Once = Once::new();
+    NO_LOGICAL.call_once(|| {
+        warn!(
+            "No logical screen info for output '{}', assuming no scale nor rotation",
+            output.name
+        )
+    });
+
+    Some((0, 0, mode.width.into(), mode.height.into(), 1.0))
 }
 
-impl From<WindowGeometry> for OurWindowGeometry {
-    fn from(window: WindowGeometry) -> Self {
-        Self {
-            id: window.id,
-            x: window.x,
-            y: window.y,
-            width: window.width,
-            height: window.height,
+/// Area of a window relative to the output at `origin`, in logical pixels.
+///
+/// Window geometries are in the global layout space, shared by every output.
+fn output_area(geometry: &WindowGeometry, (x, y): (i32, i32)) -> Rect {
+    Rect::from_xywh(
+        geometry.x - x,
+        geometry.y - y,
+        geometry.width,
+        geometry.height,
+    )
+}
+
+/// Windows of `wanted` without a geometry.
+fn missing_geometries(wanted: &[u64], geometries: &[WindowGeometry]) -> Vec<u64> {
+    wanted
+        .iter()
+        .copied()
+        .filter(|id| !geometries.iter().any(|g| g.id == *id))
+        .collect()
+}
+
+/// Fetch the window geometries, retrying a few times while some `wanted` windows have none.
+///
+/// niri may list a freshly opened window before reporting its geometry: without retrying, it
+/// would only get its hint on the next window event, if any. Windows still missing after the
+/// retries are left out.
+async fn fetch_geometries<F>(mut fetch: F, wanted: &[u64]) -> Option<Vec<WindowGeometry>>
+where
+    F: FnMut() -> Option<Vec<WindowGeometry>>,
+{
+    const RETRIES: usize = 3;
+    const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+    let mut attempt = 0;
+    loop {
+        let geometries = fetch()?;
+        let missing = missing_geometries(wanted, &geometries);
+
+        if missing.is_empty() {
+            return Some(geometries);
         }
+        if attempt == RETRIES {
+            warn!("No geometry reported for windows {missing:?}, skipping them");
+            return Some(geometries);
+        }
+
+        debug!("No geometry yet for windows {missing:?}, retrying");
+        attempt += 1;
+        sleep(RETRY_DELAY).await;
     }
 }
 
+/// Part of `area` on `screen`, unless less than 10 logical pixels show in either dimension.
+fn clip_to_screen(area: &Rect, screen: &Rect) -> Option<Rect> {
+    const MIN_SIZE: i32 = 10;
+
+    area.intersection(screen)
+        .filter(|a| a.x2 - a.x1 >= MIN_SIZE && a.y2 - a.y1 >= MIN_SIZE)
+}
+
 fn windows_on_screen(windows: &mut Vec<NiriWindows>, screen_width: i32, screen_height: i32) {
-    windows.retain_mut(|w| {
-        let gx = w.geometry.x;
-        let gy = w.geometry.y;
-        let gw = w.geometry.width;
-        let gh = w.geometry.height;
-
-        let left = gx.max(0);
-        let top = gy.max(0);
-        let right = (gx + gw).min(screen_width);
-        let bottom = (gy + gh).min(screen_height);
-
-        let width = right - left;
-        let height = bottom - top;
-
-        if left < right && top < bottom && width >= 10 && height >= 10 {
-            w.geometry.x = left;
-            w.geometry.y = top;
-            w.geometry.width = right - left;
-            w.geometry.height = bottom - top;
+    let screen = Rect::new(0, 0, screen_width, screen_height);
+
+    windows.retain_mut(|w| match clip_to_screen(&w.area, &screen) {
+        Some(area) => {
+            w.area = area;
             true
-        } else {
-            false
         }
+        None => false,
     });
 }
 
@@ -630,29 +710,38 @@ where
     }
 }
 
-// Globals bad, but if I need to pass an argument though 3 functions for no reason only for checking if something changed, uh, let me commit this sin then
-// Last bool is for initial applying
-static GLOBAL_EINK_SETTINGS: OnceLock<
-    Mutex<(ThresholdLevel, Dithering, RedrawOptions, DriverMode, bool)>,
-> = OnceLock::new();
-
-async fn get_global_settings()
--> tokio::sync::MutexGuard<'static, (ThresholdLevel, Dithering, RedrawOptions, DriverMode, bool)> {
-    GLOBAL_EINK_SETTINGS
-        .get_or_init(|| Mutex::new(Default::default()))
-        .lock()
-        .await
+/// Report the settings building hints DBus would warn about, see [Hint::is_wasteful].
+///
+/// With strict hints, they are logged as errors so that questionable settings don't go unnoticed.
+fn report_wasteful_settings(settings: &[EinkWindowSetting], strict: bool) {
+    for setting in settings {
+        // Fast mode picks its hint itself, only the normal mode ones are the user's choice
+        if !matches!(setting.settings, DriverMode::Normal(_)) {
+            continue;
+        }
+
+        let (hint, ..) = setting_hint(&setting.settings);
+        if let Some(reason) = hint.is_wasteful() {
+            let level = if strict { Level::Error } else { Level::Warn };
+            let app_id = &setting.app_id;
+            log!(
+                level,
+                "Settings of '{app_id}' build a wasteful hint {hint}: {reason}"
+            );
+        }
+    }
 }
 
-async fn setting_to_hint(setting: &EinkWindowSetting, focused: bool, socket: &mut Socket) -> Hint {
+/// Hint of the windows using `settings`, along with the global settings they need.
+fn setting_hint(settings: &DriverMode) -> (Hint, ThresholdLevel, Dithering, RedrawOptions) {
     use pinenote_service::types::rockchip_ebc::{HintBitDepth, HintConvertMode};
-    use quill_data_provider_lib::{BitDepth, Conversion, DriverMode, Redraw};
+    use quill_data_provider_lib::{BitDepth, Conversion, Redraw};
 
     let mut treshold: ThresholdLevel = Default::default();
     let mut dithering_mode: Dithering = Default::default();
     let mut redraw_options: RedrawOptions = Default::default();
 
-    let hint = match &setting.settings {
+    let hint = match settings {
         DriverMode::Normal(bit_depth) => match bit_depth {
             BitDepth::Y1(conv, level) => {
                 let cm = match conv {
@@ -701,8 +790,20 @@ async fn setting_to_hint(setting: &EinkWindowSetting, focused: bool, socket: &mu
         }
     };
 
+    (hint, treshold, dithering_mode, redraw_options)
+}
+
+async fn setting_to_hint(
+    applied: &Mutex<AppliedSettings>,
+    setting: &EinkWindowSetting,
+    focused: bool,
+    socket: &mut Socket,
+    tx: &CommandSender,
+) -> Hint {
+    let (hint, treshold, dithering_mode, redraw_options) = setting_hint(&setting.settings);
+
     if focused {
-        let mut older_settings = get_global_settings().await;
+        let mut older_settings = applied.lock().await;
         let is_different_settings = match (&older_settings.3, &setting.settings) {
             (DriverMode::Normal(_), DriverMode::Fast(_)) => true,
             (DriverMode::Fast(_), DriverMode::Normal(_)) => true,
@@ -716,6 +817,7 @@ async fn setting_to_hint(setting: &EinkWindowSetting, focused: bool, socket: &mu
         {
             set_global_things(
                 socket,
+                tx,
                 &treshold,
                 &dithering_mode,
                 &redraw_options,
@@ -739,6 +841,7 @@ async fn setting_to_hint(setting: &EinkWindowSetting, focused: bool, socket: &mu
 
 pub async fn set_global_things(
     socket: &mut Socket,
+    tx: &CommandSender,
     threshold: &ThresholdLevel,
     dithering_mode: &Dithering,
     redraw_options: &RedrawOptions,
@@ -746,8 +849,17 @@ pub async fn set_global_things(
 ) {
     threshold.set().await;
     dithering_mode.set().await;
-    redraw_options.set().await;
-    driver_mode.set().await; // Sets normal or fast globally based on this focused window settings
+
+    let options = ebc::Property::SetRedrawOptions(core_redraw_options(redraw_options));
+    if let Err(e) = tx.send(options).await {
+        error!("Failed to set the focused window redraw options: {e:?}");
+    }
+
+    // Sets normal or fast globally based on this focused window settings, once the focus settled
+    let request = ebc::Property::RequestFocusDriverMode(core_driver_mode(driver_mode));
+    if let Err(e) = tx.send(request).await {
+        error!("Failed to request the focused window driver mode: {e:?}");
+    }
 
     // Now we need to "rewrite" things on the screen
     // Hacky but whatever
@@ -760,3 +872,100 @@ pub async fn set_global_things(
         .send(Request::Action(niri_ipc::Action::ToggleDebugTint {}))
         .ok();
 }
+
+/// Driver mode of a quill setting, without the hint settings it carries.
+fn core_driver_mode(mode: &DriverMode) -> rockchip_ebc::DriverMode {
+    match mode {
+        DriverMode::Normal(_) => rockchip_ebc::DriverMode::Normal,
+        DriverMode::Fast(_) => rockchip_ebc::DriverMode::Fast,
+    }
+}
+
+/// Redraw options of a quill setting, applied by the core like the DBus ones.
+fn core_redraw_options(options: &RedrawOptions) -> rockchip_ebc::RedrawOptions {
+    rockchip_ebc::RedrawOptions {
+        redraw_delay: options.redraw_delay,
+        early_cancellation_addition: options.early_cancellation_addition,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geometry(x: i32, y: i32, width: i32, height: i32) -> WindowGeometry {
+        WindowGeometry {
+            id: 1,
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn geometry_of(id: u64) -> WindowGeometry {
+        WindowGeometry {
+            id,
+            ..geometry(0, 0, 100, 100)
+        }
+    }
+
+    #[test]
+    fn output_area_offset() {
+        // Output right of a 1920 pixels wide one
+        let area = output_area(&geometry(1930, 20, 300, 200), (1920, 0));
+
+        assert_eq!(Rect::new(10, 20, 310, 220), area);
+    }
+
+    #[test]
+    fn output_area_scaled() {
+        let area = output_area(&geometry(1930, 21, 301, 200), (1920, 0));
+
+        // Corners are scaled independently, rounding half up
+        assert_eq!(Rect::new(15, 32, 467, 332), area.scaled(1.5));
+        assert_eq!(area, area.scaled(1.0));
+    }
+
+    #[test]
+    fn windows_clipped_to_screen() {
+        let screen = Rect::new(0, 0, 1000, 800);
+        let area = output_area(&geometry(1870, 10, 100, 100), (1920, 0));
+
+        assert_eq!(
+            Some(Rect::new(0, 10, 50, 110)),
+            clip_to_screen(&area, &screen)
+        );
+        // Only 5 pixels on screen
+        let area = Rect::from_xywh(995, 10, 100, 100);
+        assert_eq!(None, clip_to_screen(&area, &screen));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn geometry_missing_retried() {
+        // The second window is listed before niri reports its geometry
+        let mut replies = vec![vec![geometry_of(1), geometry_of(2)], vec![geometry_of(1)]];
+        let mut fetches = 0;
+        let fetch = || {
+            fetches += 1;
+            replies.pop()
+        };
+
+        let geometries = fetch_geometries(fetch, &[1, 2]).await.unwrap();
+        assert_eq!(2, fetches);
+        assert!(missing_geometries(&[1, 2], &geometries).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn geometry_missing_gives_up() {
+        let mut fetches = 0;
+        let fetch = || {
+            fetches += 1;
+            Some(vec![geometry_of(1)])
+        };
+
+        let geometries = fetch_geometries(fetch, &[1, 2]).await.unwrap();
+        assert_eq!(4, fetches);
+        assert_eq!(vec![2], missing_geometries(&[1, 2], &geometries));
+    }
+}
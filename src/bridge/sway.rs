@@ -1,23 +1,19 @@
-use std::{
-    collections::{HashMap, HashSet},
-    time::Duration,
-};
+use std::time::Duration;
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result};
 use futures_lite::stream::StreamExt;
-use nalgebra::Matrix3;
 use nix::libc::pid_t;
-use pinenote_service::types::{Rect, rockchip_ebc::Hint};
-use log::{error, warn};
+use pinenote_service::{
+    pixel_manager::PixelManager,
+    types::{Rect, rockchip_ebc::Hint},
+};
+use log::{error, info, warn};
 use swayipc_async::{
     Connection, Event, EventStream, EventType, Node, NodeBorder, NodeType, Rect as SwayRect,
 };
-use tokio::sync::{
-    mpsc::{self, Sender},
-    oneshot,
-};
 
-use crate::ebc;
+use super::sync::{BridgeSync, SyncWindow, WindowState};
+use crate::{ebc, shared_state::Shared};
 
 mod utils;
 
@@ -25,58 +21,41 @@ mod utils;
 struct SwayWindow {
     id: i64,
     pid: pid_t,
+    app_id: String,
     title: String,
     area: Rect,
     visible: bool,
     floating: bool,
     fullscreen: bool,
+    focused: bool,
     hint: Option<Hint>,
+    /// Hint marks which couldn't be parsed.
+    malformed_marks: Vec<String>,
     z_index: i32,
 }
 
-impl SwayWindow {
-    fn diff(&self, other: &Self) -> Option<ebc::WindowUpdate> {
-        if self != other {
-            let &Self {
-                ref title,
-                ref area,
-                visible,
-                fullscreen,
-                hint,
-                z_index,
-                ..
-            } = other;
-
-            Some(ebc::WindowUpdate {
-                title: if &self.title != title {
-                    Some(title.clone())
-                } else {
-                    None
-                },
-                area: if &self.area != area {
-                    Some(area.clone())
-                } else {
-                    None
-                },
-                visible: if self.visible != visible {
-                    Some(visible)
-                } else {
-                    None
-                },
-                fullscreen: if self.fullscreen != fullscreen {
-                    Some(fullscreen)
-                } else {
-                    None
-                },
-                hint: if self.hint != hint { Some(hint) } else { None },
-                z_index: if self.z_index != z_index {
-                    Some(z_index)
-                } else {
-                    None
-                },
-            })
-        } else {
-            None
+impl SyncWindow for SwayWindow {
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn pid(&self) -> pid_t {
+        self.pid
+    }
+
+    fn app_id(&self) -> &str {
+        &self.app_id
+    }
+
+    fn state(&self) -> WindowState {
+        WindowState {
+            title: self.title.clone(),
+            area: self.area.clone(),
+            hint: self.hint,
+            visible: self.visible,
+            fullscreen: self.fullscreen,
+            z_index: self.z_index,
+            focused: self.focused,
         }
     }
 }
@@ -112,52 +91,71 @@ impl TryFrom<&Node> for SwayWindow {
 
         let title = node.name.as_deref().unwrap_or("NO_TITLE").to_owned();
 
+        // Native wayland clients report an app_id, XWayland ones only have a class.
+        let app_id = node
+            .app_id
+            .clone()
+            .or_else(|| node.window_properties.as_ref()?.class.clone())
+            .unwrap_or_default();
+
         let area = Rect::from_xywh(x, y, width, height);
 
-        let hint = node.marks.iter().find_map(|m| {
-            if m.starts_with("ebchint:") || m.starts_with("_ebchint:") {
-                m.split(':')
-                    .nth(2)
-                    .and_then(|s| Hint::try_from_human_readable(s).ok())
-            } else {
-                None
-            }
-        });
+        // Sub-surface hints aren't forwarded until the core supports sub-surfaces
+        let utils::MarkHints {
+            window: hint,
+            malformed: malformed_marks,
+            ..
+        } = utils::mark_hints(node.marks.iter().map(String::as_str));
 
         Ok(Self {
             id: node.id,
             pid,
+            app_id,
             title,
             area,
             visible,
             floating: node.node_type == NodeType::FloatingCon,
             fullscreen: node.fullscreen_mode.unwrap_or_default() != 0,
+            focused: node.focused,
             hint,
+            malformed_marks,
             z_index: 0,
             //_data
         })
     }
 }
 
+/// Report the hint marks of a window which couldn't be parsed.
+///
+/// With strict hints, they are logged as errors so that typos don't go unnoticed.
+fn report_malformed_marks(win: &SwayWindow, strict: bool) {
+    for mark in &win.malformed_marks {
+        if strict {
+            error!("Malformed hint mark '{mark}' on window '{}'", win.title);
+        } else {
+            warn!("Ignoring malformed hint mark '{mark}'");
+        }
+    }
+}
+
 pub struct SwayBridge {
     swayipc: Connection,
     swayevents: EventStream,
-    transform: Matrix3<f64>,
-    app_meta: HashMap<pid_t, (String, HashSet<i64>)>,
-    window_meta: HashMap<i64, (String, SwayWindow)>,
+    transform: utils::OutputTransform,
+    sync: BridgeSync<SwayWindow>,
+    shared: Shared,
+    strict_hints: bool,
 }
 
 impl SwayBridge {
-    const OUTPUT_NAME: &str = "DPI-1";
+    const OUTPUT_NAME: &str = PixelManager::PANEL_OUTPUT;
 
-    pub async fn new() -> Result<Self> {
+    pub async fn new(shared: Shared, strict_hints: bool) -> Result<Self> {
         let mut swayipc = Connection::new()
             .await
             .context("Failed to connect to Sway IPC")?;
 
-        let transform = utils::get_output(&mut swayipc, Self::OUTPUT_NAME)
-            .await
-            .and_then(|o| utils::output_to_transform(&o))?;
+        let transform = Self::output_transform(&mut swayipc).await;
 
         let events = vec![
             EventType::Output,
@@ -177,125 +175,22 @@ impl SwayBridge {
             swayipc,
             swayevents,
             transform,
-            app_meta: Default::default(),
-            window_meta: Default::default(),
+            sync: Default::default(),
+            shared,
+            strict_hints,
         })
     }
 
-    /// Add an application
-    async fn add_app(&mut self, pid: pid_t, tx: &mut ebc::CommandSender) -> Result<()> {
-        let (ret_tx, ret_rx) = oneshot::channel::<String>();
-        let app_key = tx
-            .with_reply(ebc::command::Application::Add(pid, ret_tx), ret_rx)
+    /// Transform from the e-ink output layout to the panel. Without one, windows are kept
+    /// untransformed rather than dropped.
+    async fn output_transform(swayipc: &mut Connection) -> utils::OutputTransform {
+        utils::get_output(swayipc, Self::OUTPUT_NAME)
             .await
-            .context("Failed to add application '{pid}'")?;
-
-        self.app_meta
-            .insert(pid, (app_key.clone(), Default::default()));
-
-        Ok(())
-    }
-
-    /// Remove stale app from the app_meta map, notifying the EbcService in the process.
-    async fn remove_stale_apps(
-        &mut self,
-        stale_pid: Vec<pid_t>,
-        tx: &mut ebc::CommandSender,
-    ) -> Result<()> {
-        for p in stale_pid {
-            let Some((app_key, win_ids)) = self.app_meta.remove(&p) else {
-                continue;
-            };
-
-            tx.send(ebc::command::Application::Remove(app_key))
-                .await
-                .context("Failed to send remove '{app_key}'")?;
-
-            for id in win_ids {
-                self.window_meta.remove(&id);
-            }
-        }
-
-        Ok(())
-    }
-
-    /// Add a new window
-    async fn add_window(&mut self, win: SwayWindow, tx: &mut ebc::CommandSender) -> Result<()> {
-        let (rtx, rx) = oneshot::channel::<String>();
-
-        let app_meta = self
-            .app_meta
-            .get_mut(&win.pid)
-            .expect("Window should be added after apps");
-        let app_key = app_meta.0.clone();
-
-        let cmd = ebc::command::Window::Add {
-            app_key,
-            title: win.title.clone(),
-            area: win.area.clone(),
-            hint: win.hint,
-            visible: win.visible,
-            fullscreen: win.fullscreen,
-            z_index: win.z_index,
-            reply: rtx,
-        };
-
-        let win_key = tx
-            .with_reply(cmd, rx)
-            .await
-            .with_context(|| "Failed to add window '{title}")?;
-
-        let id = win.id;
-        self.window_meta.insert(id, (win_key, win));
-        app_meta.1.insert(id);
-
-        Ok(())
-    }
-
-    /// Update Window
-    async fn update_window(
-        &mut self,
-        up_win: SwayWindow,
-        tx: &mut ebc::CommandSender,
-    ) -> Result<()> {
-        let &mut (ref win_key, ref mut win) = self.window_meta.get_mut(&up_win.id).unwrap();
-
-        if let Some(update) = win.diff(&up_win) {
-            tx.send(ebc::command::Window::Update {
-                win_key: win_key.clone(),
-                update,
+            .and_then(|o| utils::output_to_transform(&o))
+            .unwrap_or_else(|e| {
+                error!("{e:#?}");
+                utils::OutputTransform::identity()
             })
-            .await
-            .context("Failed to update window '{win_key}'")?;
-
-            self.window_meta
-                .entry(up_win.id)
-                .and_modify(|e| e.1 = up_win);
-        }
-
-        Ok(())
-    }
-
-    async fn remove_stale_windows(
-        &mut self,
-        stale_id: Vec<i64>,
-        tx: &mut ebc::CommandSender,
-    ) -> Result<()> {
-        for wid in stale_id {
-            let Some((win_key, win)) = self.window_meta.remove(&wid) else {
-                continue;
-            };
-
-            tx.send(ebc::command::Window::Remove(win_key))
-                .await
-                .context("Failed to remove window '{win_key}'")?;
-
-            self.app_meta.entry(win.pid).and_modify(|e| {
-                e.1.remove(&wid);
-            });
-        }
-
-        Ok(())
     }
 
     async fn process_tree(&mut self, tx: &mut ebc::CommandSender) -> Result<()> {
@@ -305,72 +200,39 @@ impl SwayBridge {
             .await
             .context("Failed to get Sway Tree")?;
 
-        let Some(output) = swaytree.find_as_ref(|n| {
-            n.node_type == NodeType::Output && n.name.as_deref().unwrap_or("") == Self::OUTPUT_NAME
-        }) else {
-            bail!("Could not find output '{}'", Self::OUTPUT_NAME)
-        };
-        let Some(workspace) = output.find_focused_as_ref(|n| n.node_type == NodeType::Workspace)
-        else {
-            bail!("No focused workspace for output '{}", Self::OUTPUT_NAME)
-        };
-
-        let (pid_set, windows) = utils::get_all_windows_and_app(workspace, &self.transform);
+        // Window coordinates are made relative to the output by the transform.
+        let workspace = utils::output_workspace(&swaytree, Self::OUTPUT_NAME)?;
+        let (_, windows) = utils::get_all_windows_and_app(workspace, &self.transform);
 
-        let stale_pid: Vec<pid_t> = self
-            .app_meta
-            .keys()
-            .filter(|k| !pid_set.contains(k))
-            .copied()
-            .collect();
-
-        self.remove_stale_apps(stale_pid, tx)
-            .await
-            .context("SwayBridge::remove_stale_apps failed")?;
-
-        for pid in pid_set {
-            if !self.app_meta.contains_key(&pid) {
-                self.add_app(pid, tx)
-                    .await
-                    .context("SwayBridge::add_app failed")?;
+        for win in &windows {
+            let reported = self.sync.window(win.id).map(|w| &w.malformed_marks);
+            if reported != Some(&win.malformed_marks) {
+                report_malformed_marks(win, self.strict_hints);
             }
         }
 
-        let mut win_set: HashSet<i64> = HashSet::new();
-        for w in windows {
-            win_set.insert(w.id);
-
-            if !self.window_meta.contains_key(&w.id) {
-                self.add_window(w, tx)
-                    .await
-                    .context("SwayBridge::add_window failed")?;
-            } else {
-                self.update_window(w, tx)
-                    .await
-                    .context("SwayBridge::update_window failed")?;
-            }
-        }
-
-        let stale_window = self
-            .window_meta
-            .keys()
-            .filter(|k| !win_set.contains(k))
-            .copied()
-            .collect();
-
-        self.remove_stale_windows(stale_window, tx)
+        self.sync
+            .sync(windows, tx)
             .await
-            .context("SwayBridge::remove_stale_window failed")?;
-
-        Ok(())
+            .context("Failed to sync windows")
     }
 
-    pub async fn run(mut self, tx: Sender<ebc::Command>) -> Result<()> {
-        let mut tx: ebc::CommandSender = tx.into();
+    pub async fn run(mut self, mut tx: ebc::CommandSender) -> Result<()> {
         let mut process_tree = true;
+        let mut paused = self.shared.watch_bridge_paused();
 
         loop {
-            if process_tree {
+            // Updates were skipped while paused, and the core may have been changed meanwhile,
+            // e.g. by LoadLayout: register everything from scratch.
+            if paused.has_changed().unwrap_or(false) && !*paused.borrow_and_update() {
+                if let Err(e) = self.sync.reset(&mut tx).await {
+                    error!("Failed to reset the windows: {e:?}");
+                }
+                process_tree = true;
+            }
+
+            // While paused, keep the pending tree processing for when we resume.
+            if process_tree && !self.shared.bridge_paused() {
                 if let Err(e) = self
                     .process_tree(&mut tx)
                     .await
@@ -392,18 +254,7 @@ impl SwayBridge {
                             process_tree = true;
                         }
                         Event::Output(_) => {
-                            match utils::get_output(&mut self.swayipc, Self::OUTPUT_NAME)
-                                .await
-                                .and_then(|o| utils::output_to_transform(&o))
-                            {
-                                Ok(t) => {
-                                    self.transform = t;
-                                }
-                                Err(e) => {
-                                    self.transform = Matrix3::identity();
-                                    error!("{e:#?}");
-                                }
-                            }
+                            self.transform = Self::output_transform(&mut self.swayipc).await;
                             process_tree = true;
                         }
                         Event::Workspace(_) => {
@@ -427,13 +278,19 @@ impl SwayBridge {
 
 const SWAY_BRIDGE: &str = "Sway";
 
-pub async fn start(tx: mpsc::Sender<ebc::Command>) -> Result<String> {
-    let sway_bridge = SwayBridge::new()
+pub async fn start(tx: ebc::CommandSender, shared: Shared, strict_hints: bool) -> Result<String> {
+    let sway_bridge = SwayBridge::new(shared.clone(), strict_hints)
         .await
         .context("While trying to start Sway bridge")?;
 
     tokio::spawn(async move {
-        let _ = sway_bridge.run(tx).await;
+        shared.set_bridge_connected(true);
+        match shared.until_shutdown(sway_bridge.run(tx)).await {
+            Some(Err(e)) => error!("Sway bridge stopped: {e:#}"),
+            Some(Ok(())) => {}
+            None => info!("Sway bridge stopped for shutdown"),
+        }
+        shared.set_bridge_connected(false);
     });
 
     Ok(SWAY_BRIDGE.into())
@@ -1,13 +1,35 @@
-use std::collections::HashSet;
+use std::{collections::HashMap, sync::Once};
 
-use anyhow::{Context, Result, anyhow, bail};
+use anyhow::{Context, Result, anyhow};
+use log::{debug, warn};
 use nalgebra::{Matrix3, Matrix3x2, Scale2, Translation2, Vector2};
 use nix::libc::pid_t;
-use pinenote_service::types::Rect;
-use swayipc_async::{Connection, Node, Output, Rect as SwayRect};
+use pinenote_service::types::{
+    Rect,
+    rockchip_ebc::{self, Hint},
+};
+use swayipc_async::{Connection, Node, NodeType, Output, Rect as SwayRect};
 
 use super::SwayWindow;
 
+/// Mapping from the e-ink output layout to the panel.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct OutputTransform {
+    pub matrix: Matrix3<f64>,
+    /// Area of the output on the panel, transformed rectangles are clamped to it.
+    pub screen: Rect,
+}
+
+impl OutputTransform {
+    /// Keep rectangles as they are, without an output to clamp them to.
+    pub fn identity() -> Self {
+        Self {
+            matrix: Matrix3::identity(),
+            screen: Rect::new(i32::MIN, i32::MIN, i32::MAX, i32::MAX),
+        }
+    }
+}
+
 struct StandardNodeIterator<'a> {
     queue: Vec<&'a Node>,
 }
@@ -30,10 +52,23 @@ fn iter_standard<'a>(node: &'a Node) -> StandardNodeIterator<'a> {
     StandardNodeIterator { queue: vec![node] }
 }
 
+/// Workspace visible on the output `name` of the sway tree.
+///
+/// Only this workspace is considered, windows on other outputs are never part of it.
+pub(super) fn output_workspace<'a>(tree: &'a Node, name: &str) -> Result<&'a Node> {
+    let output = tree
+        .find_as_ref(|n| n.node_type == NodeType::Output && n.name.as_deref() == Some(name))
+        .ok_or(anyhow!("Could not find output '{name}'"))?;
+
+    output
+        .find_focused_as_ref(|n| n.node_type == NodeType::Workspace)
+        .ok_or(anyhow!("No focused workspace for output '{name}'"))
+}
+
 pub(super) fn get_all_windows_and_app(
     workspace: &Node,
-    transform: &Matrix3<f64>,
-) -> (HashSet<pid_t>, Vec<SwayWindow>) {
+    transform: &OutputTransform,
+) -> (HashMap<pid_t, String>, Vec<SwayWindow>) {
     let mut floating_idx = 1;
 
     workspace
@@ -41,23 +76,101 @@ pub(super) fn get_all_windows_and_app(
         .iter()
         .chain(iter_standard(workspace))
         .filter_map(|n| {
-            SwayWindow::try_from(n).ok().map(|n| {
-                let area = apply_transform(n.area, transform);
-
-                if n.floating {
-                    let z_index = floating_idx;
-                    floating_idx += 1;
-
-                    SwayWindow { z_index, area, ..n }
-                } else {
-                    SwayWindow { area, ..n }
-                }
-            })
+            let n = SwayWindow::try_from(n).ok()?;
+            let Some(area) = apply_transform(n.area.clone(), transform) else {
+                warn!("Skipping window {}: degenerate output transform", n.id);
+                return None;
+            };
+
+            // Windows scaled to nothing cover no pixel, don't spend an app or window on them
+            if area.area() == 0 {
+                debug!("Skipping window {}: empty area {area:?}", n.id);
+                return None;
+            }
+
+            if n.floating {
+                let z_index = floating_idx;
+                floating_idx += 1;
+
+                Some(SwayWindow { z_index, area, ..n })
+            } else {
+                Some(SwayWindow { area, ..n })
+            }
         })
-        .map(|w| (w.pid, w))
+        .map(|w| ((w.pid, w.app_id.clone()), w))
         .collect()
 }
 
+/// Extract the hint from an `ebchint` mark.
+///
+/// Hint marks have the form `ebchint:<UNIQUE>:<HINT>` or `_ebchint:<UNIQUE>:<HINT>`. Returns
+/// None if the mark isn't a hint mark, and an error if it is but the hint can't be parsed.
+pub(super) fn mark_hint(mark: &str) -> Option<Result<Hint, rockchip_ebc::Error>> {
+    if !(mark.starts_with("ebchint:") || mark.starts_with("_ebchint:")) {
+        return None;
+    }
+
+    Some(
+        mark.split(':')
+            .nth(2)
+            .ok_or(rockchip_ebc::Error::Invalid)
+            .and_then(Hint::try_from_human_readable),
+    )
+}
+
+/// Hints collected from all the `ebchint` marks of a window.
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct MarkHints {
+    /// Hint for the whole window.
+    pub window: Option<Hint>,
+    /// Sub-surface hints, ordered by index. Indices may have gaps.
+    pub subsurfaces: Vec<(u32, Hint)>,
+    /// Hint marks whose hint can't be parsed, left for the caller to report.
+    pub malformed: Vec<String>,
+}
+
+/// Collect the hints of all the `ebchint` marks of a window.
+///
+/// A numeric `<UNIQUE>` is treated as a sub-surface index, anything else as the window hint.
+/// When several marks share an index, or several non-indexed marks exist, the first one wins.
+/// Sub-surface 0 is the window itself, so it is used as window hint if there's no other.
+pub(super) fn mark_hints<'a>(marks: impl IntoIterator<Item = &'a str>) -> MarkHints {
+    let mut hints = MarkHints::default();
+
+    for mark in marks {
+        let hint = match mark_hint(mark) {
+            None => continue,
+            Some(Ok(hint)) => hint,
+            Some(Err(_)) => {
+                hints.malformed.push(mark.to_owned());
+                continue;
+            }
+        };
+
+        match mark.split(':').nth(1).map(str::parse::<u32>) {
+            Some(Ok(index)) if hints.subsurfaces.iter().any(|&(i, _)| i == index) => {
+                warn!("Ignoring hint mark '{mark}': duplicate sub-surface index {index}")
+            }
+            Some(Ok(index)) => hints.subsurfaces.push((index, hint)),
+            _ => {
+                hints.window.get_or_insert(hint);
+            }
+        }
+    }
+
+    hints.subsurfaces.sort_by_key(|&(i, _)| i);
+
+    if hints.window.is_none() {
+        hints.window = hints
+            .subsurfaces
+            .first()
+            .filter(|&&(i, _)| i == 0)
+            .map(|&(_, hint)| hint);
+    }
+
+    hints
+}
+
 pub(super) async fn get_output(ipc: &mut Connection, name: &str) -> Result<Output> {
     ipc.get_outputs()
         .await
@@ -67,7 +180,7 @@ pub(super) async fn get_output(ipc: &mut Connection, name: &str) -> Result<Outpu
         .ok_or(anyhow!("Failed to find output '{}'", name))
 }
 
-pub(super) fn output_to_transform(output: &Output) -> Result<Matrix3<f64>> {
+pub(super) fn output_to_transform(output: &Output) -> Result<OutputTransform> {
     let scale = output
         .scale
         .ok_or(anyhow!("Could not get output scale"))
@@ -89,44 +202,466 @@ pub(super) fn output_to_transform(output: &Output) -> Result<Matrix3<f64>> {
 
     let rel_to_abs = Translation2::new(-x as f64, -y as f64);
 
-    let iso = match output
+    // Hints roughly matching beat no hint at all, so carry on without rotation
+    let (flipped, degrees) = output
         .transform
         .as_deref()
-        .ok_or(anyhow!("Bad transform"))?
-    {
-        "normal" => nalgebra::Isometry2::identity(),
-        "90" => nalgebra::Isometry2::new(Vector2::new(height as f64, 0.0), 90_f64.to_radians()),
-        "180" => nalgebra::Isometry2::new(
+        .and_then(parse_transform)
+        .unwrap_or_else(|| {
+            static UNKNOWN_TRANSFORM: Once = Once::new();
+            UNKNOWN_TRANSFORM.call_once(|| {
+                warn!(
+                    "Unknown transform {:?} for output '{}', assuming no rotation",
+                    output.transform, output.name
+                )
+            });
+
+            (false, 0)
+        });
+
+    // Flipped transforms mirror the output around its vertical axis before rotating it.
+    let flip = if flipped {
+        Matrix3::new(-1.0, 0.0, width as f64, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0)
+    } else {
+        Matrix3::identity()
+    };
+
+    let iso = match degrees {
+        90 => nalgebra::Isometry2::new(Vector2::new(height as f64, 0.0), 90_f64.to_radians()),
+        180 => nalgebra::Isometry2::new(
             Vector2::new(width as f64, height as f64),
             180_f64.to_radians(),
         ),
-        "270" => nalgebra::Isometry2::new(Vector2::new(0f64, width as f64), 270_f64.to_radians()),
-        _ => {
-            bail!("Unsupported transform")
-        }
+        270 => nalgebra::Isometry2::new(Vector2::new(0f64, width as f64), 270_f64.to_radians()),
+        _ => nalgebra::Isometry2::identity(),
+    };
+
+    let matrix = scale.to_homogeneous() * iso.to_homogeneous() * flip * rel_to_abs.to_homogeneous();
+    let screen = transform_rect(Rect::from_xywh(x, y, width, height), &matrix)
+        .ok_or(anyhow!("Degenerate transform for output '{}'", output.name))?;
+
+    Ok(OutputTransform { matrix, screen })
+}
+
+/// Mirroring and clockwise rotation in degrees of a sway output transform, `None` if unknown.
+fn parse_transform(transform: &str) -> Option<(bool, u16)> {
+    let (flipped, rotation) = match transform.strip_prefix("flipped") {
+        None => (false, transform),
+        Some("") => (true, "normal"),
+        Some(rotation) => (true, rotation.strip_prefix('-')?),
+    };
+
+    let degrees = match rotation {
+        "normal" => 0,
+        "90" => 90,
+        "180" => 180,
+        "270" => 270,
+        _ => return None,
     };
 
-    let transform = scale.to_homogeneous() * iso.to_homogeneous() * rel_to_abs.to_homogeneous();
+    Some((flipped, degrees))
+}
+
+/// Apply an output transform to a rectangle.
+///
+/// Coordinates are clamped to the output area on the panel, a rectangle outside of it ends up
+/// empty. Returns None if the transform yields non-finite coordinates, e.g. for a degenerate
+/// matrix.
+pub(super) fn apply_transform(rect: Rect, transform: &OutputTransform) -> Option<Rect> {
+    let Rect { x1, y1, x2, y2 } = transform_rect(rect, &transform.matrix)?;
+    let screen = &transform.screen;
 
-    Ok(transform)
+    Some(Rect::new(
+        x1.clamp(screen.x1, screen.x2),
+        y1.clamp(screen.y1, screen.y2),
+        x2.clamp(screen.x1, screen.x2),
+        y2.clamp(screen.y1, screen.y2),
+    ))
 }
 
-pub(super) fn apply_transform(rect: Rect, transform: &Matrix3<f64>) -> Rect {
+/// Coordinates are rounded to the nearest integer, so that floating point errors in rotations
+/// don't shift edges by one pixel.
+fn transform_rect(rect: Rect, matrix: &Matrix3<f64>) -> Option<Rect> {
     let Rect { x1, y1, x2, y2 } = rect;
 
-    let r = (transform * Matrix3x2::new(x1 as f64, x2 as f64, y1 as f64, y2 as f64, 1_f64, 1_f64))
-        .map(|f| f as i32);
+    let r = matrix * Matrix3x2::new(x1 as f64, x2 as f64, y1 as f64, y2 as f64, 1_f64, 1_f64);
+
+    if r.iter().any(|f| !f.is_finite()) {
+        return None;
+    }
+
+    let r = r.map(|f| f.round().clamp(i32::MIN as f64, i32::MAX as f64) as i32);
+
+    // Rotations swap corners around, the result has to be normalized.
+    Some(Rect::new(r[(0, 0)], r[(1, 0)], r[(0, 1)], r[(1, 1)]).normalized())
+}
+
+#[cfg(test)]
+mod tests {
+    use pinenote_service::types::rockchip_ebc::{HintBitDepth, HintConvertMode};
+
+    use super::*;
+
+    #[test]
+    fn mark_hint_valid() {
+        let expected = Hint::new(HintBitDepth::Y2, HintConvertMode::Dither, true);
+
+        assert_eq!(
+            Some(expected),
+            mark_hint("ebchint:abc:Y2|D|R").and_then(Result::ok)
+        );
+        assert_eq!(
+            Some(expected),
+            mark_hint("_ebchint:abc:Y2|D|R").and_then(Result::ok)
+        );
+    }
+
+    #[test]
+    fn mark_hint_malformed() {
+        assert!(matches!(mark_hint("ebchint:abc:Y3|D"), Some(Err(_))));
+        assert!(matches!(mark_hint("ebchint:abc"), Some(Err(_))));
+    }
+
+    #[test]
+    fn mark_hint_not_a_hint() {
+        assert!(mark_hint("some_mark").is_none());
+        assert!(mark_hint("xebchint:abc:Y4").is_none());
+    }
+
+    fn hint(s: &str) -> Hint {
+        Hint::try_from_human_readable(s).unwrap()
+    }
+
+    #[test]
+    fn mark_hints_ordered_subsurfaces() {
+        let hints = mark_hints(["ebchint:1:Y1|T", "some_mark", "_ebchint:0:Y4|D"]);
+
+        assert_eq!(Some(hint("Y4|D")), hints.window);
+        assert_eq!(
+            vec![(0, hint("Y4|D")), (1, hint("Y1|T"))],
+            hints.subsurfaces
+        );
+    }
+
+    #[test]
+    fn mark_hints_gaps_and_duplicates() {
+        let hints = mark_hints([
+            "ebchint:3:Y2",
+            "ebchint:1:Y1|T",
+            "ebchint:3:Y4",
+            "ebchint:2:Y3",
+        ]);
+
+        assert_eq!(None, hints.window);
+        assert_eq!(vec![(1, hint("Y1|T")), (3, hint("Y2"))], hints.subsurfaces);
+        assert_eq!(vec!["ebchint:2:Y3".to_string()], hints.malformed);
+    }
+
+    #[test]
+    fn mark_hints_window_mark() {
+        let hints = mark_hints(["ebchint:0:Y1", "ebchint:abc:Y4|R"]);
+
+        assert_eq!(Some(hint("Y4|R")), hints.window);
+        assert_eq!(vec![(0, hint("Y1"))], hints.subsurfaces);
+    }
+
+    const SCREEN: Rect = Rect::new(0, 0, 1872, 1404);
+
+    fn on_screen(matrix: Matrix3<f64>) -> OutputTransform {
+        OutputTransform {
+            matrix,
+            screen: SCREEN,
+        }
+    }
+
+    #[test]
+    fn apply_transform_identity() {
+        let rect = Rect::new(10, 20, 30, 40);
+
+        assert_eq!(
+            Some(rect.clone()),
+            apply_transform(rect.clone(), &OutputTransform::identity())
+        );
+        assert_eq!(
+            Some(rect.clone()),
+            apply_transform(rect, &on_screen(Matrix3::identity()))
+        );
+    }
+
+    #[test]
+    fn apply_transform_clamps_extreme() {
+        let transform = on_screen(Matrix3::new_scaling(1e12));
+
+        assert_eq!(
+            Some(SCREEN),
+            apply_transform(Rect::new(-10, 0, 10, 20), &transform)
+        );
+    }
+
+    #[test]
+    fn apply_transform_clamps_to_screen() {
+        let transform = on_screen(Matrix3::identity());
+
+        assert_eq!(
+            Some(Rect::new(1800, 0, 1872, 100)),
+            apply_transform(Rect::new(1800, -50, 1900, 100), &transform)
+        );
+        // Off screen rectangles end up empty
+        assert_eq!(
+            Some(Rect::new(1872, 100, 1872, 200)),
+            apply_transform(Rect::new(2000, 100, 2100, 200), &transform)
+        );
+    }
+
+    #[test]
+    fn apply_transform_non_finite() {
+        let nan = on_screen(Matrix3::new_scaling(f64::NAN));
+        let inf = on_screen(Matrix3::new_scaling(f64::INFINITY));
+        let rect = Rect::new(10, 20, 30, 40);
+
+        assert_eq!(None, apply_transform(rect.clone(), &nan));
+        assert_eq!(None, apply_transform(rect, &inf));
+    }
+
+    fn output(rect: (i32, i32, i32, i32), scale: f64, transform: &str) -> Output {
+        let (x, y, width, height) = rect;
+
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "name": "DPI-1",
+            "make": "",
+            "model": "",
+            "serial": "",
+            "active": true,
+            "dpms": true,
+            "primary": false,
+            "scale": scale,
+            "subpixel_hinting": null,
+            "transform": transform,
+            "current_workspace": null,
+            "current_mode": null,
+            "rect": { "x": x, "y": y, "width": width, "height": height },
+        }))
+        .unwrap()
+    }
+
+    fn view(id: i64, pid: i32, rect: (i32, i32, i32, i32)) -> serde_json::Value {
+        let (x, y, width, height) = rect;
+
+        serde_json::json!({
+            "id": id,
+            "name": format!("window{id}"),
+            "type": "con",
+            "border": "none",
+            "current_border_width": 0,
+            "layout": "none",
+            "rect": { "x": x, "y": y, "width": width, "height": height },
+            "window_rect": { "x": 0, "y": 0, "width": width, "height": height },
+            "deco_rect": { "x": 0, "y": 0, "width": 0, "height": 0 },
+            "geometry": { "x": 0, "y": 0, "width": width, "height": height },
+            "urgent": false,
+            "focused": false,
+            "focus": [],
+            "nodes": [],
+            "floating_nodes": [],
+            "sticky": false,
+            "app_id": format!("app{pid}"),
+            "pid": pid,
+            "shell": "xdg_shell",
+            "visible": true,
+        })
+    }
+
+    /// Non-window node of type `node_type`, with `nodes` as children and `focus` as focus order.
+    fn container(
+        id: i64,
+        node_type: &str,
+        name: &str,
+        nodes: Vec<serde_json::Value>,
+        focus: Vec<i64>,
+    ) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "name": name,
+            "type": node_type,
+            "border": "none",
+            "current_border_width": 0,
+            "layout": "splith",
+            "rect": { "x": 0, "y": 0, "width": 1872, "height": 1404 },
+            "window_rect": { "x": 0, "y": 0, "width": 0, "height": 0 },
+            "deco_rect": { "x": 0, "y": 0, "width": 0, "height": 0 },
+            "geometry": { "x": 0, "y": 0, "width": 0, "height": 0 },
+            "urgent": false,
+            "focused": false,
+            "focus": focus,
+            "nodes": nodes,
+            "floating_nodes": [],
+            "sticky": false,
+        })
+    }
+
+    fn workspace(views: Vec<serde_json::Value>) -> Node {
+        serde_json::from_value(container(1, "workspace", "1", views, vec![])).unwrap()
+    }
+
+    #[test]
+    fn empty_windows_skipped() {
+        let workspace = workspace(vec![
+            view(10, 100, (0, 0, 800, 600)),
+            view(11, 101, (800, 0, 0, 600)),
+            view(12, 102, (800, 0, 400, 0)),
+        ]);
+
+        let (apps, windows) = get_all_windows_and_app(&workspace, &OutputTransform::identity());
+
+        assert_eq!(HashMap::from([(100, "app100".to_string())]), apps);
+        assert_eq!(vec![10], windows.iter().map(|w| w.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn windows_scaled_to_nothing_skipped() {
+        let workspace = workspace(vec![view(10, 100, (0, 0, 800, 1))]);
+
+        let (apps, windows) = get_all_windows_and_app(
+            &workspace,
+            &on_screen(Matrix3::new_nonuniform_scaling(&Vector2::new(1.0, 0.4))),
+        );
+
+        assert!(apps.is_empty());
+        assert!(windows.is_empty());
+    }
+
+    #[test]
+    fn windows_on_other_outputs_ignored() {
+        // An external monitor at the origin, the e-ink output on its right
+        let output_node = |id, name, workspace_id, views| {
+            let workspace = container(workspace_id, "workspace", name, views, vec![]);
+            container(id, "output", name, vec![workspace], vec![workspace_id])
+        };
+        let tree = container(
+            1,
+            "root",
+            "root",
+            vec![
+                output_node(2, "HDMI-A-1", 3, vec![view(10, 100, (0, 0, 1920, 1080))]),
+                output_node(4, "DPI-1", 5, vec![view(11, 101, (1920, 0, 800, 600))]),
+            ],
+            vec![2, 4],
+        );
+        let tree: Node = serde_json::from_value(tree).unwrap();
+        let transform = output_to_transform(&output((1920, 0, 1872, 1404), 1.0, "normal")).unwrap();
+
+        let workspace = output_workspace(&tree, "DPI-1").unwrap();
+        let (apps, windows) = get_all_windows_and_app(workspace, &transform);
+
+        assert_eq!(HashMap::from([(101, "app101".to_string())]), apps);
+        assert_eq!(
+            vec![Rect::from_xywh(0, 0, 800, 600)],
+            windows.into_iter().map(|w| w.area).collect::<Vec<_>>()
+        );
+        assert!(output_workspace(&tree, "HDMI-A-2").is_err());
+    }
 
-    let mut xs = [*r.index((0, 0)), *r.index((0, 1))];
-    let mut ys = [*r.index((1, 0)), *r.index((1, 1))];
+    /// Map the 10x20 rectangle at the output's top-left corner to panel coordinates.
+    fn map_corner(output: &Output) -> Option<Rect> {
+        let Output { rect, .. } = output;
+        let transform = output_to_transform(output).unwrap();
+
+        apply_transform(Rect::from_xywh(rect.x, rect.y, 10, 20), &transform)
+    }
+
+    #[test]
+    fn output_transform_normal() {
+        let output = output((0, 0, 1872, 1404), 1.0, "normal");
+
+        assert_eq!(Some(Rect::new(0, 0, 10, 20)), map_corner(&output));
+    }
+
+    #[test]
+    fn output_transform_normal_scaled_offset() {
+        let output = output((100, 50, 936, 702), 2.0, "normal");
+
+        assert_eq!(Some(Rect::new(0, 0, 20, 40)), map_corner(&output));
+        assert_eq!(SCREEN, output_to_transform(&output).unwrap().screen);
+    }
+
+    #[test]
+    fn output_transform_90() {
+        let output = output((0, 0, 1404, 1872), 1.0, "90");
+
+        assert_eq!(Some(Rect::new(1852, 0, 1872, 10)), map_corner(&output));
+        assert_eq!(SCREEN, output_to_transform(&output).unwrap().screen);
+    }
+
+    #[test]
+    fn output_transform_180() {
+        let output = output((0, 0, 1872, 1404), 1.0, "180");
+
+        assert_eq!(Some(Rect::new(1862, 1384, 1872, 1404)), map_corner(&output));
+    }
+
+    #[test]
+    fn output_transform_270() {
+        let output = output((0, 0, 1404, 1872), 1.0, "270");
+
+        assert_eq!(Some(Rect::new(0, 1394, 20, 1404)), map_corner(&output));
+    }
+
+    #[test]
+    fn output_transform_flipped() {
+        let output = output((0, 0, 1872, 1404), 1.0, "flipped");
+
+        assert_eq!(Some(Rect::new(1862, 0, 1872, 20)), map_corner(&output));
+    }
+
+    #[test]
+    fn output_transform_flipped_90() {
+        let output = output((0, 0, 1404, 1872), 1.0, "flipped-90");
+
+        assert_eq!(Some(Rect::new(1852, 1394, 1872, 1404)), map_corner(&output));
+    }
+
+    #[test]
+    fn output_transform_flipped_180() {
+        let output = output((0, 0, 1872, 1404), 1.0, "flipped-180");
+
+        assert_eq!(Some(Rect::new(0, 1384, 10, 1404)), map_corner(&output));
+    }
+
+    #[test]
+    fn output_transform_flipped_270() {
+        let output = output((0, 0, 1404, 1872), 1.0, "flipped-270");
+
+        assert_eq!(Some(Rect::new(0, 0, 20, 10)), map_corner(&output));
+    }
+
+    #[test]
+    fn parse_transform_names() {
+        assert_eq!(Some((false, 0)), parse_transform("normal"));
+        assert_eq!(Some((false, 270)), parse_transform("270"));
+        assert_eq!(Some((true, 0)), parse_transform("flipped"));
+        assert_eq!(Some((true, 90)), parse_transform("flipped-90"));
+
+        for transform in ["45", "flipped-45", "flipped90", "Normal", ""] {
+            assert_eq!(None, parse_transform(transform), "{transform}");
+        }
+    }
+
+    #[test]
+    fn output_transform_unsupported() {
+        // Falls back to no rotation, keeping the output scale and position
+        for transform in ["45", "flipped-45", "flipped90"] {
+            let output = output((100, 50, 936, 702), 2.0, transform);
+
+            let expected = Some(Rect::new(0, 0, 20, 40));
+            assert_eq!(expected, map_corner(&output), "{transform}");
+        }
+    }
 
-    xs.sort();
-    ys.sort();
+    #[test]
+    fn output_transform_inactive() {
+        let output = output((0, 0, 1872, 1404), -1.0, "normal");
 
-    Rect {
-        x1: xs[0],
-        y1: ys[0],
-        x2: xs[1],
-        y2: ys[1],
+        assert!(output_to_transform(&output).is_err());
     }
 }
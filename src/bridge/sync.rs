@@ -0,0 +1,554 @@
+//! Keep the core in sync with the windows of a compositor.
+//!
+//! Bridges list the windows they want the core to know about after every compositor event, and
+//! [BridgeSync] turns the differences with the previous list into commands: applications and
+//! windows added, windows updated, and those gone removed.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use nix::libc::pid_t;
+use pinenote_service::types::{Rect, rockchip_ebc::Hint};
+use tokio::sync::oneshot;
+
+use crate::ebc;
+
+/// What the core knows about a window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WindowState {
+    pub title: String,
+    pub area: Rect,
+    pub hint: Option<Hint>,
+    pub visible: bool,
+    pub fullscreen: bool,
+    pub z_index: i32,
+    pub focused: bool,
+}
+
+impl WindowState {
+    /// Update to `new`, None if nothing changed.
+    ///
+    /// Compositors don't know about content margins nor pinning, those are left to clients.
+    pub fn diff(&self, new: &Self) -> Option<ebc::WindowUpdate> {
+        if self == new {
+            return None;
+        }
+
+        Some(ebc::WindowUpdate {
+            title: (self.title != new.title).then(|| new.title.clone()),
+            area: (self.area != new.area).then(|| new.area.clone()),
+            hint: (self.hint != new.hint).then_some(new.hint),
+            visible: (self.visible != new.visible).then_some(new.visible),
+            fullscreen: (self.fullscreen != new.fullscreen).then_some(new.fullscreen),
+            z_index: (self.z_index != new.z_index).then_some(new.z_index),
+            focused: (self.focused != new.focused).then_some(new.focused),
+            content_inset: None,
+            always_on_top: None,
+        })
+    }
+}
+
+/// A compositor window, as tracked by [BridgeSync].
+pub trait SyncWindow {
+    /// Compositor identifier of the window, also used as its external id in the core.
+    fn id(&self) -> i64;
+    /// Process owning the window. Windows of the same process belong to the same application.
+    fn pid(&self) -> pid_t;
+    fn app_id(&self) -> &str;
+    fn state(&self) -> WindowState;
+}
+
+/// Changes between the tracked windows and the current ones, see [BridgeSync::diff].
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    /// Processes without any window left. Removing their application removes their windows.
+    pub removed_apps: Vec<pid_t>,
+    /// Processes with windows for the first time, and their app_id.
+    pub added_apps: Vec<(pid_t, String)>,
+    /// Windows to add, in the order they were listed.
+    pub added: Vec<i64>,
+    /// Windows whose [state](SyncWindow::state) changed.
+    pub updated: Vec<i64>,
+    /// Windows gone, whose application is still there.
+    pub removed: Vec<i64>,
+}
+
+/// Applications and windows the core was told about, by process and window id.
+pub struct BridgeSync<W> {
+    /// Key of the application of each process, and the ids of its windows.
+    apps: HashMap<pid_t, (String, HashSet<i64>)>,
+    /// Key and last known version of each window.
+    windows: HashMap<i64, (String, W)>,
+}
+
+impl<W> Default for BridgeSync<W> {
+    fn default() -> Self {
+        Self {
+            apps: Default::default(),
+            windows: Default::default(),
+        }
+    }
+}
+
+impl<W: SyncWindow> BridgeSync<W> {
+    /// Last known version of a window, None if it isn't tracked.
+    pub fn window(&self, id: i64) -> Option<&W> {
+        self.windows.get(&id).map(|(_, win)| win)
+    }
+
+    /// Compute what changed between the tracked windows and `windows`.
+    pub fn diff(&self, windows: &[W]) -> Diff {
+        let pids: HashSet<pid_t> = windows.iter().map(W::pid).collect();
+        let ids: HashSet<i64> = windows.iter().map(W::id).collect();
+
+        let mut diff = Diff {
+            removed_apps: self
+                .apps
+                .keys()
+                .filter(|pid| !pids.contains(pid))
+                .copied()
+                .collect(),
+            ..Default::default()
+        };
+
+        for win in windows {
+            let new_app = !self.apps.contains_key(&win.pid())
+                && !diff.added_apps.iter().any(|(pid, _)| *pid == win.pid());
+            if new_app {
+                diff.added_apps.push((win.pid(), win.app_id().to_string()));
+            }
+
+            match self.windows.get(&win.id()) {
+                None => diff.added.push(win.id()),
+                Some((_, old)) if old.state() != win.state() => diff.updated.push(win.id()),
+                Some(_) => {}
+            }
+        }
+
+        diff.removed = self
+            .windows
+            .iter()
+            .filter(|(id, (_, win))| !ids.contains(id) && pids.contains(&win.pid()))
+            .map(|(id, _)| *id)
+            .collect();
+
+        diff
+    }
+
+    /// Send the commands bringing the core from the tracked windows to `windows`.
+    pub async fn sync(&mut self, windows: Vec<W>, tx: &mut ebc::CommandSender) -> Result<()> {
+        let diff = self.diff(&windows);
+        let mut windows: HashMap<i64, W> = windows.into_iter().map(|w| (w.id(), w)).collect();
+
+        for pid in diff.removed_apps {
+            self.remove_app(pid, tx)
+                .await
+                .context("BridgeSync::remove_app failed")?;
+        }
+
+        for (pid, app_id) in diff.added_apps {
+            self.add_app(pid, app_id, tx)
+                .await
+                .context("BridgeSync::add_app failed")?;
+        }
+
+        for id in diff.added {
+            let win = windows.remove(&id).expect("Added windows are listed");
+            self.add_window(win, tx)
+                .await
+                .context("BridgeSync::add_window failed")?;
+        }
+
+        for id in diff.updated {
+            let win = windows.remove(&id).expect("Updated windows are listed");
+            self.update_window(win, tx)
+                .await
+                .context("BridgeSync::update_window failed")?;
+        }
+
+        // Unchanged for the core, but the bridge may care about the rest of the window
+        for (id, win) in windows {
+            if let Some(tracked) = self.windows.get_mut(&id) {
+                tracked.1 = win;
+            }
+        }
+
+        for id in diff.removed {
+            self.remove_window(id, tx)
+                .await
+                .context("BridgeSync::remove_window failed")?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove every application the core was told about, along with its windows, and forget
+    /// them: the next [Self::sync] adds everything again.
+    ///
+    /// For when the core may have changed behind the bridge back, e.g. while it was paused.
+    pub async fn reset(&mut self, tx: &mut ebc::CommandSender) -> Result<()> {
+        let mut pids: Vec<pid_t> = self.apps.keys().copied().collect();
+        pids.sort();
+
+        for pid in pids {
+            self.remove_app(pid, tx)
+                .await
+                .context("BridgeSync::remove_app failed")?;
+        }
+        self.windows.clear();
+
+        Ok(())
+    }
+
+    async fn add_app(
+        &mut self,
+        pid: pid_t,
+        app_id: String,
+        tx: &mut ebc::CommandSender,
+    ) -> Result<()> {
+        let (ret_tx, ret_rx) = oneshot::channel::<String>();
+        let app_key = tx
+            .with_reply(ebc::command::Application::Add(app_id, pid, ret_tx), ret_rx)
+            .await
+            .with_context(|| format!("Failed to add application '{pid}'"))?;
+
+        self.apps.insert(pid, (app_key, Default::default()));
+
+        Ok(())
+    }
+
+    /// Remove the application of a process, along with its windows.
+    async fn remove_app(&mut self, pid: pid_t, tx: &mut ebc::CommandSender) -> Result<()> {
+        let Some((app_key, win_ids)) = self.apps.remove(&pid) else {
+            return Ok(());
+        };
+
+        tx.send(ebc::command::Application::Remove(app_key.clone()))
+            .await
+            .with_context(|| format!("Failed to send remove '{app_key}'"))?;
+
+        for id in win_ids {
+            self.windows.remove(&id);
+        }
+
+        Ok(())
+    }
+
+    /// Add a window to the application of its process.
+    ///
+    /// If it fails for the first window, the application is forgotten: the core may prune it
+    /// (see [PixelManager::prune_empty_apps]), the next [Self::sync] adds it again instead.
+    ///
+    /// [PixelManager::prune_empty_apps]: pinenote_service::pixel_manager::PixelManager::prune_empty_apps
+    async fn add_window(&mut self, win: W, tx: &mut ebc::CommandSender) -> Result<()> {
+        let (rtx, rx) = oneshot::channel::<String>();
+
+        let (app_key, win_ids) = self
+            .apps
+            .get(&win.pid())
+            .expect("Window should be added after apps");
+        let first_window = win_ids.is_empty();
+
+        let WindowState {
+            title,
+            area,
+            hint,
+            visible,
+            fullscreen,
+            z_index,
+            focused,
+        } = win.state();
+
+        let cmd = ebc::command::Window::Add {
+            app_key: app_key.clone(),
+            title: title.clone(),
+            area,
+            hint,
+            visible,
+            fullscreen,
+            z_index,
+            focused,
+            external_id: Some(win.id().to_string()),
+            reply: rtx,
+        };
+
+        let win_key = match tx.with_reply(cmd, rx).await {
+            Ok(win_key) => win_key,
+            Err(e) => {
+                if first_window {
+                    self.apps.remove(&win.pid());
+                }
+                return Err(e).with_context(|| format!("Failed to add window '{title}'"));
+            }
+        };
+
+        if let Some((_, win_ids)) = self.apps.get_mut(&win.pid()) {
+            win_ids.insert(win.id());
+        }
+        self.windows.insert(win.id(), (win_key, win));
+
+        Ok(())
+    }
+
+    async fn update_window(&mut self, win: W, tx: &mut ebc::CommandSender) -> Result<()> {
+        let Some((win_key, tracked)) = self.windows.get_mut(&win.id()) else {
+            return Ok(());
+        };
+
+        if let Some(update) = tracked.state().diff(&win.state()) {
+            tx.send(ebc::command::Window::Update {
+                win_key: win_key.clone(),
+                update,
+            })
+            .await
+            .with_context(|| format!("Failed to update window '{win_key}'"))?;
+        }
+
+        *tracked = win;
+
+        Ok(())
+    }
+
+    async fn remove_window(&mut self, id: i64, tx: &mut ebc::CommandSender) -> Result<()> {
+        let Some((win_key, win)) = self.windows.remove(&id) else {
+            return Ok(());
+        };
+
+        tx.send(ebc::command::Window::Remove(win_key.clone()))
+            .await
+            .with_context(|| format!("Failed to remove window '{win_key}'"))?;
+
+        if let Some((_, win_ids)) = self.apps.get_mut(&win.pid()) {
+            win_ids.remove(&id);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ebc::CommandStr;
+
+    #[derive(Clone)]
+    struct TestWindow {
+        id: i64,
+        pid: pid_t,
+        area: Rect,
+    }
+
+    impl SyncWindow for TestWindow {
+        fn id(&self) -> i64 {
+            self.id
+        }
+
+        fn pid(&self) -> pid_t {
+            self.pid
+        }
+
+        fn app_id(&self) -> &str {
+            "app"
+        }
+
+        fn state(&self) -> WindowState {
+            WindowState {
+                title: format!("win{}", self.id),
+                area: self.area.clone(),
+                hint: None,
+                visible: true,
+                fullscreen: false,
+                z_index: 0,
+                focused: false,
+            }
+        }
+    }
+
+    fn win(id: i64, pid: pid_t) -> TestWindow {
+        TestWindow {
+            id,
+            pid,
+            area: Rect::new(0, 0, 100, 100),
+        }
+    }
+
+    /// Track `windows` as if they had been synced.
+    fn tracking(windows: &[TestWindow]) -> BridgeSync<TestWindow> {
+        let mut sync = BridgeSync::default();
+
+        for w in windows {
+            let app = sync
+                .apps
+                .entry(w.pid)
+                .or_insert((format!("app{}", w.pid), HashSet::new()));
+            app.1.insert(w.id);
+            sync.windows
+                .insert(w.id, (format!("key{}", w.id), w.clone()));
+        }
+
+        sync
+    }
+
+    #[test]
+    fn window_state_diff() {
+        let old = win(1, 10).state();
+        assert!(old.diff(&old).is_none());
+
+        let new = WindowState {
+            focused: true,
+            ..old.clone()
+        };
+        let update = old.diff(&new).unwrap();
+        assert_eq!(Some(true), update.focused);
+        assert!(update.area.is_none() && update.title.is_none());
+    }
+
+    #[test]
+    fn diff_detects_added_windows_and_apps() {
+        let sync = tracking(&[win(1, 10)]);
+
+        let diff = sync.diff(&[win(1, 10), win(2, 10), win(3, 20), win(4, 20)]);
+
+        assert_eq!(vec![(20, "app".to_string())], diff.added_apps);
+        assert_eq!(vec![2, 3, 4], diff.added);
+        assert!(diff.updated.is_empty() && diff.removed.is_empty());
+    }
+
+    #[test]
+    fn diff_detects_updated_windows() {
+        let sync = tracking(&[win(1, 10), win(2, 10)]);
+        let moved = TestWindow {
+            area: Rect::new(0, 0, 50, 50),
+            ..win(2, 10)
+        };
+
+        let diff = sync.diff(&[win(1, 10), moved]);
+
+        assert_eq!(vec![2], diff.updated);
+        assert!(diff.added.is_empty() && diff.added_apps.is_empty());
+    }
+
+    #[test]
+    fn diff_detects_removed_windows_and_apps() {
+        let sync = tracking(&[win(1, 10), win(2, 10), win(3, 20)]);
+
+        let diff = sync.diff(&[win(1, 10)]);
+
+        assert_eq!(vec![2], diff.removed);
+        // Removing the application takes care of its windows
+        assert_eq!(vec![20], diff.removed_apps);
+    }
+
+    #[tokio::test]
+    async fn sync_sends_commands() {
+        let (mut tx, mut rx) = ebc::CommandSender::channel(8);
+        let mut bridge = BridgeSync::default();
+
+        let core = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(cmd) = rx.recv().await {
+                received.push(cmd.get_command_str());
+                match cmd {
+                    ebc::Command::Application(ebc::Application::Add(_, pid, reply)) => {
+                        reply.send(format!("app{pid}")).unwrap();
+                    }
+                    ebc::Command::Window(ebc::Window::Add {
+                        external_id, reply, ..
+                    }) => {
+                        reply.send(format!("key{}", external_id.unwrap())).unwrap();
+                    }
+                    _ => {}
+                }
+            }
+            received
+        });
+
+        bridge
+            .sync(vec![win(1, 10), win(2, 10)], &mut tx)
+            .await
+            .unwrap();
+        let moved = TestWindow {
+            area: Rect::new(0, 0, 50, 50),
+            ..win(1, 10)
+        };
+        bridge.sync(vec![moved], &mut tx).await.unwrap();
+        bridge.sync(vec![], &mut tx).await.unwrap();
+        drop(tx);
+
+        let expected = [
+            "Window::Add(app:10)",
+            "Window::Add(app10)",
+            "Window::Add(app10)",
+            "Window::Update(key1)",
+            "Window::Remove(key2)",
+            "Window::Remove(app10)",
+        ];
+        assert_eq!(expected.to_vec(), core.await.unwrap());
+        assert!(bridge.window(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_first_window_forgets_app() {
+        let (mut tx, mut rx) = ebc::CommandSender::channel(8);
+        let mut bridge = BridgeSync::default();
+
+        // Fails the first window addition, like when the application was pruned meanwhile
+        let core = tokio::spawn(async move {
+            let mut received = Vec::new();
+            let mut failed = false;
+            while let Some(cmd) = rx.recv().await {
+                received.push(cmd.get_command_str());
+                match cmd {
+                    ebc::Command::Application(ebc::Application::Add(_, pid, reply)) => {
+                        reply.send(format!("app{pid}")).unwrap();
+                    }
+                    ebc::Command::Window(ebc::Window::Add { .. }) if !failed => failed = true,
+                    ebc::Command::Window(ebc::Window::Add {
+                        external_id, reply, ..
+                    }) => {
+                        reply.send(format!("key{}", external_id.unwrap())).unwrap();
+                    }
+                    _ => {}
+                }
+            }
+            received
+        });
+
+        assert!(bridge.sync(vec![win(1, 10)], &mut tx).await.is_err());
+        let diff = bridge.diff(&[win(1, 10)]);
+        assert_eq!(vec![(10, "app".to_string())], diff.added_apps);
+        bridge.sync(vec![win(1, 10)], &mut tx).await.unwrap();
+        drop(tx);
+
+        let expected = [
+            "Window::Add(app:10)",
+            "Window::Add(app10)",
+            "Window::Add(app:10)",
+            "Window::Add(app10)",
+        ];
+        assert_eq!(expected.to_vec(), core.await.unwrap());
+        assert!(bridge.window(1).is_some());
+    }
+
+    #[tokio::test]
+    async fn reset_removes_everything() {
+        let (mut tx, mut rx) = ebc::CommandSender::channel(8);
+        let mut bridge = tracking(&[win(1, 10), win(2, 10), win(3, 20)]);
+
+        bridge.reset(&mut tx).await.unwrap();
+
+        let mut received = Vec::new();
+        while let Ok(cmd) = rx.try_recv() {
+            received.push(cmd.get_command_str());
+        }
+        assert_eq!(
+            vec!["Window::Remove(app10)", "Window::Remove(app20)"],
+            received
+        );
+
+        // Everything is added again
+        let diff = bridge.diff(&[win(1, 10)]);
+        assert_eq!(vec![(10, "app".to_string())], diff.added_apps);
+        assert_eq!(vec![1], diff.added);
+    }
+}
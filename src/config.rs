@@ -0,0 +1,497 @@
+//! Service configuration
+//!
+//! The service doesn't have a configuration file yet, every setting is read from the environment
+//! at startup. Unset or invalid variables fall back to their default value.
+
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use log::{Level, log, warn};
+use pinenote_service::types::{
+    MountOrientation, ParseError, Rounding,
+    rockchip_ebc::{DitherMode, DriverMode, Hint, HintBitDepth, HintConvertMode},
+};
+
+use crate::power::{self, ColdPolicy, LowBatteryPolicy};
+
+/// Runtime configuration of the service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Number of commands that can be queued before senders start waiting.
+    pub cmd_channel_capacity: usize,
+    /// Default hint applied at startup, instead of the one persisted by the kernel.
+    pub default_hint: Option<Hint>,
+    /// Settings applied by the reading mode.
+    pub reading_profile: Profile,
+    /// Settings applied by the note mode.
+    pub note_profile: Profile,
+    /// File where the display settings are saved, to restore them on restart.
+    pub state_file: Option<PathBuf>,
+    /// Interval between sweeps removing applications whose process exited, zero to disable.
+    pub app_sweep_interval: Duration,
+    /// How DBus clients' rectangles exceeding the screen are handled.
+    pub rect_policy: RectPolicy,
+    /// Report hints that can't be parsed prominently, instead of quietly ignoring them.
+    pub strict_hints: bool,
+    /// Physical mounting of the panel, for what the service draws itself.
+    pub mount_orientation: MountOrientation,
+    /// Application ids never getting per-window hints, their windows use the default hint.
+    pub ignored_apps: Vec<String>,
+    /// Window moves below this many pixels don't recompute the hints, zero to disable.
+    pub jitter_threshold: u32,
+    /// Time the focus must stay on a window before the driver mode it wants is applied.
+    pub focus_mode_delay: Duration,
+    /// Minimum interval between two hint uploads, zero to upload them right away.
+    pub upload_interval: Duration,
+    /// Rounding of window coordinates scaled by the bridge from logical to physical pixels.
+    pub scale_rounding: Rounding,
+    /// Cap of the default hint bit depth on low battery, if any.
+    pub low_battery_policy: Option<LowBatteryPolicy>,
+    /// Driver temperature forced in the cold, if any.
+    pub cold_policy: Option<ColdPolicy>,
+    /// Interval between two readings of the battery and temperature, when a policy is set.
+    pub power_poll_interval: Duration,
+    /// Sysfs directory of the battery read by the power policies.
+    pub battery: PathBuf,
+    /// Sysfs directory of the thermal zone read by the power policies.
+    pub thermal_zone: PathBuf,
+}
+
+/// Handling of rectangles exceeding the screen, received from DBus clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RectPolicy {
+    /// Accept the rectangle as-is, only the visible part will be used.
+    #[default]
+    Accept,
+    /// Clamp the rectangle to the screen.
+    Clamp,
+    /// Refuse the rectangle.
+    Reject,
+}
+
+impl FromStr for RectPolicy {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accept" => Ok(Self::Accept),
+            "clamp" => Ok(Self::Clamp),
+            "reject" => Ok(Self::Reject),
+            _ => Err(ParseError::Unknown(s.into())),
+        }
+    }
+}
+
+/// Display settings applied all at once.
+///
+/// Profiles are written as `<hint>,<driver mode>,<dither mode>,<redraw delay>`, e.g.
+/// `Y4|T|r,normal,bayer,200`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Profile {
+    pub default_hint: Hint,
+    pub driver_mode: DriverMode,
+    pub dither_mode: DitherMode,
+    pub redraw_delay: u16,
+}
+
+impl Profile {
+    /// Crisp text: 4bpp thresholding without redraw.
+    pub const READING: Self = Self {
+        default_hint: Hint::new(HintBitDepth::Y4, HintConvertMode::Threshold, false),
+        driver_mode: DriverMode::Normal,
+        dither_mode: DitherMode::Bayer,
+        redraw_delay: 200,
+    };
+
+    /// Responsive writing: 2bpp thresholding with redraw, fast driver mode.
+    pub const NOTE: Self = Self {
+        default_hint: Hint::new(HintBitDepth::Y2, HintConvertMode::Threshold, true),
+        driver_mode: DriverMode::Fast,
+        dither_mode: DitherMode::BlueNoise16,
+        redraw_delay: 100,
+    };
+}
+
+impl FromStr for Profile {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [hint, driver_mode, dither_mode, redraw_delay] = s
+            .split(',')
+            .map(str::trim)
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| ParseError::Fields(4))?;
+
+        let driver_mode = match driver_mode.parse()? {
+            DriverMode::ZeroWaveform => Err(ParseError::Unsupported(driver_mode.into()))?,
+            mode => mode,
+        };
+
+        Ok(Self {
+            default_hint: Hint::try_from_human_readable(hint)?,
+            driver_mode,
+            dither_mode: dither_mode.parse()?,
+            redraw_delay: redraw_delay.parse()?,
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            cmd_channel_capacity: 100,
+            default_hint: None,
+            reading_profile: Profile::READING,
+            note_profile: Profile::NOTE,
+            state_file: None,
+            app_sweep_interval: Duration::from_secs(30),
+            rect_policy: RectPolicy::Accept,
+            strict_hints: false,
+            mount_orientation: MountOrientation::Normal,
+            ignored_apps: Vec::new(),
+            jitter_threshold: 0,
+            focus_mode_delay: Duration::from_millis(300),
+            upload_interval: Duration::ZERO,
+            scale_rounding: Rounding::Round,
+            low_battery_policy: None,
+            cold_policy: None,
+            power_poll_interval: Duration::from_secs(60),
+            battery: power::DEFAULT_BATTERY.into(),
+            thermal_zone: power::DEFAULT_THERMAL_ZONE.into(),
+        }
+    }
+}
+
+impl Config {
+    const CMD_CHANNEL_CAPACITY: &str = "PINENOTE_CMD_CHANNEL_CAPACITY";
+    const DEFAULT_HINT: &str = "PINENOTE_DEFAULT_HINT";
+    const READING_PROFILE: &str = "PINENOTE_READING_PROFILE";
+    const NOTE_PROFILE: &str = "PINENOTE_NOTE_PROFILE";
+    const STATE_FILE: &str = "PINENOTE_STATE_FILE";
+    const APP_SWEEP_INTERVAL: &str = "PINENOTE_APP_SWEEP_INTERVAL";
+    const RECT_POLICY: &str = "PINENOTE_RECT_POLICY";
+    const STRICT_HINTS: &str = "PINENOTE_STRICT_HINTS";
+    const MOUNT_ORIENTATION: &str = "PINENOTE_MOUNT_ORIENTATION";
+    const IGNORED_APPS: &str = "PINENOTE_IGNORED_APPS";
+    const JITTER_THRESHOLD: &str = "PINENOTE_JITTER_THRESHOLD";
+    const FOCUS_MODE_DELAY: &str = "PINENOTE_FOCUS_MODE_DELAY";
+    const UPLOAD_INTERVAL: &str = "PINENOTE_UPLOAD_INTERVAL";
+    const SCALE_ROUNDING: &str = "PINENOTE_SCALE_ROUNDING";
+    const LOW_BATTERY_POLICY: &str = "PINENOTE_LOW_BATTERY_POLICY";
+    const COLD_POLICY: &str = "PINENOTE_COLD_POLICY";
+    const POWER_POLL_INTERVAL: &str = "PINENOTE_POWER_POLL_INTERVAL";
+    const BATTERY: &str = "PINENOTE_BATTERY";
+    const THERMAL_ZONE: &str = "PINENOTE_THERMAL_ZONE";
+
+    /// Read the configuration from the process environment.
+    pub fn from_env() -> Self {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// Build the configuration using `lookup` to retrieve the variables.
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        let default = Self::default();
+
+        let cmd_channel_capacity = match parse_var(&lookup, Self::CMD_CHANNEL_CAPACITY) {
+            Some(0) => {
+                warn!("{} must be greater than 0", Self::CMD_CHANNEL_CAPACITY);
+                default.cmd_channel_capacity
+            }
+            Some(c) => c,
+            None => default.cmd_channel_capacity,
+        };
+
+        let power_poll_interval = match parse_var(&lookup, Self::POWER_POLL_INTERVAL) {
+            Some(0) => {
+                warn!("{} must be greater than 0", Self::POWER_POLL_INTERVAL);
+                default.power_poll_interval
+            }
+            Some(secs) => Duration::from_secs(secs),
+            None => default.power_poll_interval,
+        };
+        let sysfs_dir = |name| lookup(name).filter(|path| !path.trim().is_empty());
+
+        let strict_hints = parse_var(&lookup, Self::STRICT_HINTS).unwrap_or(default.strict_hints);
+
+        let default_hint = lookup(Self::DEFAULT_HINT).and_then(|value| {
+            let hint = Hint::try_from_human_readable(value.trim()).ok();
+
+            if hint.is_none() {
+                let level = if strict_hints {
+                    Level::Error
+                } else {
+                    Level::Warn
+                };
+                log!(
+                    level,
+                    "Ignoring invalid value '{value}' for {}",
+                    Self::DEFAULT_HINT
+                );
+            }
+
+            hint
+        });
+
+        Self {
+            cmd_channel_capacity,
+            default_hint,
+            reading_profile: parse_var(&lookup, Self::READING_PROFILE)
+                .unwrap_or(default.reading_profile),
+            note_profile: parse_var(&lookup, Self::NOTE_PROFILE).unwrap_or(default.note_profile),
+            state_file: lookup(Self::STATE_FILE)
+                .filter(|path| !path.trim().is_empty())
+                .map(PathBuf::from),
+            app_sweep_interval: parse_var(&lookup, Self::APP_SWEEP_INTERVAL)
+                .map(Duration::from_secs)
+                .unwrap_or(default.app_sweep_interval),
+            rect_policy: parse_var(&lookup, Self::RECT_POLICY).unwrap_or(default.rect_policy),
+            strict_hints,
+            mount_orientation: parse_var(&lookup, Self::MOUNT_ORIENTATION)
+                .unwrap_or(default.mount_orientation),
+            ignored_apps: lookup(Self::IGNORED_APPS)
+                .map(|apps| {
+                    apps.split(',')
+                        .map(str::trim)
+                        .filter(|app_id| !app_id.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or(default.ignored_apps),
+            jitter_threshold: parse_var(&lookup, Self::JITTER_THRESHOLD)
+                .unwrap_or(default.jitter_threshold),
+            focus_mode_delay: parse_var(&lookup, Self::FOCUS_MODE_DELAY)
+                .map(Duration::from_millis)
+                .unwrap_or(default.focus_mode_delay),
+            upload_interval: parse_var(&lookup, Self::UPLOAD_INTERVAL)
+                .map(Duration::from_millis)
+                .unwrap_or(default.upload_interval),
+            scale_rounding: parse_var(&lookup, Self::SCALE_ROUNDING)
+                .unwrap_or(default.scale_rounding),
+            low_battery_policy: parse_var(&lookup, Self::LOW_BATTERY_POLICY),
+            cold_policy: parse_var(&lookup, Self::COLD_POLICY),
+            power_poll_interval,
+            battery: sysfs_dir(Self::BATTERY)
+                .map(PathBuf::from)
+                .unwrap_or(default.battery),
+            thermal_zone: sysfs_dir(Self::THERMAL_ZONE)
+                .map(PathBuf::from)
+                .unwrap_or(default.thermal_zone),
+        }
+    }
+}
+
+/// Parse a variable, logging a warning if it is set but can't be parsed.
+fn parse_var<T: FromStr>(lookup: &impl Fn(&str) -> Option<String>, name: &str) -> Option<T> {
+    let value = lookup(name)?;
+
+    match value.trim().parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            warn!("Ignoring invalid value '{value}' for {name}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn config_from(vars: &[(&str, &str)]) -> Config {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        Config::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn empty_env_is_default() {
+        assert_eq!(Config::default(), config_from(&[]));
+    }
+
+    #[test]
+    fn cmd_channel_capacity() {
+        let config = config_from(&[("PINENOTE_CMD_CHANNEL_CAPACITY", "8")]);
+        assert_eq!(8, config.cmd_channel_capacity);
+
+        let config = config_from(&[("PINENOTE_CMD_CHANNEL_CAPACITY", "0")]);
+        assert_eq!(100, config.cmd_channel_capacity);
+
+        let config = config_from(&[("PINENOTE_CMD_CHANNEL_CAPACITY", "lots")]);
+        assert_eq!(100, config.cmd_channel_capacity);
+    }
+
+    #[test]
+    fn default_hint() {
+        let config = config_from(&[("PINENOTE_DEFAULT_HINT", "Y2|D")]);
+        assert_eq!(
+            Hint::try_from_human_readable("Y2|D").ok(),
+            config.default_hint
+        );
+
+        let config = config_from(&[("PINENOTE_DEFAULT_HINT", "Y3")]);
+        assert_eq!(None, config.default_hint);
+    }
+
+    #[test]
+    fn profiles() {
+        let config = config_from(&[(
+            "PINENOTE_READING_PROFILE",
+            "Y1|D|r, fast, blue-noise-32, 50",
+        )]);
+        let expected = Profile {
+            default_hint: Hint::try_from_human_readable("Y1|D|r").unwrap(),
+            driver_mode: DriverMode::Fast,
+            dither_mode: DitherMode::BlueNoise32,
+            redraw_delay: 50,
+        };
+
+        assert_eq!(expected, config.reading_profile);
+        assert_eq!(Profile::NOTE, config.note_profile);
+    }
+
+    #[test]
+    fn profiles_invalid() {
+        for profile in [
+            "Y4|T|r,normal,bayer",
+            "Y4|T|r,normal,bayer,100,1",
+            "Y3,normal,bayer,100",
+            "Y4,zero-waveform,bayer,100",
+            "Y4,normal,bayer,-1",
+        ] {
+            let config = config_from(&[("PINENOTE_NOTE_PROFILE", profile)]);
+            assert_eq!(Profile::NOTE, config.note_profile, "{profile}");
+        }
+    }
+
+    #[test]
+    fn state_file() {
+        let config = config_from(&[("PINENOTE_STATE_FILE", "/var/lib/pinenote/state.json")]);
+        assert_eq!(
+            Some(PathBuf::from("/var/lib/pinenote/state.json")),
+            config.state_file
+        );
+
+        let config = config_from(&[("PINENOTE_STATE_FILE", "")]);
+        assert_eq!(None, config.state_file);
+    }
+
+    #[test]
+    fn app_sweep_interval() {
+        let config = config_from(&[("PINENOTE_APP_SWEEP_INTERVAL", "5")]);
+        assert_eq!(Duration::from_secs(5), config.app_sweep_interval);
+
+        let config = config_from(&[("PINENOTE_APP_SWEEP_INTERVAL", "0")]);
+        assert!(config.app_sweep_interval.is_zero());
+
+        let config = config_from(&[("PINENOTE_APP_SWEEP_INTERVAL", "-1")]);
+        assert_eq!(Duration::from_secs(30), config.app_sweep_interval);
+    }
+
+    #[test]
+    fn rect_policy() {
+        let config = config_from(&[("PINENOTE_RECT_POLICY", "clamp")]);
+        assert_eq!(RectPolicy::Clamp, config.rect_policy);
+
+        let config = config_from(&[("PINENOTE_RECT_POLICY", "reject")]);
+        assert_eq!(RectPolicy::Reject, config.rect_policy);
+
+        let config = config_from(&[("PINENOTE_RECT_POLICY", "shrink")]);
+        assert_eq!(RectPolicy::Accept, config.rect_policy);
+    }
+
+    #[test]
+    fn strict_hints() {
+        let config = config_from(&[("PINENOTE_STRICT_HINTS", "true")]);
+        assert!(config.strict_hints);
+
+        let config = config_from(&[("PINENOTE_STRICT_HINTS", "yes")]);
+        assert!(!config.strict_hints);
+    }
+
+    #[test]
+    fn mount_orientation() {
+        let config = config_from(&[("PINENOTE_MOUNT_ORIENTATION", "180")]);
+        assert_eq!(MountOrientation::Rotated180, config.mount_orientation);
+
+        let config = config_from(&[("PINENOTE_MOUNT_ORIENTATION", "upside-down")]);
+        assert_eq!(MountOrientation::Normal, config.mount_orientation);
+    }
+
+    #[test]
+    fn ignored_apps() {
+        let config = config_from(&[("PINENOTE_IGNORED_APPS", "mpv, org.gnome.Games,,")]);
+        assert_eq!(vec!["mpv", "org.gnome.Games"], config.ignored_apps);
+
+        let config = config_from(&[("PINENOTE_IGNORED_APPS", "")]);
+        assert!(config.ignored_apps.is_empty());
+    }
+
+    #[test]
+    fn jitter_threshold() {
+        let config = config_from(&[("PINENOTE_JITTER_THRESHOLD", "2")]);
+        assert_eq!(2, config.jitter_threshold);
+
+        let config = config_from(&[("PINENOTE_JITTER_THRESHOLD", "-2")]);
+        assert_eq!(0, config.jitter_threshold);
+    }
+
+    #[test]
+    fn focus_mode_delay() {
+        let config = config_from(&[("PINENOTE_FOCUS_MODE_DELAY", "0")]);
+        assert!(config.focus_mode_delay.is_zero());
+
+        let config = config_from(&[("PINENOTE_FOCUS_MODE_DELAY", "1s")]);
+        assert_eq!(Duration::from_millis(300), config.focus_mode_delay);
+    }
+
+    #[test]
+    fn upload_interval() {
+        assert!(config_from(&[]).upload_interval.is_zero());
+
+        let config = config_from(&[("PINENOTE_UPLOAD_INTERVAL", "50")]);
+        assert_eq!(Duration::from_millis(50), config.upload_interval);
+    }
+
+    #[test]
+    fn scale_rounding() {
+        assert_eq!(Rounding::Round, config_from(&[]).scale_rounding);
+
+        let config = config_from(&[("PINENOTE_SCALE_ROUNDING", "cover")]);
+        assert_eq!(Rounding::Cover, config.scale_rounding);
+
+        let config = config_from(&[("PINENOTE_SCALE_ROUNDING", "ceil")]);
+        assert_eq!(Rounding::Round, config.scale_rounding);
+    }
+
+    #[test]
+    fn power_policies() {
+        let default = config_from(&[]);
+        assert_eq!(None, default.low_battery_policy);
+        assert_eq!(None, default.cold_policy);
+
+        let config = config_from(&[
+            ("PINENOTE_LOW_BATTERY_POLICY", "15,Y2"),
+            ("PINENOTE_COLD_POLICY", "bogus"),
+            ("PINENOTE_POWER_POLL_INTERVAL", "0"),
+            ("PINENOTE_BATTERY", "/sys/class/power_supply/test-battery"),
+            ("PINENOTE_THERMAL_ZONE", " "),
+        ]);
+        let low_battery = LowBatteryPolicy {
+            below: 15,
+            bit_depth: HintBitDepth::Y2,
+        };
+        assert_eq!(Some(low_battery), config.low_battery_policy);
+        assert_eq!(None, config.cold_policy);
+        assert_eq!(default.power_poll_interval, config.power_poll_interval);
+        assert_eq!(
+            PathBuf::from("/sys/class/power_supply/test-battery"),
+            config.battery
+        );
+        assert_eq!(default.thermal_zone, config.thermal_zone);
+    }
+}
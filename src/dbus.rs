@@ -1,14 +1,16 @@
 use anyhow::Result;
-use tokio::sync::mpsc;
-use zbus::{connection, fdo};
-use log::error;
+use pinenote_service::types::rockchip_ebc::Hint;
+use tokio::sync::broadcast::error::RecvError;
+use zbus::{connection, fdo, object_server::SignalEmitter};
+use log::{error, warn};
 
-use crate::ebc;
+use crate::{config::Config, ebc, shared_state::Shared};
 
 pub mod pinenotectl;
 
 pub struct Context {
-    _connection: connection::Connection,
+    connection: connection::Connection,
+    tx: ebc::CommandSender,
 }
 
 fn internal_error(e: anyhow::Error) -> fdo::Error {
@@ -16,16 +18,43 @@ fn internal_error(e: anyhow::Error) -> fdo::Error {
     fdo::Error::Failed("Internal error".into())
 }
 
+/// Let users know when they set a questionable hint. The hint is still applied.
+fn warn_wasteful(hint: &Hint) {
+    if let Some(reason) = hint.is_wasteful() {
+        warn!("Hint {hint} is wasteful: {reason}");
+    }
+}
+
 const DBUS_NAME: &str = "org.pinenote.PineNoteCtl";
 const DBUS_PATH: &str = "/org/pinenote/PineNoteCtl";
 
 impl Context {
-    pub async fn initialize(tx: mpsc::Sender<ebc::Command>, bridge: String) -> Result<Self> {
-        let ctl1 = pinenotectl::PineNoteCtl::new(tx.clone(), bridge);
-        let ebc1 = pinenotectl::Ebc1::new(tx.clone());
-        let hintmgr1 = pinenotectl::HintMgr1::new(tx.clone());
+    pub async fn initialize(
+        tx: ebc::CommandSender,
+        bridge: String,
+        shared: Shared,
+        events: ebc::EventReceiver,
+        config: &Config,
+    ) -> Result<Self> {
+        let builder = connection::Builder::session()?;
 
-        let _connection = connection::Builder::session()?
+        Self::initialize_on(builder, tx, bridge, shared, events, config).await
+    }
+
+    /// Same as [Self::initialize], on the bus `builder` connects to.
+    async fn initialize_on(
+        builder: connection::Builder<'_>,
+        tx: ebc::CommandSender,
+        bridge: String,
+        shared: Shared,
+        events: ebc::EventReceiver,
+        config: &Config,
+    ) -> Result<Self> {
+        let ctl1 = pinenotectl::PineNoteCtl::new(tx.clone(), bridge, shared);
+        let ebc1 = pinenotectl::Ebc1::new(tx.clone(), config);
+        let hintmgr1 = pinenotectl::HintMgr1::new(tx.clone(), config);
+
+        let connection = builder
             .name(DBUS_NAME)?
             .serve_at(DBUS_PATH, ctl1)?
             .serve_at(DBUS_PATH, ebc1)?
@@ -33,6 +62,216 @@ impl Context {
             .build()
             .await?;
 
-        Ok(Self { _connection })
+        tokio::spawn(forward_events(connection.clone(), events));
+
+        Ok(Self { connection, tx })
+    }
+
+    /// Set the core default hint, and notify clients of the change.
+    pub async fn apply_default_hint(&self, hint: Hint) -> Result<()> {
+        self.tx.send(ebc::Property::SetDefaultHint(hint)).await?;
+
+        let iface = self
+            .connection
+            .object_server()
+            .interface::<_, pinenotectl::Ebc1>(DBUS_PATH)
+            .await?;
+        let emitter = iface.signal_emitter();
+        let ebc1 = iface.get().await;
+
+        ebc1.default_hint_changed(emitter).await?;
+        ebc1.default_hint_hr_changed(emitter).await?;
+
+        Ok(())
+    }
+}
+
+/// Emit core events as DBus signals, until the core goes away.
+async fn forward_events(connection: connection::Connection, mut events: ebc::EventReceiver) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(n)) => {
+                warn!("Missed {n} core events");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        if let Err(e) = emit_event(&connection, &event).await {
+            error!("Failed to emit signal for {event:?}: {e:#}");
+        }
+    }
+}
+
+async fn emit_event(connection: &connection::Connection, event: &ebc::Event) -> zbus::Result<()> {
+    let emitter = SignalEmitter::new(connection, DBUS_PATH)?;
+
+    match event {
+        ebc::Event::WindowAdded { win_key, app_key } => {
+            pinenotectl::HintMgr1::window_added(&emitter, win_key, app_key).await
+        }
+        ebc::Event::WindowRemoved(win_key) => {
+            pinenotectl::HintMgr1::window_removed(&emitter, win_key).await
+        }
+        ebc::Event::HintsUpdated(hints) => {
+            let hints = pinenotectl::ebc1::human_readable(hints.clone());
+            pinenotectl::Ebc1::hints_updated(&emitter, hints).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+    };
+
+    use futures_lite::StreamExt;
+    use zbus::{MatchRule, MessageStream, message, zvariant::OwnedValue};
+
+    use pinenote_service::types::Rect;
+
+    use super::*;
+    use crate::{ebc::testing::Core, shared_state::SharedState};
+
+    /// Private message bus, stopped once dropped.
+    struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        /// Start a bus, None if `dbus-daemon` isn't available.
+        fn start() -> Option<Self> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+
+            let mut address = String::new();
+            let stdout = daemon.stdout.take()?;
+            BufReader::new(stdout).read_line(&mut address).ok()?;
+
+            Some(Self {
+                daemon,
+                address: address.trim().into(),
+            })
+        }
+
+        fn builder(&self) -> connection::Builder<'_> {
+            connection::Builder::address(self.address.as_str()).unwrap()
+        }
+
+        /// Serve the DBus interfaces of `core` on this bus.
+        async fn serve(&self, core: &Core) -> Context {
+            let events = core.events.resubscribe();
+            let shared = SharedState::new();
+            let config = Config::default();
+
+            Context::initialize_on(
+                self.builder(),
+                core.tx.clone(),
+                String::new(),
+                shared,
+                events,
+                &config,
+            )
+            .await
+            .unwrap()
+        }
+
+        /// Signals of `interface` named `member`, as seen by another client of the bus.
+        async fn signals(&self, interface: &'static str, member: &'static str) -> MessageStream {
+            let client = self.builder().build().await.unwrap();
+            let rule = MatchRule::builder()
+                .msg_type(message::Type::Signal)
+                .interface(interface)
+                .unwrap()
+                .member(member)
+                .unwrap()
+                .build();
+
+            MessageStream::for_match_rule(rule, &client, None)
+                .await
+                .unwrap()
+        }
+    }
+
+    /// Interface and changed values of the next `PropertiesChanged` signal.
+    async fn next_changes(signals: &mut MessageStream) -> (String, HashMap<String, OwnedValue>) {
+        let msg = signals.next().await.unwrap().unwrap();
+        let (interface, changed, _): (String, _, Vec<String>) = msg.body().deserialize().unwrap();
+
+        (interface, changed)
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    #[tokio::test]
+    async fn startup_default_hint_applied_and_notified() {
+        let Some(bus) = TestBus::start() else {
+            eprintln!("Skipped: dbus-daemon not available");
+            return;
+        };
+        let core = Core::start();
+        let ctx = bus.serve(&core).await;
+        let mut changes = bus
+            .signals("org.freedesktop.DBus.Properties", "PropertiesChanged")
+            .await;
+
+        let hint = Hint::try_from_human_readable("Y2|D").unwrap();
+        ctx.apply_default_hint(hint).await.unwrap();
+
+        // The core uploads it as default
+        let reported = core.ask(|tx| ebc::Property::DefaultHint(tx).into()).await;
+        assert_eq!(hint, reported);
+        core.sync().await;
+        let uploaded = core.driver.rect_hints().pop().unwrap();
+        assert_eq!(Some(hint), uploaded.default_hint);
+
+        // Clients are told about the new value
+        let (interface, changed) = next_changes(&mut changes).await;
+        assert_eq!("org.pinenote.Ebc1", interface);
+        assert!(changed.contains_key("DefaultHint"));
+        let (_, changed) = next_changes(&mut changes).await;
+        let hint_hr = changed["DefaultHintHr"].try_clone().unwrap();
+        assert_eq!("Y2|D|r", String::try_from(hint_hr).unwrap());
+    }
+
+    #[tokio::test]
+    async fn window_signals_emitted() {
+        let Some(bus) = TestBus::start() else {
+            eprintln!("Skipped: dbus-daemon not available");
+            return;
+        };
+        let core = Core::start();
+        let _ctx = bus.serve(&core).await;
+        let mut added = bus.signals("org.pinenote.HintMgr1", "WindowAdded").await;
+        let mut removed = bus.signals("org.pinenote.HintMgr1", "WindowRemoved").await;
+
+        let app_key = core.add_app("org.app").await;
+        let win_key = core
+            .add_window(&app_key, Rect::new(0, 0, 10, 10), "Y1")
+            .await;
+
+        let msg = added.next().await.unwrap().unwrap();
+        let args: (String, String) = msg.body().deserialize().unwrap();
+        assert_eq!((win_key.clone(), app_key), args);
+
+        core.send(ebc::Window::Remove(win_key.clone())).await;
+
+        let msg = removed.next().await.unwrap().unwrap();
+        let args: String = msg.body().deserialize().unwrap();
+        assert_eq!(win_key, args);
     }
 }
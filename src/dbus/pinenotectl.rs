@@ -1,5 +1,12 @@
-use pinenote_service::types::rockchip_ebc::{Hint as CoreHint, HintBitDepth, HintConvertMode};
-use tokio::sync::mpsc;
+use pinenote_service::{
+    drivers::rockchip_ebc::RockchipEbc,
+    layout::LayoutSnapshot,
+    types::rockchip_ebc::{
+        DitherMode, DriverMode, Hint as CoreHint, HintBitDepth, HintConvertMode,
+    },
+};
+use log::info;
+use tokio::sync::oneshot;
 use zbus::{
     fdo, interface,
     zvariant::{Type, Value},
@@ -11,7 +18,7 @@ pub use ebc1::Ebc1;
 pub mod hintmgr1;
 pub use hintmgr1::HintMgr1;
 
-use crate::{dbus, ebc};
+use crate::{dbus, ebc, shared_state::Shared};
 
 #[derive(Type, Value)]
 pub struct Hint {
@@ -36,13 +43,60 @@ impl From<Hint> for CoreHint {
     }
 }
 
+/// Features supported by this build, for clients to hide what isn't available.
+///
+/// Bridges are gated by cargo features, and reported as `bridge:<name>`. Modes which can be set
+/// through Ebc1 are reported as `driver-mode:<name>` and `dither-mode:<name>`.
+fn capabilities() -> Vec<String> {
+    let mut caps = Vec::new();
+
+    if cfg!(feature = "sway") {
+        caps.push("bridge:sway".to_string());
+    }
+
+    if cfg!(feature = "quill-niri") {
+        caps.push("bridge:quill-niri".to_string());
+    }
+
+    // Zero waveform is refused by Ebc1
+    for mode in [DriverMode::Normal, DriverMode::Fast] {
+        caps.push(format!("driver-mode:{mode}"));
+    }
+
+    for mode in DitherMode::ALL {
+        caps.push(format!("dither-mode:{mode}"));
+    }
+
+    caps
+}
+
+/// Reject the driver parameters which can't be accessed by name.
+fn check_param(name: &str) -> fdo::Result<()> {
+    if !RockchipEbc::TUNABLE_PARAMS.contains(&name) {
+        return Err(fdo::Error::InvalidArgs(format!(
+            "Unknown parameter '{name}', expected one of: {}",
+            RockchipEbc::TUNABLE_PARAMS.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
 pub struct PineNoteCtl {
     tx: ebc::CommandSender,
     active_bridge: String,
+    shared: Shared,
 }
 
 impl PineNoteCtl {
-    pub fn new(tx: mpsc::Sender<ebc::Command>, bridge: String) -> Self {
+    /// Version of the DBus API, as `(major, minor)`.
+    ///
+    /// The minor version is bumped when a method, property or signal is added to any interface,
+    /// or a method accepts new values. The major version follows the interfaces name suffix, e.g.
+    /// `PineNoteCtl1`: it only changes with incompatible changes, which come with new interfaces.
+    pub const API_VERSION: (u32, u32) = (1, 0);
+
+    pub fn new(tx: ebc::CommandSender, bridge: String, shared: Shared) -> Self {
         let active_bridge: String = if bridge.is_empty() {
             "generic".into()
         } else {
@@ -50,8 +104,9 @@ impl PineNoteCtl {
         };
 
         Self {
-            tx: tx.into(),
+            tx,
             active_bridge,
+            shared,
         }
     }
 }
@@ -65,8 +120,320 @@ impl PineNoteCtl {
             .map_err(dbus::internal_error)
     }
 
+    /// Write the layering of the visible windows as a Graphviz DOT graph.
+    ///
+    /// Use `-` to write it to the service stderr.
+    async fn dump_ztree(&self, path: String) -> fdo::Result<()> {
+        self.tx
+            .send(ebc::Command::DumpZTree(path))
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Draw the visible windows as an SVG image of the screen, labelled with their title,
+    /// z-index and hint.
+    ///
+    /// Use `-` to write it to the service stderr.
+    async fn export_layout_svg(&self, path: String) -> fdo::Result<()> {
+        self.tx
+            .send(ebc::Command::ExportLayoutSvg(path))
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Save the applications, windows and hints to a JSON file.
+    ///
+    /// Captures a live session, to reproduce it with `LoadLayout` or in a unit test.
+    async fn save_layout(&self, path: String) -> fdo::Result<()> {
+        let (tx, rx) = oneshot::channel();
+
+        let layout = self
+            .tx
+            .with_reply(ebc::Command::ExportLayout(tx), rx)
+            .await
+            .map_err(dbus::internal_error)?;
+
+        tokio::task::spawn_blocking(move || layout.save(&path))
+            .await
+            .map_err(|e| dbus::internal_error(e.into()))?
+            .map_err(|e| fdo::Error::Failed(format!("Failed to save layout: {e}")))
+    }
+
+    /// Replace the applications and windows with those saved by `SaveLayout`, and apply its
+    /// hints.
+    ///
+    /// Rejected while a bridge is running, as it would keep reporting the compositor windows:
+    /// pause it first. Once resumed, it registers the compositor windows from scratch.
+    async fn load_layout(&self, path: String) -> fdo::Result<()> {
+        if self.shared.bridge_connected() && !self.shared.bridge_paused() {
+            return Err(fdo::Error::Failed(
+                "The bridge is running, pause it before loading a layout".into(),
+            ));
+        }
+
+        let layout = tokio::task::spawn_blocking(move || LayoutSnapshot::load(&path))
+            .await
+            .map_err(|e| dbus::internal_error(e.into()))?
+            .map_err(|e| fdo::Error::InvalidArgs(format!("Failed to load layout: {e}")))?;
+
+        self.tx
+            .send(ebc::Command::ImportLayout(layout))
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Append every hints upload to a file, one JSON line each.
+    ///
+    /// The log is meant to be replayed, e.g. to reproduce a bug.
+    async fn start_hint_log(&self, path: String) -> fdo::Result<()> {
+        self.tx
+            .send(ebc::Command::StartHintLog(path))
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    async fn stop_hint_log(&self) -> fdo::Result<()> {
+        self.tx
+            .send(ebc::Command::StopHintLog)
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Wait until every command previously sent to the service was handled, and its hints
+    /// uploaded. Returns the sequence number of this barrier.
+    ///
+    /// Most methods return as soon as their command is queued: `Sync` lets a client know they
+    /// took effect, whichever interface they went through.
+    async fn sync(&self) -> fdo::Result<u64> {
+        let (tx, rx) = oneshot::channel();
+
+        self.tx
+            .with_reply(ebc::Command::Sync(tx), rx)
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Health of the service, for monitoring: whether the driver answers, the active bridge and
+    /// whether it is connected to its compositor, and the number of application windows.
+    #[zbus(out_args("driver_ok", "bridge", "bridge_connected", "window_count"))]
+    async fn status(&self) -> fdo::Result<(bool, String, bool, u32)> {
+        let (tx, rx) = oneshot::channel();
+
+        let core = self
+            .tx
+            .with_reply(ebc::Command::Status(tx), rx)
+            .await
+            .map_err(dbus::internal_error)?;
+
+        Ok((
+            core.driver_ok,
+            self.active_bridge.clone(),
+            self.shared.bridge_connected(),
+            core.window_count as u32,
+        ))
+    }
+
+    /// Read a rockchip_ebc parameter by name, as reported by the kernel.
+    ///
+    /// Meant for experimenting with the driver knobs lacking a dedicated API, only a fixed set of
+    /// parameters is accessible.
+    async fn get_param(&self, name: String) -> fdo::Result<String> {
+        check_param(&name)?;
+        let (tx, rx) = oneshot::channel();
+
+        self.tx
+            .with_reply(ebc::Command::GetParam(name, tx), rx)
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Write a rockchip_ebc parameter by name, see [Self::get_param]. The value isn't persisted.
+    async fn set_param(&self, name: String, value: String) -> fdo::Result<()> {
+        check_param(&name)?;
+        let (tx, rx) = oneshot::channel();
+
+        self.tx
+            .with_reply(ebc::Command::SetParam(name, value, tx), rx)
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Stop the service gracefully: the bridge stops, and the core saves its state once the
+    /// commands sent before are handled. Does nothing if the service is already stopping.
+    ///
+    /// Like every method, it is only reachable by the session owner.
+    async fn quit(&self) {
+        if self.shared.request_shutdown() {
+            info!("Shutdown requested over DBus");
+        }
+    }
+
+    /// List the features supported by this build.
+    async fn capabilities(&self) -> Vec<String> {
+        capabilities()
+    }
+
+    /// Version of the DBus API, see [PineNoteCtl::API_VERSION].
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn api_version(&self) -> (u32, u32) {
+        Self::API_VERSION
+    }
+
     #[zbus(property)]
     async fn active_bridge(&self) -> String {
         self.active_bridge.clone()
     }
+
+    /// Whether the active bridge is paused.
+    ///
+    /// A paused bridge keeps tracking the compositor but stops sending updates, so that hints set
+    /// manually through HintMgr1 aren't overwritten.
+    #[zbus(property)]
+    async fn bridge_paused(&self) -> bool {
+        self.shared.bridge_paused()
+    }
+
+    #[zbus(property)]
+    async fn set_bridge_paused(&self, paused: bool) {
+        self.shared.set_bridge_paused(paused);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pinenote_service::types::Rect;
+
+    use super::*;
+    use crate::{ebc::testing::Core, shared_state::SharedState, temp_dir::TempDir};
+
+    #[test]
+    fn capabilities_reflect_features() {
+        let caps = capabilities();
+        let has = |cap: &str| caps.iter().any(|c| c == cap);
+
+        assert_eq!(cfg!(feature = "sway"), has("bridge:sway"));
+        assert_eq!(cfg!(feature = "quill-niri"), has("bridge:quill-niri"));
+
+        assert!(has("driver-mode:fast"));
+        assert!(!has("driver-mode:zero-waveform"));
+        assert!(has("dither-mode:blue-noise-32"));
+    }
+
+    #[tokio::test]
+    async fn api_version_reported() {
+        let (tx, _rx) = ebc::CommandSender::channel(1);
+        let ctl = PineNoteCtl::new(tx, String::new(), SharedState::new());
+
+        let version = ctl.api_version().await;
+        assert_eq!(PineNoteCtl::API_VERSION, version);
+        // The major version matches the interfaces name
+        assert_eq!(1, version.0);
+    }
+
+    #[tokio::test]
+    async fn sync_waits_for_queued_commands() {
+        let core = Core::start();
+        let ctl = PineNoteCtl::new(core.tx.clone(), String::new(), SharedState::new());
+        let dir = TempDir::new("sync");
+        let path = dir.create().join("ztree.dot");
+
+        ctl.dump_ztree(path.to_string_lossy().into()).await.unwrap();
+        ctl.stop_hint_log().await.unwrap();
+
+        assert_eq!(1, ctl.sync().await.unwrap());
+        assert!(path.exists());
+        assert_eq!(2, ctl.sync().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn params_restricted() {
+        let (tx, mut rx) = ebc::CommandSender::channel(4);
+        let ctl = PineNoteCtl::new(tx, String::new(), SharedState::new());
+
+        for name in ["redraw_delay", "../../power/state"] {
+            assert!(matches!(
+                ctl.get_param(name.into()).await,
+                Err(fdo::Error::InvalidArgs(_))
+            ));
+            assert!(matches!(
+                ctl.set_param(name.into(), "0".into()).await,
+                Err(fdo::Error::InvalidArgs(_))
+            ));
+        }
+        // Rejected before reaching the core
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn params_round_trip() {
+        let core = Core::start();
+        let ctl = PineNoteCtl::new(core.tx.clone(), String::new(), SharedState::new());
+
+        ctl.set_param("temp_override".into(), "25".into())
+            .await
+            .unwrap();
+        assert_eq!("25", ctl.get_param("temp_override".into()).await.unwrap());
+        assert_eq!(vec!["set_param", "param"], core.driver.calls());
+    }
+
+    #[tokio::test]
+    async fn load_layout_needs_paused_bridge() {
+        let (tx, mut rx) = ebc::CommandSender::channel(4);
+        let shared = SharedState::new();
+        let ctl = PineNoteCtl::new(tx, String::new(), shared.clone());
+        let path = "/nonexistent/layout.json".to_string();
+
+        shared.set_bridge_connected(true);
+        assert!(matches!(
+            ctl.load_layout(path.clone()).await,
+            Err(fdo::Error::Failed(_))
+        ));
+
+        // Paused, the layout itself is checked
+        shared.set_bridge_paused(true);
+        assert!(matches!(
+            ctl.load_layout(path).await,
+            Err(fdo::Error::InvalidArgs(_))
+        ));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn quit_requests_shutdown_once() {
+        let (tx, _rx) = ebc::CommandSender::channel(4);
+        let shared = SharedState::new();
+        let ctl = PineNoteCtl::new(tx, String::new(), shared.clone());
+
+        let bridge = tokio::spawn({
+            let shared = shared.clone();
+            async move { shared.until_shutdown(std::future::pending::<()>()).await }
+        });
+
+        ctl.quit().await;
+        assert!(shared.shutdown_requested());
+        assert_eq!(None, bridge.await.unwrap());
+
+        // Already stopping
+        ctl.quit().await;
+        assert!(!shared.request_shutdown());
+    }
+
+    #[tokio::test]
+    async fn status_reports_core_and_bridge() {
+        let core = Core::start();
+        let shared = SharedState::new();
+        let ctl = PineNoteCtl::new(core.tx.clone(), "Sway".into(), shared.clone());
+
+        let app_key = core.add_app("org.app").await;
+        core.add_window(&app_key, Rect::new(0, 0, 10, 10), "Y1")
+            .await;
+        // A driver not answering
+        core.driver.fail("probe");
+
+        let status = ctl.status().await.unwrap();
+        assert_eq!((false, "Sway".into(), false, 1), status);
+
+        shared.set_bridge_connected(true);
+        assert!(ctl.status().await.unwrap().2);
+    }
 }
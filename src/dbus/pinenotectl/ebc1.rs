@@ -1,23 +1,164 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
-use pinenote_service::types::rockchip_ebc::{DitherMode, DriverMode, Hint as CoreHint};
-use tokio::sync::{mpsc, oneshot};
-use zbus::{fdo, interface, object_server::SignalEmitter};
+use pinenote_service::types::{
+    Rect,
+    rockchip_ebc::{
+        DitherMode, DriverMode, Hint as CoreHint, HintBitDepth, RectHint, RedrawOptions,
+    },
+};
+use tokio::sync::oneshot;
+use zbus::{
+    fdo, interface,
+    object_server::SignalEmitter,
+    zvariant::{self, OwnedValue, Value},
+};
 
 use crate::{
+    config::{Config, Profile},
     dbus,
     ebc::{self, OffScreenError},
 };
 
+/// Static capabilities of the panel, for a screen covering `area`, see [Ebc1::panel_info].
+fn panel_info(area: &Rect) -> zvariant::Result<HashMap<String, OwnedValue>> {
+    let bit_depths: Vec<_> = HintBitDepth::ALL.iter().map(|d| d.name()).collect();
+    let dither_methods: Vec<_> = DitherMode::ALL.iter().map(|m| m.name()).collect();
+
+    [
+        ("width", Value::from(area.x2 - area.x1)),
+        ("height", Value::from(area.y2 - area.y1)),
+        ("bit_depths", Value::from(bit_depths)),
+        ("dither_methods", Value::from(dither_methods)),
+        // The driver has it, but Ebc1 refuses to switch to it
+        ("zero_waveform", Value::from(false)),
+    ]
+    .into_iter()
+    .map(|(key, value)| Ok((key.to_string(), value.try_into()?)))
+    .collect()
+}
+
+/// Rectangle hints as sent over DBus, using the [human readable](CoreHint::try_from_human_readable)
+/// hints.
+pub fn human_readable(hints: Vec<RectHint>) -> Vec<(Rect, String)> {
+    hints
+        .into_iter()
+        .map(|RectHint { rect, hint }| (rect, hint.to_string()))
+        .collect()
+}
+
+/// DBus error for an off screen image at `path` failing to upload.
+fn off_screen_error(e: OffScreenError, path: String) -> fdo::Error {
+    match e {
+        OffScreenError::LoadFailed => fdo::Error::FileNotFound(path),
+        OffScreenError::DecodeFailed => {
+            fdo::Error::Failed(format!("Failed to load '{path}': Bad format"))
+        }
+        OffScreenError::UploadFailed => {
+            fdo::Error::Failed("Could not upload image to driver".into())
+        }
+        OffScreenError::BadRegion => fdo::Error::InvalidArgs("Region out of screen".into()),
+        OffScreenError::NoImage => {
+            fdo::Error::Failed("No off screen image to patch, set one first".into())
+        }
+    }
+}
+
 pub struct Ebc1 {
     ebc_tx: ebc::CommandSender,
+    reading_profile: Profile,
+    note_profile: Profile,
 }
 
 impl Ebc1 {
-    pub fn new(ebc_tx: mpsc::Sender<ebc::Command>) -> Self {
+    pub fn new(ebc_tx: ebc::CommandSender, config: &Config) -> Self {
         Self {
-            ebc_tx: ebc_tx.into(),
+            ebc_tx,
+            reading_profile: config.reading_profile,
+            note_profile: config.note_profile,
+        }
+    }
+
+    /// Apply a profile, and notify clients of all the properties it changes.
+    async fn apply_profile(
+        &self,
+        profile: Profile,
+        emitter: &SignalEmitter<'_>,
+    ) -> fdo::Result<()> {
+        self.ebc_tx
+            .send(ebc::Command::ApplyProfile(profile))
+            .await
+            .map_err(dbus::internal_error)?;
+
+        self.default_hint_changed(emitter).await?;
+        self.default_hint_hr_changed(emitter).await?;
+        self.driver_mode_changed(emitter).await?;
+        self.dither_mode_changed(emitter).await?;
+        self.redraw_delay_changed(emitter).await?;
+
+        Ok(())
+    }
+
+    /// Set the redraw delay, then refresh the screen so that its effect shows right away.
+    ///
+    /// The refresh is deferred rather than dropped by the rate limit, so that adjusting the delay
+    /// interactively always shows the last value.
+    async fn set_redraw_delay_and_refresh(&self, redraw_delay: u16) -> fdo::Result<()> {
+        self.ebc_tx
+            .send(ebc::Property::SetRedrawDelay(redraw_delay))
+            .await
+            .map_err(dbus::internal_error)?;
+
+        self.ebc_tx
+            .send(ebc::Command::GlobalRefreshDeferred)
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Set the default hint from its [human readable](CoreHint::try_from_human_readable) form.
+    async fn set_default_hint_from_str(&self, hint: &str) -> fdo::Result<()> {
+        let Ok(hint) = CoreHint::try_from_human_readable(hint) else {
+            return Err(fdo::Error::InvalidArgs("Invalid format".into()));
+        };
+        dbus::warn_wasteful(&hint);
+
+        self.ebc_tx
+            .send(ebc::Property::SetDefaultHint(hint))
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    async fn set_forced_bit_depth(&self, bit_depth: Option<HintBitDepth>) -> fdo::Result<()> {
+        self.ebc_tx
+            .send(ebc::Property::SetForcedBitDepth(bit_depth))
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Upload the off screen image at `path`, changing its orientation unless `raw`.
+    async fn upload_off_screen(
+        &self,
+        path: String,
+        raw: bool,
+        emitter: &SignalEmitter<'_>,
+    ) -> fdo::Result<()> {
+        let (tx, rx) = oneshot::channel::<Result<(), OffScreenError>>();
+
+        let res = self
+            .ebc_tx
+            .with_reply(ebc::Command::OffScreen(path.clone(), raw, tx), rx)
+            .await
+            .map_err(dbus::internal_error)?;
+
+        if let Err(e) = res {
+            if matches!(e, OffScreenError::UploadFailed) {
+                self.off_screen_override_changed(emitter).await?;
+            }
+            Err(off_screen_error(e, path))?
+        } else {
+            self.off_screen_override_changed(emitter).await?
         }
+
+        Ok(())
     }
 }
 
@@ -30,6 +171,101 @@ impl Ebc1 {
             .map_err(dbus::internal_error)
     }
 
+    /// Show the computed hints on the panel, each region filled with a gray
+    /// level depending on its bit depth. Disabling restores the off screen image.
+    async fn debug_visualize(&self, enable: bool) -> fdo::Result<()> {
+        self.ebc_tx
+            .send(ebc::Command::DebugVisualize(enable))
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Use `bit_depth` (`Y1`, `Y2` or `Y4`) for every hint, e.g. everything to `Y1` for speed,
+    /// keeping their convert mode and redraw. Lasts until *ClearForceGlobalBitDepth*.
+    async fn force_global_bit_depth(&self, bit_depth: String) -> fdo::Result<()> {
+        let bit_depth: HintBitDepth = bit_depth
+            .parse()
+            .map_err(|_| fdo::Error::InvalidArgs(format!("Invalid bit depth '{bit_depth}'")))?;
+
+        self.set_forced_bit_depth(Some(bit_depth)).await
+    }
+
+    /// Give the hints their own bit depth back.
+    async fn clear_force_global_bit_depth(&self) -> fdo::Result<()> {
+        self.set_forced_bit_depth(None).await
+    }
+
+    /// Rectangles and human readable hints last uploaded to the driver.
+    async fn current_hints(&self) -> fdo::Result<Vec<(Rect, String)>> {
+        let (tx, rx) = oneshot::channel::<Vec<RectHint>>();
+
+        let hints = self
+            .ebc_tx
+            .with_reply(ebc::Property::CurrentHints(tx), rx)
+            .await
+            .map_err(dbus::internal_error)?;
+
+        Ok(human_readable(hints))
+    }
+
+    /// Whether the hints last uploaded match the ones computed from the current windows, once the
+    /// pending changes are uploaded. False means a change didn't update the hints.
+    ///
+    /// The driver can't read its hints back, this compares against what was sent to it.
+    async fn verify_hints(&self) -> fdo::Result<bool> {
+        let (tx, rx) = oneshot::channel::<bool>();
+
+        self.ebc_tx
+            .with_reply(ebc::Property::VerifyHints(tx), rx)
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// What the panel supports, for settings UIs: `width` and `height` in pixels, the hint
+    /// `bit_depths` and `dither_methods` accepted, and whether `zero_waveform` can be used.
+    ///
+    /// Unlike the properties, these don't change while the service runs.
+    async fn panel_info(&self) -> fdo::Result<HashMap<String, OwnedValue>> {
+        let (tx, rx) = oneshot::channel::<Rect>();
+
+        let area = self
+            .ebc_tx
+            .with_reply(ebc::Property::ScreenArea(tx), rx)
+            .await
+            .map_err(dbus::internal_error)?;
+
+        panel_info(&area).map_err(|e| dbus::internal_error(e.into()))
+    }
+
+    /// Apply the reading profile, tuned for crisp text.
+    async fn reading_mode(
+        &self,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> fdo::Result<()> {
+        self.apply_profile(self.reading_profile, &emitter).await
+    }
+
+    /// Apply the note profile, tuned for responsive writing.
+    async fn note_mode(
+        &self,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> fdo::Result<()> {
+        self.apply_profile(self.note_profile, &emitter).await
+    }
+
+    /// Set the redraw delay and refresh the screen, so that its effect is
+    /// visible immediately. Handy when tuning it interactively.
+    async fn redraw_delay_preview(
+        &self,
+        redraw_delay: u16,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> fdo::Result<()> {
+        self.set_redraw_delay_and_refresh(redraw_delay).await?;
+        self.redraw_delay_changed(&emitter).await?;
+
+        Ok(())
+    }
+
     async fn dump_framebuffers(&self, directory: String) -> fdo::Result<()> {
         self.ebc_tx
             .send(ebc::Command::FbDumpToDir(directory))
@@ -37,6 +273,23 @@ impl Ebc1 {
             .map_err(dbus::internal_error)
     }
 
+    /// Switch to `driver_mode` (`normal` or `fast`), wanted by the window just focused, once the
+    /// focus stayed on it for a while. Requests made in the meantime replace it, so alt-tabbing
+    /// through windows only applies the mode of the last one.
+    async fn request_focus_driver_mode(&self, driver_mode: String) -> fdo::Result<()> {
+        let driver_mode = match driver_mode.parse() {
+            Ok(DriverMode::ZeroWaveform) | Err(_) => Err(fdo::Error::InvalidArgs(format!(
+                "Invalid driver mode '{driver_mode}'"
+            )))?,
+            Ok(mode) => mode,
+        };
+
+        self.ebc_tx
+            .send(ebc::Property::RequestFocusDriverMode(driver_mode))
+            .await
+            .map_err(dbus::internal_error)
+    }
+
     async fn cycle_driver_mode(
         &self,
         #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
@@ -83,32 +336,58 @@ impl Ebc1 {
         path: String,
         #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
     ) -> fdo::Result<()> {
+        self.upload_off_screen(path, false, &emitter).await
+    }
+
+    /// Like `SetOffScreen`, but the image is uploaded as is, already in the panel scan
+    /// orientation.
+    async fn set_off_screen_raw(
+        &self,
+        path: String,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> fdo::Result<()> {
+        self.upload_off_screen(path, true, &emitter).await
+    }
+
+    /// Replace `area` of the off screen image with the image at `path`, resized to fit, e.g. to
+    /// update a clock without uploading a whole image. Both are in the panel scan orientation,
+    /// like `SetOffScreenRaw`.
+    async fn set_off_screen_region(&self, path: String, area: Rect) -> fdo::Result<()> {
         let (tx, rx) = oneshot::channel::<Result<(), OffScreenError>>();
 
         let res = self
             .ebc_tx
-            .with_reply(ebc::Command::OffScreen(path.clone(), tx), rx)
+            .with_reply(ebc::Command::OffScreenRegion(path.clone(), area, tx), rx)
             .await
             .map_err(dbus::internal_error)?;
 
-        if let Err(e) = res {
-            match e {
-                OffScreenError::LoadFailed => Err(fdo::Error::FileNotFound(path))?,
-                OffScreenError::DecodeFailed => Err(fdo::Error::Failed(format!(
-                    "Failed to load '{path}': Bad format"
-                )))?,
-                OffScreenError::UploadFailed => {
-                    self.off_screen_override_changed(&emitter).await?;
-                    Err(fdo::Error::Failed(
-                        "Could not upload image to driver".into(),
-                    ))?;
-                }
-            }
-        } else {
-            self.off_screen_override_changed(&emitter).await?
+        res.map_err(|e| off_screen_error(e, path))
+    }
+
+    /// Use a single gray level, from 0 (black) to 15 (white), as the off screen image.
+    async fn set_off_screen_solid(
+        &self,
+        level: u8,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> fdo::Result<()> {
+        if level > ebc::Ctl::MAX_GRAY_LEVEL {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Gray level {level} is out of range 0-{}",
+                ebc::Ctl::MAX_GRAY_LEVEL
+            )));
         }
 
-        Ok(())
+        let (tx, rx) = oneshot::channel::<Result<(), OffScreenError>>();
+
+        let res = self
+            .ebc_tx
+            .with_reply(ebc::Command::OffScreenSolid(level, tx), rx)
+            .await
+            .map_err(dbus::internal_error)?;
+
+        self.off_screen_override_changed(&emitter).await?;
+
+        res.map_err(|_| fdo::Error::Failed("Could not upload image to driver".into()))
     }
 
     #[zbus(property)]
@@ -140,6 +419,66 @@ impl Ebc1 {
             .map_err(zbus::Error::from)
     }
 
+    #[zbus(property)]
+    async fn precise_hints(&self) -> fdo::Result<bool> {
+        let (tx, rx) = oneshot::channel::<bool>();
+
+        self.ebc_tx
+            .with_reply(ebc::Property::PreciseHints(tx), rx)
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    #[zbus(property)]
+    async fn set_precise_hints(&self, precise: bool) -> Result<(), zbus::Error> {
+        self.ebc_tx
+            .send(ebc::Property::SetPreciseHints(precise))
+            .await
+            .map_err(dbus::internal_error)
+            .map_err(zbus::Error::from)
+    }
+
+    /// Reduce the rectangles sent to the driver using the windows layering. Unset it to debug the
+    /// reduction: every visible window then sends its whole area.
+    #[zbus(property)]
+    async fn minimize_hints(&self) -> fdo::Result<bool> {
+        let (tx, rx) = oneshot::channel::<bool>();
+
+        self.ebc_tx
+            .with_reply(ebc::Property::MinimizeHints(tx), rx)
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    #[zbus(property)]
+    async fn set_minimize_hints(&self, minimize: bool) -> Result<(), zbus::Error> {
+        self.ebc_tx
+            .send(ebc::Property::SetMinimizeHints(minimize))
+            .await
+            .map_err(dbus::internal_error)
+            .map_err(zbus::Error::from)
+    }
+
+    /// Emit *HintsUpdated* on every upload. Off by default, as it is costly with many windows.
+    #[zbus(property)]
+    async fn emit_hint_updates(&self) -> fdo::Result<bool> {
+        let (tx, rx) = oneshot::channel::<bool>();
+
+        self.ebc_tx
+            .with_reply(ebc::Property::EmitHintUpdates(tx), rx)
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    #[zbus(property)]
+    async fn set_emit_hint_updates(&self, emit: bool) -> Result<(), zbus::Error> {
+        self.ebc_tx
+            .send(ebc::Property::SetEmitHintUpdates(emit))
+            .await
+            .map_err(dbus::internal_error)
+            .map_err(zbus::Error::from)
+    }
+
     #[zbus(property)]
     async fn default_hint(&self) -> fdo::Result<super::Hint> {
         let (tx, rx) = oneshot::channel::<CoreHint>();
@@ -158,6 +497,7 @@ impl Ebc1 {
         #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
     ) -> Result<(), zbus::Error> {
         let hint: CoreHint = hint.into();
+        dbus::warn_wasteful(&hint);
 
         self.ebc_tx
             .send(ebc::Property::SetDefaultHint(hint))
@@ -189,16 +529,7 @@ impl Ebc1 {
         hint: String,
         #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
     ) -> Result<(), zbus::Error> {
-        let Ok(hint) = CoreHint::try_from_human_readable(hint.as_str()) else {
-            return Err(fdo::Error::InvalidArgs("Invalid format".into()).into());
-        };
-
-        self.ebc_tx
-            .send(ebc::Property::SetDefaultHint(hint))
-            .await
-            .map_err(dbus::internal_error)
-            .map_err(zbus::Error::from)?;
-
+        self.set_default_hint_from_str(&hint).await?;
         self.default_hint_changed(&emitter).await?;
 
         Ok(())
@@ -264,6 +595,62 @@ impl Ebc1 {
             .map_err(zbus::Error::from)
     }
 
+    /// Fast drawing parameters: redraw delay and early cancellation addition.
+    ///
+    /// Applies to every hint with the redraw bit set, whichever bridge or
+    /// client set it.
+    #[zbus(property)]
+    async fn redraw_options(&self) -> fdo::Result<RedrawOptions> {
+        let (tx, reply) = oneshot::channel::<RedrawOptions>();
+
+        self.ebc_tx
+            .with_reply(ebc::Property::RedrawOptions(tx), reply)
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    #[zbus(property)]
+    async fn set_redraw_options(
+        &self,
+        options: RedrawOptions,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> Result<(), zbus::Error> {
+        self.ebc_tx
+            .send(ebc::Property::SetRedrawOptions(options))
+            .await
+            .map_err(dbus::internal_error)
+            .map_err(zbus::Error::from)?;
+
+        self.redraw_delay_changed(&emitter).await?;
+
+        Ok(())
+    }
+
+    /// Minimum interval between two global refreshes, in milliseconds.
+    ///
+    /// Refreshes requested sooner are dropped. 0 disables the limit.
+    #[zbus(property)]
+    async fn global_refresh_interval(&self) -> fdo::Result<u32> {
+        let (tx, reply) = oneshot::channel::<Duration>();
+
+        self.ebc_tx
+            .with_reply(ebc::Property::GlobalRefreshInterval(tx), reply)
+            .await
+            .map_err(dbus::internal_error)
+            .map(|interval| interval.as_millis().try_into().unwrap_or(u32::MAX))
+    }
+
+    #[zbus(property)]
+    async fn set_global_refresh_interval(&self, interval: u32) -> Result<(), zbus::Error> {
+        let interval = Duration::from_millis(interval.into());
+
+        self.ebc_tx
+            .send(ebc::Property::SetGlobalRefreshInterval(interval))
+            .await
+            .map_err(dbus::internal_error)
+            .map_err(zbus::Error::from)
+    }
+
     #[zbus(property)]
     async fn redraw_delay(&self) -> fdo::Result<u16> {
         let (tx, reply) = oneshot::channel::<u16>();
@@ -282,4 +669,86 @@ impl Ebc1 {
             .map_err(dbus::internal_error)
             .map_err(zbus::Error::from)
     }
+
+    /// Emitted after each upload of hints while *EmitHintUpdates* is set, carrying the rectangles
+    /// and human readable hints uploaded, like *CurrentHints*.
+    #[zbus(signal)]
+    pub async fn hints_updated(
+        emitter: &SignalEmitter<'_>,
+        hints: Vec<(Rect, String)>,
+    ) -> zbus::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ebc::testing::{Core, MockDriver};
+
+    #[tokio::test]
+    async fn redraw_delay_preview_refreshes() {
+        let (tx, mut rx) = ebc::CommandSender::channel(4);
+        let ebc1 = Ebc1::new(tx, &Config::default());
+
+        ebc1.set_redraw_delay_and_refresh(150).await.unwrap();
+
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ebc::Command::Property(ebc::Property::SetRedrawDelay(150)))
+        ));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ebc::Command::GlobalRefreshDeferred)
+        ));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rapid_redraw_delay_previews_refresh() {
+        let core = Core::start();
+        let ebc1 = Ebc1::new(core.tx.clone(), &Config::default());
+
+        ebc1.set_redraw_delay_and_refresh(150).await.unwrap();
+        ebc1.set_redraw_delay_and_refresh(200).await.unwrap();
+        core.sync().await;
+        assert_eq!(1, core.driver.count("global_refresh"));
+
+        // The second one is delayed to the end of the refresh interval
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        core.sync().await;
+        assert_eq!(2, core.driver.count("global_refresh"));
+    }
+
+    #[tokio::test]
+    async fn default_hint_hr_round_trip() {
+        let core = Core::start();
+        let ebc1 = Ebc1::new(core.tx.clone(), &Config::default());
+
+        ebc1.set_default_hint_from_str("Y2|D|R").await.unwrap();
+        assert_eq!("Y2|D|R", ebc1.default_hint_hr().await.unwrap());
+
+        assert!(matches!(
+            ebc1.set_default_hint_from_str("Y3").await,
+            Err(fdo::Error::InvalidArgs(_))
+        ));
+        assert_eq!("Y2|D|R", ebc1.default_hint_hr().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn panel_info_reports_driver_area() {
+        // A driver with a smaller panel
+        let driver = MockDriver::with_screen_area(Rect::new(0, 0, 800, 600));
+        let core = Core::start_with(driver, |ctl| ctl);
+        let ebc1 = Ebc1::new(core.tx.clone(), &Config::default());
+
+        let info = ebc1.panel_info().await.unwrap();
+        let get = |key: &str| Value::from(info[key].try_clone().unwrap());
+
+        assert_eq!(Value::from(800), get("width"));
+        assert_eq!(Value::from(600), get("height"));
+        assert_eq!(Value::from(vec!["Y1", "Y2", "Y4"]), get("bit_depths"));
+        assert_eq!(Value::from(false), get("zero_waveform"));
+
+        let methods: Vec<String> = get("dither_methods").try_into().unwrap();
+        assert!(methods.iter().any(|m| m == "blue-noise-32"));
+    }
 }
@@ -1,13 +1,22 @@
+use std::time::Duration;
+
 use nix::libc::pid_t;
-use pinenote_service::types::{Rect, rockchip_ebc::Hint};
+use pinenote_service::{
+    pixel_manager::{Inset, PixelManager},
+    types::{MountOrientation, Rect, rockchip_ebc::Hint},
+};
 use serde::Deserialize;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{OnceCell, oneshot};
 use zbus::{
     fdo, interface,
+    object_server::SignalEmitter,
     zvariant::{Type, Value},
 };
 
-use crate::{dbus, ebc};
+use crate::{
+    config::{Config, RectPolicy},
+    dbus, ebc,
+};
 
 #[derive(Type, Value, Deserialize)]
 struct Window {
@@ -21,11 +30,48 @@ struct Window {
 
 pub struct HintMgr1 {
     tx: ebc::CommandSender,
+    rect_policy: RectPolicy,
+    mount_orientation: MountOrientation,
+    screen_area: OnceCell<Rect>,
 }
 
 impl HintMgr1 {
-    pub fn new(tx: mpsc::Sender<ebc::Command>) -> Self {
-        Self { tx: tx.into() }
+    pub fn new(tx: ebc::CommandSender, config: &Config) -> Self {
+        Self {
+            tx,
+            rect_policy: config.rect_policy,
+            mount_orientation: config.mount_orientation,
+            screen_area: OnceCell::new(),
+        }
+    }
+
+    async fn screen_area(&self) -> fdo::Result<&Rect> {
+        self.screen_area
+            .get_or_try_init(|| async {
+                let (tx, rx) = oneshot::channel::<Rect>();
+                self.tx.with_reply(ebc::Property::ScreenArea(tx), rx).await
+            })
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Validate a client rectangle, and apply the configured [RectPolicy].
+    async fn check_rect(&self, rect: Rect) -> fdo::Result<Rect> {
+        let rect = validate_rect(rect)?;
+
+        if self.rect_policy == RectPolicy::Accept {
+            return Ok(rect);
+        }
+
+        apply_rect_policy(rect, self.screen_area().await?, self.rect_policy)
+    }
+
+    /// Validate a client region, given in the frame of the mounted panel, and map it to the
+    /// panel.
+    async fn check_region(&self, rect: Rect) -> fdo::Result<Rect> {
+        let screen = self.screen_area().await?;
+
+        mount_region(rect, screen, self.rect_policy, self.mount_orientation)
     }
 
     async fn send_win(&self, win: ebc::Window) -> fdo::Result<()> {
@@ -37,10 +83,11 @@ fn parse_hint(hint: String) -> fdo::Result<Option<Hint>> {
     let ret = if hint.is_empty() {
         None
     } else {
-        Some(
-            Hint::try_from_human_readable(hint.as_str())
-                .map_err(|_| fdo::Error::InvalidArgs(format!("Unrecognized Hint {hint}")))?,
-        )
+        let hint = Hint::try_from_human_readable(hint.as_str())
+            .map_err(|_| fdo::Error::InvalidArgs(format!("Unrecognized Hint {hint}")))?;
+        dbus::warn_wasteful(&hint);
+
+        Some(hint)
     };
 
     Ok(ret)
@@ -56,6 +103,61 @@ fn validate_rect(rect: Rect) -> fdo::Result<Rect> {
     }
 }
 
+/// The lowest z-index is reserved to the background region.
+fn validate_z_index(z_index: i32) -> fdo::Result<i32> {
+    if z_index == PixelManager::BACKGROUND_Z_INDEX {
+        Err(fdo::Error::InvalidArgs(format!(
+            "z-index {z_index} is reserved"
+        )))
+    } else {
+        Ok(z_index)
+    }
+}
+
+/// Regions only exist to be hinted, unlike windows which may be empty while resized or hidden.
+fn reject_empty(rect: Rect) -> fdo::Result<Rect> {
+    if rect.x1 == rect.x2 || rect.y1 == rect.y2 {
+        Err(fdo::Error::InvalidArgs(
+            "A region requires a non-empty area".into(),
+        ))
+    } else {
+        Ok(rect)
+    }
+}
+
+fn mount_region(
+    rect: Rect,
+    screen: &Rect,
+    policy: RectPolicy,
+    mount: MountOrientation,
+) -> fdo::Result<Rect> {
+    let size = (screen.x2, screen.y2);
+    let (width, height) = mount.mounted_size(size);
+    let mounted = Rect::new(0, 0, width, height);
+
+    let rect = apply_rect_policy(validate_rect(rect)?, &mounted, policy)?;
+
+    Ok(mount.map_rect(&reject_empty(rect)?, size))
+}
+
+fn apply_rect_policy(rect: Rect, screen: &Rect, policy: RectPolicy) -> fdo::Result<Rect> {
+    // An empty rectangle covers no pixel, there is nothing to exceed
+    if policy == RectPolicy::Accept || rect.area() == 0 {
+        return Ok(rect);
+    }
+
+    match (rect.intersection(screen), policy) {
+        (Some(visible), RectPolicy::Reject) if visible == rect => Ok(rect),
+        (Some(visible), RectPolicy::Clamp) => Ok(visible),
+        (None, RectPolicy::Clamp) => Err(fdo::Error::InvalidArgs(
+            "Rectangle is outside of the screen".into(),
+        )),
+        _ => Err(fdo::Error::InvalidArgs(
+            "Rectangle exceeds the screen".into(),
+        )),
+    }
+}
+
 /// DBus interface to manage per Window Hints.
 ///
 /// # Window Attributes
@@ -110,6 +212,14 @@ impl HintMgr1 {
     /// When an application is removed, all remaining window metadata are
     /// discarded.
     async fn app_register(&self, pid: i32) -> fdo::Result<String> {
+        self.app_register_with_id(pid, String::new()).await
+    }
+
+    /// Register a new Application, identified by an app_id.
+    ///
+    /// Same as `AppRegister`, but the app_id is used to build the application
+    /// key, so that several applications sharing a pid can be told apart.
+    async fn app_register_with_id(&self, pid: i32, app_id: String) -> fdo::Result<String> {
         let (tx, rx) = oneshot::channel::<String>();
 
         if pid <= 0 {
@@ -122,7 +232,7 @@ impl HintMgr1 {
         // testing with busctl and dbus-send always returns systemd pid
 
         self.tx
-            .with_reply(ebc::Application::Add(pid, tx), rx)
+            .with_reply(ebc::Application::Add(app_id, pid, tx), rx)
             .await
             .map_err(dbus::internal_error)
     }
@@ -137,6 +247,67 @@ impl HintMgr1 {
             .map_err(dbus::internal_error)
     }
 
+    /// Remove every application without any window
+    ///
+    /// Returns the number of removed applications. Applications are expected to
+    /// be removed by their bridge, this cleans up the ones left behind.
+    async fn app_prune_empty(&self) -> fdo::Result<u32> {
+        let (tx, rx) = oneshot::channel::<usize>();
+
+        self.tx
+            .with_reply(ebc::Application::PruneEmpty(tx), rx)
+            .await
+            .map_err(dbus::internal_error)
+            .map(|pruned| pruned as u32)
+    }
+
+    /// Hint all the visible windows of an application as a single region
+    ///
+    /// The bounding box of the windows takes the application default hint, at
+    /// the highest z-index among them, whatever the windows hints are. Saves
+    /// rectangles for applications made of many small windows.
+    async fn app_set_merge_windows(&self, app_key: String, merge: bool) -> fdo::Result<()> {
+        let (reply, rx) = oneshot::channel();
+        let cmd = ebc::Application::SetMergeWindows {
+            app_key,
+            merge,
+            reply,
+        };
+
+        let known = self
+            .tx
+            .with_reply(cmd, rx)
+            .await
+            .map_err(dbus::internal_error)?;
+
+        known
+            .then_some(())
+            .ok_or_else(|| fdo::Error::InvalidArgs("Unknown application".into()))
+    }
+
+    /// Pin every window of an application on top of the others
+    ///
+    /// Like `WindowUpdateAlwaysOnTop` for each of its windows, the ones
+    /// added later included, e.g. for a palette application.
+    async fn app_set_always_on_top(&self, app_key: String, always_on_top: bool) -> fdo::Result<()> {
+        let (reply, rx) = oneshot::channel();
+        let cmd = ebc::Application::SetAlwaysOnTop {
+            app_key,
+            always_on_top,
+            reply,
+        };
+
+        let known = self
+            .tx
+            .with_reply(cmd, rx)
+            .await
+            .map_err(dbus::internal_error)?;
+
+        known
+            .then_some(())
+            .ok_or_else(|| fdo::Error::InvalidArgs("Unknown application".into()))
+    }
+
     /// Adds a new window
     ///
     /// This method register a new Window and specifies its attribute. If the
@@ -154,7 +325,8 @@ impl HintMgr1 {
         } = win;
 
         let hint = parse_hint(hint)?;
-        let area = validate_rect(area)?;
+        let area = self.check_rect(area).await?;
+        let z_index = validate_z_index(z_index)?;
 
         let add = ebc::Window::Add {
             app_key,
@@ -164,6 +336,8 @@ impl HintMgr1 {
             visible,
             fullscreen,
             z_index,
+            focused: false,
+            external_id: None,
             reply,
         };
         self.tx
@@ -188,7 +362,8 @@ impl HintMgr1 {
         } = win;
 
         let hint = parse_hint(hint)?;
-        let area = validate_rect(area)?;
+        let area = self.check_rect(area).await?;
+        let z_index = validate_z_index(z_index)?;
 
         let update = ebc::WindowUpdate {
             title: Some(title),
@@ -197,6 +372,7 @@ impl HintMgr1 {
             visible: Some(visible),
             fullscreen: Some(fullscreen),
             z_index: Some(z_index),
+            ..Default::default()
         };
 
         self.send_win(ebc::Window::Update { win_key, update }).await
@@ -214,7 +390,7 @@ impl HintMgr1 {
 
     /// Update the window area
     async fn window_update_area(&self, win_key: String, area: Rect) -> fdo::Result<()> {
-        let area = validate_rect(area)?;
+        let area = self.check_rect(area).await?;
 
         let update = ebc::WindowUpdate {
             area: Some(area),
@@ -224,6 +400,50 @@ impl HintMgr1 {
         self.send_win(ebc::Window::Update { win_key, update }).await
     }
 
+    /// Set the margins around the window content, in pixels.
+    ///
+    /// The window hint only applies to its content, margins use the application or global
+    /// default hint. Zero margins make the hint apply to the whole window again.
+    async fn window_update_inset(
+        &self,
+        win_key: String,
+        top: i32,
+        right: i32,
+        bottom: i32,
+        left: i32,
+    ) -> fdo::Result<()> {
+        if [top, right, bottom, left].iter().any(|m| *m < 0) {
+            return Err(fdo::Error::InvalidArgs("Negative margin".into()));
+        }
+
+        let update = ebc::WindowUpdate {
+            content_inset: Some(Inset {
+                top,
+                right,
+                bottom,
+                left,
+            }),
+            ..Default::default()
+        };
+
+        self.send_win(ebc::Window::Update { win_key, update }).await
+    }
+
+    /// Pin the window on top of the others when computing the hints, whatever the z-index the
+    /// compositor reports, e.g. for a floating ruler or palette.
+    async fn window_update_always_on_top(
+        &self,
+        win_key: String,
+        always_on_top: bool,
+    ) -> fdo::Result<()> {
+        let update = ebc::WindowUpdate {
+            always_on_top: Some(always_on_top),
+            ..Default::default()
+        };
+
+        self.send_win(ebc::Window::Update { win_key, update }).await
+    }
+
     /// Set or unset the window rendering hints.
     async fn window_update_hint(&self, win_key: String, hint: String) -> fdo::Result<()> {
         let hint = parse_hint(hint)?;
@@ -258,15 +478,280 @@ impl HintMgr1 {
     /// Set the window's z-index
     async fn window_update_zindex(&self, win_key: String, z_index: i32) -> fdo::Result<()> {
         let update = ebc::WindowUpdate {
-            z_index: Some(z_index),
+            z_index: Some(validate_z_index(z_index)?),
+            ..Default::default()
+        };
+
+        self.send_win(ebc::Window::Update { win_key, update }).await
+    }
+
+    /// Set or unset the window's focused flag
+    ///
+    /// At most one window is focused: focusing a window unfocuses the previous
+    /// one.
+    async fn window_update_focused(&self, win_key: String, focused: bool) -> fdo::Result<()> {
+        let update = ebc::WindowUpdate {
+            focused: Some(focused),
             ..Default::default()
         };
 
         self.send_win(ebc::Window::Update { win_key, update }).await
     }
 
+    /// Set or unset the rendering hints of the focused window
+    ///
+    /// Meant for keybinds acting on the window being looked at, without
+    /// knowing its key. Fails if no window is focused.
+    async fn set_focused_window_hint(&self, hint: String) -> fdo::Result<()> {
+        let (reply, rx) = oneshot::channel::<Option<String>>();
+        let hint = parse_hint(hint)?;
+
+        self.tx
+            .with_reply(ebc::Window::SetFocusedHint { hint, reply }, rx)
+            .await
+            .map_err(dbus::internal_error)?
+            .map(|_| ())
+            .ok_or_else(|| fdo::Error::Failed("No window is focused".into()))
+    }
+
+    /// Refresh the screen area covered by a window
+    ///
+    /// Gives immediate feedback after changing a window hint. Nothing is
+    /// refreshed if the window isn't visible.
+    async fn window_refresh(&self, win_key: String) -> fdo::Result<()> {
+        self.send_win(ebc::Window::Refresh(win_key)).await
+    }
+
+    /// How many updates a window received, each one recomputing the hints, and its area left
+    /// visible by the windows above, in pixels. Helps finding a window updating every frame.
+    #[zbus(out_args("updates", "visible_area"))]
+    async fn window_stats(&self, win_key: String) -> fdo::Result<(u64, i64)> {
+        let (reply, rx) = oneshot::channel();
+
+        let stats = self
+            .tx
+            .with_reply(ebc::Window::Stats { win_key, reply }, rx)
+            .await
+            .map_err(dbus::internal_error)?
+            .ok_or_else(|| fdo::Error::InvalidArgs("Unknown window".into()))?;
+
+        Ok((stats.updates, stats.visible_area))
+    }
+
+    /// Topmost visible window covering the pixel at (`x`, `y`) and the human readable hint it
+    /// is rendered with. Both are empty when no window covers it, the default hint applies. For
+    /// the windows of an application merged with `AppSetMergeWindows`, the application key is
+    /// returned instead.
+    #[zbus(out_args("win_key", "hint"))]
+    async fn window_at(&self, x: i32, y: i32) -> fdo::Result<(String, String)> {
+        let (reply, rx) = oneshot::channel();
+
+        let window = self
+            .tx
+            .with_reply(ebc::Window::At { x, y, reply }, rx)
+            .await
+            .map_err(dbus::internal_error)?;
+
+        Ok(window
+            .map(|(win_key, hint)| (win_key, hint.to_string()))
+            .unwrap_or_default())
+    }
+
     /// Remove a window
     async fn window_remove(&self, key: String) -> fdo::Result<()> {
         self.send_win(ebc::Window::Remove(key)).await
     }
+
+    /// Hint a screen region directly
+    ///
+    /// Registers a visible region with the given hint and z-index, without
+    /// having to register an application and a window first. Returns a key to
+    /// remove the region with `RegionRemove`. Regions are reported as windows
+    /// of a reserved application by the window signals.
+    ///
+    /// Unlike window areas, the area is given in the frame of the mounted
+    /// panel, see `PINENOTE_MOUNT_ORIENTATION`.
+    async fn region_set_hint(&self, area: Rect, hint: String, z_index: i32) -> fdo::Result<String> {
+        let (reply, rx) = oneshot::channel::<String>();
+
+        let hint = parse_hint(hint)?
+            .ok_or_else(|| fdo::Error::InvalidArgs("A region requires a hint".into()))?;
+        let area = self.check_region(area).await?;
+        let z_index = validate_z_index(z_index)?;
+
+        let add = ebc::Window::AddRegion {
+            area,
+            hint,
+            z_index,
+            reply,
+        };
+        self.tx
+            .with_reply(add, rx)
+            .await
+            .map_err(dbus::internal_error)
+    }
+
+    /// Hint a screen region on top of every window for `duration_ms`, then revert it
+    ///
+    /// Meant for transient feedback, e.g. rendering a button press fast. The screen is refreshed
+    /// when the flash starts and when it ends. Flashing an area already flashing replaces it.
+    /// Like for `RegionSetHint`, the area is given in the frame of the mounted panel.
+    async fn flash_region(&self, area: Rect, hint: String, duration_ms: u32) -> fdo::Result<()> {
+        let hint = parse_hint(hint)?
+            .ok_or_else(|| fdo::Error::InvalidArgs("A region requires a hint".into()))?;
+        let area = self.check_region(area).await?;
+
+        let flash = ebc::Command::FlashRegion {
+            area,
+            hint,
+            duration: Duration::from_millis(duration_ms.into()),
+        };
+        self.tx.send(flash).await.map_err(dbus::internal_error)
+    }
+
+    /// Remove a region added by `RegionSetHint`
+    async fn region_remove(&self, region_key: String) -> fdo::Result<()> {
+        self.send_win(ebc::Window::RemoveRegion(region_key)).await
+    }
+
+    /// Hint the desktop background
+    ///
+    /// Pixels no window covers use the default hint. This sets a distinct hint
+    /// for them, using a full screen region below every other window (its
+    /// z-index, the lowest i32, is reserved). An empty hint removes the
+    /// background.
+    async fn background_set_hint(&self, hint: String) -> fdo::Result<()> {
+        let hint = parse_hint(hint)?;
+
+        self.send_win(ebc::Window::SetBackground(hint)).await
+    }
+
+    /// Emitted when a window is added, by a bridge or through DBus.
+    #[zbus(signal)]
+    pub async fn window_added(
+        emitter: &SignalEmitter<'_>,
+        win_key: &str,
+        app_key: &str,
+    ) -> zbus::Result<()>;
+
+    /// Emitted when a window is removed, by a bridge or through DBus. Removing
+    /// an application emits it for each of its windows.
+    #[zbus(signal)]
+    pub async fn window_removed(emitter: &SignalEmitter<'_>, win_key: &str) -> zbus::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCREEN: Rect = Rect::new(0, 0, 1872, 1404);
+
+    #[test]
+    fn parse_hint_rejects_malformed() {
+        // Clients always get an error, strict hints or not
+        for hint in ["Y3", "Y4|X", "y4", "Y4 "] {
+            assert!(
+                matches!(parse_hint(hint.into()), Err(fdo::Error::InvalidArgs(_))),
+                "{hint}"
+            );
+        }
+
+        assert!(matches!(parse_hint(String::new()), Ok(None)));
+        assert!(matches!(parse_hint("Y2|D".into()), Ok(Some(_))));
+    }
+
+    #[test]
+    fn background_z_index_reserved() {
+        assert!(matches!(
+            validate_z_index(i32::MIN),
+            Err(fdo::Error::InvalidArgs(_))
+        ));
+
+        for z_index in [i32::MIN + 1, 0, i32::MAX] {
+            assert_eq!(Ok(z_index), validate_z_index(z_index));
+        }
+    }
+
+    #[test]
+    fn empty_region_rejected() {
+        for rect in [Rect::new(10, 10, 10, 20), Rect::new(10, 10, 20, 10)] {
+            assert!(matches!(
+                reject_empty(rect),
+                Err(fdo::Error::InvalidArgs(_))
+            ));
+        }
+
+        let rect = Rect::new(10, 10, 11, 11);
+        assert_eq!(Ok(rect.clone()), reject_empty(rect));
+    }
+
+    #[test]
+    fn region_mounted() {
+        // The top left corner of a panel mounted upside down is its bottom right corner
+        let rect = Rect::new(0, 0, 10, 20);
+        assert_eq!(
+            Ok(Rect::new(1862, 1384, 1872, 1404)),
+            mount_region(
+                rect,
+                &SCREEN,
+                RectPolicy::Reject,
+                MountOrientation::Rotated180
+            )
+        );
+
+        // Policies apply to the mounted frame, a portrait one here
+        let rect = Rect::new(0, 1800, 1404, 1900);
+        assert_eq!(
+            Ok(Rect::new(0, 0, 72, 1404)),
+            mount_region(
+                rect.clone(),
+                &SCREEN,
+                RectPolicy::Clamp,
+                MountOrientation::Rotated270
+            )
+        );
+        assert!(
+            mount_region(
+                rect,
+                &SCREEN,
+                RectPolicy::Reject,
+                MountOrientation::Rotated270
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn oversized_rect_accept() {
+        let rect = Rect::new(1000, 1000, 2000, 1600);
+
+        assert_eq!(
+            Ok(rect.clone()),
+            apply_rect_policy(rect, &SCREEN, RectPolicy::Accept)
+        );
+    }
+
+    #[test]
+    fn oversized_rect_clamp() {
+        let rect = Rect::new(1000, 1000, 2000, 1600);
+        assert_eq!(
+            Ok(Rect::new(1000, 1000, 1872, 1404)),
+            apply_rect_policy(rect, &SCREEN, RectPolicy::Clamp)
+        );
+
+        let outside = Rect::new(2000, 0, 2100, 100);
+        assert!(apply_rect_policy(outside, &SCREEN, RectPolicy::Clamp).is_err());
+    }
+
+    #[test]
+    fn oversized_rect_reject() {
+        let rect = Rect::new(1000, 1000, 2000, 1600);
+        assert!(apply_rect_policy(rect, &SCREEN, RectPolicy::Reject).is_err());
+
+        let inside = Rect::new(0, 0, 1872, 1404);
+        assert_eq!(
+            Ok(inside.clone()),
+            apply_rect_policy(inside, &SCREEN, RectPolicy::Reject)
+        );
+    }
 }
@@ -1,6 +1,6 @@
 //! rockchip_ebc driver support
 
-use std::os::fd::AsRawFd;
+use std::{os::fd::AsRawFd, path::Path};
 
 use thiserror::Error;
 
@@ -9,11 +9,13 @@ use crate::{
     pixel_manager::ComputedHints,
     sysfs::{
         self,
-        attribute::{AttributeBase, Boolean, Int32, RGeneric, RInt32, TypedRead, TypedWrite},
+        attribute::{
+            AttributeBase, Boolean, Generic, Int32, RGeneric, RInt32, TypedRead, TypedWrite,
+        },
     },
     types::{
         Rect,
-        rockchip_ebc::{DitherMode, FrameBuffers, Hint, Mode},
+        rockchip_ebc::{DitherMode, FrameBuffers, Hint, Mode, RedrawOptions},
     },
 };
 
@@ -27,6 +29,12 @@ pub enum DriverError {
     SysFs(#[from] sysfs::attribute::Error),
     #[error("Bad size. Expected {0}, got {1}")]
     BadSize(usize, usize),
+    #[error("Screen area {0:?} doesn't cover any pixel")]
+    EmptyScreenArea(Rect),
+    #[error("Too many rectangle hints: {0}, the driver takes at most {1}")]
+    TooManyRects(usize, usize),
+    #[error("Parameter '{0}' is not tunable")]
+    UnknownParam(String),
 }
 
 /// Control structure for the RockchipEbc driver
@@ -44,6 +52,10 @@ pub struct RockchipEbc {
     bw_threshold: RInt32,
     y2_dt_threshold: Int32,
     y2_th_threshold: Int32,
+    /// Fixed temperature used to pick the waveform instead of the panel sensor reading.
+    ///
+    /// The driver has no parameter to turn temperature compensation off: forcing a temperature
+    /// through this one is the only lever.
     temp_override: Int32,
     hskew_override: Int32,
     rect_hint_batch: Int32,
@@ -54,6 +66,30 @@ impl RockchipEbc {
     const DEV_PATH: &str = "/dev/dri/by-path/platform-fdec0000.ebc-card";
     const SCREEN_RECT: Rect = Rect::new(0, 0, 1872, 1404);
 
+    /// Most rectangle hints uploaded at once.
+    ///
+    /// The driver doesn't advertise any limit, this one is the service's own: it bounds the array
+    /// the driver copies on every upload, which a runaway layout could otherwise make arbitrarily
+    /// large. The core falls back to coarser hints rather than going over it.
+    pub const MAX_RECT_HINTS: usize = 1024;
+
+    /// Parameters which can be read and written by name, see [Self::param].
+    ///
+    /// Mostly knobs without a typed API. `early_cancellation_addition` is also part of the
+    /// [RedrawOptions], writing it here bypasses the value the service saves with its state.
+    /// Modes and the default hint, which the service manages itself, go through their own calls.
+    pub const TUNABLE_PARAMS: &[&str] = &[
+        "early_cancellation_addition",
+        "hskew_override",
+        "limit_fb_blits",
+        "rect_hint_batch",
+        "refresh_thread_wait_idle",
+        "shrink_virtual_window",
+        "temp_override",
+        "y2_dt_threshold",
+        "y2_th_threshold",
+    ];
+
     pub fn new() -> Self {
         Self {
             default_hint: Self::make_param("default_hint"),
@@ -74,6 +110,18 @@ impl RockchipEbc {
         }
     }
 
+    /// Whether the driver is loaded and its device node exists.
+    ///
+    /// The driver may be loaded after the service starts, nothing works until then.
+    pub fn available() -> bool {
+        Path::new(Self::SYSFS_PATH_BASE).exists() && Path::new(Self::DEV_PATH).exists()
+    }
+
+    /// Whether the driver answers, using a cheap read of one of its parameters.
+    pub fn probe(&self) -> bool {
+        Self::available() && self.redraw_delay.read().is_ok()
+    }
+
     /// Get the hints applied to uncovered pixels.
     pub fn default_hint(&self) -> Result<Hint, crate::sysfs::attribute::Error> {
         self.default_hint.read()
@@ -108,7 +156,12 @@ impl RockchipEbc {
         Ok(())
     }
 
+    /// Replace the rectangle hints, and the default hint if set.
+    ///
+    /// The driver only takes hints: there is no ioctl, nor debugfs entry, to read them back.
     pub fn upload_rect_hints(&self, rect_hints: ComputedHints) -> Result<(), DriverError> {
+        Self::check_rect_count(rect_hints.rect_hints.len())?;
+
         let file = ioctls::open_device(Self::DEV_PATH)?;
         let ComputedHints {
             default_hint,
@@ -133,8 +186,26 @@ impl RockchipEbc {
         Ok(())
     }
 
+    /// Area covered by the panel, guaranteed to span at least one pixel in each dimension.
     pub fn screen_area(&self) -> Result<Rect, DriverError> {
-        Ok(Self::SCREEN_RECT.clone())
+        Self::check_screen_area(Self::SCREEN_RECT.clone())
+    }
+
+    /// Reject screen areas with a zero or negative dimension, everything would be clipped away.
+    fn check_screen_area(area: Rect) -> Result<Rect, DriverError> {
+        if area.x2 - area.x1 <= 0 || area.y2 - area.y1 <= 0 {
+            return Err(DriverError::EmptyScreenArea(area));
+        }
+
+        Ok(area)
+    }
+
+    fn check_rect_count(count: usize) -> Result<(), DriverError> {
+        if count > Self::MAX_RECT_HINTS {
+            return Err(DriverError::TooManyRects(count, Self::MAX_RECT_HINTS));
+        }
+
+        Ok(())
     }
 
     pub fn extract_framebuffers(&self) -> Result<FrameBuffers, DriverError> {
@@ -177,6 +248,25 @@ impl RockchipEbc {
         Ok(())
     }
 
+    /// Get the fast drawing parameters.
+    pub fn redraw_options(&self) -> Result<RedrawOptions, DriverError> {
+        let redraw_delay = self.mode()?.redraw_delay.unwrap_or_default();
+
+        Ok(RedrawOptions {
+            redraw_delay,
+            early_cancellation_addition: self.early_cancellation_addition.read()?,
+        })
+    }
+
+    /// Set the fast drawing parameters.
+    pub fn set_redraw_options(&self, options: RedrawOptions) -> Result<(), DriverError> {
+        self.set_mode(options.into())?;
+        self.early_cancellation_addition
+            .write(options.early_cancellation_addition)?;
+
+        Ok(())
+    }
+
     /// Upload content for Off Screen
     pub fn upload_off_screen(&self, screen_content: Vec<u8>) -> Result<(), DriverError> {
         let Rect { x2, y2, .. } = Self::SCREEN_RECT;
@@ -199,6 +289,25 @@ impl RockchipEbc {
         Ok(())
     }
 
+    /// Read one of the [Self::TUNABLE_PARAMS], as reported by the kernel.
+    pub fn param(&self, name: &str) -> Result<String, DriverError> {
+        Ok(Self::tunable_param(Self::SYSFS_PATH_BASE, name)?.read()?)
+    }
+
+    /// Write one of the [Self::TUNABLE_PARAMS]. The value is passed as is, the kernel validates
+    /// it.
+    pub fn set_param(&self, name: &str, value: String) -> Result<(), DriverError> {
+        Ok(Self::tunable_param(Self::SYSFS_PATH_BASE, name)?.write(value)?)
+    }
+
+    fn tunable_param(base: &str, name: &str) -> Result<Generic<String>, DriverError> {
+        if !Self::TUNABLE_PARAMS.contains(&name) {
+            return Err(DriverError::UnknownParam(name.into()));
+        }
+
+        Ok(Generic::from_path(format!("{base}/{name}")))
+    }
+
     fn make_param<T: AttributeBase>(name: &str) -> T {
         T::from_path(format!("{}/{}", Self::SYSFS_PATH_BASE, name))
     }
@@ -209,3 +318,67 @@ impl Default for RockchipEbc {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn screen_area_degenerate() {
+        for area in [
+            Rect::new(0, 0, 0, 0),
+            Rect::new(0, 0, 1872, 0),
+            Rect::new(0, 0, -1872, 1404),
+        ] {
+            assert!(matches!(
+                RockchipEbc::check_screen_area(area),
+                Err(DriverError::EmptyScreenArea(_))
+            ));
+        }
+
+        assert!(RockchipEbc::check_screen_area(RockchipEbc::SCREEN_RECT).is_ok());
+    }
+
+    #[test]
+    fn too_many_rect_hints() {
+        let hint = Hint::try_from_human_readable("Y4").unwrap();
+        let hints = ComputedHints {
+            default_hint: None,
+            rect_hints: vec![
+                crate::types::rockchip_ebc::RectHint {
+                    rect: Rect::new(0, 0, 1, 1),
+                    hint,
+                };
+                RockchipEbc::MAX_RECT_HINTS + 1
+            ],
+        };
+
+        // Refused before reaching the device
+        assert!(matches!(
+            RockchipEbc::new().upload_rect_hints(hints),
+            Err(DriverError::TooManyRects(1025, 1024))
+        ));
+        assert!(RockchipEbc::check_rect_count(RockchipEbc::MAX_RECT_HINTS).is_ok());
+    }
+
+    #[test]
+    fn tunable_param_round_trip() {
+        let dir = TempDir::new("params");
+        let base = dir.create();
+        std::fs::write(base.join("temp_override"), "0\n").unwrap();
+        let base_str = base.to_str().unwrap();
+
+        let param = RockchipEbc::tunable_param(base_str, "temp_override").unwrap();
+        assert_eq!("0", param.read().unwrap());
+        param.write("25".to_string()).unwrap();
+        assert_eq!("25", param.read().unwrap());
+
+        for name in ["redraw_delay", "../../../etc/passwd", ""] {
+            assert!(matches!(
+                RockchipEbc::tunable_param(base_str, name),
+                Err(DriverError::UnknownParam(_))
+            ));
+        }
+    }
+}
@@ -1,27 +1,99 @@
+use std::time::Duration;
+
 use anyhow::Context;
 use nix::libc::pid_t;
-use pinenote_service::types::{
-    Rect,
-    rockchip_ebc::{DitherMode, DriverMode, Hint},
+use pinenote_service::{
+    layout::LayoutSnapshot,
+    pixel_manager::{Inset, WindowStats},
+    types::{
+        Rect,
+        rockchip_ebc::{DitherMode, DriverMode, Hint, HintBitDepth, RectHint, RedrawOptions},
+    },
 };
 use tokio::sync::{mpsc, oneshot};
 
 use super::OffScreenError;
+use crate::config::Profile;
 
 pub enum Command {
     Application(Application),
+    ApplyProfile(Profile),
+    DebugVisualize(bool),
     Dump(String),
+    DumpZTree(String),
+    /// Reply with a snapshot of the applications, windows and hints, see
+    /// [PixelManager::export_layout](pinenote_service::pixel_manager::PixelManager::export_layout).
+    ExportLayout(oneshot::Sender<LayoutSnapshot>),
+    /// Write the window layout as an SVG image, see
+    /// [PixelManager::layout_svg](pinenote_service::pixel_manager::PixelManager::layout_svg).
+    ExportLayoutSvg(String),
     FbDumpToDir(String),
+    /// Read a driver parameter, one of `RockchipEbc::TUNABLE_PARAMS`.
+    GetParam(String, oneshot::Sender<String>),
     GlobalRefresh,
+    /// Global refresh which isn't dropped by the rate limit, but delayed to the end of the
+    /// minimum interval. For refreshes showing a change the user waits for.
+    GlobalRefreshDeferred,
+    /// Replace the applications and windows with those of a snapshot, and apply its hints.
+    ImportLayout(LayoutSnapshot),
+    /// Hint a region on top of every window for `duration`, then revert it. Both changes
+    /// trigger a global refresh.
+    FlashRegion {
+        area: Rect,
+        hint: Hint,
+        duration: Duration,
+    },
     Property(Property),
+    /// Stop serving once the commands sent before are handled, saving the pending state.
+    Quit,
     SetMode(DriverMode, DitherMode, u16),
+    /// Write a driver parameter, replying once written.
+    SetParam(String, String, oneshot::Sender<()>),
+    /// Append every hints upload to a log file, see [pinenote_service::hint_log].
+    StartHintLog(String),
+    StopHintLog,
+    Status(oneshot::Sender<CoreStatus>),
+    /// Barrier: replied to once every command sent before it was handled, with its sequence
+    /// number.
+    Sync(oneshot::Sender<u64>),
     Window(Window),
-    OffScreen(String, oneshot::Sender<Result<(), OffScreenError>>),
+    /// Upload an off screen image, as is if the flag is set.
+    OffScreen(String, bool, oneshot::Sender<Result<(), OffScreenError>>),
+    /// Upload a full screen of a single gray level, from 0 (black) to 15 (white).
+    OffScreenSolid(u8, oneshot::Sender<Result<(), OffScreenError>>),
+    /// Replace a region of the off screen image with the image at a path, in the panel scan
+    /// orientation.
+    OffScreenRegion(String, Rect, oneshot::Sender<Result<(), OffScreenError>>),
+}
+
+/// Health of the core, for monitoring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreStatus {
+    /// Whether the driver answered a probe.
+    pub driver_ok: bool,
+    /// Number of application windows.
+    pub window_count: usize,
 }
 
 pub enum Application {
-    Add(pid_t, oneshot::Sender<String>),
+    Add(String, pid_t, oneshot::Sender<String>),
     Remove(String),
+    /// Remove the applications without any window, replying with how many were removed.
+    PruneEmpty(oneshot::Sender<usize>),
+    /// Hint the windows of an application as a single region or not, replying whether the
+    /// application exists.
+    SetMergeWindows {
+        app_key: String,
+        merge: bool,
+        reply: oneshot::Sender<bool>,
+    },
+    /// Set the windows of an application always on top or not, replying whether the
+    /// application exists.
+    SetAlwaysOnTop {
+        app_key: String,
+        always_on_top: bool,
+        reply: oneshot::Sender<bool>,
+    },
 }
 
 pub enum Property {
@@ -29,13 +101,34 @@ pub enum Property {
     SetDefaultHint(Hint),
     DriverMode(oneshot::Sender<DriverMode>),
     SetDriverMode(DriverMode),
+    /// Driver mode wanted by the newly focused window, applied once the focus stopped moving.
+    RequestFocusDriverMode(DriverMode),
     DitherMode(oneshot::Sender<DitherMode>),
     SetDitherMode(DitherMode),
     RedrawDelay(oneshot::Sender<u16>),
     SetRedrawDelay(u16),
+    RedrawOptions(oneshot::Sender<RedrawOptions>),
+    SetRedrawOptions(RedrawOptions),
     OffScreenDisable(oneshot::Sender<bool>),
     SetOffScreenDisable(bool),
     OffScreenOverride(oneshot::Sender<String>),
+    CurrentHints(oneshot::Sender<Vec<RectHint>>),
+    /// Reply whether the hints of the current windows are the ones last uploaded.
+    VerifyHints(oneshot::Sender<bool>),
+    PreciseHints(oneshot::Sender<bool>),
+    SetPreciseHints(bool),
+    MinimizeHints(oneshot::Sender<bool>),
+    SetMinimizeHints(bool),
+    EmitHintUpdates(oneshot::Sender<bool>),
+    SetEmitHintUpdates(bool),
+    /// Replace the bit depth of every hint uploaded, `None` to stop.
+    SetForcedBitDepth(Option<HintBitDepth>),
+    /// Cap the bit depth of the default hint, `None` to lift the cap. Unlike SetDefaultHint,
+    /// the cap isn't saved with the state.
+    SetDefaultBitDepthCap(Option<HintBitDepth>),
+    ScreenArea(oneshot::Sender<Rect>),
+    GlobalRefreshInterval(oneshot::Sender<Duration>),
+    SetGlobalRefreshInterval(Duration),
 }
 
 #[derive(Default)]
@@ -46,6 +139,9 @@ pub struct WindowUpdate {
     pub visible: Option<bool>,
     pub fullscreen: Option<bool>,
     pub z_index: Option<i32>,
+    pub focused: Option<bool>,
+    pub content_inset: Option<Inset>,
+    pub always_on_top: Option<bool>,
 }
 
 pub enum Window {
@@ -57,13 +153,64 @@ pub enum Window {
         visible: bool,
         fullscreen: bool,
         z_index: i32,
+        focused: bool,
+        /// Compositor identifier, adding the same one twice returns the existing window.
+        external_id: Option<String>,
         reply: oneshot::Sender<String>,
     },
     Update {
         win_key: String,
         update: WindowUpdate,
     },
+    Refresh(String),
     Remove(String),
+    /// Hint a screen region, not tied to any application window.
+    AddRegion {
+        area: Rect,
+        hint: Hint,
+        z_index: i32,
+        reply: oneshot::Sender<String>,
+    },
+    RemoveRegion(String),
+    /// Hint the pixels no window covers, `None` leaves them to the default hint.
+    SetBackground(Option<Hint>),
+    /// Set or unset the hint of the focused window, replying with its key if there is one.
+    SetFocusedHint {
+        hint: Option<Hint>,
+        reply: oneshot::Sender<Option<String>>,
+    },
+    /// Reply with the statistics of a window, `None` if there is no such window.
+    Stats {
+        win_key: String,
+        reply: oneshot::Sender<Option<WindowStats>>,
+    },
+    /// Reply with the topmost window covering a pixel and its hint there, see
+    /// [PixelManager::window_at](pinenote_service::pixel_manager::PixelManager::window_at).
+    At {
+        x: i32,
+        y: i32,
+        reply: oneshot::Sender<Option<(String, Hint)>>,
+    },
+}
+
+impl Command {
+    /// Whether the command changes the settings persisted across restarts.
+    pub fn changes_state(&self) -> bool {
+        use self::Property::*;
+
+        match self {
+            Self::ApplyProfile(_) | Self::ImportLayout(_) | Self::SetMode(_, _, _) => true,
+            Self::Property(p) => matches!(
+                p,
+                SetDefaultHint(_)
+                    | SetDriverMode(_)
+                    | SetDitherMode(_)
+                    | SetRedrawDelay(_)
+                    | SetRedrawOptions(_)
+            ),
+            _ => false,
+        }
+    }
 }
 
 pub trait CommandStr {
@@ -76,13 +223,30 @@ impl CommandStr for Command {
 
         match self {
             Application(a) => format!("Window::{}", a.get_command_str()),
+            ApplyProfile(_) => "ApplyProfile".into(),
+            DebugVisualize(_) => "DebugVisualize".into(),
             Dump(_) => "Dump".into(),
+            DumpZTree(_) => "DumpZTree".into(),
+            ExportLayout(_) => "ExportLayout".into(),
+            ExportLayoutSvg(_) => "ExportLayoutSvg".into(),
             FbDumpToDir(_) => "FrameBufferDumpToDir".into(),
+            GetParam(name, _) => format!("GetParam({name})"),
             GlobalRefresh => "GlobalRefresh".into(),
+            GlobalRefreshDeferred => "GlobalRefreshDeferred".into(),
+            ImportLayout(_) => "ImportLayout".into(),
+            FlashRegion { duration, .. } => format!("FlashRegion({duration:?})"),
             Property(p) => format!("Property::{}", p.get_command_str()),
+            Quit => "Quit".into(),
+            StartHintLog(_) => "StartHintLog".into(),
+            StopHintLog => "StopHintLog".into(),
+            Status(_) => "Status".into(),
+            Sync(_) => "Sync".into(),
             SetMode(_, _, _) => "SetMode".into(),
+            SetParam(name, _, _) => format!("SetParam({name})"),
             Window(w) => format!("Window::{}", w.get_command_str()),
-            OffScreen(_, _) => "OffScreen".into(),
+            OffScreen(_, _, _) => "OffScreen".into(),
+            OffScreenSolid(level, _) => format!("OffScreenSolid({level})"),
+            OffScreenRegion(_, area, _) => format!("OffScreenRegion({area:?})"),
         }
     }
 }
@@ -90,8 +254,17 @@ impl CommandStr for Command {
 impl CommandStr for Application {
     fn get_command_str(&self) -> String {
         match self {
-            Self::Add(p, _) => format!("Add({p})"),
+            Self::Add(a, p, _) => format!("Add({a}:{p})"),
             Self::Remove(k) => format!("Remove({k})"),
+            Self::PruneEmpty(_) => "PruneEmpty".into(),
+            Self::SetMergeWindows { app_key, merge, .. } => {
+                format!("SetMergeWindows({app_key}, {merge})")
+            }
+            Self::SetAlwaysOnTop {
+                app_key,
+                always_on_top,
+                ..
+            } => format!("SetAlwaysOnTop({app_key}, {always_on_top})"),
         }
     }
 }
@@ -104,13 +277,29 @@ impl CommandStr for Property {
             SetDefaultHint(_) => "DefaultHint::Set".into(),
             DriverMode(_) => "DriverMode::Get".into(),
             SetDriverMode(_) => "DriverMode::Set".into(),
+            RequestFocusDriverMode(mode) => format!("DriverMode::RequestFocus({})", mode.name()),
             DitherMode(_) => "DitherMode::Get".into(),
             SetDitherMode(_) => "DitherMode::Set".into(),
             RedrawDelay(_) => "RedrawDelay::Get".into(),
             SetRedrawDelay(_) => "RedrawDelay::Set".into(),
+            RedrawOptions(_) => "RedrawOptions::Get".into(),
+            SetRedrawOptions(_) => "RedrawOptions::Set".into(),
             OffScreenDisable(_) => "OffScreenDisable::Get".into(),
             SetOffScreenDisable(_) => "OffScreenDisable::Set".into(),
             OffScreenOverride(_) => "OffScreenOverride".into(),
+            CurrentHints(_) => "CurrentHints".into(),
+            VerifyHints(_) => "VerifyHints".into(),
+            PreciseHints(_) => "PreciseHints::Get".into(),
+            SetPreciseHints(_) => "PreciseHints::Set".into(),
+            MinimizeHints(_) => "MinimizeHints::Get".into(),
+            SetMinimizeHints(_) => "MinimizeHints::Set".into(),
+            EmitHintUpdates(_) => "EmitHintUpdates::Get".into(),
+            SetEmitHintUpdates(_) => "EmitHintUpdates::Set".into(),
+            SetForcedBitDepth(_) => "ForcedBitDepth::Set".into(),
+            SetDefaultBitDepthCap(_) => "DefaultBitDepthCap::Set".into(),
+            ScreenArea(_) => "ScreenArea".into(),
+            GlobalRefreshInterval(_) => "GlobalRefreshInterval::Get".into(),
+            SetGlobalRefreshInterval(_) => "GlobalRefreshInterval::Set".into(),
         }
     }
 }
@@ -120,7 +309,14 @@ impl CommandStr for Window {
         match self {
             Self::Add { app_key, .. } => format!("Add({app_key})"),
             Self::Update { win_key, .. } => format!("Update({win_key})"),
+            Self::Refresh(k) => format!("Refresh({k})"),
             Self::Remove(k) => format!("Remove({k})"),
+            Self::AddRegion { z_index, .. } => format!("AddRegion({z_index})"),
+            Self::RemoveRegion(k) => format!("RemoveRegion({k})"),
+            Self::SetBackground(_) => "SetBackground".into(),
+            Self::SetFocusedHint { .. } => "SetFocusedHint".into(),
+            Self::Stats { win_key, .. } => format!("Stats({win_key})"),
+            Self::At { x, y, .. } => format!("At({x},{y})"),
         }
     }
 }
@@ -143,9 +339,24 @@ impl From<Window> for Command {
     }
 }
 
+/// Sending half of the core command channel, cloned by every bridge and DBus interface.
+#[derive(Clone)]
 pub struct CommandSender(mpsc::Sender<Command>);
 
+impl std::fmt::Debug for CommandSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CommandSender").finish_non_exhaustive()
+    }
+}
+
 impl CommandSender {
+    /// Create the core command channel, queueing up to `capacity` commands.
+    pub fn channel(capacity: usize) -> (Self, mpsc::Receiver<Command>) {
+        let (tx, rx) = mpsc::channel(capacity);
+
+        (Self(tx), rx)
+    }
+
     async fn do_send(&self, cmd: Command, ctx: &String) -> anyhow::Result<()> {
         self.0
             .send(cmd)
@@ -180,3 +391,29 @@ impl From<mpsc::Sender<Command>> for CommandSender {
         Self(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cloned_sender_shares_channel() {
+        let (tx, mut rx) = CommandSender::channel(4);
+        let cloned = tx.clone();
+
+        tx.send(Command::GlobalRefresh).await.unwrap();
+        cloned.send(Command::StopHintLog).await.unwrap();
+        drop((tx, cloned));
+
+        assert!(matches!(rx.recv().await, Some(Command::GlobalRefresh)));
+        assert!(matches!(rx.recv().await, Some(Command::StopHintLog)));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn sender_debug_hides_channel() {
+        let (tx, _rx) = CommandSender::channel(1);
+
+        assert_eq!("CommandSender { .. }", format!("{tx:?}"));
+    }
+}
@@ -1,36 +1,419 @@
-use std::{io::Write, path::PathBuf, time::SystemTime};
-use log::error;
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    io::Write,
+    ops::ControlFlow,
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use log::{debug, error, info, warn};
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
+use futures_lite::FutureExt;
+use image::{DynamicImage, GrayImage};
 use pinenote_service::{
     drivers::rockchip_ebc::RockchipEbc,
+    hint_log::HintLog,
     pixel_manager as pm,
-    types::rockchip_ebc::{FrameBuffers, Mode},
+    state::State,
+    types::{
+        MountOrientation, Rect,
+        rockchip_ebc::{DriverMode, FrameBuffers, Hint, Mode},
+    },
 };
 use tokio::{
     io::AsyncWriteExt,
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinHandle,
+    time::{Instant, MissedTickBehavior},
 };
 
-use super::command::{self as cmd, CommandStr};
+use super::{
+    command::{self as cmd, CommandStr},
+    driver::Driver,
+    event::{Event, EventReceiver},
+};
 
 pub struct Ctl {
-    driver: RockchipEbc,
+    driver: Arc<dyn Driver>,
+    /// Maximum time a driver call may take before being considered hung.
+    driver_timeout: Duration,
     pixel_manager: pm::PixelManager,
     display_width: u32,
     display_height: u32,
     offscreen_override: String,
+    /// Off screen buffer last uploaded, in the panel scan orientation, to patch regions of it.
+    offscreen_buffer: Option<Vec<u8>>,
+    /// Off screen images being prepared, uploaded once ready.
+    offscreen_queue: OffScreenQueue,
+    /// Last hints successfully uploaded to the driver.
+    uploaded_hints: UploadedHints,
+    events: broadcast::Sender<Event>,
+    /// Where to persist the display settings, if enabled.
+    state_file: Option<PathBuf>,
+    /// When the pending state save is due.
+    state_save_at: Option<Instant>,
+    /// Interval between sweeps of applications whose process exited, if enabled.
+    app_sweep_interval: Option<Duration>,
+    /// Drops global refreshes requested too close to each other.
+    refresh_limiter: RateLimiter,
+    /// When the global refresh deferred by the minimum interval is due, if any.
+    refresh_at: Option<Instant>,
+    /// Regions hinted for a limited time.
+    flashes: Flashes,
+    /// Log of the uploaded hints, if enabled.
+    hint_log: Option<HintLog>,
+    /// Whether the hints changed since the last upload.
+    hints_dirty: bool,
+    /// Defers the uploads of outdated hints following another one too closely.
+    upload_pacer: RateLimiter,
+    /// Broadcast every upload of hints, for live visualizers.
+    emit_hint_updates: bool,
+    /// Number of sync barriers handled so far.
+    sync_seq: u64,
+    /// Physical mounting of the panel, applied to the off screen images.
+    mount_orientation: MountOrientation,
+    /// Window moves below this many pixels are ignored, see [pm::WindowData::is_jitter].
+    jitter_threshold: u32,
+    /// Driver mode wanted by the focused window, waiting for the focus to settle.
+    focus_mode: Debounce<DriverMode>,
 }
 
 pub enum OffScreenError {
     LoadFailed,
     DecodeFailed,
     UploadFailed,
+    /// The region to patch doesn't fit in the screen.
+    BadRegion,
+    /// There is no off screen image to patch yet.
+    NoImage,
+}
+
+/// Reply to an off screen command, once its image is uploaded.
+type OffScreenReply = oneshot::Sender<Result<(), OffScreenError>>;
+
+/// Send a reply back to the command issuer.
+///
+/// A dropped receiver only means the client went away before we could answer, which is not worth
+/// failing the command for. Returns whether the reply was delivered.
+fn send_reply<T>(reply: oneshot::Sender<T>, value: T, ctx: &str) -> bool {
+    let delivered = reply.send(value).is_ok();
+
+    if !delivered {
+        debug!("Receiver for {ctx} reply was dropped, client went away");
+    }
+
+    delivered
+}
+
+/// Minimum interval between two operations, global refreshes or hint uploads.
+///
+/// A global refresh flashes the whole panel for about a second, repeating it right away only
+/// wears the panel and the user patience. Under continuous window motion, hints would be uploaded
+/// faster than the panel renders them: hints outdated within the interval wait for its end, only
+/// the latest state is then uploaded.
+struct RateLimiter {
+    min_interval: Duration,
+    last: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last: None,
+        }
+    }
+
+    /// Whether an operation requested at `now` may go through. Accepted operations start a new
+    /// interval, dropped ones don't.
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        let allowed = self.wait_until(now).is_none();
+
+        if allowed {
+            self.record(now);
+        }
+
+        allowed
+    }
+
+    /// When the next operation may go through, None if it may at `now`.
+    fn wait_until(&self, now: Instant) -> Option<Instant> {
+        let next = self.last? + self.min_interval;
+
+        (next > now).then_some(next)
+    }
+
+    /// Record an operation done at `now`, whether it was acquired or not.
+    fn record(&mut self, now: Instant) {
+        self.last = Some(now);
+    }
+}
+
+/// Regions hinted until a deadline, see [cmd::Command::FlashRegion].
+///
+/// Flashes of different areas stack, a flash of an area already flashing replaces it. A window
+/// changing under a flash cancels it, so that the stale flash doesn't hide the new layout.
+#[derive(Default)]
+struct Flashes {
+    /// Region key and deadline of each flashing area.
+    regions: HashMap<Rect, (String, Instant)>,
+}
+
+impl Flashes {
+    /// Track the region `key` until `deadline`. Returns the key of the region it replaces, if any.
+    fn insert(&mut self, area: Rect, key: String, deadline: Instant) -> Option<String> {
+        self.regions
+            .insert(area, (key, deadline))
+            .map(|(replaced, _)| replaced)
+    }
+
+    /// Earliest deadline, if anything is flashing.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.regions.values().map(|(_, deadline)| *deadline).min()
+    }
+
+    /// Stop tracking the regions overlapping `area`, whatever their deadline, returning their
+    /// keys.
+    fn cancel_overlapping(&mut self, area: &Rect) -> Vec<String> {
+        let cancelled: Vec<_> = self
+            .regions
+            .keys()
+            .filter(|flash| flash.overlap_area(area) > 0)
+            .cloned()
+            .collect();
+
+        cancelled
+            .into_iter()
+            .filter_map(|flash| self.regions.remove(&flash))
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Stop tracking every region, returning their keys.
+    fn take_all(&mut self) -> Vec<String> {
+        self.regions.drain().map(|(_, (key, _))| key).collect()
+    }
+
+    /// Stop tracking the regions whose deadline passed at `now`, returning their keys.
+    fn take_expired(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<_> = self
+            .regions
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(area, _)| area.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|area| self.regions.remove(&area))
+            .map(|(key, _)| key)
+            .collect()
+    }
+}
+
+/// Turn a panic of `fut` into an error, so that a bug in one command handler doesn't stop the
+/// core while bridges and DBus clients keep sending commands.
+///
+/// The state the handler was changing may be left half updated, which beats a dead service.
+async fn catch_panic(fut: impl Future<Output = Result<()>>) -> Result<()> {
+    let payload = match AssertUnwindSafe(fut).catch_unwind().await {
+        Ok(res) => return res,
+        Err(payload) => payload,
+    };
+
+    let msg = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause");
+
+    bail!("Panicked: {msg}")
+}
+
+/// Refuse the modes the service doesn't support, whichever command asks for them.
+///
+/// The zero waveform driver mode isn't supported yet, like Ebc1 *DriverMode* rejects it. The
+/// redraw delay needs no check, the driver takes any number of frames.
+fn check_mode(mode: &Mode) -> Result<()> {
+    if mode.driver_mode == Some(DriverMode::ZeroWaveform) {
+        bail!("Driver mode {} is not supported", DriverMode::ZeroWaveform);
+    }
+
+    Ok(())
+}
+
+/// Warning for an `app` whose key is already registered, None if the key is new.
+///
+/// The registered application is reused, so distinct applications a bridge mixed up would
+/// silently share their windows and hint.
+fn app_key_collision(pixel_manager: &pm::PixelManager, app: &pm::Application) -> Option<String> {
+    let key = app.key();
+    let existing = pixel_manager.app(&key).ok()?;
+
+    Some(format!(
+        "Application key '{key}' is already registered (app_id '{}', pid {}), reusing it",
+        existing.app_id(),
+        existing.pid()
+    ))
+}
+
+/// Event reporting the `hints` just uploaded, if `enabled`.
+fn hints_updated(enabled: bool, hints: &pm::ComputedHints) -> Option<Event> {
+    enabled.then(|| Event::HintsUpdated(hints.rect_hints.clone()))
+}
+
+/// Hints last uploaded to the driver.
+///
+/// The driver can't read its hints back, this is the only record of what it holds.
+#[derive(Default)]
+struct UploadedHints {
+    hints: pm::ComputedHints,
+}
+
+impl UploadedHints {
+    /// Upload `hints` using `upload`, recording them once it succeeded.
+    async fn upload<F, Fut>(&mut self, hints: pm::ComputedHints, upload: F) -> Result<()>
+    where
+        F: FnOnce(pm::ComputedHints) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        upload(hints.clone()).await?;
+        self.hints = hints;
+
+        Ok(())
+    }
+
+    fn hints(&self) -> &pm::ComputedHints {
+        &self.hints
+    }
+
+    /// Whether `expected` are the hints last uploaded.
+    fn matches(&self, expected: &pm::ComputedHints) -> bool {
+        self.hints == *expected
+    }
+}
+
+/// Value applied only once no other one was requested for a while.
+///
+/// Alt-tabbing quickly through windows wanting different driver modes would otherwise switch the
+/// mode at every window, flickering the whole panel each time.
+struct Debounce<T> {
+    delay: Duration,
+    pending: Option<(T, Instant)>,
+}
+
+impl<T> Debounce<T> {
+    fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            pending: None,
+        }
+    }
+
+    /// Request `value` at `now`, replacing the pending one and restarting the delay.
+    fn request(&mut self, value: T, now: Instant) {
+        self.pending = Some((value, now + self.delay));
+    }
+
+    /// When the pending value is due, if any.
+    fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|(_, deadline)| *deadline)
+    }
+
+    /// Take the pending value if it was left alone for the whole delay at `now`.
+    fn take_due(&mut self, now: Instant) -> Option<T> {
+        match self.pending.take() {
+            Some((value, deadline)) if deadline <= now => Some(value),
+            pending => {
+                self.pending = pending;
+                None
+            }
+        }
+    }
+}
+
+/// Off screen image prepared for the driver.
+enum OffScreenImage {
+    /// A whole screen, and what it shows for [Ctl::offscreen_override].
+    Full(String, Vec<u8>),
+    /// The pixels replacing an area of the last uploaded screen.
+    Patch(Rect, Vec<u8>),
+}
+
+/// Off screen image ready to upload, with the reply to its command, see [OffScreenQueue::next].
+type PreparedOffScreen = (
+    Result<OffScreenImage, OffScreenError>,
+    OffScreenReply,
+    &'static str,
+);
+
+/// Off screen command whose image is being prepared.
+struct PendingOffScreen {
+    task: JoinHandle<Result<OffScreenImage, OffScreenError>>,
+    reply: OffScreenReply,
+    ctx: &'static str,
+}
+
+/// Off screen images being prepared on the blocking thread pool.
+///
+/// Decoding and resizing a large image takes a while, during which windows would freeze if the
+/// command loop waited for it. Images are handed back in the order they were requested, whichever
+/// is ready first, so that the last requested one is also the last uploaded.
+#[derive(Default)]
+struct OffScreenQueue {
+    pending: VecDeque<PendingOffScreen>,
+}
+
+impl OffScreenQueue {
+    /// Prepare an image with `prepare`, for the command `ctx` to answer through `reply`.
+    fn push<F>(&mut self, ctx: &'static str, reply: OffScreenReply, prepare: F)
+    where
+        F: FnOnce() -> Result<OffScreenImage, OffScreenError> + Send + 'static,
+    {
+        let task = tokio::task::spawn_blocking(prepare);
+
+        self.pending
+            .push_back(PendingOffScreen { task, reply, ctx });
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Wait for the oldest image to be prepared, or forever if there is none.
+    ///
+    /// Cancel safe: the image stays queued until it is returned.
+    async fn next(&mut self) -> PreparedOffScreen {
+        let Some(front) = self.pending.front_mut() else {
+            return std::future::pending().await;
+        };
+
+        let res = (&mut front.task).await;
+        let PendingOffScreen { reply, ctx, .. } = self.pending.pop_front().expect("Awaited above");
+
+        let image = res.unwrap_or_else(|e| {
+            error!("Preparing off screen image for {ctx} panicked: {e}");
+            Err(OffScreenError::DecodeFailed)
+        });
+
+        (image, reply, ctx)
+    }
 }
 
 mod utils {
+    use std::time::Duration;
+
     use anyhow::Result;
     use image::{DynamicImage, ImageReader, imageops::FilterType, metadata::Orientation};
+    use log::warn;
+    use pinenote_service::types::{MountOrientation, Rect};
+    use tokio::{
+        sync::mpsc,
+        time::{Instant, Interval},
+    };
 
     use super::OffScreenError;
 
@@ -43,11 +426,30 @@ mod utils {
             .into())
     }
 
-    pub fn transform_off_screen(mut img: DynamicImage, width: u32, height: u32) -> DynamicImage {
-        if img.height() > img.width() {
-            img.apply_orientation(Orientation::Rotate90FlipH);
-        } else {
-            img.apply_orientation(Orientation::FlipHorizontal);
+    /// Fit an image to the panel, turning it to the panel scan orientation for a panel mounted as
+    /// `mount`.
+    ///
+    /// Without `mount`, the image is raw: expected to be oriented already, it is only resized.
+    pub fn transform_off_screen(
+        mut img: DynamicImage,
+        width: u32,
+        height: u32,
+        mount: Option<MountOrientation>,
+    ) -> DynamicImage {
+        if let Some(mount) = mount {
+            img = match mount {
+                MountOrientation::Normal => img,
+                MountOrientation::Rotated90 => img.rotate270(),
+                MountOrientation::Rotated180 => img.rotate180(),
+                MountOrientation::Rotated270 => img.rotate90(),
+            };
+
+            let orientation = if img.height() > img.width() {
+                Orientation::Rotate90FlipH
+            } else {
+                Orientation::FlipHorizontal
+            };
+            img.apply_orientation(orientation);
         }
 
         if (img.width(), img.height()) != (width, height) {
@@ -56,201 +458,942 @@ mod utils {
 
         img
     }
+
+    /// Copy `patch`, the pixels of `area` row by row, into an off screen `buffer` of `width`
+    /// pixels per row.
+    ///
+    /// Returns false, leaving the buffer untouched, if `area` doesn't fit in the buffer or
+    /// `patch` doesn't have its size.
+    pub fn patch_off_screen(buffer: &mut [u8], width: u32, area: &Rect, patch: &[u8]) -> bool {
+        let width = width as usize;
+        let height = buffer.len().checked_div(width).unwrap_or(0);
+        let Rect { x1, y1, x2, y2 } = area.clone();
+        let fits = 0 <= x1 && x1 < x2 && x2 as usize <= width;
+        if !(fits && 0 <= y1 && y1 < y2 && y2 as usize <= height) {
+            return false;
+        }
+
+        let area_width = (x2 - x1) as usize;
+        if patch.len() != area_width * (y2 - y1) as usize {
+            return false;
+        }
+
+        for (y, row) in (y1 as usize..).zip(patch.chunks_exact(area_width)) {
+            let start = y * width + x1 as usize;
+            buffer[start..start + area_width].copy_from_slice(row);
+        }
+
+        true
+    }
+
+    /// The off screen buffer of an image, holding 4bpp gray levels.
+    pub fn gray_levels(img: DynamicImage) -> Vec<u8> {
+        img.into_bytes().iter().map(|p| p >> 4).collect()
+    }
+
+    /// An off screen buffer of a single 4bpp gray level.
+    pub fn solid_off_screen(level: u8, width: u32, height: u32) -> Vec<u8> {
+        vec![level; width as usize * height as usize]
+    }
+
+    /// Sleep until `deadline`, or forever if there is none.
+    pub async fn sleep_until(deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Poll `ready` until it returns true, doubling the delay between attempts up to `max_delay`.
+    pub async fn wait_until(
+        what: &str,
+        mut ready: impl FnMut() -> bool,
+        mut delay: Duration,
+        max_delay: Duration,
+    ) {
+        while !ready() {
+            warn!("Waiting for {what}, retrying in {delay:?}");
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(max_delay);
+        }
+    }
+
+    /// Take up to `max` messages already queued in `rx`, without waiting for more.
+    pub fn try_recv_burst<T>(rx: &mut mpsc::Receiver<T>, max: usize) -> Vec<T> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .take(max)
+            .collect()
+    }
+
+    /// Wait for the next tick of `interval`, or forever if there is none.
+    pub async fn tick(interval: Option<&mut Interval>) {
+        match interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
 }
 
 impl Ctl {
+    /// Maximum time a driver call may take before being considered hung.
+    const DRIVER_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Number of events kept for slow listeners before they start missing some.
+    const EVENT_CHANNEL_CAPACITY: usize = 32;
+    /// Delay between a settings change and its save, so that bursts (e.g. cycling modes) are
+    /// written once.
+    const STATE_SAVE_DELAY: Duration = Duration::from_secs(2);
+    /// Default minimum interval between two global refreshes, about the time one takes.
+    const GLOBAL_REFRESH_MIN_INTERVAL: Duration = Duration::from_secs(1);
+    /// First and longest delays between two checks for the driver.
+    const DRIVER_WAIT_DELAY: Duration = Duration::from_secs(1);
+    const DRIVER_WAIT_MAX_DELAY: Duration = Duration::from_secs(30);
+    /// Maximum number of queued commands handled before uploading the hints, so that a steady
+    /// stream of commands still reaches the screen.
+    const MAX_BURST: usize = 64;
+    /// Flashes show on top of every window.
+    const FLASH_Z_INDEX: i32 = i32::MAX;
+    /// Whitest level of the off screen buffer, which holds 4bpp gray levels.
+    pub const MAX_GRAY_LEVEL: u8 = 15;
+
+    /// Wait for the rockchip_ebc driver to be loaded, which may happen after the service started.
+    pub async fn wait_for_driver() {
+        utils::wait_until(
+            "the rockchip_ebc driver",
+            RockchipEbc::available,
+            Self::DRIVER_WAIT_DELAY,
+            Self::DRIVER_WAIT_MAX_DELAY,
+        )
+        .await
+    }
+
     pub fn new() -> Result<Ctl> {
-        let driver = RockchipEbc::new();
+        Self::with_driver(Arc::new(RockchipEbc::new()))
+    }
 
+    /// Serve the commands using `driver`, [RockchipEbc] outside of tests.
+    pub fn with_driver(driver: Arc<dyn Driver>) -> Result<Ctl> {
         let default_hint = driver.default_hint()?;
-        let screen_area = driver.screen_area()?;
+        let screen_area = driver.screen_area().context("Invalid screen geometry")?;
         let display_width = screen_area.x2 as u32;
         let display_height = screen_area.y2 as u32;
 
         Ok(Ctl {
-            driver: RockchipEbc::new(),
+            driver,
+            driver_timeout: Self::DRIVER_TIMEOUT,
             pixel_manager: pm::PixelManager::new(default_hint, screen_area),
             display_width,
             display_height,
             offscreen_override: "unknown".into(),
+            offscreen_buffer: None,
+            offscreen_queue: OffScreenQueue::default(),
+            uploaded_hints: UploadedHints::default(),
+            events: broadcast::channel(Self::EVENT_CHANNEL_CAPACITY).0,
+            state_file: None,
+            state_save_at: None,
+            app_sweep_interval: None,
+            refresh_limiter: RateLimiter::new(Self::GLOBAL_REFRESH_MIN_INTERVAL),
+            refresh_at: None,
+            flashes: Flashes::default(),
+            hint_log: None,
+            hints_dirty: false,
+            upload_pacer: RateLimiter::new(Duration::ZERO),
+            emit_hint_updates: false,
+            sync_seq: 0,
+            mount_orientation: MountOrientation::Normal,
+            jitter_threshold: 0,
+            focus_mode: Debounce::new(Duration::ZERO),
         })
     }
 
-    fn load_offscreen(
-        &mut self,
-        path: String,
-        reply: oneshot::Sender<Result<(), OffScreenError>>,
-    ) -> Result<()> {
-        let img = match utils::load_image(&path) {
-            Ok(img) => img,
-            Err(e) => {
-                reply
-                    .send(Err(e))
-                    .map_err(|_| anyhow!("Failed to send error"))?;
-                return Ok(());
-            }
+    /// Periodically remove applications whose process exited, along with their windows.
+    ///
+    /// This is a safety net for bridges crashing before cleaning up. A zero interval disables it.
+    pub fn with_app_sweep_interval(self, interval: Duration) -> Self {
+        Self {
+            app_sweep_interval: (!interval.is_zero()).then_some(interval),
+            ..self
+        }
+    }
+
+    /// Turn the off screen images to match the physical mounting of the panel.
+    pub fn with_mount_orientation(self, mount_orientation: MountOrientation) -> Self {
+        Self {
+            mount_orientation,
+            ..self
+        }
+    }
+
+    /// Ignore the window updates only moving it by less than `threshold` pixels.
+    pub fn with_jitter_threshold(self, jitter_threshold: u32) -> Self {
+        Self {
+            jitter_threshold,
+            ..self
+        }
+    }
+
+    /// Apply the driver mode wanted by the focused window once the focus stayed `delay` on it,
+    /// instead of right away.
+    pub fn with_focus_mode_delay(self, delay: Duration) -> Self {
+        Self {
+            focus_mode: Debounce::new(delay),
+            ..self
+        }
+    }
+
+    /// Upload the hints at most once per `interval`, the latest ones once it elapsed. A zero
+    /// interval uploads them as soon as the pending commands are handled.
+    pub fn with_upload_interval(self, interval: Duration) -> Self {
+        Self {
+            upload_pacer: RateLimiter::new(interval),
+            ..self
+        }
+    }
+
+    /// Leave the windows of these application ids to the default hint.
+    pub fn with_ignored_apps(mut self, app_ids: impl IntoIterator<Item = String>) -> Self {
+        self.pixel_manager.ignored_apps = app_ids.into_iter().collect();
+        self
+    }
+
+    /// Persist the display settings to `path`, restoring the ones saved by a previous run.
+    pub async fn restore_state(&mut self, path: PathBuf) -> Result<()> {
+        self.state_file = Some(path.clone());
+
+        let Some(state) = State::load(&path)
+            .with_context(|| format!("Failed to load state from '{}'", path.display()))?
+        else {
+            return Ok(());
+        };
+
+        let mode = Mode {
+            driver_mode: Some(state.driver_mode),
+            dither_mode: Some(state.dither_mode),
+            redraw_delay: Some(state.redraw_delay),
         };
+        self.set_mode(mode).await?;
 
-        let img = utils::transform_off_screen(img, self.display_width, self.display_height);
+        self.pixel_manager.default_hint = state.default_hint;
+        self.invalidate_hints();
+        self.flush_hints().await
+    }
 
-        let bytes: Vec<u8> = img.into_bytes().iter().map(|p| p >> 4).collect();
+    /// Remove the applications whose process exited.
+    async fn sweep_dead_apps(&mut self) -> Result<()> {
+        let dead = self.pixel_manager.dead_apps(pm::process_alive);
+        if dead.is_empty() {
+            return Ok(());
+        }
 
-        match self.driver.upload_off_screen(bytes) {
-            Ok(_) => {
-                self.offscreen_override = path;
-                reply
-                    .send(Ok(()))
-                    .map_err(|_| anyhow!("Failed to send Ok reply to SetOffScreen"))?;
-            }
-            Err(e) => {
-                self.offscreen_override = "error".into();
+        for app_key in dead {
+            warn!("Process of application {app_key} exited, removing it");
 
-                reply
-                    .send(Err(OffScreenError::UploadFailed))
-                    .map_err(|_| anyhow!("Failed to send error"))?;
-                Err(e)?;
+            for win_key in self.pixel_manager.app_remove(&app_key) {
+                self.notify(Event::WindowRemoved(win_key));
             }
         }
 
+        self.invalidate_hints();
         Ok(())
     }
 
-    fn recompute_hints(&self) -> Result<()> {
-        let hints = self
-            .pixel_manager
-            .compute_hints()
-            .context("Failed to compute new hints")?;
+    /// Save the display settings to the state file.
+    async fn save_state(&mut self) -> Result<()> {
+        self.state_save_at = None;
 
-        self.driver
-            .upload_rect_hints(hints)
-            .context("Failed to upload hints")
-    }
+        let Some(path) = self.state_file.clone() else {
+            return Ok(());
+        };
 
-    fn dump(&self, mut output: impl Write) {
-        let _ = writeln!(output, "=========== EBC_CTL DUMP ===========");
-        let _ = writeln!(output, "PixelManager: ");
-        let _ = writeln!(output, "{:#?}", self.pixel_manager);
-        let _ = writeln!(output, "=========== ! EBC_CTL DUMP ===========");
-    }
+        let Mode {
+            driver_mode,
+            dither_mode,
+            redraw_delay,
+        } = self.driver_call("mode", |d| d.mode()).await?;
 
-    /// Dump Framebuffer data to a specific directory
-    async fn fb_dump_dir(fbs: FrameBuffers, path: String, stamp: u64) -> Result<()> {
-        let mut path = PathBuf::from(&path);
-        path.push(format!("dump_{}", stamp));
+        let state = State {
+            default_hint: self.pixel_manager.default_hint,
+            driver_mode: driver_mode.ok_or(anyhow!("No DriverMode found."))?,
+            dither_mode: dither_mode.ok_or(anyhow!("No DitherMode found"))?,
+            redraw_delay: redraw_delay.ok_or(anyhow!("No redraw delay found"))?,
+        };
 
-        tokio::fs::create_dir_all(&path)
+        tokio::task::spawn_blocking(move || state.save(&path))
             .await
-            .with_context(|| format!("Failed to create '{:?}'", path))?;
+            .context("State save panicked")?
+            .context("Failed to save state")
+    }
 
-        let mut fopt = tokio::fs::OpenOptions::new();
-        fopt.create(true).mode(0o644).write(true).truncate(true);
+    /// Subscribe to the core events.
+    pub fn subscribe(&self) -> EventReceiver {
+        self.events.subscribe()
+    }
 
-        let dump = async |filename: &str, vec: &Vec<u8>| -> Result<()> {
-            let path = path.join(filename);
-            fopt.open(&path)
-                .await
-                .with_context(|| format!("Failed to open '{:?}'", path))?
-                .write_all(vec)
-                .await
-                .with_context(|| format!("Failed to write '{:?}'", path))?;
-            Ok(())
-        };
+    /// Broadcast an event. Having no listener is not an error.
+    fn notify(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
 
-        dump("buf_inner_outer_nextprev.bin", fbs.inner_outer_nextprev()).await?;
-        dump("buf_hints.bin", fbs.hints()).await?;
-        dump("buf_prelim_target.bin", fbs.prelim_target()).await?;
-        dump("buf_phase1.bin", fbs.phase1()).await?;
-        dump("buf_phase2.bin", fbs.phase2()).await?;
+    /// Run a driver call on the blocking thread pool, giving up after the driver timeout,
+    /// [Self::DRIVER_TIMEOUT] outside of tests.
+    ///
+    /// If the driver wedges, the blocking thread is leaked, but the command loop keeps serving.
+    async fn driver_call<T, E, F>(&self, name: &str, f: F) -> Result<T>
+    where
+        F: FnOnce(&dyn Driver) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Self::call_driver(self.driver.clone(), self.driver_timeout, name, f).await
+    }
 
-        Ok(())
+    /// Change the driver mode fields which are set, once [checked](check_mode).
+    async fn set_mode(&self, mode: Mode) -> Result<()> {
+        check_mode(&mode)?;
+
+        self.driver_call("set_mode", |d| d.set_mode(mode)).await
     }
 
-    async fn dispatch_app(&mut self, app_cmd: cmd::Application) -> Result<()> {
-        use cmd::Application::*;
+    /// [Self::driver_call], for when `self` is borrowed elsewhere.
+    async fn call_driver<T, E, F>(
+        driver: Arc<dyn Driver>,
+        timeout: Duration,
+        name: &str,
+        f: F,
+    ) -> Result<T>
+    where
+        F: FnOnce(&dyn Driver) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let task = tokio::task::spawn_blocking(move || f(driver.as_ref()));
 
-        match app_cmd {
-            Add(pid, reply) => {
-                let app_key = self.pixel_manager.app_add(pm::Application::new("", pid));
-                reply
-                    .send(app_key)
-                    .map_err(|e| anyhow!("Failed to send response: {e}"))?;
-            }
-            Remove(app_id) => {
-                self.pixel_manager.app_remove(&app_id);
-                self.recompute_hints()?;
+        match tokio::time::timeout(timeout, task).await {
+            Ok(res) => Ok(res.with_context(|| format!("Driver call {name} panicked"))??),
+            Err(_) => {
+                error!("Driver call {name} timed out after {timeout:?}");
+                bail!("Driver call {name} timed out")
             }
         }
+    }
 
-        Ok(())
+    /// Load the image at `path` as the off screen image, uploaded once decoded and fitted to the
+    /// panel. Without `raw`, it is turned to match the panel mounting.
+    fn load_offscreen(&mut self, path: String, raw: bool, reply: OffScreenReply) {
+        let (width, height) = (self.display_width, self.display_height);
+        let mount = (!raw).then_some(self.mount_orientation);
+
+        self.offscreen_queue.push("SetOffScreen", reply, move || {
+            let img = utils::load_image(&path)?;
+            let img = utils::transform_off_screen(img, width, height, mount);
+
+            Ok(OffScreenImage::Full(path, utils::gray_levels(img)))
+        });
     }
 
-    async fn dispatch_props(&mut self, prop_cmd: cmd::Property) -> Result<()> {
-        use cmd::Property::*;
+    /// Upload a full screen of a single gray level as the off screen image.
+    ///
+    /// `level` is expected to be at most [Self::MAX_GRAY_LEVEL]. It goes through the off screen
+    /// queue anyway, so that it isn't overwritten by an image requested before it.
+    fn load_offscreen_solid(&mut self, level: u8, reply: OffScreenReply) {
+        let (width, height) = (self.display_width, self.display_height);
 
-        match prop_cmd {
-            DefaultHint(tx) => {
-                let h = self.pixel_manager.default_hint;
-                tx.send(h)
-                    .map_err(|_| anyhow!("Failed to send back default hint"))?;
-            }
-            SetDefaultHint(h) => {
-                self.pixel_manager.default_hint = h;
+        let prepare = move || {
+            let bytes = utils::solid_off_screen(level, width, height);
 
-                self.recompute_hints()?;
-            }
-            DriverMode(tx) => {
-                let Mode { driver_mode, .. } = self.driver.mode()?;
+            Ok(OffScreenImage::Full(format!("solid:{level}"), bytes))
+        };
+        self.offscreen_queue
+            .push("SetOffScreenSolid", reply, prepare);
+    }
 
-                let dm = driver_mode.ok_or(anyhow!("No DriverMode found."))?;
-                tx.send(dm)
-                    .map_err(|_| anyhow!("Failed to send back driver mode"))?;
-            }
-            SetDriverMode(mode) => {
-                self.driver.set_mode(Mode {
-                    driver_mode: Some(mode),
-                    ..Default::default()
-                })?;
-            }
-            DitherMode(tx) => {
-                let Mode { dither_mode, .. } = self.driver.mode()?;
+    /// Replace the `area` of the off screen image with the image at `path`, resized to fit.
+    ///
+    /// The driver only takes whole buffers, the last one uploaded is patched and uploaded again.
+    /// Like [Self::load_offscreen] with `raw`, the area and image are in the panel scan
+    /// orientation.
+    fn load_offscreen_region(&mut self, path: String, area: Rect, reply: OffScreenReply) {
+        let screen = Rect::new(0, 0, self.display_width as i32, self.display_height as i32);
+        if area.intersection(&screen).as_ref() != Some(&area) {
+            send_reply(reply, Err(OffScreenError::BadRegion), "SetOffScreenRegion");
+            return;
+        }
 
-                let dm = dither_mode.ok_or(anyhow!("No DitherMode found"))?;
+        let prepare = move || {
+            let (width, height) = ((area.x2 - area.x1) as u32, (area.y2 - area.y1) as u32);
+            let img = utils::load_image(&path)?;
+            let patch = utils::transform_off_screen(img, width, height, None);
 
-                tx.send(dm)
-                    .map_err(|_| anyhow!("Failed to send dither mode back"))?;
-            }
-            SetDitherMode(dith) => {
-                self.driver.set_mode(Mode {
-                    dither_mode: Some(dith),
-                    ..Default::default()
-                })?;
+            Ok(OffScreenImage::Patch(area, utils::gray_levels(patch)))
+        };
+        self.offscreen_queue
+            .push("SetOffScreenRegion", reply, prepare);
+    }
+
+    /// Upload an off screen `image` prepared by the [OffScreenQueue], answering its command.
+    ///
+    /// A failed upload of a full screen leaves the driver with an unknown image, a failed patch
+    /// leaves the previous one.
+    async fn upload_offscreen(
+        &mut self,
+        image: Result<OffScreenImage, OffScreenError>,
+        reply: OffScreenReply,
+        ctx: &str,
+    ) -> Result<()> {
+        let image = match image {
+            Ok(image) => image,
+            Err(e) => {
+                send_reply(reply, Err(e), ctx);
+                return Ok(());
             }
-            RedrawDelay(tx) => {
-                let Mode { redraw_delay, .. } = self.driver.mode()?;
+        };
 
-                let rd = redraw_delay.ok_or(anyhow!("No redraw delay found"))?;
+        let (name, bytes) = match image {
+            OffScreenImage::Full(name, bytes) => (Some(name), bytes),
+            OffScreenImage::Patch(area, patch) => {
+                let Some(mut bytes) = self.offscreen_buffer.clone() else {
+                    send_reply(reply, Err(OffScreenError::NoImage), ctx);
+                    return Ok(());
+                };
+                utils::patch_off_screen(&mut bytes, self.display_width, &area, &patch);
 
-                tx.send(rd)
-                    .map_err(|_| anyhow!("Failed to send redraw delay back"))?;
-            }
-            SetRedrawDelay(rd) => {
-                self.driver.set_mode(Mode {
-                    redraw_delay: Some(rd),
-                    ..Default::default()
-                })?;
+                (None, bytes)
             }
-            OffScreenDisable(tx) => {
-                let v = self.driver.no_off_screen()?;
+        };
+        let uploaded = bytes.clone();
 
-                tx.send(v)
-                    .map_err(|_| anyhow!("Failed to send OffScreenDisable value"))?;
-            }
-            SetOffScreenDisable(val) => {
-                self.driver.set_no_off_screen(val)?;
+        match self
+            .driver_call("upload_off_screen", move |d| d.upload_off_screen(bytes))
+            .await
+        {
+            Ok(_) => {
+                if let Some(name) = name {
+                    self.offscreen_override = name;
+                }
+                self.offscreen_buffer = Some(uploaded);
+                send_reply(reply, Ok(()), ctx);
+            }
+            Err(e) => {
+                if name.is_some() {
+                    self.offscreen_override = "error".into();
+                    self.offscreen_buffer = None;
+                }
+
+                send_reply(reply, Err(OffScreenError::UploadFailed), ctx);
+                Err(e)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the off screen images being prepared, uploading them in order.
+    async fn drain_offscreen(&mut self) {
+        while !self.offscreen_queue.is_empty() {
+            let (image, reply, ctx) = self.offscreen_queue.next().await;
+
+            if let Err(e) = self.upload_offscreen(image, reply, ctx).await {
+                error!("{e:?}");
+            }
+        }
+    }
+
+    /// Show (or stop showing) the computed hints on the panel, using the off screen buffer.
+    ///
+    /// When disabling, the off screen image last uploaded is uploaded again if there is one.
+    async fn debug_visualize(&self, enable: bool) -> Result<()> {
+        let (width, height) = (self.display_width, self.display_height);
+        let bytes = if enable {
+            let buf = self
+                .pixel_manager
+                .compute_hints()
+                .context("Failed to compute hints")?
+                .visualize(width, height);
+
+            // Computed hints are in the panel frame already, only the scan flip is left
+            GrayImage::from_raw(width, height, buf).map(|img| {
+                let img = DynamicImage::ImageLuma8(img);
+                let normal = Some(MountOrientation::Normal);
+                utils::gray_levels(utils::transform_off_screen(img, width, height, normal))
+            })
+        } else {
+            self.offscreen_buffer.clone()
+        };
+
+        if let Some(bytes) = bytes {
+            self.driver_call("upload_off_screen", move |d| d.upload_off_screen(bytes))
+                .await?;
+        } else if enable {
+            bail!("Visualization buffer doesn't match the screen size");
+        } else {
+            warn!("No off screen image to restore");
+        }
+
+        self.driver_call("global_refresh", |d| d.global_refresh())
+            .await
+            .context("RockchipEbc::global_refresh failed")
+    }
+
+    /// Hint `area` with `hint` on top of every window for `duration`, refreshing the screen.
+    async fn flash_region(&mut self, area: Rect, hint: Hint, duration: Duration) -> Result<()> {
+        let key = self
+            .pixel_manager
+            .region_add(area.clone(), hint, Self::FLASH_Z_INDEX);
+
+        if let Some(replaced) = self.flashes.insert(area, key, Instant::now() + duration) {
+            self.pixel_manager.region_remove(&replaced);
+        }
+
+        self.invalidate_hints();
+        self.global_refresh_limited().await
+    }
+
+    /// Remove the flashes whose time is up, refreshing the screen.
+    async fn revert_flashes(&mut self) -> Result<()> {
+        let expired = self.flashes.take_expired(Instant::now());
+
+        for key in &expired {
+            self.pixel_manager.region_remove(key);
+        }
+
+        if !expired.is_empty() {
+            self.invalidate_hints();
+            self.global_refresh_deferred().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove every flash and show the result right away, whatever the refresh interval, when
+    /// stopping: nothing would revert them later.
+    async fn end_flashes(&mut self) -> Result<()> {
+        let flashes = self.flashes.take_all();
+
+        for key in &flashes {
+            self.pixel_manager.region_remove(key);
+        }
+
+        if !flashes.is_empty() {
+            self.invalidate_hints();
+            self.refresh_at = Some(Instant::now());
+        }
+
+        // A deferred refresh would be lost too
+        if self.refresh_at.take().is_some() {
+            self.flush_hints().await?;
+            self.driver_call("global_refresh", |d| d.global_refresh())
+                .await
+                .context("RockchipEbc::global_refresh failed")?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the flashes overlapping any of `areas`, where a window changed, refreshing the
+    /// screen like [Self::revert_flashes].
+    ///
+    /// The window is redrawn anyway, but the flash may stick out of it.
+    async fn cancel_flashes(&mut self, areas: &[Rect]) -> Result<()> {
+        let mut cancelled = false;
+
+        for area in areas {
+            for key in self.flashes.cancel_overlapping(area) {
+                debug!("Window changed under flash {key}, cancelling it");
+                self.pixel_manager.region_remove(&key);
+                cancelled = true;
+            }
+        }
+
+        if cancelled {
+            self.invalidate_hints();
+            self.global_refresh_deferred().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply the driver mode of the focused window, if the focus settled.
+    async fn apply_focus_mode(&mut self) -> Result<()> {
+        let Some(driver_mode) = self.focus_mode.take_due(Instant::now()) else {
+            return Ok(());
+        };
+
+        let mode = Mode {
+            driver_mode: Some(driver_mode),
+            ..Default::default()
+        };
+        self.set_mode(mode).await
+    }
+
+    /// Trigger a global refresh, unless another one was done less than the minimum interval ago.
+    async fn global_refresh_limited(&mut self) -> Result<()> {
+        if !self.refresh_limiter.try_acquire(Instant::now()) {
+            info!(
+                "Dropping global refresh, last one was less than {:?} ago",
+                self.refresh_limiter.min_interval
+            );
+            return Ok(());
+        }
+        // Showing the latest hints, it covers the deferred refresh
+        self.refresh_at = None;
+
+        // The refresh must show the hints of the commands handled so far
+        self.flush_hints().await?;
+
+        self.driver_call("global_refresh", |d| d.global_refresh())
+            .await
+            .context("RockchipEbc::global_refresh failed")
+    }
+
+    /// Same as [Self::global_refresh_limited], deferring the refresh to the end of the minimum
+    /// interval instead of dropping it.
+    ///
+    /// For the refreshes which can't be dropped, like the one reverting a flash: the panel would
+    /// keep showing it.
+    async fn global_refresh_deferred(&mut self) -> Result<()> {
+        let Some(at) = self.refresh_limiter.wait_until(Instant::now()) else {
+            return self.global_refresh_limited().await;
+        };
+
+        debug!("Deferring global refresh to the end of the minimum interval");
+        self.refresh_at = Some(at);
+        Ok(())
+    }
+
+    /// Mark the hints as outdated, they are uploaded once the pending commands are handled.
+    fn invalidate_hints(&mut self) {
+        self.hints_dirty = true;
+    }
+
+    /// Compute and upload the hints, if they are outdated. They stay outdated if the upload
+    /// fails, to be retried after the next command.
+    async fn flush_hints(&mut self) -> Result<()> {
+        if !self.hints_dirty {
+            return Ok(());
+        }
+
+        let hints = self.computed_hints()?;
+        let (driver, timeout) = (self.driver.clone(), self.driver_timeout);
+
+        self.uploaded_hints
+            .upload(hints, |hints| {
+                Self::call_driver(driver, timeout, "upload_rect_hints", move |d| {
+                    d.upload_rect_hints(hints)
+                })
+            })
+            .await
+            .context("Failed to upload hints")?;
+        self.hints_dirty = false;
+        self.upload_pacer.record(Instant::now());
+
+        if let Some(event) = hints_updated(self.emit_hint_updates, self.uploaded_hints.hints()) {
+            self.notify(event);
+        }
+
+        if let Some(log) = &mut self.hint_log
+            && let Err(e) = log.append(self.uploaded_hints.hints())
+        {
+            error!("Failed to log hints, stopping the hint log: {e}");
+            self.hint_log = None;
+        }
+
+        Ok(())
+    }
+
+    /// Same as [Ctl::flush_hints], unless the last upload was less than the upload interval ago.
+    async fn flush_hints_paced(&mut self) -> Result<()> {
+        if self.upload_pacer.wait_until(Instant::now()).is_some() {
+            return Ok(());
+        }
+
+        self.flush_hints().await
+    }
+
+    /// When the outdated hints deferred by the upload interval are due, if any.
+    fn paced_upload_at(&self) -> Option<Instant> {
+        if !self.hints_dirty {
+            return None;
+        }
+
+        self.upload_pacer.wait_until(Instant::now())
+    }
+
+    /// Hints of the current windows, falling back to coarser ones over the upload limit, then to
+    /// the default hint alone if even those are over it.
+    fn computed_hints(&self) -> Result<pm::ComputedHints> {
+        const LIMIT: usize = RockchipEbc::MAX_RECT_HINTS;
+
+        let hints = self
+            .pixel_manager
+            .compute_hints()
+            .context("Failed to compute new hints")?;
+
+        if hints.rect_hints.len() <= LIMIT {
+            return Ok(hints);
+        }
+
+        warn!(
+            "{} rectangle hints is over the upload limit of {LIMIT}, falling back to bounding boxes",
+            hints.rect_hints.len(),
+        );
+        let coarse = self
+            .pixel_manager
+            .compute_hints_coarse()
+            .context("Failed to compute new hints")?;
+
+        if coarse.rect_hints.len() <= LIMIT {
+            return Ok(coarse);
+        }
+
+        error!(
+            "{} windows are over the upload limit of {LIMIT}, only the default hint applies",
+            coarse.rect_hints.len(),
+        );
+        Ok(pm::ComputedHints {
+            rect_hints: Vec::new(),
+            ..coarse
+        })
+    }
+
+    fn dump(&self, mut output: impl Write) {
+        let _ = writeln!(output, "=========== EBC_CTL DUMP ===========");
+        let _ = writeln!(output, "PixelManager: ");
+        let _ = writeln!(output, "{:#?}", self.pixel_manager);
+        let _ = writeln!(output, "=========== ! EBC_CTL DUMP ===========");
+    }
+
+    /// Dump Framebuffer data to a specific directory
+    async fn fb_dump_dir(fbs: FrameBuffers, path: String, stamp: u64) -> Result<()> {
+        let mut path = PathBuf::from(&path);
+        path.push(format!("dump_{}", stamp));
+
+        tokio::fs::create_dir_all(&path)
+            .await
+            .with_context(|| format!("Failed to create '{:?}'", path))?;
+
+        let mut fopt = tokio::fs::OpenOptions::new();
+        fopt.create(true).mode(0o644).write(true).truncate(true);
+
+        let dump = async |filename: &str, vec: &Vec<u8>| -> Result<()> {
+            let path = path.join(filename);
+            fopt.open(&path)
+                .await
+                .with_context(|| format!("Failed to open '{:?}'", path))?
+                .write_all(vec)
+                .await
+                .with_context(|| format!("Failed to write '{:?}'", path))?;
+            Ok(())
+        };
+
+        dump("buf_inner_outer_nextprev.bin", fbs.inner_outer_nextprev()).await?;
+        dump("buf_hints.bin", fbs.hints()).await?;
+        dump("buf_prelim_target.bin", fbs.prelim_target()).await?;
+        dump("buf_phase1.bin", fbs.phase1()).await?;
+        dump("buf_phase2.bin", fbs.phase2()).await?;
+
+        Ok(())
+    }
+
+    async fn dispatch_app(&mut self, app_cmd: cmd::Application) -> Result<()> {
+        use cmd::Application::*;
+
+        match app_cmd {
+            Add(app_id, pid, reply) => {
+                let app = pm::Application::new(app_id, pid);
+                if let Some(warning) = app_key_collision(&self.pixel_manager, &app) {
+                    warn!("{warning}");
+                }
+                let app_key = self.pixel_manager.app_add(app);
+                send_reply(reply, app_key, "Application::Add");
+            }
+            Remove(app_id) => {
+                for win_key in self.pixel_manager.app_remove(&app_id) {
+                    self.notify(Event::WindowRemoved(win_key));
+                }
+                self.invalidate_hints();
+            }
+            PruneEmpty(reply) => {
+                let pruned = self.pixel_manager.prune_empty_apps();
+                if pruned > 0 {
+                    info!("Pruned {pruned} application(s) without window");
+                }
+                send_reply(reply, pruned, "Application::PruneEmpty");
+            }
+            SetMergeWindows {
+                app_key,
+                merge,
+                reply,
+            } => {
+                let res = self.pixel_manager.app_set_merge_windows(&app_key, merge);
+                if res.is_ok() {
+                    self.invalidate_hints();
+                }
+                send_reply(reply, res.is_ok(), "Application::SetMergeWindows");
+            }
+            SetAlwaysOnTop {
+                app_key,
+                always_on_top,
+                reply,
+            } => {
+                let res = self
+                    .pixel_manager
+                    .app_set_always_on_top(&app_key, always_on_top);
+                if res.is_ok() {
+                    self.invalidate_hints();
+                }
+                send_reply(reply, res.is_ok(), "Application::SetAlwaysOnTop");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn dispatch_props(&mut self, prop_cmd: cmd::Property) -> Result<()> {
+        use cmd::Property::*;
+
+        match prop_cmd {
+            DefaultHint(tx) => {
+                let h = self.pixel_manager.default_hint;
+                send_reply(tx, h, "DefaultHint");
+            }
+            SetDefaultHint(h) => {
+                self.pixel_manager.default_hint = h;
+
+                self.invalidate_hints();
+            }
+            DriverMode(tx) => {
+                let Mode { driver_mode, .. } = self.driver_call("mode", |d| d.mode()).await?;
+
+                let dm = driver_mode.ok_or(anyhow!("No DriverMode found."))?;
+                send_reply(tx, dm, "DriverMode");
+            }
+            SetDriverMode(mode) => {
+                let mode = Mode {
+                    driver_mode: Some(mode),
+                    ..Default::default()
+                };
+                self.set_mode(mode).await?;
+            }
+            RequestFocusDriverMode(mode) => {
+                self.focus_mode.request(mode, Instant::now());
+            }
+            DitherMode(tx) => {
+                let Mode { dither_mode, .. } = self.driver_call("mode", |d| d.mode()).await?;
+
+                let dm = dither_mode.ok_or(anyhow!("No DitherMode found"))?;
+
+                send_reply(tx, dm, "DitherMode");
+            }
+            SetDitherMode(dith) => {
+                let mode = Mode {
+                    dither_mode: Some(dith),
+                    ..Default::default()
+                };
+                self.set_mode(mode).await?;
+            }
+            RedrawDelay(tx) => {
+                let Mode { redraw_delay, .. } = self.driver_call("mode", |d| d.mode()).await?;
+
+                let rd = redraw_delay.ok_or(anyhow!("No redraw delay found"))?;
+
+                send_reply(tx, rd, "RedrawDelay");
+            }
+            SetRedrawDelay(rd) => {
+                let mode = Mode {
+                    redraw_delay: Some(rd),
+                    ..Default::default()
+                };
+                self.set_mode(mode).await?;
+            }
+            RedrawOptions(tx) => {
+                let options = self
+                    .driver_call("redraw_options", |d| d.redraw_options())
+                    .await?;
+
+                send_reply(tx, options, "RedrawOptions");
+            }
+            SetRedrawOptions(options) => {
+                self.driver_call("set_redraw_options", move |d| d.set_redraw_options(options))
+                    .await?;
+            }
+            OffScreenDisable(tx) => {
+                let v = self
+                    .driver_call("no_off_screen", |d| d.no_off_screen())
+                    .await?;
+
+                send_reply(tx, v, "OffScreenDisable");
+            }
+            SetOffScreenDisable(val) => {
+                self.driver_call("set_no_off_screen", move |d| d.set_no_off_screen(val))
+                    .await?;
             }
             OffScreenOverride(tx) => {
-                tx.send(self.offscreen_override.clone())
-                    .map_err(|_| anyhow!("Failed to send OffScreen override path"))?;
+                send_reply(tx, self.offscreen_override.clone(), "OffScreenOverride");
+            }
+            CurrentHints(tx) => {
+                self.flush_hints().await?;
+                let hints = self.uploaded_hints.hints().rect_hints.clone();
+                send_reply(tx, hints, "CurrentHints");
+            }
+            VerifyHints(tx) => {
+                // A failed upload leaves other hints than the current ones, which is the answer
+                let in_sync = self
+                    .flush_hints()
+                    .await
+                    .and_then(|()| self.computed_hints())
+                    .map(|hints| self.uploaded_hints.matches(&hints))
+                    .unwrap_or_else(|e| {
+                        error!("Failed to verify the hints: {e:?}");
+                        false
+                    });
+                if !in_sync {
+                    warn!("The hints last uploaded don't match the current windows");
+                }
+                send_reply(tx, in_sync, "VerifyHints");
+            }
+            PreciseHints(tx) => {
+                send_reply(tx, self.pixel_manager.precise, "PreciseHints");
+            }
+            ScreenArea(tx) => {
+                let area = self.pixel_manager.screen_area().clone();
+                send_reply(tx, area, "ScreenArea");
+            }
+            GlobalRefreshInterval(tx) => {
+                let interval = self.refresh_limiter.min_interval;
+                send_reply(tx, interval, "GlobalRefreshInterval");
+            }
+            SetGlobalRefreshInterval(interval) => {
+                self.refresh_limiter.min_interval = interval;
+            }
+            SetPreciseHints(precise) => {
+                self.pixel_manager.precise = precise;
+
+                self.invalidate_hints();
+            }
+            MinimizeHints(tx) => {
+                send_reply(tx, self.pixel_manager.minimize, "MinimizeHints");
+            }
+            SetMinimizeHints(minimize) => {
+                self.pixel_manager.minimize = minimize;
+
+                self.invalidate_hints();
+            }
+            EmitHintUpdates(tx) => {
+                send_reply(tx, self.emit_hint_updates, "EmitHintUpdates");
+            }
+            SetEmitHintUpdates(emit) => {
+                self.emit_hint_updates = emit;
+            }
+            SetForcedBitDepth(bit_depth) => {
+                self.pixel_manager.forced_bit_depth = bit_depth;
+
+                self.invalidate_hints();
+            }
+            SetDefaultBitDepthCap(cap) => {
+                self.pixel_manager.default_bit_depth_cap = cap;
+
+                self.invalidate_hints();
             }
         }
 
@@ -269,20 +1412,46 @@ impl Ctl {
                 visible,
                 fullscreen,
                 z_index,
+                focused,
+                external_id,
                 reply,
             } => {
+                let flash_area = area.clone();
+
+                let mut window = pm::Window::new(
+                    app_key.clone(),
+                    title,
+                    area,
+                    hint,
+                    visible,
+                    fullscreen,
+                    z_index,
+                )
+                .with_focus(focused);
+                if let Some(external_id) = external_id {
+                    window = window.with_external_id(external_id);
+                }
+                let new_key = window.key().to_owned();
+
                 let win_key = self
                     .pixel_manager
-                    .window_add(pm::Window::new(
-                        app_key, title, area, hint, visible, fullscreen, z_index,
-                    ))
+                    .window_add(window)
                     .context("PixelManager::window_add failed")?;
 
-                reply
-                    .send(win_key)
-                    .map_err(|e| anyhow!("Failed to send response: {e:?}"))?;
+                // The window may already exist, then nothing changed
+                let added = win_key == new_key;
+                if added {
+                    self.notify(Event::WindowAdded {
+                        win_key: win_key.clone(),
+                        app_key,
+                    });
+                }
+                send_reply(reply, win_key, "Window::Add");
 
-                self.recompute_hints()?;
+                if added {
+                    self.invalidate_hints();
+                    self.cancel_flashes(&[flash_area]).await?;
+                }
             }
             Update {
                 win_key,
@@ -294,6 +1463,9 @@ impl Ctl {
                         visible,
                         fullscreen,
                         z_index,
+                        focused,
+                        content_inset,
+                        always_on_top,
                     },
             } => {
                 let win = self
@@ -308,17 +1480,124 @@ impl Ctl {
                     visible: visible.unwrap_or(win.data.visible),
                     fullscreen: fullscreen.unwrap_or(win.data.fullscreen),
                     z_index: z_index.unwrap_or(win.data.z_index),
+                    focused: focused.unwrap_or(win.data.focused),
+                    content_inset: content_inset.unwrap_or(win.data.content_inset),
+                    always_on_top: always_on_top.unwrap_or(win.data.always_on_top),
                 };
 
-                self.pixel_manager
-                    .window_update(&win_key, update)
-                    .context("Failed to update window {win_key}")?;
+                // Windows updating without any change hint at a bridge thrashing
+                let fields = win.data.changed_fields(&update);
+                if fields.is_empty() {
+                    debug!("Window {win_key} updated without any change");
+                } else {
+                    debug!("Window {win_key} updated: {}", fields.join(", "));
+                }
 
-                self.recompute_hints()?;
+                // Only layout changes end the flashes under the window, not e.g. a new title
+                let layout = ["area", "hint", "visible", "z_index"];
+                let changed = if fields.iter().any(|f| layout.contains(f)) {
+                    vec![win.data.area.clone(), update.area.clone()]
+                } else {
+                    vec![]
+                };
+
+                // Not applied either, so that moves adding up past the threshold go through
+                if win.data.is_jitter(&update, self.jitter_threshold) {
+                    debug!("Window {win_key} moved below the jitter threshold, ignored");
+                    return Ok(());
+                }
+
+                self.pixel_manager
+                    .window_update(&win_key, update)
+                    .context("Failed to update window {win_key}")?;
+
+                self.invalidate_hints();
+                self.cancel_flashes(&changed).await?;
+            }
+            Refresh(win_key) => {
+                let area = self
+                    .pixel_manager
+                    .window_visible_area(&win_key)
+                    .with_context(|| format!("Failed to get window {win_key}"))?;
+
+                if let Some(area) = area {
+                    // rockchip_ebc doesn't expose a region refresh, fall back to a global one.
+                    debug!("Refreshing window {win_key} area {area:?}");
+                    self.global_refresh_limited().await?;
+                }
             }
             Remove(win_id) => {
-                self.pixel_manager.window_remove(win_id);
-                self.recompute_hints()?;
+                let area = self
+                    .pixel_manager
+                    .window(&win_id)
+                    .map(|w| w.data.area.clone());
+
+                if self.pixel_manager.window_remove(win_id.clone()) {
+                    self.notify(Event::WindowRemoved(win_id));
+                }
+                self.invalidate_hints();
+
+                if let Ok(area) = area {
+                    self.cancel_flashes(&[area]).await?;
+                }
+            }
+            AddRegion {
+                area,
+                hint,
+                z_index,
+                reply,
+            } => {
+                let region_key = self.pixel_manager.region_add(area, hint, z_index);
+
+                self.notify(Event::WindowAdded {
+                    win_key: region_key.clone(),
+                    app_key: pm::PixelManager::regions_app_key(),
+                });
+                send_reply(reply, region_key, "Window::AddRegion");
+
+                self.invalidate_hints();
+            }
+            RemoveRegion(region_key) => {
+                if self.pixel_manager.region_remove(&region_key) {
+                    self.notify(Event::WindowRemoved(region_key));
+                } else {
+                    warn!("No region with key '{region_key}'");
+                }
+                self.invalidate_hints();
+            }
+            SetBackground(Some(hint)) => {
+                let existed = self.pixel_manager.background_key().is_some();
+                let background_key = self.pixel_manager.background_set_hint(hint);
+
+                if !existed {
+                    self.notify(Event::WindowAdded {
+                        win_key: background_key,
+                        app_key: pm::PixelManager::regions_app_key(),
+                    });
+                }
+                self.invalidate_hints();
+            }
+            SetBackground(None) => {
+                if let Some(background_key) = self.pixel_manager.background_remove() {
+                    self.notify(Event::WindowRemoved(background_key));
+                }
+                self.invalidate_hints();
+            }
+            SetFocusedHint { hint, reply } => {
+                let win_key = self.pixel_manager.focused_set_hint(hint);
+                let changed = win_key.is_some();
+                send_reply(reply, win_key, "Window::SetFocusedHint");
+
+                if changed {
+                    self.invalidate_hints();
+                }
+            }
+            Stats { win_key, reply } => {
+                let stats = self.pixel_manager.window_stats(&win_key).ok();
+                send_reply(reply, stats, "Window::Stats");
+            }
+            At { x, y, reply } => {
+                send_reply(reply, self.pixel_manager.window_at(x, y), "Window::At");
             }
         }
 
@@ -329,6 +1608,18 @@ impl Ctl {
         use cmd::Command::*;
         match cmd {
             Application(a) => self.dispatch_app(a).await?,
+            ApplyProfile(profile) => {
+                let mode = Mode {
+                    driver_mode: Some(profile.driver_mode),
+                    dither_mode: Some(profile.dither_mode),
+                    redraw_delay: Some(profile.redraw_delay),
+                };
+                self.set_mode(mode).await?;
+
+                self.pixel_manager.default_hint = profile.default_hint;
+                self.invalidate_hints();
+            }
+            DebugVisualize(enable) => self.debug_visualize(enable).await?,
             Dump(path) => {
                 if path == "-" {
                     self.dump(std::io::stderr())
@@ -342,10 +1633,77 @@ impl Ctl {
                     self.dump(std::io::stderr());
                 }
             }
+            DumpZTree(path) => {
+                let dot = self.pixel_manager.ztree().to_dot();
+
+                if path == "-" {
+                    eprint!("{dot}");
+                } else {
+                    tokio::fs::write(&path, dot)
+                        .await
+                        .with_context(|| format!("Failed to write '{path}'"))?;
+                }
+            }
+            ExportLayout(reply) => {
+                let layout = self.pixel_manager.export_layout();
+                send_reply(reply, layout, "ExportLayout");
+            }
+            ImportLayout(layout) => {
+                let (removed, added) = self.pixel_manager.import_layout(layout);
+                for win_key in removed {
+                    self.notify(Event::WindowRemoved(win_key));
+                }
+                for (win_key, app_key) in added {
+                    self.notify(Event::WindowAdded { win_key, app_key });
+                }
+                self.invalidate_hints();
+            }
+            ExportLayoutSvg(path) => {
+                let svg = self.pixel_manager.layout_svg();
+
+                if path == "-" {
+                    eprint!("{svg}");
+                } else {
+                    tokio::fs::write(&path, svg)
+                        .await
+                        .with_context(|| format!("Failed to write '{path}'"))?;
+                }
+            }
+            StartHintLog(path) => {
+                let log = HintLog::open(&path)
+                    .with_context(|| format!("Failed to open hint log '{path}'"))?;
+
+                info!("Logging hints to '{path}'");
+                self.hint_log = Some(log);
+            }
+            StopHintLog => {
+                if self.hint_log.take().is_some() {
+                    info!("Hint log stopped");
+                }
+            }
+            Status(reply) => {
+                let driver_ok = self
+                    .driver_call("probe", |d| Ok::<_, Infallible>(d.probe()))
+                    .await
+                    .unwrap_or(false);
+                let status = cmd::CoreStatus {
+                    driver_ok,
+                    window_count: self.pixel_manager.window_count(),
+                };
+                send_reply(reply, status, "Status");
+            }
+            Sync(reply) => {
+                // The commands before the barrier are handled, make sure their off screen
+                // images and hints are uploaded as well.
+                self.drain_offscreen().await;
+                self.flush_hints().await?;
+                self.sync_seq += 1;
+                send_reply(reply, self.sync_seq, "Sync");
+            }
             FbDumpToDir(path) => {
                 let fbs = self
-                    .driver
-                    .extract_framebuffers()
+                    .driver_call("extract_framebuffers", |d| d.extract_framebuffers())
+                    .await
                     .context("Could not retrieve framebuffers")?;
 
                 let now = SystemTime::now()
@@ -362,39 +1720,1068 @@ impl Ctl {
                     }
                 });
             }
-            GlobalRefresh => {
-                self.driver
-                    .global_refresh()
-                    .context("RockchipEbc::global_refresh failed")?;
+            GetParam(name, reply) => {
+                let value = self.driver_call("param", move |d| d.param(&name)).await?;
+                send_reply(reply, value, "GetParam");
             }
+            GlobalRefresh => self.global_refresh_limited().await?,
+            GlobalRefreshDeferred => self.global_refresh_deferred().await?,
+            FlashRegion {
+                area,
+                hint,
+                duration,
+            } => self.flash_region(area, hint, duration).await?,
             Property(p) => {
                 self.dispatch_props(p).await?;
             }
+            // Handled before dispatching
+            Quit => {}
             SetMode(dr, di, rd) => {
-                self.driver.set_mode(Mode {
+                let mode = Mode {
                     driver_mode: Some(dr),
                     dither_mode: Some(di),
                     redraw_delay: Some(rd),
-                })?;
+                };
+                self.set_mode(mode).await?;
+            }
+            SetParam(name, value, reply) => {
+                self.driver_call("set_param", move |d| d.set_param(&name, value))
+                    .await?;
+                send_reply(reply, (), "SetParam");
             }
             Window(w) => self.dispatch_window(w).await?,
-            OffScreen(p, reply) => self.load_offscreen(p, reply)?,
+            OffScreen(p, raw, reply) => self.load_offscreen(p, raw, reply),
+            OffScreenSolid(level, reply) => self.load_offscreen_solid(level, reply),
+            OffScreenRegion(p, area, reply) => self.load_offscreen_region(p, area, reply),
         };
 
         Ok(())
     }
 
+    /// Handle a single command, scheduling a state save if needed. Breaks on [cmd::Command::Quit].
+    async fn handle(&mut self, cmd: cmd::Command) -> ControlFlow<()> {
+        if matches!(cmd, cmd::Command::Quit) {
+            info!("Quit requested, stopping");
+            // The off screen commands before Quit are answered too
+            self.drain_offscreen().await;
+            return ControlFlow::Break(());
+        }
+
+        let ctx = cmd.get_command_str();
+        let changes_state = cmd.changes_state();
+
+        match catch_panic(self.dispatch(cmd))
+            .await
+            .with_context(|| format!("While handling {ctx}"))
+        {
+            Ok(()) if changes_state && self.state_file.is_some() => {
+                self.state_save_at = Some(Instant::now() + Self::STATE_SAVE_DELAY);
+            }
+            Ok(()) => {}
+            Err(e) => error!("{e:?}"),
+        }
+
+        ControlFlow::Continue(())
+    }
+
     pub async fn serve(&mut self, mut rx: mpsc::Receiver<cmd::Command>) {
-        while let Some(cmd) = rx.recv().await {
-            let ctx = cmd.get_command_str();
+        let mut app_sweep = self.app_sweep_interval.map(|period| {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
 
-            if let Err(e) = self
-                .dispatch(cmd)
+        'serve: loop {
+            tokio::select! {
+                cmd = rx.recv() => {
+                    let Some(cmd) = cmd else {
+                        break;
+                    };
+                    if self.handle(cmd).await.is_break() {
+                        break;
+                    }
+
+                    // A burst of commands (e.g. a workspace switch) only needs the hints of the
+                    // final state, upload them once.
+                    for cmd in utils::try_recv_burst(&mut rx, Self::MAX_BURST) {
+                        if self.handle(cmd).await.is_break() {
+                            break 'serve;
+                        }
+                    }
+                }
+                _ = utils::sleep_until(self.state_save_at) => {
+                    if let Err(e) = self.save_state().await {
+                        error!("{e:?}");
+                    }
+                }
+                _ = utils::tick(app_sweep.as_mut()) => {
+                    if let Err(e) = self.sweep_dead_apps().await {
+                        error!("{e:?}");
+                    }
+                }
+                _ = utils::sleep_until(self.flashes.next_deadline()) => {
+                    if let Err(e) = self.revert_flashes().await {
+                        error!("{e:?}");
+                    }
+                }
+                _ = utils::sleep_until(self.refresh_at) => {
+                    self.refresh_at = None;
+                    if let Err(e) = self.global_refresh_deferred().await {
+                        error!("{e:?}");
+                    }
+                }
+                _ = utils::sleep_until(self.focus_mode.deadline()) => {
+                    if let Err(e) = self.apply_focus_mode().await {
+                        error!("{e:?}");
+                    }
+                }
+                // The deferred hints are uploaded below
+                _ = utils::sleep_until(self.paced_upload_at()) => {}
+                (image, reply, ctx) = self.offscreen_queue.next() => {
+                    if let Err(e) = self.upload_offscreen(image, reply, ctx).await {
+                        error!("{e:?}");
+                    }
+                }
+            };
+
+            if let Err(e) = self.flush_hints_paced().await {
+                error!("{e:?}");
+            }
+        }
+
+        // Leave the panel as the last commands set it, the paced hints included
+        if let Err(e) = self.end_flashes().await {
+            error!("{e:?}");
+        }
+        if let Err(e) = self.flush_hints().await {
+            error!("{e:?}");
+        }
+
+        if self.state_save_at.is_some()
+            && let Err(e) = self.save_state().await
+        {
+            error!("{e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ebc::testing::{Core, MockDriver},
+        temp_dir::TempDir,
+    };
+
+    #[test]
+    fn send_reply_delivered() {
+        let (tx, mut rx) = oneshot::channel::<u16>();
+
+        assert!(send_reply(tx, 42, "test"));
+        assert_eq!(Ok(42), rx.try_recv());
+    }
+
+    #[tokio::test]
+    async fn panicking_command_doesnt_stop_serving() {
+        let core = Core::start();
+        core.driver.panic_on("global_refresh");
+
+        core.send(cmd::Command::GlobalRefresh).await;
+        let status = core.ask(cmd::Command::Status).await;
+
+        assert!(status.driver_ok);
+        assert_eq!(vec!["global_refresh", "probe"], core.driver.calls());
+        assert!(core.serving());
+    }
+
+    #[tokio::test]
+    async fn hung_driver_call_times_out() {
+        let (timeout, hang) = (Duration::from_millis(50), Duration::from_millis(500));
+        let driver = MockDriver::new();
+        driver.delay("global_refresh", hang);
+        let driver = Arc::new(driver);
+
+        let res = Ctl::call_driver(driver.clone(), timeout, "global_refresh", |d| {
+            d.global_refresh()
+        })
+        .await;
+        assert_eq!(
+            "Driver call global_refresh timed out",
+            res.unwrap_err().to_string()
+        );
+
+        // The loop gives up on the hung call and keeps answering
+        let core = Core::start_with(MockDriver::new(), |ctl| Ctl {
+            driver_timeout: timeout,
+            ..ctl
+        });
+        core.driver.delay("global_refresh", hang);
+        core.send(cmd::Command::GlobalRefresh).await;
+
+        let status = core.ask(cmd::Command::Status);
+        let status = tokio::time::timeout(Duration::from_millis(250), status)
+            .await
+            .expect("The core is blocked on the hung call");
+        assert!(status.driver_ok);
+        assert_eq!(vec!["global_refresh", "probe"], core.driver.calls());
+    }
+
+    #[tokio::test]
+    async fn small_channel_applies_backpressure() {
+        // Whether a burst sent while the core is busy on a slow call is queued right away
+        async fn burst_queued(capacity: usize) -> bool {
+            let core = Core::start_with_capacity(MockDriver::new(), capacity, |ctl| ctl);
+            core.driver
+                .delay("global_refresh", Duration::from_millis(200));
+            core.send(cmd::Command::GlobalRefresh).await;
+
+            let burst = async {
+                for _ in 0..8 {
+                    core.send(cmd::Command::StopHintLog).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_millis(100), burst)
                 .await
-                .with_context(|| format!("While handling {ctx}"))
-            {
-                error!("{e:?}")
+                .is_ok()
+        }
+
+        assert!(!burst_queued(2).await);
+        assert!(burst_queued(16).await);
+    }
+
+    #[tokio::test]
+    async fn serve_stops_on_quit() {
+        let dir = TempDir::new("quit");
+        let path = dir.join("state.json");
+
+        let core = Core::start_with(MockDriver::new(), |ctl| Ctl {
+            state_file: Some(path.clone()),
+            ..ctl
+        });
+        let app_key = core.add_app("org.app").await;
+        core.add_window(&app_key, Rect::new(0, 0, 10, 10), "Y1")
+            .await;
+        let hint = Hint::try_from_human_readable("Y2|T").unwrap();
+        core.send(cmd::Property::SetDefaultHint(hint)).await;
+
+        let ctl = core.quit().await;
+
+        // The commands before Quit are handled, and the pending state saved right away
+        assert_eq!(1, ctl.pixel_manager.window_count());
+        let state = State::load(&path).unwrap().unwrap();
+        assert_eq!(hint, state.default_hint);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quit_uploads_paced_hints_and_ends_flashes() {
+        let core = Core::start_with(MockDriver::new(), |ctl| {
+            ctl.with_upload_interval(Duration::from_secs(1))
+        });
+        let driver = core.driver.clone();
+
+        let area = Rect::new(0, 0, 10, 10);
+        core.send(cmd::Command::FlashRegion {
+            area: area.clone(),
+            hint: Hint::try_from_human_readable("Y1|T").unwrap(),
+            duration: Duration::from_secs(10),
+        })
+        .await;
+        let hint = Hint::try_from_human_readable("Y2|T").unwrap();
+        core.send(cmd::Property::SetDefaultHint(hint)).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Paced by the upload of the flash
+        assert_eq!(1, driver.rect_hints().len());
+
+        core.quit().await;
+
+        let last = driver.rect_hints().pop().unwrap();
+        assert_eq!(Some(hint), last.default_hint);
+        assert!(last.rect_hints.iter().all(|h| h.rect != area));
+        assert_eq!(2, driver.count("global_refresh"));
+    }
+
+    #[tokio::test]
+    async fn default_bit_depth_cap_not_saved() {
+        use pinenote_service::types::rockchip_ebc::{HintBitDepth, HintConvertMode};
+
+        let dir = TempDir::new("cap");
+        let path = dir.join("state.json");
+
+        let core = Core::start_with(MockDriver::new(), |ctl| Ctl {
+            state_file: Some(path.clone()),
+            ..ctl
+        });
+        let hint = Hint::new(HintBitDepth::Y4, HintConvertMode::Threshold, false);
+        core.send(cmd::Property::SetDefaultHint(hint)).await;
+        core.send(cmd::Property::SetDefaultBitDepthCap(Some(HintBitDepth::Y1)))
+            .await;
+
+        // Reported and saved as set, only uploaded capped
+        let reported = core.ask(|tx| cmd::Property::DefaultHint(tx).into()).await;
+        assert_eq!(hint, reported);
+        core.sync().await;
+        let uploaded = core.driver.rect_hints().pop().unwrap();
+        assert_eq!(
+            Some(hint.with_bit_depth(HintBitDepth::Y1)),
+            uploaded.default_hint
+        );
+
+        core.quit().await;
+        let state = State::load(&path).unwrap().unwrap();
+        assert_eq!(hint, state.default_hint);
+    }
+
+    #[tokio::test]
+    async fn profiles_applied_at_once() {
+        use crate::config::Profile;
+
+        for profile in [Profile::READING, Profile::NOTE] {
+            let core = Core::start();
+
+            core.send(cmd::Command::ApplyProfile(profile)).await;
+            core.sync().await;
+
+            assert_eq!(1, core.driver.count("set_mode"));
+            let mode = core.driver.mode().unwrap();
+            assert_eq!(Some(profile.driver_mode), mode.driver_mode);
+            assert_eq!(Some(profile.dither_mode), mode.dither_mode);
+            assert_eq!(Some(profile.redraw_delay), mode.redraw_delay);
+
+            let uploaded = core.driver.rect_hints().pop().unwrap();
+            assert_eq!(Some(profile.default_hint), uploaded.default_hint);
+        }
+    }
+
+    #[tokio::test]
+    async fn visualization_ignores_mount() {
+        async fn visualization(mount: MountOrientation) -> Vec<u8> {
+            let core = Core::start_with(MockDriver::new(), |ctl| ctl.with_mount_orientation(mount));
+            let app_key = core.add_app("org.app").await;
+            core.add_window(&app_key, Rect::new(0, 0, 10, 20), "Y1")
+                .await;
+
+            core.send(cmd::Command::DebugVisualize(true)).await;
+            core.sync().await;
+            core.driver.off_screens().pop().unwrap()
+        }
+
+        let normal = visualization(MountOrientation::Normal).await;
+        for mount in [
+            MountOrientation::Rotated90,
+            MountOrientation::Rotated180,
+            MountOrientation::Rotated270,
+        ] {
+            assert!(normal == visualization(mount).await, "{mount:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn catch_panic_keeps_errors() {
+        let res = catch_panic(async { bail!("Failed") }).await;
+        assert_eq!("Failed", res.unwrap_err().to_string());
+
+        let res = catch_panic(async { panic!("Broken {}", 42) }).await;
+        assert_eq!("Panicked: Broken 42", res.unwrap_err().to_string());
+    }
+
+    #[test]
+    fn send_reply_dropped_receiver() {
+        let (tx, rx) = oneshot::channel::<u16>();
+        drop(rx);
+
+        assert!(!send_reply(tx, 42, "test"));
+    }
+
+    #[test]
+    fn refresh_limiter_drops_rapid_refreshes() {
+        let mut limiter = RateLimiter::new(Duration::from_secs(1));
+        let start = Instant::now();
+
+        assert!(limiter.try_acquire(start));
+        assert!(!limiter.try_acquire(start + Duration::from_millis(200)));
+        // Dropped refreshes don't extend the interval
+        assert!(limiter.try_acquire(start + Duration::from_secs(1)));
+        assert!(!limiter.try_acquire(start + Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn hints_updated_only_when_enabled() {
+        use pinenote_service::types::rockchip_ebc::RectHint;
+
+        let rect_hint = RectHint {
+            rect: Rect::new(0, 0, 100, 50),
+            hint: Hint::try_from_human_readable("Y1|T").unwrap(),
+        };
+        let hints = pm::ComputedHints {
+            default_hint: None,
+            rect_hints: vec![rect_hint.clone()],
+        };
+
+        assert_eq!(
+            Some(Event::HintsUpdated(vec![rect_hint])),
+            hints_updated(true, &hints)
+        );
+        assert_eq!(None, hints_updated(false, &hints));
+    }
+
+    #[tokio::test]
+    async fn uploaded_hints_match_last_upload() {
+        let mut uploaded = UploadedHints::default();
+        // Driver stand in, keeping what it was given
+        let mut driver = Vec::new();
+
+        let first = pm::ComputedHints::with_hint(Hint::try_from_human_readable("Y4").unwrap());
+        let upload = |hints| {
+            driver.push(hints);
+            async { Ok(()) }
+        };
+        uploaded.upload(first.clone(), upload).await.unwrap();
+        assert_eq!(Some(&first), driver.last());
+        assert!(uploaded.matches(&first));
+
+        // A failed upload leaves the driver, and the record, with the previous hints
+        let second = pm::ComputedHints::with_hint(Hint::try_from_human_readable("Y1").unwrap());
+        let failed = uploaded
+            .upload(second.clone(), |_| async { Err(anyhow!("ioctl failed")) })
+            .await;
+        assert!(failed.is_err());
+        assert!(uploaded.matches(&first));
+        assert!(!uploaded.matches(&second));
+    }
+
+    #[tokio::test]
+    async fn wait_until_path_appears() {
+        let dir = TempDir::new("wait");
+        let device = dir.join("ebc-card");
+
+        let creator = tokio::spawn({
+            let device = device.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                std::fs::create_dir_all(device.parent().unwrap()).unwrap();
+                std::fs::write(&device, "").unwrap();
             }
+        });
+
+        let mut attempts = 0;
+        let wait = utils::wait_until(
+            "test device",
+            || {
+                attempts += 1;
+                device.exists()
+            },
+            Duration::from_millis(5),
+            Duration::from_millis(20),
+        );
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .expect("Device should have been found");
+
+        creator.await.unwrap();
+        assert!(attempts > 1);
+    }
+
+    #[test]
+    fn try_recv_burst_takes_queued_commands() {
+        let (tx, mut rx) = mpsc::channel(8);
+        for i in 0..5 {
+            tx.try_send(i).unwrap();
+        }
+
+        assert_eq!(vec![0, 1, 2], utils::try_recv_burst(&mut rx, 3));
+        assert_eq!(vec![3, 4], utils::try_recv_burst(&mut rx, 3));
+        // Nothing queued, nothing to wait for
+        assert!(utils::try_recv_burst(&mut rx, 3).is_empty());
+    }
+
+    #[tokio::test]
+    async fn burst_uploads_hints_once() {
+        let core = Core::start();
+        let app_key = core.add_app("org.app").await;
+        let win_key = core
+            .add_window(&app_key, Rect::new(0, 0, 10, 10), "Y1")
+            .await;
+        core.sync().await;
+
+        // Queued before the core gets to handle them
+        for x in 1..=5 {
+            core.move_window(&win_key, Rect::new(x, 0, x + 10, 10))
+                .await;
+        }
+        core.sync().await;
+
+        let uploads = core.driver.rect_hints();
+        assert_eq!(2, uploads.len());
+        assert_eq!(Rect::new(5, 0, 15, 10), uploads[1].rect_hints[0].rect);
+    }
+
+    #[tokio::test]
+    async fn hints_over_upload_limit_fall_back() {
+        const LIMIT: i32 = RockchipEbc::MAX_RECT_HINTS as i32;
+        let screen = Rect::new(0, 0, 2 * LIMIT + 2, 10);
+        let core = Core::start_with(MockDriver::with_screen_area(screen.clone()), |ctl| ctl);
+        let app_key = core.add_app("org.app").await;
+
+        let mut replies = Vec::new();
+        let mut add = async |area, z_index| {
+            let (reply, rx) = oneshot::channel();
+            let cmd = cmd::Window::Add {
+                app_key: app_key.clone(),
+                title: "Window".into(),
+                area,
+                hint: Some(Hint::try_from_human_readable("Y1").unwrap()),
+                visible: true,
+                fullscreen: false,
+                z_index,
+                focused: false,
+                external_id: None,
+                reply,
+            };
+            core.send(cmd).await;
+            replies.push(rx);
+        };
+        let last_upload = || core.driver.rect_hints().pop().unwrap().rect_hints.len();
+
+        // A background split around the small windows on top of it
+        add(screen, 0).await;
+        for x in 0..LIMIT - 1 {
+            add(Rect::new(2 * x + 1, 4, 2 * x + 2, 5), 1).await;
+        }
+        core.sync().await;
+        assert_eq!(LIMIT as usize, last_upload());
+
+        // Bounding boxes are over the limit too
+        add(Rect::new(2 * LIMIT - 1, 4, 2 * LIMIT, 5), 1).await;
+        core.sync().await;
+        assert_eq!(0, last_upload());
+
+        // None was refused for being over the limit
+        let uploads = core.driver.rect_hints().len();
+        assert_eq!(uploads, core.driver.count("upload_rect_hints"));
+    }
+
+    #[tokio::test]
+    async fn failed_hint_upload_is_retried() {
+        let core = Core::start();
+        let app_key = core.add_app("org.app").await;
+        let win_key = core
+            .add_window(&app_key, Rect::new(0, 0, 10, 10), "Y1")
+            .await;
+        core.sync().await;
+
+        core.driver.fail("upload_rect_hints");
+        core.move_window(&win_key, Rect::new(5, 0, 15, 10)).await;
+        // The second reply comes once the upload following the first command was attempted
+        for _ in 0..2 {
+            core.ask(cmd::Command::Status).await;
+        }
+        assert_eq!(1, core.driver.rect_hints().len());
+
+        core.driver.unfail("upload_rect_hints");
+        for _ in 0..2 {
+            core.ask(cmd::Command::Status).await;
+        }
+
+        let uploads = core.driver.rect_hints();
+        assert_eq!(2, uploads.len());
+        assert_eq!(Rect::new(5, 0, 15, 10), uploads[1].rect_hints[0].rect);
+    }
+
+    #[tokio::test]
+    async fn verify_hints_after_failed_upload() {
+        let core = Core::start();
+        let app_key = core.add_app("org.app").await;
+        let verify = || core.ask(|reply| cmd::Property::VerifyHints(reply).into());
+
+        core.driver.fail("upload_rect_hints");
+        core.add_window(&app_key, Rect::new(0, 0, 10, 10), "Y1")
+            .await;
+        assert!(!verify().await);
+
+        core.driver.unfail("upload_rect_hints");
+        assert!(verify().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rapid_global_refreshes_refresh_once() {
+        let core = Core::start();
+        let interval = Duration::from_millis(500);
+        core.send(cmd::Property::SetGlobalRefreshInterval(interval))
+            .await;
+
+        core.send(cmd::Command::GlobalRefresh).await;
+        core.send(cmd::Command::GlobalRefresh).await;
+        core.sync().await;
+        assert_eq!(1, core.driver.count("global_refresh"));
+
+        // Past the interval, refreshes go through again
+        tokio::time::sleep(interval).await;
+        core.send(cmd::Command::GlobalRefresh).await;
+        core.sync().await;
+        assert_eq!(2, core.driver.count("global_refresh"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn short_flash_reverts_after_refresh_interval() {
+        let core = Core::start();
+        let start = Instant::now();
+
+        let hint = Hint::try_from_human_readable("Y1|T").unwrap();
+        let area = Rect::new(0, 0, 10, 10);
+        let duration = Duration::from_millis(200);
+        core.send(cmd::Command::FlashRegion {
+            area: area.clone(),
+            hint,
+            duration,
+        })
+        .await;
+
+        tokio::time::sleep_until(start + Duration::from_millis(500)).await;
+        assert_eq!(1, core.driver.count("global_refresh"));
+        // Reverted, waiting for the refresh interval to show it
+        let last = core.driver.rect_hints().pop().unwrap();
+        assert!(last.rect_hints.iter().all(|h| h.rect != area));
+
+        tokio::time::sleep_until(start + Duration::from_millis(1100)).await;
+        assert_eq!(2, core.driver.count("global_refresh"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bridge_app_survives_sweep() {
+        let core = Core::start_with(MockDriver::new(), |ctl| {
+            ctl.with_app_sweep_interval(Duration::from_secs(1))
+        });
+
+        // Like the quill-niri bridge, registering a negated window id as there's no process
+        let add = |reply| cmd::Application::Add("org.niri".into(), -7, reply).into();
+        let app_key = core.ask(add).await;
+        core.add_window(&app_key, Rect::new(0, 0, 10, 10), "Y1")
+            .await;
+        // Whereas an application whose process is gone is swept
+        let add = |reply| cmd::Application::Add("org.dead".into(), i32::MAX, reply).into();
+        let app_key = core.ask(add).await;
+        core.add_window(&app_key, Rect::new(10, 10, 20, 20), "Y1")
+            .await;
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(1, core.ask(cmd::Command::Status).await.window_count);
+    }
+
+    #[tokio::test]
+    async fn window_readded_isnt_notified() {
+        let mut core = Core::start();
+        let app_key = core.add_app("org.app").await;
+
+        let add = |reply| {
+            cmd::Window::Add {
+                app_key: app_key.clone(),
+                title: "Window".into(),
+                area: Rect::new(0, 0, 10, 10),
+                hint: None,
+                visible: true,
+                fullscreen: false,
+                z_index: 0,
+                focused: false,
+                external_id: Some("42".into()),
+                reply,
+            }
+            .into()
+        };
+        let win_key = core.ask(add).await;
+        assert_eq!(core.ask(add).await, win_key);
+
+        let added = Event::WindowAdded {
+            win_key,
+            app_key: app_key.clone(),
+        };
+        assert_eq!(added, core.events.recv().await.unwrap());
+        assert!(core.events.try_recv().is_err());
+    }
+
+    #[test]
+    fn refresh_limiter_disabled() {
+        let mut limiter = RateLimiter::new(Duration::ZERO);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire(now));
+        assert!(limiter.try_acquire(now));
+    }
+
+    #[test]
+    fn transform_off_screen_raw() {
+        let img = || {
+            let buf = GrayImage::from_raw(3, 2, vec![1, 2, 3, 4, 5, 6]).unwrap();
+            DynamicImage::ImageLuma8(buf)
+        };
+
+        let mount = Some(MountOrientation::Normal);
+        let flipped = utils::transform_off_screen(img(), 3, 2, mount);
+        assert_eq!(&[3, 2, 1, 6, 5, 4], flipped.as_bytes());
+
+        let raw = utils::transform_off_screen(img(), 3, 2, None);
+        assert_eq!(&[1, 2, 3, 4, 5, 6], raw.as_bytes());
+    }
+
+    #[test]
+    fn solid_off_screen_fills_screen() {
+        let buf = utils::solid_off_screen(7, 1872, 1404);
+
+        assert_eq!(1872 * 1404, buf.len());
+        assert!(buf.iter().all(|&p| p == 7));
+    }
+
+    #[test]
+    fn check_mode_rejects_zero_waveform() {
+        use pinenote_service::types::rockchip_ebc::DitherMode;
+
+        let set_mode = |driver_mode| Mode {
+            driver_mode,
+            dither_mode: Some(DitherMode::Bayer),
+            redraw_delay: Some(100),
+        };
+
+        assert!(check_mode(&set_mode(Some(DriverMode::ZeroWaveform))).is_err());
+        assert!(check_mode(&set_mode(Some(DriverMode::Fast))).is_ok());
+        // Leaving the driver mode as is
+        assert!(check_mode(&set_mode(None)).is_ok());
+    }
+
+    #[test]
+    fn app_key_collision_warns() {
+        use pinenote_service::types::rockchip_ebc::{HintBitDepth, HintConvertMode};
+
+        let hint = Hint::new(HintBitDepth::Y4, HintConvertMode::Dither, false);
+        let mut pixel_manager = pm::PixelManager::new(hint, Rect::new(0, 0, 100, 100));
+        let app = || pm::Application::new("org.app", 1234);
+
+        assert_eq!(None, app_key_collision(&pixel_manager, &app()));
+        pixel_manager.app_add(app());
+
+        let warning = app_key_collision(&pixel_manager, &app()).unwrap();
+        assert!(warning.contains("'org.app:1234'"));
+        // Same app_id, another process
+        let other = pm::Application::new("org.app", 5678);
+        assert_eq!(None, app_key_collision(&pixel_manager, &other));
+    }
+
+    #[test]
+    fn patch_off_screen_region() {
+        let mut buf = utils::solid_off_screen(15, 4, 3);
+        let center = Rect::new(1, 1, 3, 3);
+
+        assert!(utils::patch_off_screen(&mut buf, 4, &center, &[1, 2, 3, 4]));
+        let expected = [[15, 15, 15, 15], [15, 1, 2, 15], [15, 3, 4, 15]].concat();
+        assert_eq!(expected, buf);
+
+        // Whole buffer
+        let (whole, patch) = (Rect::new(0, 0, 4, 3), vec![0; 12]);
+        assert!(utils::patch_off_screen(&mut buf, 4, &whole, &patch));
+        assert_eq!(patch, buf);
+    }
+
+    #[test]
+    fn patch_off_screen_rejects_bad_region() {
+        let mut buf = utils::solid_off_screen(15, 4, 3);
+        let patch = [0; 4];
+
+        // Out of the buffer, empty, or not the size of the patch
+        for area in [
+            Rect::new(3, 2, 5, 4),
+            Rect::new(-1, 0, 1, 2),
+            Rect::new(2, 2, 2, 2),
+            Rect::new(0, 0, 3, 1),
+        ] {
+            assert!(!utils::patch_off_screen(&mut buf, 4, &area, &patch));
+        }
+        assert!(buf.iter().all(|&p| p == 15));
+    }
+
+    #[test]
+    fn transform_off_screen_mounted() {
+        // A 2x3 portrait image in the mounted frame of a 3x2 panel
+        let buf = GrayImage::from_raw(2, 3, vec![1, 2, 3, 4, 5, 6]).unwrap();
+        let img = DynamicImage::ImageLuma8(buf);
+
+        let mount = MountOrientation::Rotated90;
+        let oriented = utils::transform_off_screen(img, 3, 2, Some(mount));
+
+        // The top left pixel of the mounted frame, once flipped to the scan orientation
+        let corner = mount.map_rect(&Rect::new(0, 0, 1, 1), (3, 2));
+        let x = 2 - corner.x1 as usize;
+        let y = corner.y1 as usize;
+        assert_eq!(1, oriented.as_bytes()[y * 3 + x]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flash_reverts_after_duration() {
+        let mut flashes = Flashes::default();
+        let start = Instant::now();
+        let area = Rect::new(0, 0, 100, 100);
+
+        flashes.insert(area, "flash".into(), start + Duration::from_millis(300));
+        assert!(flashes.take_expired(Instant::now()).is_empty());
+
+        // What serve waits on before reverting
+        utils::sleep_until(flashes.next_deadline()).await;
+
+        assert_eq!(start + Duration::from_millis(300), Instant::now());
+        assert_eq!(vec!["flash"], flashes.take_expired(Instant::now()));
+        assert!(flashes.next_deadline().is_none());
+    }
+
+    #[test]
+    fn flashes_overlap() {
+        let mut flashes = Flashes::default();
+        let now = Instant::now();
+        let area = Rect::new(0, 0, 100, 100);
+
+        flashes.insert(area.clone(), "first".into(), now + Duration::from_secs(1));
+        // Same area, last wins
+        let replaced = flashes.insert(area, "second".into(), now + Duration::from_secs(2));
+        assert_eq!(Some("first".into()), replaced);
+        // Other area, stacked
+        let other = Rect::new(50, 50, 150, 150);
+        assert!(flashes.insert(other, "third".into(), now).is_none());
+
+        assert_eq!(Some(now), flashes.next_deadline());
+        assert_eq!(vec!["third"], flashes.take_expired(now));
+        assert_eq!(Some(now + Duration::from_secs(2)), flashes.next_deadline());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn focus_mode_applies_last_after_delay() {
+        let delay = Duration::from_millis(300);
+        let core = Core::start_with(MockDriver::new(), |ctl| ctl.with_focus_mode_delay(delay));
+
+        // Alt-tabbing through windows wanting different modes
+        for mode in [DriverMode::Fast, DriverMode::Normal, DriverMode::Fast] {
+            core.send(cmd::Property::RequestFocusDriverMode(mode)).await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(0, core.driver.count("set_mode"));
         }
+        let last = Instant::now() - Duration::from_millis(100);
+
+        tokio::time::sleep_until(last + delay - Duration::from_millis(1)).await;
+        core.sync().await;
+        assert_eq!(0, core.driver.count("set_mode"));
+
+        tokio::time::sleep_until(last + delay + Duration::from_millis(1)).await;
+        core.sync().await;
+        assert_eq!(1, core.driver.count("set_mode"));
+        assert_eq!(Some(DriverMode::Fast), core.driver.driver_mode());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn upload_pacer_coalesces_burst() {
+        let interval = Duration::from_millis(100);
+        let core = Core::start_with(MockDriver::new(), |ctl| ctl.with_upload_interval(interval));
+        let app_key = core.add_app("org.app").await;
+
+        let start = Instant::now();
+        let win_key = core
+            .add_window(&app_key, Rect::new(0, 0, 10, 10), "Y1")
+            .await;
+
+        // A window moving every 10ms
+        for x in 1..=5 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            core.move_window(&win_key, Rect::new(x, 0, x + 10, 10))
+                .await;
+        }
+        tokio::time::sleep_until(start + interval).await;
+        assert_eq!(1, core.driver.count("upload_rect_hints"));
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let uploads = core.driver.rect_hints();
+        assert_eq!(2, uploads.len());
+        assert_eq!(Rect::new(5, 0, 15, 10), uploads[1].rect_hints[0].rect);
+    }
+
+    #[test]
+    fn upload_pacer_disabled() {
+        let mut pacer = RateLimiter::new(Duration::ZERO);
+        let now = Instant::now();
+
+        pacer.record(now);
+
+        assert!(pacer.wait_until(now).is_none());
+    }
+
+    #[test]
+    fn window_update_cancels_overlapping_flash() {
+        let mut flashes = Flashes::default();
+        let now = Instant::now();
+        let (soon, later) = (now + Duration::from_secs(1), now + Duration::from_secs(2));
+
+        flashes.insert(Rect::new(0, 0, 100, 100), "flash".into(), soon);
+        flashes.insert(Rect::new(200, 0, 300, 100), "other".into(), later);
+
+        // A window moving across the first flash only
+        let window = Rect::new(90, 90, 150, 150);
+        assert_eq!(vec!["flash"], flashes.cancel_overlapping(&window));
+        assert_eq!(Some(later), flashes.next_deadline());
+
+        // Touching edges don't overlap
+        let window = Rect::new(100, 0, 200, 100);
+        assert!(flashes.cancel_overlapping(&window).is_empty());
+        assert_eq!(vec!["other"], flashes.take_expired(later));
+    }
+
+    /// Path in `dir` to an image which can't be read until [write_slow_image], a FIFO.
+    fn slow_image(dir: &TempDir) -> String {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let path = dir.create().join("slow.png");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(0, unsafe { nix::libc::mkfifo(c_path.as_ptr(), 0o600) });
+
+        path.to_string_lossy().into()
+    }
+
+    /// Write a small image to a [slow_image], once the core opened it.
+    async fn write_slow_image(path: String) {
+        let write = move || {
+            let mut png = std::io::Cursor::new(Vec::new());
+            GrayImage::new(4, 4)
+                .write_to(&mut png, image::ImageFormat::Png)
+                .unwrap();
+
+            std::fs::write(&path, png.into_inner()).unwrap();
+        };
+
+        tokio::task::spawn_blocking(write).await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn window_update_through_dispatch_cancels_flash() {
+        let core = Core::start();
+        let start = Instant::now();
+        let app_key = core.add_app("org.app").await;
+        let win_key = core
+            .add_window(&app_key, Rect::new(0, 0, 10, 10), "Y1")
+            .await;
+
+        let hint = Hint::try_from_human_readable("Y2|T").unwrap();
+        core.send(cmd::Command::FlashRegion {
+            area: Rect::new(0, 0, 20, 20),
+            hint,
+            duration: Duration::from_secs(5),
+        })
+        .await;
+        let flashing = || {
+            let last = core.driver.rect_hints().pop().unwrap();
+            last.rect_hints.iter().any(|h| h.hint == hint)
+        };
+
+        // A new title doesn't change what the flash covers
+        let title = cmd::WindowUpdate {
+            title: Some("Renamed".into()),
+            ..Default::default()
+        };
+        core.send(cmd::Window::Update {
+            win_key: win_key.clone(),
+            update: title,
+        })
+        .await;
+        core.sync().await;
+        assert!(flashing());
+
+        core.move_window(&win_key, Rect::new(5, 5, 15, 15)).await;
+        core.sync().await;
+        assert!(!flashing());
+
+        // Refreshed once the interval since the flash elapsed, and not again at its deadline
+        tokio::time::sleep_until(start + Duration::from_secs(6)).await;
+        assert_eq!(2, core.driver.count("global_refresh"));
+    }
+
+    #[tokio::test]
+    async fn slow_off_screen_doesnt_block_commands() {
+        let core = Core::start();
+        let dir = TempDir::new("slow");
+        let path = slow_image(&dir);
+        let (reply, replied) = oneshot::channel();
+
+        core.send(cmd::Command::OffScreen(path.clone(), false, reply))
+            .await;
+        // Handled while the image is still being read
+        core.add_app("org.app").await;
+        assert!(core.driver.off_screens().is_empty());
+
+        write_slow_image(path).await;
+        assert!(replied.await.unwrap().is_ok());
+        assert_eq!(1, core.driver.off_screens().len());
+    }
+
+    #[tokio::test]
+    async fn sync_waits_for_off_screen() {
+        let core = Core::start();
+        let dir = TempDir::new("slow");
+        let path = slow_image(&dir);
+        let (reply, _replied) = oneshot::channel();
+
+        core.send(cmd::Command::OffScreen(path.clone(), false, reply))
+            .await;
+        let sync = tokio::spawn({
+            let tx = core.tx.clone();
+            let (reply, rx) = oneshot::channel();
+            async move { tx.with_reply(cmd::Command::Sync(reply), rx).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!sync.is_finished());
+
+        write_slow_image(path).await;
+        assert_eq!(1, sync.await.unwrap().unwrap());
+        assert_eq!(1, core.driver.off_screens().len());
+    }
+
+    #[tokio::test]
+    async fn quit_answers_pending_off_screen() {
+        let core = Core::start();
+        let dir = TempDir::new("slow");
+        let path = slow_image(&dir);
+        let (reply, replied) = oneshot::channel();
+
+        core.send(cmd::Command::OffScreen(path.clone(), false, reply))
+            .await;
+        let writer = tokio::spawn(write_slow_image(path));
+        let driver = core.driver.clone();
+        core.quit().await;
+
+        assert!(replied.await.unwrap().is_ok());
+        assert_eq!(1, driver.off_screens().len());
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn off_screen_queue_keeps_order() {
+        let mut queue = OffScreenQueue::default();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let full = |name: &str| Ok(OffScreenImage::Full(name.into(), vec![]));
+
+        let slow = full("slow");
+        queue.push("SetOffScreen", oneshot::channel().0, move || {
+            released.recv().unwrap();
+            slow
+        });
+        let fast = full("solid:0");
+        queue.push("SetOffScreenSolid", oneshot::channel().0, move || fast);
+        queue.push("SetOffScreenRegion", oneshot::channel().0, || {
+            Err(OffScreenError::DecodeFailed)
+        });
+
+        release.send(()).unwrap();
+
+        // The slow image comes first even though the solid one was ready before it
+        let (image, _, ctx) = queue.next().await;
+        assert!(matches!(image, Ok(OffScreenImage::Full(name, _)) if name == "slow"));
+        assert_eq!("SetOffScreen", ctx);
+        assert_eq!("SetOffScreenSolid", queue.next().await.2);
+        let (image, _, _) = queue.next().await;
+        assert!(matches!(image, Err(OffScreenError::DecodeFailed)));
+
+        // Nothing left, waits forever
+        let next = tokio::time::timeout(Duration::from_millis(10), queue.next());
+        assert!(next.await.is_err());
     }
 }
@@ -0,0 +1,92 @@
+use pinenote_service::{
+    drivers::rockchip_ebc::{DriverError, RockchipEbc},
+    pixel_manager::ComputedHints,
+    types::{
+        Rect,
+        rockchip_ebc::{FrameBuffers, Hint, Mode, RedrawOptions},
+    },
+};
+
+/// Driver calls made by the core, see [RockchipEbc] for their meaning.
+///
+/// The core only talks to the panel through this trait, so that the command loop can be served
+/// against a mock in tests.
+pub trait Driver: Send + Sync + 'static {
+    fn probe(&self) -> bool;
+    fn default_hint(&self) -> Result<Hint, DriverError>;
+    fn screen_area(&self) -> Result<Rect, DriverError>;
+    fn global_refresh(&self) -> Result<(), DriverError>;
+    fn upload_rect_hints(&self, hints: ComputedHints) -> Result<(), DriverError>;
+    fn extract_framebuffers(&self) -> Result<FrameBuffers, DriverError>;
+    fn mode(&self) -> Result<Mode, DriverError>;
+    fn set_mode(&self, mode: Mode) -> Result<(), DriverError>;
+    fn redraw_options(&self) -> Result<RedrawOptions, DriverError>;
+    fn set_redraw_options(&self, options: RedrawOptions) -> Result<(), DriverError>;
+    fn no_off_screen(&self) -> Result<bool, DriverError>;
+    fn set_no_off_screen(&self, value: bool) -> Result<(), DriverError>;
+    fn upload_off_screen(&self, screen_content: Vec<u8>) -> Result<(), DriverError>;
+    fn param(&self, name: &str) -> Result<String, DriverError>;
+    fn set_param(&self, name: &str, value: String) -> Result<(), DriverError>;
+}
+
+impl Driver for RockchipEbc {
+    fn probe(&self) -> bool {
+        RockchipEbc::probe(self)
+    }
+
+    fn default_hint(&self) -> Result<Hint, DriverError> {
+        Ok(RockchipEbc::default_hint(self)?)
+    }
+
+    fn screen_area(&self) -> Result<Rect, DriverError> {
+        RockchipEbc::screen_area(self)
+    }
+
+    fn global_refresh(&self) -> Result<(), DriverError> {
+        RockchipEbc::global_refresh(self)
+    }
+
+    fn upload_rect_hints(&self, hints: ComputedHints) -> Result<(), DriverError> {
+        RockchipEbc::upload_rect_hints(self, hints)
+    }
+
+    fn extract_framebuffers(&self) -> Result<FrameBuffers, DriverError> {
+        RockchipEbc::extract_framebuffers(self)
+    }
+
+    fn mode(&self) -> Result<Mode, DriverError> {
+        RockchipEbc::mode(self)
+    }
+
+    fn set_mode(&self, mode: Mode) -> Result<(), DriverError> {
+        RockchipEbc::set_mode(self, mode)
+    }
+
+    fn redraw_options(&self) -> Result<RedrawOptions, DriverError> {
+        RockchipEbc::redraw_options(self)
+    }
+
+    fn set_redraw_options(&self, options: RedrawOptions) -> Result<(), DriverError> {
+        RockchipEbc::set_redraw_options(self, options)
+    }
+
+    fn no_off_screen(&self) -> Result<bool, DriverError> {
+        Ok(RockchipEbc::no_off_screen(self)?)
+    }
+
+    fn set_no_off_screen(&self, value: bool) -> Result<(), DriverError> {
+        Ok(RockchipEbc::set_no_off_screen(self, value)?)
+    }
+
+    fn upload_off_screen(&self, screen_content: Vec<u8>) -> Result<(), DriverError> {
+        RockchipEbc::upload_off_screen(self, screen_content)
+    }
+
+    fn param(&self, name: &str) -> Result<String, DriverError> {
+        RockchipEbc::param(self, name)
+    }
+
+    fn set_param(&self, name: &str, value: String) -> Result<(), DriverError> {
+        RockchipEbc::set_param(self, name, value)
+    }
+}
@@ -0,0 +1,18 @@
+use pinenote_service::types::rockchip_ebc::RectHint;
+use tokio::sync::broadcast;
+
+/// Notable changes in the core, broadcast to interested listeners (e.g. DBus).
+///
+/// Events are sent whatever the origin of the change, bridge or DBus.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    WindowAdded {
+        win_key: String,
+        app_key: String,
+    },
+    WindowRemoved(String),
+    /// Rectangle hints just uploaded to the driver, only sent when enabled as it is costly.
+    HintsUpdated(Vec<RectHint>),
+}
+
+pub type EventReceiver = broadcast::Receiver<Event>;
@@ -0,0 +1,389 @@
+//! Serve the core against a mock driver, to test commands end to end.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use pinenote_service::{
+    drivers::rockchip_ebc::{DriverError, RockchipEbc},
+    pixel_manager::ComputedHints,
+    types::{
+        Rect,
+        rockchip_ebc::{DitherMode, DriverMode, FrameBuffers, Hint, Mode, RedrawOptions},
+    },
+};
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use super::{
+    Application, Command, CommandSender, Ctl, Driver, EventReceiver, Window, WindowUpdate,
+};
+
+/// Driver recording the calls made to it, instead of driving a panel.
+pub struct MockDriver {
+    screen_area: Rect,
+    state: Mutex<MockState>,
+}
+
+#[derive(Default)]
+struct MockState {
+    calls: Vec<&'static str>,
+    rect_hints: Vec<ComputedHints>,
+    off_screens: Vec<Vec<u8>>,
+    mode: Mode,
+    early_cancellation_addition: i32,
+    no_off_screen: bool,
+    params: HashMap<String, String>,
+    /// Calls failing, panicking, or taking a while.
+    failing: HashSet<&'static str>,
+    panicking: HashSet<&'static str>,
+    delays: HashMap<&'static str, Duration>,
+}
+
+impl MockDriver {
+    /// Default screen, small enough for off screen images to be quick to prepare.
+    pub const SCREEN_AREA: Rect = Rect::new(0, 0, 64, 48);
+
+    pub fn new() -> Self {
+        Self::with_screen_area(Self::SCREEN_AREA)
+    }
+
+    pub fn with_screen_area(screen_area: Rect) -> Self {
+        let mode = Mode {
+            driver_mode: Some(DriverMode::Normal),
+            dither_mode: Some(DitherMode::Bayer),
+            redraw_delay: Some(100),
+        };
+
+        Self {
+            screen_area,
+            state: Mutex::new(MockState {
+                mode,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Make the `call` fail from now on.
+    pub fn fail(&self, call: &'static str) {
+        self.state().failing.insert(call);
+    }
+
+    /// Make the `call` succeed again.
+    pub fn unfail(&self, call: &'static str) {
+        self.state().failing.remove(call);
+    }
+
+    /// Make the `call` panic from now on.
+    pub fn panic_on(&self, call: &'static str) {
+        self.state().panicking.insert(call);
+    }
+
+    /// Make the `call` block its thread for `delay`.
+    pub fn delay(&self, call: &'static str, delay: Duration) {
+        self.state().delays.insert(call, delay);
+    }
+
+    /// Calls made so far, in order.
+    pub fn calls(&self) -> Vec<&'static str> {
+        self.state().calls.clone()
+    }
+
+    /// Number of times `call` was made.
+    pub fn count(&self, call: &str) -> usize {
+        self.state().calls.iter().filter(|c| **c == call).count()
+    }
+
+    /// Hints successfully uploaded, in order.
+    pub fn rect_hints(&self) -> Vec<ComputedHints> {
+        self.state().rect_hints.clone()
+    }
+
+    /// Off screen buffers successfully uploaded, in order.
+    pub fn off_screens(&self) -> Vec<Vec<u8>> {
+        self.state().off_screens.clone()
+    }
+
+    /// Driver mode currently set.
+    pub fn driver_mode(&self) -> Option<DriverMode> {
+        self.state().mode.driver_mode
+    }
+
+    /// Record `call`, then fail, panic or wait as configured.
+    fn call(&self, call: &'static str) -> Result<(), DriverError> {
+        let delay = {
+            let mut state = self.state();
+            state.calls.push(call);
+
+            if state.panicking.contains(call) {
+                drop(state);
+                panic!("Mock {call} panicked");
+            }
+            if state.failing.contains(call) {
+                return Err(DriverError::UnknownParam(call.into()));
+            }
+
+            state.delays.get(call).copied()
+        };
+
+        if let Some(delay) = delay {
+            std::thread::sleep(delay);
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for MockDriver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Driver for MockDriver {
+    fn probe(&self) -> bool {
+        self.call("probe").is_ok()
+    }
+
+    fn default_hint(&self) -> Result<Hint, DriverError> {
+        Ok(Hint::try_from_human_readable("Y4|T").unwrap())
+    }
+
+    fn screen_area(&self) -> Result<Rect, DriverError> {
+        Ok(self.screen_area.clone())
+    }
+
+    fn global_refresh(&self) -> Result<(), DriverError> {
+        self.call("global_refresh")
+    }
+
+    fn upload_rect_hints(&self, hints: ComputedHints) -> Result<(), DriverError> {
+        self.call("upload_rect_hints")?;
+
+        let count = hints.rect_hints.len();
+        if count > RockchipEbc::MAX_RECT_HINTS {
+            return Err(DriverError::TooManyRects(
+                count,
+                RockchipEbc::MAX_RECT_HINTS,
+            ));
+        }
+        self.state().rect_hints.push(hints);
+
+        Ok(())
+    }
+
+    fn extract_framebuffers(&self) -> Result<FrameBuffers, DriverError> {
+        self.call("extract_framebuffers")?;
+
+        Ok(FrameBuffers::new(self.screen_area.x2, self.screen_area.y2))
+    }
+
+    fn mode(&self) -> Result<Mode, DriverError> {
+        self.call("mode")?;
+
+        let Mode {
+            driver_mode,
+            dither_mode,
+            redraw_delay,
+        } = self.state().mode;
+        Ok(Mode {
+            driver_mode,
+            dither_mode,
+            redraw_delay,
+        })
+    }
+
+    fn set_mode(&self, mode: Mode) -> Result<(), DriverError> {
+        self.call("set_mode")?;
+
+        let current = &mut self.state().mode;
+        current.driver_mode = mode.driver_mode.or(current.driver_mode);
+        current.dither_mode = mode.dither_mode.or(current.dither_mode);
+        current.redraw_delay = mode.redraw_delay.or(current.redraw_delay);
+
+        Ok(())
+    }
+
+    fn redraw_options(&self) -> Result<RedrawOptions, DriverError> {
+        self.call("redraw_options")?;
+
+        let state = self.state();
+        Ok(RedrawOptions {
+            redraw_delay: state.mode.redraw_delay.unwrap_or_default(),
+            early_cancellation_addition: state.early_cancellation_addition,
+        })
+    }
+
+    fn set_redraw_options(&self, options: RedrawOptions) -> Result<(), DriverError> {
+        self.call("set_redraw_options")?;
+
+        let mut state = self.state();
+        state.mode.redraw_delay = Some(options.redraw_delay);
+        state.early_cancellation_addition = options.early_cancellation_addition;
+
+        Ok(())
+    }
+
+    fn no_off_screen(&self) -> Result<bool, DriverError> {
+        self.call("no_off_screen")?;
+
+        Ok(self.state().no_off_screen)
+    }
+
+    fn set_no_off_screen(&self, value: bool) -> Result<(), DriverError> {
+        self.call("set_no_off_screen")?;
+
+        self.state().no_off_screen = value;
+        Ok(())
+    }
+
+    fn upload_off_screen(&self, screen_content: Vec<u8>) -> Result<(), DriverError> {
+        self.call("upload_off_screen")?;
+
+        let num_pixel = self.screen_area.area() as usize;
+        if screen_content.len() != num_pixel {
+            return Err(DriverError::BadSize(num_pixel, screen_content.len()));
+        }
+        self.state().off_screens.push(screen_content);
+
+        Ok(())
+    }
+
+    fn param(&self, name: &str) -> Result<String, DriverError> {
+        self.call("param")?;
+
+        self.state()
+            .params
+            .get(name)
+            .cloned()
+            .ok_or_else(|| DriverError::UnknownParam(name.into()))
+    }
+
+    fn set_param(&self, name: &str, value: String) -> Result<(), DriverError> {
+        self.call("set_param")?;
+
+        self.state().params.insert(name.into(), value);
+        Ok(())
+    }
+}
+
+/// Core served in the background against a [MockDriver].
+pub struct Core {
+    pub tx: CommandSender,
+    pub driver: Arc<MockDriver>,
+    pub events: EventReceiver,
+    serving: JoinHandle<Ctl>,
+}
+
+impl Core {
+    /// Serve a core using `driver`, once set up with `setup`.
+    pub fn start_with(driver: MockDriver, setup: impl FnOnce(Ctl) -> Ctl) -> Self {
+        Self::start_with_capacity(driver, 16, setup)
+    }
+
+    /// Same as [Self::start_with], queueing up to `capacity` commands.
+    pub fn start_with_capacity(
+        driver: MockDriver,
+        capacity: usize,
+        setup: impl FnOnce(Ctl) -> Ctl,
+    ) -> Self {
+        let driver = Arc::new(driver);
+        let ctl = Ctl::with_driver(driver.clone()).expect("The mock driver has a valid screen");
+        let mut ctl = setup(ctl);
+
+        let events = ctl.subscribe();
+        let (tx, rx) = CommandSender::channel(capacity);
+        let serving = tokio::spawn(async move {
+            ctl.serve(rx).await;
+            ctl
+        });
+
+        Self {
+            tx,
+            driver,
+            events,
+            serving,
+        }
+    }
+
+    /// Serve a core with the default settings.
+    pub fn start() -> Self {
+        Self::start_with(MockDriver::new(), |ctl| ctl)
+    }
+
+    /// Send `cmd`, which the core handles in order.
+    pub async fn send(&self, cmd: impl Into<Command>) {
+        self.tx.send(cmd).await.unwrap();
+    }
+
+    /// Send the command built by `cmd` around a reply channel, and wait for the reply.
+    pub async fn ask<T>(&self, cmd: impl FnOnce(oneshot::Sender<T>) -> Command) -> T {
+        let (reply, rx) = oneshot::channel();
+
+        self.tx.with_reply(cmd(reply), rx).await.unwrap()
+    }
+
+    /// Wait for the commands sent so far to be handled.
+    pub async fn sync(&self) -> u64 {
+        self.ask(Command::Sync).await
+    }
+
+    /// Register an application, returning its key.
+    pub async fn add_app(&self, app_id: &str) -> String {
+        self.ask(|reply| Application::Add(app_id.into(), 1, reply).into())
+            .await
+    }
+
+    /// Add a visible window of `app_key` over `area`, returning its key.
+    pub async fn add_window(&self, app_key: &str, area: Rect, hint: &str) -> String {
+        let hint = Hint::try_from_human_readable(hint).unwrap();
+
+        self.ask(|reply| {
+            Window::Add {
+                app_key: app_key.into(),
+                title: "Window".into(),
+                area,
+                hint: Some(hint),
+                visible: true,
+                fullscreen: false,
+                z_index: 0,
+                focused: false,
+                external_id: None,
+                reply,
+            }
+            .into()
+        })
+        .await
+    }
+
+    /// Move the window `win_key` to `area`.
+    pub async fn move_window(&self, win_key: &str, area: Rect) {
+        let update = WindowUpdate {
+            area: Some(area),
+            ..Default::default()
+        };
+
+        self.send(Window::Update {
+            win_key: win_key.into(),
+            update,
+        })
+        .await
+    }
+
+    /// Stop serving, returning the core once its loop ended.
+    pub async fn quit(self) -> Ctl {
+        self.send(Command::Quit).await;
+
+        self.serving.await.expect("The core loop panicked")
+    }
+
+    /// Whether the loop is still serving.
+    pub fn serving(&self) -> bool {
+        !self.serving.is_finished()
+    }
+}
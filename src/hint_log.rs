@@ -0,0 +1,185 @@
+//! Log of the hints uploaded to the driver, for replay
+//!
+//! Each upload is appended as a JSON line, hints using their
+//! [human readable](Hint::try_from_human_readable) form.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    pixel_manager::ComputedHints,
+    types::{
+        Rect,
+        rockchip_ebc::{self, Hint, RectHint},
+    },
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Malformed hint log entry")]
+    Format(#[from] serde_json::Error),
+    #[error("Invalid hint '{0}' in hint log")]
+    Hint(String, #[source] rockchip_ebc::Error),
+}
+
+/// One upload of hints to the driver.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub default_hint: Option<String>,
+    pub rect_hints: Vec<(Rect, String)>,
+}
+
+impl Entry {
+    pub fn new(timestamp_ms: u64, hints: &ComputedHints) -> Self {
+        Self {
+            timestamp_ms,
+            default_hint: hints.default_hint.map(|h| h.to_string()),
+            rect_hints: hints
+                .rect_hints
+                .iter()
+                .map(|RectHint { rect, hint }| (rect.clone(), hint.to_string()))
+                .collect(),
+        }
+    }
+
+    /// The hints as they were uploaded.
+    pub fn hints(&self) -> Result<ComputedHints, Error> {
+        let parse = |hint: &String| {
+            Hint::try_from_human_readable(hint).map_err(|e| Error::Hint(hint.clone(), e))
+        };
+
+        Ok(ComputedHints {
+            default_hint: self.default_hint.as_ref().map(parse).transpose()?,
+            rect_hints: self
+                .rect_hints
+                .iter()
+                .map(|(rect, hint)| {
+                    parse(hint).map(|hint| RectHint {
+                        rect: rect.clone(),
+                        hint,
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Append-only log of the uploaded hints.
+pub struct HintLog {
+    file: File,
+}
+
+impl HintLog {
+    /// Open the log at `path`, appending to it if it already exists.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self { file })
+    }
+
+    /// Append an upload, timestamped with the current time.
+    pub fn append(&mut self, hints: &ComputedHints) -> Result<(), Error> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        self.append_entry(&Entry::new(timestamp_ms, hints))
+    }
+
+    fn append_entry(&mut self, entry: &Entry) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        // A single write per line, so that a reader never sees half an entry.
+        self.file.write_all(&line)?;
+
+        Ok(())
+    }
+
+    /// Read back every entry of the log at `path`.
+    pub fn read(path: impl AsRef<Path>) -> Result<Vec<Entry>, Error> {
+        BufReader::new(File::open(path)?)
+            .lines()
+            .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    fn hint(hr: &str) -> Hint {
+        Hint::try_from_human_readable(hr).unwrap()
+    }
+
+    fn computed(rects: usize) -> ComputedHints {
+        ComputedHints {
+            default_hint: Some(hint("Y4|T|R")),
+            rect_hints: (0..rects as i32)
+                .map(|i| RectHint {
+                    rect: Rect::new(0, 0, 100 * (i + 1), 100),
+                    hint: hint("Y1|D"),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn one_line_per_upload() {
+        let dir = TempDir::new("hints");
+        let path = dir.join("hints.jsonl");
+
+        let mut log = HintLog::open(&path).unwrap();
+        log.append(&computed(1)).unwrap();
+        log.append(&computed(3)).unwrap();
+
+        let entries = HintLog::read(&path).unwrap();
+        assert_eq!(2, fs::read_to_string(&path).unwrap().lines().count());
+        let rect_counts: Vec<_> = entries.iter().map(|e| e.rect_hints.len()).collect();
+        assert_eq!(vec![1, 3], rect_counts);
+        assert_eq!(computed(3), entries[1].hints().unwrap());
+
+        // Reopening appends
+        let mut log = HintLog::open(&path).unwrap();
+        log.append(&computed(0)).unwrap();
+        assert_eq!(3, HintLog::read(&path).unwrap().len());
+    }
+
+    #[test]
+    fn read_malformed() {
+        let dir = TempDir::new("hints");
+        let path = dir.join("hints.jsonl");
+        HintLog::open(&path).unwrap();
+
+        fs::write(&path, "{\n").unwrap();
+        assert!(matches!(HintLog::read(&path), Err(Error::Format(_))));
+
+        let entry = Entry {
+            timestamp_ms: 0,
+            default_hint: Some("Y3".into()),
+            rect_hints: vec![],
+        };
+        assert!(matches!(entry.hints(), Err(Error::Hint(..))));
+    }
+}
@@ -0,0 +1,67 @@
+//! Snapshot of the window layout, to capture a live session and replay it
+//!
+//! Hints use their [human readable](Hint::try_from_human_readable) form, like the state file.
+
+use std::{fs, io, path::Path};
+
+use nix::libc::pid_t;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{pixel_manager::WindowData, state::human_readable_hint, types::rockchip_ebc::Hint};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Malformed layout snapshot")]
+    Format(#[from] serde_json::Error),
+}
+
+/// Applications, windows and hints of a [PixelManager](crate::pixel_manager::PixelManager), see
+/// [PixelManager::export_layout](crate::pixel_manager::PixelManager::export_layout).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayoutSnapshot {
+    #[serde(with = "human_readable_hint")]
+    pub default_hint: Hint,
+    /// Hint of the background region, if any.
+    #[serde(default, with = "human_readable_hint::option")]
+    pub background: Option<Hint>,
+    /// Applications, regions included, by key.
+    pub apps: Vec<AppSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AppSnapshot {
+    pub app_id: String,
+    pub pid: pid_t,
+    #[serde(default, with = "human_readable_hint::option")]
+    pub default_hint: Option<Hint>,
+    #[serde(default)]
+    pub merge_windows: bool,
+    #[serde(default)]
+    pub always_on_top: bool,
+    /// Windows, by z-index.
+    pub windows: Vec<WindowSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WindowSnapshot {
+    /// Identifier of the window in the compositor, if any. Window keys aren't kept.
+    #[serde(default)]
+    pub external_id: Option<String>,
+    #[serde(flatten)]
+    pub data: WindowData,
+}
+
+impl LayoutSnapshot {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+
+        Ok(())
+    }
+}
@@ -54,9 +54,13 @@ pub mod ioctls {
 }
 
 pub mod types {
+    pub mod mount;
+    pub mod parse;
     pub mod rect;
     pub mod rockchip_ebc;
-    pub use rect::Rect;
+    pub use mount::MountOrientation;
+    pub use parse::ParseError;
+    pub use rect::{Rect, Rounding};
 
     pub mod ztree;
 }
@@ -65,4 +69,13 @@ pub mod sysfs {
     pub mod attribute;
 }
 
+pub mod hint_log;
+
+pub mod layout;
+
 pub mod pixel_manager;
+
+pub mod state;
+
+#[cfg(test)]
+pub mod temp_dir;
@@ -1,13 +1,12 @@
 use anyhow::Result;
-use tokio::{signal, sync::mpsc};
-use log::{debug, error};
+use tokio::signal;
+use log::{debug, error, info};
 
 #[cfg(feature = "bridges")]
 pub mod bridge {
-    use tokio::sync::mpsc;
     use log::error;
 
-    use crate::ebc;
+    use crate::{config::Config, ebc, shared_state::Shared};
 
     #[cfg(feature = "sway")]
     pub mod sway;
@@ -15,65 +14,190 @@ pub mod bridge {
     #[cfg(feature = "quill-niri")]
     pub mod quill_niri;
 
-    pub async fn start(tx: mpsc::Sender<ebc::Command>) -> Option<String> {
+    #[cfg(any(feature = "sway", feature = "quill-niri"))]
+    pub mod sync;
+
+    /// Names of the bridges compiled in.
+    pub const COMPILED: &[&str] = &[
+        #[cfg(feature = "sway")]
+        "sway",
+        #[cfg(feature = "quill-niri")]
+        "quill-niri",
+    ];
+
+    /// Outcome of [start].
+    #[derive(Debug)]
+    pub struct Bridges {
+        /// Name of the bridge tracking the compositor, if any could start.
+        pub active: Option<String>,
+        /// Bridges compiled in, see [COMPILED].
+        pub compiled: &'static [&'static str],
+    }
+
+    /// Start the compositor bridge.
+    ///
+    /// While the bridge is [paused](crate::shared_state::SharedState::bridge_paused), it keeps
+    /// tracking the compositor but doesn't send any update to the core. Without any bridge
+    /// compiled in, the service still runs, leaving the windows to DBus clients.
+    #[cfg_attr(not(feature = "sway"), allow(unused_variables))]
+    pub async fn start(tx: ebc::CommandSender, shared: Shared, config: &Config) -> Bridges {
         #[cfg(feature = "sway")]
-        let res = sway::start(tx.clone()).await;
+        let res = Some(sway::start(tx.clone(), shared.clone(), config.strict_hints).await);
 
         #[cfg(feature = "quill-niri")]
-        let res = quill_niri::start(tx.clone()).await;
+        let res = Some(
+            quill_niri::start(
+                tx.clone(),
+                shared.clone(),
+                config.scale_rounding,
+                config.strict_hints,
+            )
+            .await,
+        );
 
         // Add here other bridges with AND for the check to work
         #[cfg(not(any(feature = "sway", feature = "quill-niri")))]
-        {
-            compile_error!(
-                "bridges feature is enabled but no specific bridges are enabled, this is wrong, enable sway feature for example"
-            );
-        }
-
-        match res {
-            Ok(s) => Some(s),
-            Err(e) => {
+        let res: Option<anyhow::Result<String>> = {
+            log::warn!("No bridge compiled in, only DBus clients will report windows");
+            None
+        };
+
+        let active = match res {
+            Some(Ok(s)) => Some(s),
+            Some(Err(e)) => {
                 error!("{e:#?}");
                 None
             }
+            None => None,
+        };
+
+        Bridges {
+            active,
+            compiled: COMPILED,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn compiled_follows_features() {
+            let compiled = |name| COMPILED.contains(&name);
+
+            assert_eq!(cfg!(feature = "sway"), compiled("sway"));
+            assert_eq!(cfg!(feature = "quill-niri"), compiled("quill-niri"));
+        }
+
+        #[cfg(not(any(feature = "sway", feature = "quill-niri")))]
+        #[tokio::test]
+        async fn start_without_bridge() {
+            let (tx, _rx) = ebc::CommandSender::channel(1);
+            let shared = crate::shared_state::SharedState::new();
+
+            let bridges = start(tx, shared, &Config::default()).await;
+
+            assert!(bridges.active.is_none());
+            assert!(bridges.compiled.is_empty());
         }
     }
 }
 
+pub mod config;
+
 pub mod dbus;
 
+pub mod power;
+
+pub mod shared_state;
+
+#[cfg(test)]
+pub mod temp_dir;
+
 pub mod ebc {
     pub mod command;
     pub use command::*;
     pub mod ctl;
     pub use ctl::*;
+    pub mod driver;
+    pub use driver::*;
+    pub mod event;
+    pub use event::*;
+    #[cfg(test)]
+    pub mod testing;
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
-    let (tx, rx) = mpsc::channel(100);
-    let mut ebc = ebc::Ctl::new()?;
+    let config = config::Config::from_env();
+    let (tx, rx) = ebc::CommandSender::channel(config.cmd_channel_capacity);
+    ebc::Ctl::wait_for_driver().await;
+    let mut ebc = ebc::Ctl::new()?
+        .with_app_sweep_interval(config.app_sweep_interval)
+        .with_mount_orientation(config.mount_orientation)
+        .with_ignored_apps(config.ignored_apps.clone())
+        .with_jitter_threshold(config.jitter_threshold)
+        .with_focus_mode_delay(config.focus_mode_delay)
+        .with_upload_interval(config.upload_interval);
+    let events = ebc.subscribe();
+
+    if let Some(path) = config.state_file.clone()
+        && let Err(e) = ebc.restore_state(path).await
+    {
+        error!("Failed to restore state: {e:#}");
+    }
 
-    tokio::spawn(async move {
+    let core = tokio::spawn(async move {
         ebc.serve(rx).await;
     });
 
+    let shared = shared_state::SharedState::new();
+
     #[cfg(feature = "bridges")]
-    let selected_bridge = bridge::start(tx.clone()).await.unwrap_or_default();
+    let selected_bridge = {
+        let bridges = bridge::start(tx.clone(), shared.clone(), &config).await;
+        if bridges.active.is_none() {
+            log::warn!("No bridge running, compiled in: {:?}", bridges.compiled);
+        }
+        bridges.active.unwrap_or_default()
+    };
     #[cfg(not(feature = "bridges"))]
     let selected_bridge = String::new();
 
-    let _dbus_ctx = dbus::Context::initialize(tx.clone(), selected_bridge).await?;
+    let dbus_ctx =
+        dbus::Context::initialize(tx.clone(), selected_bridge, shared.clone(), events, &config)
+            .await?;
+
+    if let Some(hint) = config.default_hint
+        && let Err(e) = dbus_ctx.apply_default_hint(hint).await
+    {
+        error!("Failed to apply default hint: {e:#}");
+    }
+
+    // Started last, so that it caps the default hint applied above
+    let power_monitor = power::start(tx.clone(), shared.clone(), &config);
 
     debug!("Started?");
 
-    match signal::ctrl_c().await {
-        Ok(()) => {}
-        Err(err) => {
-            error!("Unable to listen for shutdown signal: {}", err);
+    tokio::select! {
+        res = signal::ctrl_c() => {
+            if let Err(err) = res {
+                error!("Unable to listen for shutdown signal: {}", err);
+            }
         }
-    };
+        _ = shared.wait_shutdown() => {}
+    }
+
+    // Stop the bridges, and let the core handle what they sent before saving its state
+    shared.request_shutdown();
+    if let Some(power_monitor) = power_monitor {
+        let _ = power_monitor.await;
+    }
+    if tx.send(ebc::Command::Quit).await.is_ok() {
+        let _ = core.await;
+    }
+    info!("Stopped");
 
     Ok(())
 }
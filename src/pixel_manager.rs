@@ -29,6 +29,18 @@
 //! given window will only produce one rectangle[^rec_per_win]. However the rectangle produce is
 //! always the bounding box of the window's visible area.
 //!
+//! When [PixelManager::precise] is set, the second goal is favored instead: every window produces
+//! as many rectangles as needed to cover exactly its visible area.
+//!
+//! Unsetting [PixelManager::minimize] skips all of this, for debugging: every visible window
+//! emits its whole area clipped to the screen, in z-order.
+//!
+//! A window set [always on top](WindowData::always_on_top) masks every other window, whatever its
+//! z-index.
+//!
+//! A window with a [content inset](WindowData::content_inset) only uses its hint for its content,
+//! its margins get the hint of its Application, or the global one.
+//!
 //! ## Example
 //!
 //! Given the following windows:
@@ -63,17 +75,32 @@
 //! [driver]: crate::drivers::rockchip_ebc::RockchipEbc
 //! [rendering mode]: crate::types::rockchip_ebc::Hint
 
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    path::Path,
+};
 use thiserror::Error;
 
 use nix::libc::pid_t;
+use serde::{Deserialize, Serialize};
 
-use crate::types::{
-    Rect,
-    rockchip_ebc::{Hint, RectHint},
-    ztree::{ZSurface, ZTree},
+use crate::{
+    layout::{AppSnapshot, LayoutSnapshot, WindowSnapshot},
+    state::human_readable_hint,
+    types::{
+        Rect,
+        rect::SplitRect,
+        rockchip_ebc::{Hint, HintBitDepth, RectHint},
+        ztree::{ZSurface, ZTree},
+    },
 };
 
+/// Check whether a process is still running, using procfs.
+pub fn process_alive(pid: pid_t) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
 /// Application representation.
 ///
 /// This struct represent a running process, and hold the default configuration for any of the
@@ -83,6 +110,12 @@ pub struct Application {
     app_id: String,
     pid: pid_t,
     default_hint: Option<Hint>,
+    /// Hint the bounding box of all the visible windows at once, see
+    /// [PixelManager::app_set_merge_windows].
+    merge_windows: bool,
+    /// Mask the other windows with every window of the application, see
+    /// [PixelManager::app_set_always_on_top].
+    always_on_top: bool,
     windows: HashSet<String>,
 }
 
@@ -93,6 +126,8 @@ impl Application {
             app_id: app_id.into(),
             pid,
             default_hint: None,
+            merge_windows: false,
+            always_on_top: false,
             windows: Default::default(),
         }
     }
@@ -103,12 +138,34 @@ impl Application {
             app_id: app_id.into(),
             pid,
             default_hint,
+            merge_windows: false,
+            always_on_top: false,
             windows: Default::default(),
         }
     }
 
+    /// Application identifier, as reported by the compositor.
+    pub fn app_id(&self) -> &str {
+        &self.app_id
+    }
+
+    /// Process id of the application.
+    pub fn pid(&self) -> pid_t {
+        self.pid
+    }
+
+    /// Whether the windows are hinted as a single region.
+    pub fn merge_windows(&self) -> bool {
+        self.merge_windows
+    }
+
+    /// Whether the windows mask the others as if on top of them.
+    pub fn always_on_top(&self) -> bool {
+        self.always_on_top
+    }
+
     /// Return the application unique Key.
-    fn key(&self) -> String {
+    pub fn key(&self) -> String {
         format!("{}:{}", self.app_id, self.pid)
     }
 
@@ -123,14 +180,83 @@ impl Application {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct WindowData {
     pub title: String,
     pub area: Rect,
+    #[serde(with = "human_readable_hint::option")]
     pub hint: Option<Hint>,
     pub visible: bool,
     pub fullscreen: bool,
     pub z_index: i32,
+    /// Whether the window has the compositor focus. At most one window is focused.
+    pub focused: bool,
+    /// Margins around the window content, which don't use the window hint.
+    pub content_inset: Inset,
+    /// Mask the other windows as if on top of them, without changing the reported z-index. Kept
+    /// across z-index updates, unlike raising the window.
+    pub always_on_top: bool,
+}
+
+impl WindowData {
+    /// Names of the fields which differ in `other`.
+    pub fn changed_fields(&self, other: &Self) -> Vec<&'static str> {
+        [
+            ("title", self.title != other.title),
+            ("area", self.area != other.area),
+            ("hint", self.hint != other.hint),
+            ("visible", self.visible != other.visible),
+            ("fullscreen", self.fullscreen != other.fullscreen),
+            ("z_index", self.z_index != other.z_index),
+            ("focused", self.focused != other.focused),
+            ("content_inset", self.content_inset != other.content_inset),
+            ("always_on_top", self.always_on_top != other.always_on_top),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+
+    /// Whether `other` only moves the area edges by less than `threshold` pixels, e.g. a popup
+    /// following the cursor. Nothing is jitter with a zero threshold.
+    pub fn is_jitter(&self, other: &Self, threshold: u32) -> bool {
+        let (a, b) = (&self.area, &other.area);
+        let moved = [a.x1 - b.x1, a.y1 - b.y1, a.x2 - b.x2, a.y2 - b.y2]
+            .into_iter()
+            .map(i32::unsigned_abs)
+            .max()
+            .unwrap_or_default();
+
+        moved < threshold && self.changed_fields(other).iter().all(|&f| f == "area")
+    }
+}
+
+/// Margins of a window around its content, e.g. an application padding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Inset {
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+    pub left: i32,
+}
+
+impl Inset {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Content rectangle of `area`. Margins larger than the area leave an empty content.
+    pub fn content(&self, area: &Rect) -> Rect {
+        let x1 = area.x1.saturating_add(self.left).min(area.x2);
+        let y1 = area.y1.saturating_add(self.top).min(area.y2);
+
+        Rect::new(
+            x1,
+            y1,
+            area.x2.saturating_sub(self.right).max(x1),
+            area.y2.saturating_sub(self.bottom).max(y1),
+        )
+    }
 }
 
 /// Represent an on-screen window
@@ -141,9 +267,22 @@ pub struct WindowData {
 pub struct Window {
     uid: String,
     app_key: String,
+    /// Identifier of the window in the compositor, if any.
+    external_id: Option<String>,
+    /// Number of updates received, see [WindowStats].
+    updates: u64,
     pub data: WindowData, //sub_surface: Vec<Surface>
 }
 
+/// Cost of a window, to find those updating too often or covering a lot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WindowStats {
+    /// Number of updates received, each one triggering a hints recompute.
+    pub updates: u64,
+    /// Exact area of the window left visible by the windows above, in pixels.
+    pub visible_area: i64,
+}
+
 impl Window {
     pub fn new(
         app_key: impl Into<String>,
@@ -154,45 +293,103 @@ impl Window {
         fullscreen: bool,
         z_index: i32,
     ) -> Self {
-        let uid = uuid::Uuid::new_v4().to_string();
-        let app_key = app_key.into();
-        let title = title.into();
+        let data = WindowData {
+            title: title.into(),
+            area,
+            hint,
+            visible,
+            fullscreen,
+            z_index,
+            focused: false,
+            content_inset: Inset::default(),
+            always_on_top: false,
+        };
+
+        Self::from_data(app_key, data)
+    }
 
+    /// Create a window of the Application `app_key` from all its attributes.
+    pub fn from_data(app_key: impl Into<String>, data: WindowData) -> Self {
         Self {
-            uid,
-            app_key,
-            data: WindowData {
-                title,
-                area,
-                hint,
-                visible,
-                fullscreen,
-                z_index,
-            },
+            uid: uuid::Uuid::new_v4().to_string(),
+            app_key: app_key.into(),
+            external_id: None,
+            updates: 0,
+            data,
+        }
+    }
+
+    /// Set whether the window has the compositor focus.
+    pub fn with_focus(mut self, focused: bool) -> Self {
+        self.data.focused = focused;
+        self
+    }
+
+    /// Set the compositor identifier of the window.
+    ///
+    /// Adding a window whose external id is already known for the application returns the existing
+    /// window key instead of creating a duplicate, which makes retrying an add safe.
+    pub fn with_external_id(self, external_id: impl Into<String>) -> Self {
+        Self {
+            external_id: Some(external_id.into()),
+            ..self
         }
     }
 
+    pub fn external_id(&self) -> Option<&str> {
+        self.external_id.as_deref()
+    }
+
+    /// Window unique key.
+    pub fn key(&self) -> &str {
+        &self.uid
+    }
+
+    /// Key of the Application owning the window.
+    pub fn app_key(&self) -> &str {
+        &self.app_key
+    }
+
     pub fn is_fullscreen(&self) -> bool {
         self.data.fullscreen
     }
 
+    pub fn is_focused(&self) -> bool {
+        self.data.focused
+    }
+
+    /// Area of the window content, inside its margins.
+    pub fn content_area(&self) -> Rect {
+        self.data.content_inset.content(&self.data.area)
+    }
+
+    /// z-index used to mask the other windows, see [WindowData::always_on_top].
+    pub fn masking_z_index(&self) -> i32 {
+        if self.data.always_on_top {
+            PixelManager::ALWAYS_ON_TOP_Z_INDEX
+        } else {
+            self.data.z_index
+        }
+    }
+
     pub fn zsurface(&self, screen_area: &Rect) -> Option<ZSurface> {
         if self.data.visible {
             self.data
                 .area
                 .intersection(screen_area)
-                .map(|rect| ZSurface::new(self.data.z_index, self.uid.clone(), rect))
+                .map(|rect| ZSurface::new(self.masking_z_index(), self.uid.clone(), rect))
         } else {
             None
         }
     }
 
     pub fn update(&mut self, data: WindowData) {
-        self.data = data
+        self.data = data;
+        self.updates += 1;
     }
 }
 
-#[derive(Debug, PartialEq, Default)]
+#[derive(Clone, Debug, PartialEq, Default)]
 pub struct ComputedHints {
     pub default_hint: Option<Hint>,
     pub rect_hints: Vec<RectHint>,
@@ -212,6 +409,50 @@ impl ComputedHints {
             ..Default::default()
         }
     }
+
+    /// Replace the bit depth of every hint, keeping their convert mode and redraw.
+    pub fn force_bit_depth(&mut self, bit_depth: HintBitDepth) {
+        let hints = self.rect_hints.iter_mut().map(|rh| &mut rh.hint);
+
+        for hint in self.default_hint.iter_mut().chain(hints) {
+            *hint = hint.with_bit_depth(bit_depth);
+        }
+    }
+
+    /// Gray level used to represent a hint in [ComputedHints::visualize].
+    pub fn gray_level(hint: Hint) -> u8 {
+        match hint.bit_depth() {
+            HintBitDepth::Y1 => 0x30,
+            HintBitDepth::Y2 => 0x80,
+            HintBitDepth::Y4 => 0xd0,
+        }
+    }
+
+    /// Render the hints as a `width` x `height` 8-bit grayscale buffer, for debugging.
+    ///
+    /// Every rectangle is filled with the [gray level](ComputedHints::gray_level) of its hint bit
+    /// depth, later rectangles being drawn above earlier ones. Uncovered pixels use the default
+    /// hint if any, or white otherwise.
+    pub fn visualize(&self, width: u32, height: u32) -> Vec<u8> {
+        let (width, height) = (width as i32, height as i32);
+        let background = self.default_hint.map(Self::gray_level).unwrap_or(0xff);
+        let mut buf = vec![background; width as usize * height as usize];
+
+        let screen = Rect::new(0, 0, width, height);
+        for RectHint { rect, hint } in &self.rect_hints {
+            let Some(Rect { x1, y1, x2, y2 }) = rect.intersection(&screen) else {
+                continue;
+            };
+
+            let level = Self::gray_level(*hint);
+            for y in y1..y2 {
+                let row = (y * width) as usize;
+                buf[row + x1 as usize..row + x2 as usize].fill(level);
+            }
+        }
+
+        buf
+    }
 }
 
 /// Manage per pixel hints
@@ -219,11 +460,39 @@ impl ComputedHints {
 pub struct PixelManager {
     /// Default Hints to use for uncovered pixels
     pub default_hint: Hint,
+    /// Emit the exact visible rectangles of each window instead of their bounding box
+    pub precise: bool,
+    /// Reduce the rectangles using the windows layering. When unset, every visible window emits
+    /// its whole area, occluded or not: a debugging escape hatch.
+    pub minimize: bool,
+    /// Bit depth replacing the one of every computed hint, default hint included, when set.
+    pub forced_bit_depth: Option<HintBitDepth>,
+    /// Highest bit depth of the default hint when set. A runtime cap: `default_hint` is left as
+    /// is, and is what gets reported and saved.
+    pub default_bit_depth_cap: Option<HintBitDepth>,
+    /// Application ids whose windows use the default hint, see [PixelManager::window_add].
+    pub ignored_apps: HashSet<String>,
     /// Rectangle representing the full screen.
     screen_area: Rect,
+    /// Outputs other than the [panel](PixelManager::PANEL_OUTPUT), which uses `default_hint` and
+    /// `screen_area`.
+    outputs: HashMap<String, Output>,
 
     applications: HashMap<String, Application>,
     windows: HashMap<String, Window>,
+    /// Window keys, indexed by application key and external id.
+    external_ids: HashMap<(String, String), String>,
+    /// Key of the [background](PixelManager::background_set_hint) region, if any.
+    background: Option<String>,
+}
+
+/// Display with its own default hint, see [PixelManager::output_add].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    /// Rectangle representing the full output.
+    pub area: Rect,
+    /// Hint of the pixels no window covers.
+    pub default_hint: Hint,
 }
 
 #[derive(Error, Debug, PartialEq)]
@@ -232,16 +501,94 @@ pub enum PixelManagerError {
     UnknownApp(String),
     #[error("No window with uid '{0}' found")]
     UnknownWindow(String),
+    #[error("No output named '{0}' found")]
+    UnknownOutput(String),
 }
 
 impl PixelManager {
+    /// Application id owning the [regions](PixelManager::region_add).
+    pub const REGIONS_APP_ID: &str = "pinenote-regions";
+    /// Clients can't register pid 0, so the regions application key never clashes with theirs.
+    const REGIONS_PID: pid_t = 0;
+    /// z-index of the background region, reserved: nothing else should be placed that low.
+    pub const BACKGROUND_Z_INDEX: i32 = i32::MIN;
+    /// z-index of the [always on top](WindowData::always_on_top) windows. `i32::MAX` is left to the
+    /// regions meant to cover everything.
+    pub const ALWAYS_ON_TOP_Z_INDEX: i32 = i32::MAX - 1;
+    /// Name of the e-ink panel output, as the compositors report it.
+    pub const PANEL_OUTPUT: &str = "DPI-1";
+
     pub fn new(default_hint: Hint, screen_area: Rect) -> Self {
         Self {
             default_hint,
+            precise: false,
+            minimize: true,
+            forced_bit_depth: None,
+            default_bit_depth_cap: None,
+            ignored_apps: Default::default(),
             screen_area,
+            outputs: Default::default(),
             applications: Default::default(),
             windows: Default::default(),
+            external_ids: Default::default(),
+            background: None,
+        }
+    }
+
+    /// Rectangle representing the full screen.
+    pub fn screen_area(&self) -> &Rect {
+        &self.screen_area
+    }
+
+    /// Register another output, replacing the one with the same name if any. The panel output
+    /// can't be replaced, it is always registered.
+    ///
+    /// Windows and hints are only computed for the panel so far: other outputs only keep their
+    /// settings, for when several e-ink outputs are supported.
+    pub fn output_add(&mut self, name: impl Into<String>, output: Output) -> bool {
+        let name = name.into();
+        if name == Self::PANEL_OUTPUT {
+            return false;
+        }
+
+        self.outputs.insert(name, output);
+        true
+    }
+
+    /// Settings of the output `name`, the panel included.
+    pub fn output(&self, name: &str) -> Result<Output, PixelManagerError> {
+        if name == Self::PANEL_OUTPUT {
+            return Ok(Output {
+                area: self.screen_area.clone(),
+                default_hint: self.default_hint,
+            });
         }
+
+        self.outputs
+            .get(name)
+            .cloned()
+            .ok_or(PixelManagerError::UnknownOutput(name.to_owned()))
+    }
+
+    /// Set the hint of the pixels no window covers on the output `name`. For the panel, this is
+    /// [PixelManager::default_hint].
+    pub fn set_output_default_hint(
+        &mut self,
+        name: &str,
+        hint: Hint,
+    ) -> Result<(), PixelManagerError> {
+        let default_hint = if name == Self::PANEL_OUTPUT {
+            &mut self.default_hint
+        } else {
+            &mut self
+                .outputs
+                .get_mut(name)
+                .ok_or(PixelManagerError::UnknownOutput(name.to_owned()))?
+                .default_hint
+        };
+
+        *default_hint = hint;
+        Ok(())
     }
 
     pub fn app(&self, app_key: &String) -> Result<&Application, PixelManagerError> {
@@ -257,6 +604,9 @@ impl PixelManager {
     }
 
     /// Add a new Application to the controller
+    ///
+    /// If an Application with the same key is already registered, it is kept and its key
+    /// returned.
     pub fn app_add(&mut self, app: Application) -> String {
         let key = app.key();
 
@@ -267,15 +617,54 @@ impl PixelManager {
         key
     }
 
+    /// List every registered Application.
+    pub fn list_apps(&self) -> impl Iterator<Item = &Application> {
+        self.applications.values()
+    }
+
+    /// Keys of the Applications whose process is gone, according to `is_alive`.
+    ///
+    /// Applications without a process (pid <= 0), like the regions one or the ones of bridges
+    /// which can't tell the pid, are never reported.
+    pub fn dead_apps(&self, is_alive: impl Fn(pid_t) -> bool) -> Vec<String> {
+        let mut dead: Vec<_> = self
+            .applications
+            .iter()
+            .filter(|(_, app)| app.pid > 0 && !is_alive(app.pid))
+            .map(|(key, _)| key.clone())
+            .collect();
+        dead.sort();
+
+        dead
+    }
+
+    /// Remove every Application without any Window, returning how many were removed.
+    ///
+    /// Bridges register the Application before its first window, and a failed window addition
+    /// leaves it behind. They forget such an Application, adding it again along with the window
+    /// on their next sync, so pruning it under them is fine.
+    pub fn prune_empty_apps(&mut self) -> usize {
+        let before = self.applications.len();
+        self.applications.retain(|_, app| !app.windows.is_empty());
+
+        before - self.applications.len()
+    }
+
     /// Remove an Application and its associated Window.
-    pub fn app_remove(&mut self, app_key: &String) {
+    ///
+    /// Returns the keys of the removed windows.
+    pub fn app_remove(&mut self, app_key: &String) -> Vec<String> {
         let Some(app) = self.applications.remove(app_key) else {
-            return;
+            return Vec::new();
         };
 
-        for win_key in app.windows {
-            self.windows.remove(&win_key);
+        for win_key in &app.windows {
+            if let Some(win) = self.windows.remove(win_key) {
+                self.external_id_remove(&win);
+            }
         }
+
+        app.windows.into_iter().collect()
     }
 
     /// Access default hint for a specif app
@@ -301,6 +690,47 @@ impl PixelManager {
         Ok(())
     }
 
+    /// Hint the visible windows of an Application as a single region.
+    ///
+    /// The bounding box of the windows is hinted with the Application default hint, at the
+    /// highest z-index among them. Hints of the windows themselves are ignored. Useful for
+    /// applications made of many small windows, which would otherwise take a rectangle each.
+    pub fn app_set_merge_windows(
+        &mut self,
+        app_key: &String,
+        merge: bool,
+    ) -> Result<(), PixelManagerError> {
+        self.app_mut(app_key)?.merge_windows = merge;
+
+        Ok(())
+    }
+
+    /// Set every window of an Application [always on top](WindowData::always_on_top).
+    ///
+    /// Unlike the window flag, it also applies to the windows added later, e.g. for an application
+    /// whose only purpose is to float above the others.
+    pub fn app_set_always_on_top(
+        &mut self,
+        app_key: &String,
+        always_on_top: bool,
+    ) -> Result<(), PixelManagerError> {
+        self.app_mut(app_key)?.always_on_top = always_on_top;
+
+        Ok(())
+    }
+
+    /// z-index `win` masks the other windows at, whether it or its application is
+    /// [always on top](Self::app_set_always_on_top).
+    fn masking_z_index(&self, win: &Window) -> i32 {
+        let app = self.applications.get(&win.app_key);
+
+        if app.is_some_and(Application::always_on_top) {
+            Self::ALWAYS_ON_TOP_Z_INDEX
+        } else {
+            win.masking_z_index()
+        }
+    }
+
     pub fn window(&self, win_key: &String) -> Result<&Window, PixelManagerError> {
         self.windows
             .get(win_key)
@@ -314,6 +744,10 @@ impl PixelManager {
     }
 
     /// Add a new window, and link it to an application.
+    ///
+    /// If the application already has a window with the same external id, its key is returned and
+    /// nothing is added. Windows of the [ignored applications](PixelManager::ignored_apps) are
+    /// added as usual, but the area they cover uses the default hint whatever their own.
     pub fn window_add(&mut self, window: Window) -> Result<String, PixelManagerError> {
         let app_key = window.app_key.clone();
         let uid = window.uid.clone();
@@ -322,7 +756,21 @@ impl PixelManager {
             Err(PixelManagerError::UnknownApp(app_key.clone()))?;
         };
 
+        if let Some(existing) = window
+            .external_id()
+            .and_then(|id| self.window_by_external_id(&app_key, id))
+        {
+            return Ok(existing.uid.clone());
+        }
+
         if !self.windows.contains_key(&window.uid) {
+            if let Some(external_id) = &window.external_id {
+                let index = (app_key.clone(), external_id.clone());
+                self.external_ids.insert(index, uid.clone());
+            }
+            if window.is_focused() {
+                self.unfocus_all();
+            }
             self.windows.insert(uid.clone(), window);
 
             self.applications
@@ -333,16 +781,205 @@ impl PixelManager {
         Ok(uid)
     }
 
+    /// Find a window of an application using the identifier given by the compositor.
+    pub fn window_by_external_id(&self, app_key: &str, external_id: &str) -> Option<&Window> {
+        self.external_ids
+            .get(&(app_key.to_owned(), external_id.to_owned()))
+            .and_then(|k| self.windows.get(k))
+    }
+
+    /// Remove a window from the external id index, if it's the one indexed.
+    fn external_id_remove(&mut self, win: &Window) {
+        let Some(external_id) = &win.external_id else {
+            return;
+        };
+
+        let index = (win.app_key.clone(), external_id.clone());
+        if self.external_ids.get(&index) == Some(&win.uid) {
+            self.external_ids.remove(&index);
+        }
+    }
+
+    /// Number of application windows, regions aside.
+    pub fn window_count(&self) -> usize {
+        let regions_app_key = Self::regions_app_key();
+
+        self.windows
+            .values()
+            .filter(|w| w.app_key != regions_app_key)
+            .count()
+    }
+
     /// Remove a window using its key.
-    pub fn window_remove(&mut self, win_uid: String) {
+    ///
+    /// Returns whether the window existed.
+    pub fn window_remove(&mut self, win_uid: String) -> bool {
         let Some(win) = self.windows.remove(&win_uid) else {
-            return;
+            return false;
         };
+        self.external_id_remove(&win);
         let app_key = win.app_key;
 
         self.applications
             .entry(app_key)
             .and_modify(|a| a.window_remove(&win_uid));
+
+        true
+    }
+
+    /// Key of the Application owning the regions.
+    pub fn regions_app_key() -> String {
+        Application::new(Self::REGIONS_APP_ID, Self::REGIONS_PID).key()
+    }
+
+    /// Add a visible hinted region, without any application window behind it.
+    ///
+    /// Regions are windows owned by a reserved application, the returned key is a window key.
+    pub fn region_add(&mut self, area: Rect, hint: Hint, z_index: i32) -> String {
+        let app_key = self.app_add(Application::new(Self::REGIONS_APP_ID, Self::REGIONS_PID));
+        let window = Window::new(app_key, "", area, Some(hint), true, false, z_index);
+
+        self.window_add(window)
+            .expect("Regions application was just added")
+    }
+
+    /// Remove a region using its key.
+    ///
+    /// Returns whether the region existed. Windows that aren't regions are left alone.
+    pub fn region_remove(&mut self, region_key: &String) -> bool {
+        let is_region = self
+            .windows
+            .get(region_key)
+            .is_some_and(|w| w.app_key == Self::regions_app_key());
+
+        is_region && self.window_remove(region_key.clone())
+    }
+
+    /// Key of the background region, if any.
+    pub fn background_key(&self) -> Option<&String> {
+        self.background
+            .as_ref()
+            .filter(|key| self.windows.contains_key(*key))
+    }
+
+    /// Hint of the background region, if any.
+    pub fn background_hint(&self) -> Option<Hint> {
+        self.background_key()
+            .and_then(|key| self.windows.get(key))
+            .and_then(|win| win.data.hint)
+    }
+
+    /// Hint the pixels not covered by any window with `hint`, instead of the default hint.
+    ///
+    /// The background is a full screen [region](PixelManager::region_add) at
+    /// [PixelManager::BACKGROUND_Z_INDEX], created on first use. Returns its key.
+    pub fn background_set_hint(&mut self, hint: Hint) -> String {
+        if let Some(key) = self.background_key().cloned() {
+            self.window_set_hint(&key, hint)
+                .expect("Background region was just checked");
+            return key;
+        }
+
+        let area = self.screen_area.clone();
+        let key = self.region_add(area, hint, Self::BACKGROUND_Z_INDEX);
+        self.background = Some(key.clone());
+
+        key
+    }
+
+    /// Remove the background region, uncovered pixels use the default hint again.
+    ///
+    /// Returns the key of the removed region, if there was one.
+    pub fn background_remove(&mut self) -> Option<String> {
+        let key = self.background.take()?;
+
+        self.region_remove(&key).then_some(key)
+    }
+
+    /// Snapshot of the applications, windows and hints, to reproduce them with
+    /// [PixelManager::import_layout].
+    pub fn export_layout(&self) -> LayoutSnapshot {
+        let background = self.background_key();
+
+        let mut apps: Vec<_> = self
+            .applications
+            .values()
+            .map(|app| {
+                let mut windows: Vec<_> = app
+                    .windows
+                    .iter()
+                    .filter(|key| Some(*key) != background)
+                    .filter_map(|key| self.windows.get(key))
+                    .collect();
+                windows.sort_by(|a, b| (a.data.z_index, &a.uid).cmp(&(b.data.z_index, &b.uid)));
+
+                AppSnapshot {
+                    app_id: app.app_id.clone(),
+                    pid: app.pid,
+                    default_hint: app.default_hint,
+                    merge_windows: app.merge_windows,
+                    always_on_top: app.always_on_top,
+                    windows: windows
+                        .into_iter()
+                        .map(|win| WindowSnapshot {
+                            external_id: win.external_id.clone(),
+                            data: win.data.clone(),
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        apps.sort_by_key(|app| (app.app_id.clone(), app.pid));
+
+        LayoutSnapshot {
+            default_hint: self.default_hint,
+            background: self.background_hint(),
+            apps,
+        }
+    }
+
+    /// Replace the applications and windows with those of `snapshot`, and apply its hints.
+    ///
+    /// Windows get new keys. Returns the keys of the windows removed, then those of the windows
+    /// added along with their application key.
+    pub fn import_layout(
+        &mut self,
+        snapshot: LayoutSnapshot,
+    ) -> (Vec<String>, Vec<(String, String)>) {
+        let removed: Vec<String> = self.windows.keys().cloned().collect();
+        self.applications.clear();
+        self.windows.clear();
+        self.external_ids.clear();
+        self.background = None;
+
+        self.default_hint = snapshot.default_hint;
+        if let Some(hint) = snapshot.background {
+            self.background_set_hint(hint);
+        }
+
+        let mut added = Vec::new();
+        for app in snapshot.apps {
+            let mut application = Application::with_hint(app.app_id, app.pid, app.default_hint);
+            application.merge_windows = app.merge_windows;
+            application.always_on_top = app.always_on_top;
+            let app_key = self.app_add(application);
+
+            for win in app.windows {
+                let window = Window {
+                    external_id: win.external_id,
+                    ..Window::from_data(&app_key, win.data)
+                };
+
+                match self.window_add(window) {
+                    Ok(key) if self.windows.contains_key(&key) => {
+                        added.push((key, app_key.clone()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        (removed, added)
     }
 
     pub fn window_update(
@@ -350,13 +987,38 @@ impl PixelManager {
         win_key: &String,
         data: WindowData,
     ) -> Result<(), PixelManagerError> {
-        let window = self.window_mut(win_key)?;
+        self.window(win_key)?;
 
-        window.update(data);
+        if data.focused {
+            self.unfocus_all();
+        }
+        self.window_mut(win_key)?.update(data);
 
         Ok(())
     }
 
+    /// The window having the compositor focus, if any.
+    pub fn focused_window(&self) -> Option<&Window> {
+        self.windows.values().find(|w| w.is_focused())
+    }
+
+    /// Set or unset the hint of the focused window.
+    ///
+    /// Returns the key of the focused window, or `None` if no window is focused.
+    pub fn focused_set_hint(&mut self, hint: Option<Hint>) -> Option<String> {
+        let win = self.windows.values_mut().find(|w| w.is_focused())?;
+        win.data.hint = hint;
+
+        Some(win.uid.clone())
+    }
+
+    /// Only one window has the focus, the previous one loses it when another one gains it.
+    fn unfocus_all(&mut self) {
+        self.windows
+            .values_mut()
+            .for_each(|w| w.data.focused = false);
+    }
+
     /// Set a window specific hint.
     pub fn window_set_hint(
         &mut self,
@@ -379,107 +1041,410 @@ impl PixelManager {
         Ok(())
     }
 
-    pub fn window_hint(&self, win_key: &String) -> Result<Option<Hint>, PixelManagerError> {
-        self.window(win_key).map(|w| w.data.hint)
+    /// Visible area of a window, clipped to the screen, whatever covers it. None if the window is
+    /// hidden or entirely off screen.
+    pub fn window_visible_area(&self, win_key: &String) -> Result<Option<Rect>, PixelManagerError> {
+        self.window(win_key)
+            .map(|w| w.zsurface(&self.screen_area).map(|s| s.area))
     }
 
-    pub fn window_hint_fallback(&self, win_key: &String) -> Result<Hint, PixelManagerError> {
+    /// Statistics of a window, see [WindowStats].
+    pub fn window_stats(&self, win_key: &String) -> Result<WindowStats, PixelManagerError> {
         let win = self.window(win_key)?;
+        let Some(surface) = win.zsurface(&self.screen_area) else {
+            return Ok(WindowStats {
+                updates: win.updates,
+                visible_area: 0,
+            });
+        };
 
-        if let Some(hint) = win.data.hint {
-            Ok(hint)
-        } else {
-            let app = self.app(&win.app_key)?;
-            Ok(app.default_hint.unwrap_or(self.default_hint))
-        }
+        // Windows of merged applications are part of their application surface
+        let visible_area = self
+            .ztree()
+            .leaves()
+            .filter(|(_, reference, _)| *reference == win.uid || *reference == win.app_key)
+            .flat_map(|(_, _, area)| area.iter())
+            .filter_map(|r| r.intersection(&surface.area))
+            .map(|r| r.area())
+            .sum();
+
+        Ok(WindowStats {
+            updates: win.updates,
+            visible_area,
+        })
     }
 
-    /// Compute visible RectHint.
-    pub fn compute_hints(&self) -> Result<ComputedHints, PixelManagerError> {
-        let mut ret = ComputedHints::with_hint(self.default_hint);
-        let mut ztree = ZTree::new();
+    /// Topmost visible window covering the pixel at (`x`, `y`), and the hint it is rendered with,
+    /// e.g. to find out why a pixel renders as Y1. None if no window covers it, the default hint
+    /// applies there. Windows of [merged](Self::app_set_merge_windows) applications are rendered
+    /// as one, the key of their application is returned instead.
+    ///
+    /// The hint is the one uploaded: the margin hint outside of the window content, and the
+    /// [forced bit depth](Self::forced_bit_depth) applied.
+    pub fn window_at(&self, x: i32, y: i32) -> Option<(String, Hint)> {
+        if !self.screen_area.contains_point(x, y) {
+            return None;
+        }
 
-        for win in self.windows.values() {
-            if win.is_fullscreen() {
-                let mut hint = ComputedHints::new();
-                hint.rect_hints = vec![RectHint {
-                    rect: self.screen_area.clone(),
-                    hint: self.window_hint_fallback(&win.uid)?,
-                }];
-                return Ok(hint);
-            }
+        // Like compute_hints, a fullscreen window covers everything else
+        let key = match self.windows.values().find(|w| w.is_fullscreen()) {
+            Some(win) => win.uid.clone(),
+            None => self
+                .ztree()
+                .leaves()
+                .find(|(_, _, area)| area.iter().any(|r| r.contains_point(x, y)))
+                .map(|(_, reference, _)| reference.to_string())?,
+        };
 
-            if let Some(s) = win.zsurface(&self.screen_area) {
-                ztree.insert(s);
-            }
+        if self.applications.contains_key(&key) {
+            let hint = self.app_hint_fallback(&key).ok()?;
+            return Some((key, self.forced(hint)));
         }
 
-        let ztree = self
-            .windows
-            .values()
-            .filter_map(|w| w.zsurface(&self.screen_area))
-            .fold(ZTree::new(), |mut tree, s| {
-                tree.insert(s);
-                tree
-            });
+        let win = self.window(&key).ok()?;
+        let in_margin = !win.is_fullscreen()
+            && !win.data.content_inset.is_empty()
+            && !win.content_area().contains_point(x, y);
+        let hint = if in_margin {
+            self.app_hint_fallback(&win.app_key).ok()?
+        } else {
+            self.window_hint_fallback(&key).ok()?
+        };
 
-        ret.rect_hints = ztree
-            .flatten()
-            .into_iter()
-            .map(
-                |ZSurface {
-                     area: rect,
-                     reference,
-                     ..
-                 }| {
-                    self.window_hint_fallback(&reference)
-                        .map(|hint| RectHint { rect, hint })
-                },
-            )
-            .collect::<Result<_, _>>()?;
+        Some((key, self.forced(hint)))
+    }
 
-        Ok(ret)
+    /// The default hint in effect, lowered to the [cap](Self::default_bit_depth_cap) if any.
+    fn capped_default_hint(&self) -> Hint {
+        match self.default_bit_depth_cap {
+            Some(cap) if self.default_hint.bit_depth() as u8 > cap as u8 => {
+                self.default_hint.with_bit_depth(cap)
+            }
+            _ => self.default_hint,
+        }
     }
-}
 
-#[cfg(test)]
-#[allow(dead_code)]
-mod tests {
-    use super::*;
-    use crate::types::{
-        Rect,
-        rockchip_ebc::{Hint, HintBitDepth as BitDepth, HintConvertMode},
-    };
+    /// `hint` as uploaded, with the [forced bit depth](Self::forced_bit_depth) if any.
+    fn forced(&self, hint: Hint) -> Hint {
+        match self.forced_bit_depth {
+            Some(bit_depth) => hint.with_bit_depth(bit_depth),
+            None => hint,
+        }
+    }
 
-    const Y4DITHER_REDRAW: Hint = Hint::new(BitDepth::Y4, HintConvertMode::Dither, true);
-    const Y4DITHER: Hint = Hint::new(BitDepth::Y4, HintConvertMode::Dither, false);
-    const Y2DITHER_REDRAW: Hint = Hint::new(BitDepth::Y4, HintConvertMode::Dither, true);
-    const Y2DITHER: Hint = Hint::new(BitDepth::Y4, HintConvertMode::Dither, false);
+    pub fn window_hint(&self, win_key: &String) -> Result<Option<Hint>, PixelManagerError> {
+        self.window(win_key).map(|w| w.data.hint)
+    }
 
-    const SCREEN_RECT: Rect = Rect::new(0, 0, 1872, 1404);
+    pub fn window_hint_fallback(&self, win_key: &String) -> Result<Hint, PixelManagerError> {
+        let win = self.window(win_key)?;
 
-    fn setup_manager() -> PixelManager {
-        PixelManager::new(Y4DITHER_REDRAW, SCREEN_RECT.clone())
+        match win.data.hint {
+            Some(hint) if !self.is_ignored(&win.app_key)? => Ok(hint),
+            _ => self.app_hint_fallback(&win.app_key),
+        }
     }
 
-    #[test]
-    fn empty_sets_default() {
-        let mut mgr = setup_manager();
+    /// Hint for the windows of an application without their own hint.
+    fn app_hint_fallback(&self, app_key: &String) -> Result<Hint, PixelManagerError> {
+        let app = self.app(app_key)?;
 
-        let expected = ComputedHints::with_hint(Y4DITHER_REDRAW);
+        if self.is_ignored(app_key)? {
+            return Ok(self.capped_default_hint());
+        }
 
-        assert_eq!(expected, mgr.compute_hints().unwrap());
+        Ok(app
+            .default_hint
+            .unwrap_or_else(|| self.capped_default_hint()))
+    }
 
-        let expected = ComputedHints::with_hint(Y2DITHER_REDRAW);
+    /// Whether the application is one of the [ignored ones](PixelManager::ignored_apps).
+    fn is_ignored(&self, app_key: &String) -> Result<bool, PixelManagerError> {
+        Ok(self.ignored_apps.contains(self.app(app_key)?.app_id()))
+    }
 
-        mgr.default_hint = Y2DITHER_REDRAW;
+    /// Hints of a visible window surface, split between the window content and its margins.
+    fn surface_hints(&self, surface: ZSurface) -> Result<Vec<RectHint>, PixelManagerError> {
+        let ZSurface {
+            area: rect,
+            reference,
+            ..
+        } = surface;
+
+        if self.applications.contains_key(&reference) {
+            let hint = self.app_hint_fallback(&reference)?;
+            return Ok(vec![RectHint { rect, hint }]);
+        }
 
-        assert_eq!(expected, mgr.compute_hints().unwrap());
-    }
+        let win = self.window(&reference)?;
+        let hint = self.window_hint_fallback(&reference)?;
+        let margin_hint = self.app_hint_fallback(&win.app_key)?;
 
-    #[test]
-    fn clip_window_to_screen() -> Result<(), PixelManagerError> {
-        let mut mgr = setup_manager();
+        if win.data.content_inset.is_empty() || hint == margin_hint {
+            return Ok(vec![RectHint { rect, hint }]);
+        }
+
+        let content = win.content_area();
+        let margins = SplitRect::from(rect.clone()).mask_with(&content);
+
+        Ok(rect
+            .intersection(&content)
+            .map(|rect| RectHint { rect, hint })
+            .into_iter()
+            .chain(margins.into_iter().map(|rect| RectHint {
+                rect,
+                hint: margin_hint,
+            }))
+            .collect())
+    }
+
+    /// Build the ZTree of the visible windows, referenced by their keys. The windows of
+    /// [merged](Self::app_set_merge_windows) applications are a single surface, referenced by the
+    /// application key.
+    ///
+    /// Fullscreen windows aren't special cased here, see [PixelManager::compute_hints].
+    pub fn ztree(&self) -> ZTree {
+        self.hint_surfaces()
+            .into_iter()
+            .fold(ZTree::new(), |mut tree, s| {
+                tree.insert(s);
+                tree
+            })
+    }
+
+    /// Render the visible windows as an SVG image of the screen, for debugging.
+    ///
+    /// Windows are drawn from the lowest z-index up as translucent rectangles, colored by the bit
+    /// depth of their hint and labelled with their title, z-index and hint. The screen itself is
+    /// filled with the default hint color.
+    pub fn layout_svg(&self) -> String {
+        let escape = |s: &str| {
+            s.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+        };
+        let color = |hint: Hint| match hint.bit_depth() {
+            HintBitDepth::Y1 => "#e06c75",
+            HintBitDepth::Y2 => "#98c379",
+            HintBitDepth::Y4 => "#61afef",
+        };
+        let rect = |svg: &mut String, area: &Rect, fill: &str| {
+            let Rect { x1, y1, x2, y2 } = area.clone().normalized();
+            let _ = write!(
+                svg,
+                "  <rect x=\"{x1}\" y=\"{y1}\" width=\"{}\" height=\"{}\" fill=\"{fill}\"",
+                x2 - x1,
+                y2 - y1
+            );
+        };
+
+        let Rect { x1, y1, x2, y2 } = &self.screen_area;
+        let (width, height) = (x2 - x1, y2 - y1);
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+             viewBox=\"{x1} {y1} {width} {height}\">\n"
+        );
+
+        let background = color(self.forced(self.capped_default_hint()));
+        rect(&mut svg, &self.screen_area, background);
+        svg.push_str("/>\n");
+
+        let mut windows: Vec<_> = self.windows.values().filter(|w| w.data.visible).collect();
+        windows.sort_by_key(|w| (self.masking_z_index(w), &w.uid));
+
+        for win in windows {
+            let Ok(hint) = self.window_hint_fallback(&win.uid) else {
+                continue;
+            };
+            let hint = self.forced(hint);
+            let area = win.data.area.clone().normalized();
+
+            rect(&mut svg, &area, color(hint));
+            svg.push_str(" fill-opacity=\"0.6\" stroke=\"black\"/>\n");
+            let _ = writeln!(
+                svg,
+                "  <text x=\"{}\" y=\"{}\" font-size=\"24\">{} (z {}, {hint})</text>",
+                area.x1 + 4,
+                area.y1 + 28,
+                escape(&win.data.title),
+                win.data.z_index
+            );
+        }
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// Surfaces to hint: those of the visible windows clipped to the screen, the windows of
+    /// [merged](PixelManager::app_set_merge_windows) applications being replaced by a single
+    /// surface referencing the application.
+    fn hint_surfaces(&self) -> Vec<ZSurface> {
+        let mut surfaces = Vec::new();
+        let mut merged: HashMap<&String, ZSurface> = HashMap::new();
+
+        for win in self.windows.values() {
+            let Some(mut surface) = win.zsurface(&self.screen_area) else {
+                continue;
+            };
+            surface.z_index = self.masking_z_index(win);
+            let app = self.applications.get(&win.app_key);
+
+            if !app.is_some_and(Application::merge_windows) {
+                surfaces.push(surface);
+                continue;
+            }
+
+            merged
+                .entry(&win.app_key)
+                .and_modify(|m| {
+                    let (a, b) = (&m.area, &surface.area);
+                    m.area = Rect::new(
+                        a.x1.min(b.x1),
+                        a.y1.min(b.y1),
+                        a.x2.max(b.x2),
+                        a.y2.max(b.y2),
+                    );
+                    m.z_index = m.z_index.max(surface.z_index);
+                })
+                .or_insert_with(|| {
+                    ZSurface::new(surface.z_index, &win.app_key, surface.area.clone())
+                });
+        }
+
+        surfaces.extend(merged.into_values());
+        surfaces
+    }
+
+    /// Surfaces to hint, without any masking, by z-index.
+    fn unminimized_surfaces(&self) -> Vec<ZSurface> {
+        let mut surfaces = self.hint_surfaces();
+
+        surfaces.sort_by(|a, b| (a.z_index, &a.reference).cmp(&(b.z_index, &b.reference)));
+        surfaces
+    }
+
+    /// Compute visible RectHint.
+    pub fn compute_hints(&self) -> Result<ComputedHints, PixelManagerError> {
+        self.compute_hints_with(self.minimize, self.precise)
+    }
+
+    /// Compute visible RectHint with the coarsest reduction, one bounding box per window, whatever
+    /// [Self::precise] and [Self::minimize] are set to.
+    ///
+    /// A fallback for when [Self::compute_hints] produces more rectangles than the driver takes.
+    pub fn compute_hints_coarse(&self) -> Result<ComputedHints, PixelManagerError> {
+        self.compute_hints_with(true, false)
+    }
+
+    fn compute_hints_with(
+        &self,
+        minimize: bool,
+        precise: bool,
+    ) -> Result<ComputedHints, PixelManagerError> {
+        let mut hints = self.compute_window_hints(minimize, precise)?;
+
+        if let Some(bit_depth) = self.forced_bit_depth {
+            hints.force_bit_depth(bit_depth);
+        }
+
+        Ok(hints)
+    }
+
+    fn compute_window_hints(
+        &self,
+        minimize: bool,
+        precise: bool,
+    ) -> Result<ComputedHints, PixelManagerError> {
+        let mut ret = ComputedHints::with_hint(self.capped_default_hint());
+
+        if let Some(win) = self.windows.values().find(|w| w.is_fullscreen()) {
+            let mut hint = ComputedHints::new();
+            hint.rect_hints = vec![RectHint {
+                rect: self.screen_area.clone(),
+                hint: self.window_hint_fallback(&win.uid)?,
+            }];
+            return Ok(hint);
+        }
+
+        let surfaces = if !minimize {
+            self.unminimized_surfaces()
+        } else if precise {
+            self.ztree().flatten_precise()
+        } else {
+            self.ztree().flatten()
+        };
+
+        for surface in surfaces {
+            ret.rect_hints.extend(self.surface_hints(surface)?);
+        }
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+    use crate::types::{
+        Rect,
+        rockchip_ebc::{Hint, HintBitDepth as BitDepth, HintConvertMode},
+    };
+
+    const Y4DITHER_REDRAW: Hint = Hint::new(BitDepth::Y4, HintConvertMode::Dither, true);
+    const Y4DITHER: Hint = Hint::new(BitDepth::Y4, HintConvertMode::Dither, false);
+    const Y2DITHER_REDRAW: Hint = Hint::new(BitDepth::Y4, HintConvertMode::Dither, true);
+    const Y2DITHER: Hint = Hint::new(BitDepth::Y4, HintConvertMode::Dither, false);
+
+    const SCREEN_RECT: Rect = Rect::new(0, 0, 1872, 1404);
+
+    fn setup_manager() -> PixelManager {
+        PixelManager::new(Y4DITHER_REDRAW, SCREEN_RECT.clone())
+    }
+
+    #[test]
+    fn empty_sets_default() {
+        let mut mgr = setup_manager();
+
+        let expected = ComputedHints::with_hint(Y4DITHER_REDRAW);
+
+        assert_eq!(expected, mgr.compute_hints().unwrap());
+
+        let expected = ComputedHints::with_hint(Y2DITHER_REDRAW);
+
+        mgr.default_hint = Y2DITHER_REDRAW;
+
+        assert_eq!(expected, mgr.compute_hints().unwrap());
+    }
+
+    #[test]
+    fn app_key_uses_app_id() {
+        let mut mgr = setup_manager();
+
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+
+        assert_eq!("testapp:1234", app_key);
+
+        let apps: Vec<_> = mgr.list_apps().map(|a| (a.app_id(), a.pid())).collect();
+        assert_eq!(vec![("testapp", 1234)], apps);
+    }
+
+    #[test]
+    fn app_key_distinct_app_id() {
+        let mut mgr = setup_manager();
+
+        let anonymous = mgr.app_add(Application::new("", 1234));
+        let named = mgr.app_add(Application::new("testapp", 1234));
+
+        assert_eq!(":1234", anonymous);
+        assert_eq!("testapp:1234", named);
+        assert_eq!(2, mgr.list_apps().count());
+    }
+
+    #[test]
+    fn clip_window_to_screen() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
 
         let app = Application::new("testapp", 1234);
         let app_key = mgr.app_add(app);
@@ -838,4 +1803,1135 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn precise_partially_covered_window() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let lower = Window::new(
+            app_key,
+            "Lower",
+            Rect::new(0, 0, 400, 400),
+            Some(Y2DITHER),
+            true,
+            false,
+            0,
+        );
+        mgr.window_add(lower)?;
+
+        let app_key = mgr.app_add(Application::new("testapp", 1235));
+        let upper = Window::new(
+            app_key,
+            "Upper",
+            Rect::new(100, 100, 500, 500),
+            Some(Y4DITHER),
+            true,
+            false,
+            1,
+        );
+        mgr.window_add(upper)?;
+
+        // The lower window is L-shaped visible, its bounding box is left untouched.
+        let bounding = mgr.compute_hints()?;
+        assert_eq!(2, bounding.rect_hints.len());
+        assert_eq!(Rect::new(0, 0, 400, 400), bounding.rect_hints[0].rect);
+
+        mgr.precise = true;
+
+        let expected = vec![
+            RectHint {
+                rect: Rect::new(0, 0, 100, 400),
+                hint: Y2DITHER,
+            },
+            RectHint {
+                rect: Rect::new(100, 0, 400, 100),
+                hint: Y2DITHER,
+            },
+            RectHint {
+                rect: Rect::new(100, 100, 500, 500),
+                hint: Y4DITHER,
+            },
+        ];
+        assert_eq!(expected, mgr.compute_hints()?.rect_hints);
+
+        Ok(())
+    }
+
+    #[test]
+    fn window_visible_area() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let clipped = Window::new(
+            app_key.clone(),
+            "Clipped",
+            Rect::new(1800, 1300, 2000, 1500),
+            None,
+            true,
+            false,
+            0,
+        );
+        let clipped = mgr.window_add(clipped)?;
+        let hidden = Window::new(
+            app_key.clone(),
+            "Hidden",
+            Rect::new(0, 0, 100, 100),
+            None,
+            false,
+            false,
+            0,
+        );
+        let hidden = mgr.window_add(hidden)?;
+        let new_window = |area| Window::new(&app_key, "", area, None, true, false, 0);
+        let area = Rect::new(10, 10, 100, 100);
+        let shown = mgr.window_add(new_window(area.clone()))?;
+        let off_screen = mgr.window_add(new_window(Rect::new(1900, 0, 2000, 100)))?;
+
+        assert_eq!(Some(area), mgr.window_visible_area(&shown)?);
+        assert_eq!(
+            Some(Rect::new(1800, 1300, 1872, 1404)),
+            mgr.window_visible_area(&clipped)?
+        );
+        assert_eq!(None, mgr.window_visible_area(&off_screen)?);
+        assert_eq!(None, mgr.window_visible_area(&hidden)?);
+        assert_eq!(
+            Err(PixelManagerError::UnknownWindow("unknown".into())),
+            mgr.window_visible_area(&"unknown".into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn visualize_gray_levels() {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+        const Y2: Hint = Hint::new(BitDepth::Y2, HintConvertMode::Dither, false);
+
+        let hints = ComputedHints {
+            default_hint: Some(Y4DITHER),
+            rect_hints: vec![
+                RectHint {
+                    rect: Rect::new(0, 0, 2, 2),
+                    hint: Y1,
+                },
+                RectHint {
+                    rect: Rect::new(1, 1, 6, 3),
+                    hint: Y2,
+                },
+            ],
+        };
+
+        let expected = [
+            [0x30, 0x30, 0xd0, 0xd0],
+            [0x30, 0x80, 0x80, 0x80],
+            [0xd0, 0x80, 0x80, 0x80],
+        ]
+        .concat();
+
+        assert_eq!(expected, hints.visualize(4, 3));
+    }
+
+    #[test]
+    fn visualize_no_default_hint() {
+        let hints = ComputedHints::new();
+
+        assert_eq!(vec![0xff; 6], hints.visualize(3, 2));
+    }
+
+    #[test]
+    fn remove_reports_windows() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let mut win_keys = Vec::new();
+        for title in ["Window1", "Window2", "Window3"] {
+            let win = Window::new(
+                app_key.clone(),
+                title,
+                Rect::new(0, 0, 100, 100),
+                None,
+                true,
+                false,
+                0,
+            );
+            win_keys.push(mgr.window_add(win)?);
+        }
+
+        assert!(mgr.window_remove(win_keys.remove(0)));
+        assert!(!mgr.window_remove("unknown".into()));
+
+        let mut removed = mgr.app_remove(&app_key);
+        removed.sort();
+        win_keys.sort();
+        assert_eq!(win_keys, removed);
+        assert!(mgr.app_remove(&app_key).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn window_add_external_id() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let other_app_key = mgr.app_add(Application::new("testapp", 1235));
+        let window = |app_key: &String| {
+            Window::new(
+                app_key.clone(),
+                "TestWindow",
+                Rect::new(0, 0, 100, 100),
+                None,
+                true,
+                false,
+                0,
+            )
+            .with_external_id("42")
+        };
+
+        let first = mgr.window_add(window(&app_key))?;
+        let retry = mgr.window_add(window(&app_key))?;
+        assert_eq!(first, retry);
+        assert_eq!(1, mgr.compute_hints()?.rect_hints.len());
+
+        // External ids are scoped to the application
+        let other = mgr.window_add(window(&other_app_key))?;
+        assert_ne!(first, other);
+
+        // Windows without external id are never merged
+        let anonymous = Window::new(
+            app_key.clone(),
+            "Anonymous",
+            Rect::new(0, 0, 100, 100),
+            None,
+            true,
+            false,
+            0,
+        );
+        assert_ne!(first, mgr.window_add(anonymous)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn window_by_external_id() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let window = |external_id: &str| {
+            Window::new(
+                app_key.clone(),
+                "TestWindow",
+                Rect::new(0, 0, 100, 100),
+                None,
+                true,
+                false,
+                0,
+            )
+            .with_external_id(external_id)
+        };
+
+        let first = mgr.window_add(window("1"))?;
+        let second = mgr.window_add(window("2"))?;
+
+        assert_ne!(first, second);
+        assert_eq!(
+            Some(first.as_str()),
+            mgr.window_by_external_id(&app_key, "1").map(Window::key)
+        );
+        assert_eq!(
+            Some(second.as_str()),
+            mgr.window_by_external_id(&app_key, "2").map(Window::key)
+        );
+        assert!(mgr.window_by_external_id(&app_key, "3").is_none());
+
+        mgr.window_remove(first);
+        assert!(mgr.window_by_external_id(&app_key, "1").is_none());
+
+        mgr.app_remove(&app_key);
+        assert!(mgr.window_by_external_id(&app_key, "2").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn external_ids_per_app() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        // Bridges only make external ids unique within an application
+        let window = |app_key: &String| {
+            Window::new(app_key, "", Rect::new(0, 0, 100, 100), None, true, false, 0)
+                .with_external_id("1")
+        };
+        let (app1, app2) = (
+            mgr.app_add(Application::new("app", 1)),
+            mgr.app_add(Application::new("app", 2)),
+        );
+        let win1 = mgr.window_add(window(&app1))?;
+        let win2 = mgr.window_add(window(&app2))?;
+
+        assert_ne!(win1, win2);
+        assert_eq!(win1, mgr.window_add(window(&app1))?);
+        assert_eq!(2, mgr.window_count());
+
+        mgr.window_remove(win2);
+        assert_eq!(
+            Some(win1.as_str()),
+            mgr.window_by_external_id(&app1, "1").map(Window::key)
+        );
+        assert!(mgr.window_by_external_id(&app2, "1").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn region_add_remove() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let win = Window::new(
+            app_key,
+            "TestWindow",
+            Rect::new(0, 0, 500, 500),
+            Some(Y4DITHER_REDRAW),
+            true,
+            false,
+            0,
+        );
+        let win_key = mgr.window_add(win)?;
+
+        let region_rect = Rect::new(100, 100, 200, 200);
+        let region_key = mgr.region_add(region_rect.clone(), Y2DITHER, 1);
+
+        assert_eq!(
+            PixelManager::regions_app_key(),
+            mgr.window(&region_key)?.app_key()
+        );
+
+        let expected = ComputedHints {
+            default_hint: Some(mgr.default_hint),
+            rect_hints: vec![
+                RectHint {
+                    rect: Rect::new(0, 0, 500, 500),
+                    hint: Y4DITHER_REDRAW,
+                },
+                RectHint {
+                    rect: region_rect,
+                    hint: Y2DITHER,
+                },
+            ],
+        };
+        assert_eq!(expected, mgr.compute_hints()?);
+
+        // Only regions can be removed as such
+        assert!(!mgr.region_remove(&win_key));
+        assert!(mgr.region_remove(&region_key));
+        assert!(!mgr.region_remove(&region_key));
+
+        let expected = ComputedHints {
+            default_hint: Some(mgr.default_hint),
+            rect_hints: vec![RectHint {
+                rect: Rect::new(0, 0, 500, 500),
+                hint: Y4DITHER_REDRAW,
+            }],
+        };
+        assert_eq!(expected, mgr.compute_hints()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn background() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+
+        let mut mgr = setup_manager();
+        mgr.precise = true;
+
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let win = Window::new(
+            app_key,
+            "TestWindow",
+            Rect::new(0, 0, 500, 500),
+            Some(Y4DITHER),
+            true,
+            false,
+            PixelManager::BACKGROUND_Z_INDEX + 1,
+        );
+        mgr.window_add(win)?;
+
+        assert_eq!(None, mgr.background_hint());
+        let key = mgr.background_set_hint(Y1);
+        assert_eq!(Some(Y1), mgr.background_hint());
+
+        let hints = mgr.compute_hints()?.rect_hints;
+        let (background, window): (Vec<_>, Vec<_>) = hints.iter().partition(|h| h.hint == Y1);
+        assert_eq!(1, window.len());
+        let window_rect = &window[0].rect;
+        assert_eq!(&Rect::new(0, 0, 500, 500), window_rect);
+        // The background only covers what the window doesn't
+        assert!(
+            background
+                .iter()
+                .all(|h| h.rect.intersection(window_rect).is_none())
+        );
+        assert_eq!(
+            SCREEN_RECT.area() - window_rect.area(),
+            background.iter().map(|h| h.rect.area()).sum::<i64>()
+        );
+
+        // Setting it again updates the same region
+        assert_eq!(key, mgr.background_set_hint(Y4DITHER_REDRAW));
+        assert_eq!(Some(Y4DITHER_REDRAW), mgr.background_hint());
+
+        assert_eq!(Some(key), mgr.background_remove());
+        assert_eq!(None, mgr.background_remove());
+        assert_eq!(None, mgr.background_hint());
+
+        let expected = vec![RectHint {
+            rect: Rect::new(0, 0, 500, 500),
+            hint: Y4DITHER,
+        }];
+        assert_eq!(expected, mgr.compute_hints()?.rect_hints);
+
+        Ok(())
+    }
+
+    #[test]
+    fn window_data_changed_fields() {
+        let area = Rect::new(0, 0, 100, 100);
+        let data = Window::new("app", "title", area, None, true, false, 0).data;
+
+        assert!(data.changed_fields(&data.clone()).is_empty());
+
+        let mut update = data.clone();
+        update.area = Rect::new(0, 0, 200, 100);
+        update.hint = Some(Y2DITHER);
+        update.z_index = 3;
+        assert_eq!(
+            vec!["area", "hint", "z_index"],
+            data.changed_fields(&update)
+        );
+    }
+
+    #[test]
+    fn window_data_jitter_threshold() {
+        let area = Rect::new(10, 10, 110, 110);
+        let data = Window::new("app", "title", area, None, true, false, 0).data;
+        let moved = |dx, dy| {
+            let mut update = data.clone();
+            update.area = Rect::from_xywh(10 + dx, 10 + dy, 100, 100);
+            update
+        };
+
+        assert!(data.is_jitter(&moved(1, -2), 3));
+        assert!(!data.is_jitter(&moved(3, 0), 3));
+        assert!(!data.is_jitter(&moved(0, -3), 3));
+
+        // Resizing moves the far edges only
+        let mut resized = data.clone();
+        resized.area.x2 += 2;
+        assert!(data.is_jitter(&resized, 3));
+
+        // Anything else changing isn't jitter
+        let mut retitled = moved(1, 0);
+        retitled.title = "other".into();
+        assert!(!data.is_jitter(&retitled, 3));
+
+        // The default threshold skips nothing
+        assert!(!data.is_jitter(&data.clone(), 0));
+    }
+
+    #[test]
+    fn focus_tracking() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let area = Rect::new(0, 0, 100, 100);
+        let new_window = |focused| {
+            Window::new(&app_key, "", area.clone(), None, true, false, 0).with_focus(focused)
+        };
+
+        assert!(mgr.focused_window().is_none());
+        assert_eq!(None, mgr.focused_set_hint(Some(Y2DITHER)));
+
+        let first = mgr.window_add(new_window(true))?;
+        let second = mgr.window_add(new_window(false))?;
+        assert_eq!(first, mgr.focused_window().unwrap().key());
+
+        // Focusing another window takes the focus away from the previous one
+        let third = mgr.window_add(new_window(true))?;
+        assert_eq!(third, mgr.focused_window().unwrap().key());
+        assert!(!mgr.window(&first)?.is_focused());
+
+        let mut data = mgr.window(&second)?.data.clone();
+        data.focused = true;
+        mgr.window_update(&second, data)?;
+        assert_eq!(second, mgr.focused_window().unwrap().key());
+        assert!(!mgr.window(&third)?.is_focused());
+
+        mgr.window_remove(second.clone());
+        assert!(mgr.focused_window().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn focused_set_hint() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let area = Rect::new(0, 0, 100, 100);
+        let other = Window::new(&app_key, "", area.clone(), None, true, false, 0);
+        let other = mgr.window_add(other)?;
+        let focused = mgr.window_add(
+            Window::new(&app_key, "", area, Some(Y4DITHER), true, false, 1).with_focus(true),
+        )?;
+
+        let hint = Some(Y2DITHER_REDRAW);
+        assert_eq!(Some(focused.clone()), mgr.focused_set_hint(hint));
+        assert_eq!(hint, mgr.window_hint(&focused)?);
+        assert_eq!(None, mgr.window_hint(&other)?);
+
+        assert_eq!(Some(focused.clone()), mgr.focused_set_hint(None));
+        assert_eq!(None, mgr.window_hint(&focused)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dead_apps() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        let alive = mgr.app_add(Application::new("alive", 1234));
+        let dead = mgr.app_add(Application::new("dead", 1235));
+        mgr.region_add(Rect::new(0, 0, 100, 100), Y2DITHER, 0);
+
+        let is_alive = |pid| pid == 1234;
+        assert_eq!(vec![dead.clone()], mgr.dead_apps(is_alive));
+
+        mgr.app_remove(&dead);
+        assert!(mgr.dead_apps(is_alive).is_empty());
+        assert_eq!(vec![alive], mgr.dead_apps(|_| false));
+
+        Ok(())
+    }
+
+    #[test]
+    fn prune_empty_apps() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        let empty = mgr.app_add(Application::new("empty", 1234));
+        let full = mgr.app_add(Application::new("full", 1235));
+        let emptied = mgr.app_add(Application::new("emptied", 1236));
+        let area = Rect::new(0, 0, 100, 100);
+        mgr.window_add(Window::new(&full, "", area.clone(), None, true, false, 0))?;
+        let win = mgr.window_add(Window::new(&emptied, "", area, None, true, false, 0))?;
+        mgr.window_remove(win);
+
+        assert_eq!(2, mgr.prune_empty_apps());
+        assert!(mgr.app(&empty).is_err());
+        assert!(mgr.app(&emptied).is_err());
+        assert!(mgr.app(&full).is_ok());
+        assert_eq!(0, mgr.prune_empty_apps());
+
+        Ok(())
+    }
+
+    #[test]
+    fn inset_content() {
+        let area = Rect::new(100, 100, 500, 400);
+        let mut win = Window::new("app", "", area.clone(), None, true, false, 0);
+        assert_eq!(area, win.content_area());
+
+        win.data.content_inset = Inset {
+            top: 10,
+            right: 20,
+            bottom: 30,
+            left: 40,
+        };
+        assert_eq!(Rect::new(140, 110, 480, 370), win.content_area());
+
+        // Margins wider than the window leave nothing
+        win.data.content_inset.left = 1000;
+        assert_eq!(0, win.content_area().area());
+
+        // Even when they don't fit in an i32
+        win.data.content_inset = Inset {
+            top: i32::MAX,
+            right: i32::MAX,
+            bottom: i32::MAX,
+            left: i32::MAX,
+        };
+        assert_eq!(Rect::new(500, 400, 500, 400), win.content_area());
+    }
+
+    #[test]
+    fn inset_margins_use_app_hint() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+
+        let mut mgr = setup_manager();
+        mgr.precise = true;
+
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let area = Rect::new(0, 0, 500, 500);
+        let mut win = Window::new(app_key, "", area.clone(), Some(Y1), true, false, 0);
+        win.data.content_inset = Inset {
+            top: 50,
+            ..Default::default()
+        };
+        mgr.window_add(win)?;
+
+        let hints = mgr.compute_hints()?.rect_hints;
+
+        assert!(hints.contains(&RectHint {
+            rect: Rect::new(0, 50, 500, 500),
+            hint: Y1,
+        }));
+        assert!(hints.contains(&RectHint {
+            rect: Rect::new(0, 0, 500, 50),
+            hint: Y4DITHER_REDRAW,
+        }));
+        assert_eq!(2, hints.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unminimized_keeps_occluded() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+
+        let mut mgr = setup_manager();
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+
+        // Bottom window partially covered, middle one fully covered
+        for (area, z_index) in [
+            (Rect::new(0, 0, 500, 500), 0),
+            (Rect::new(100, 100, 200, 200), 1),
+            (Rect::new(0, 0, 500, 300), 2),
+        ] {
+            let win = Window::new(&app_key, "", area, Some(Y1), true, false, z_index);
+            mgr.window_add(win)?;
+        }
+
+        let minimized = mgr.compute_hints()?.rect_hints;
+        mgr.minimize = false;
+        let unminimized = mgr.compute_hints()?.rect_hints;
+
+        assert_eq!(2, minimized.len());
+        let areas: Vec<_> = unminimized.iter().map(|rh| rh.rect.clone()).collect();
+        assert_eq!(
+            vec![
+                Rect::new(0, 0, 500, 500),
+                Rect::new(100, 100, 200, 200),
+                Rect::new(0, 0, 500, 300),
+            ],
+            areas
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn coarse_one_rect_per_window() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+
+        // The top window punches a hole in the middle of the bottom one
+        for (area, z_index) in [
+            (Rect::new(0, 0, 300, 300), 0),
+            (Rect::new(100, 100, 200, 200), 1),
+        ] {
+            let win = Window::new(&app_key, "", area, None, true, false, z_index);
+            mgr.window_add(win)?;
+        }
+
+        mgr.precise = true;
+        mgr.minimize = true;
+        assert!(mgr.compute_hints()?.rect_hints.len() > 2);
+
+        let coarse = mgr.compute_hints_coarse()?;
+        let areas: Vec<_> = coarse.rect_hints.iter().map(|rh| rh.rect.clone()).collect();
+        assert_eq!(
+            vec![Rect::new(0, 0, 300, 300), Rect::new(100, 100, 200, 200)],
+            areas
+        );
+
+        mgr.minimize = false;
+        assert_eq!(coarse, mgr.compute_hints_coarse()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn always_on_top_masks_higher_windows() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+
+        let mut mgr = setup_manager();
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+
+        let area = Rect::new(0, 0, 100, 100);
+        let ruler = Window::new(&app_key, "", area.clone(), Some(Y1), true, false, 0);
+        let ruler = mgr.window_add(ruler)?;
+        let doc = mgr.window_add(Window::new(&app_key, "", area, None, true, false, 5))?;
+
+        let hints = |mgr: &PixelManager| -> Result<Vec<Hint>, PixelManagerError> {
+            let hints = mgr.compute_hints()?.rect_hints;
+            Ok(hints.iter().map(|rh| rh.hint).collect())
+        };
+        assert_eq!(vec![Y4DITHER_REDRAW], hints(&mgr)?);
+
+        let mut data = mgr.window(&ruler)?.data.clone();
+        data.always_on_top = true;
+        mgr.window_update(&ruler, data)?;
+        assert_eq!(vec![Y1], hints(&mgr)?);
+        assert_eq!(0, mgr.window(&ruler)?.data.z_index);
+
+        // Sticky across compositor updates
+        let mut data = mgr.window(&doc)?.data.clone();
+        data.z_index = 10;
+        mgr.window_update(&doc, data)?;
+        assert_eq!(vec![Y1], hints(&mgr)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn always_on_top_app_masks_higher_windows() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+
+        let mut mgr = setup_manager();
+        let palette_key = mgr.app_add(Application::with_hint("palette", 1234, Some(Y1)));
+        let doc_key = mgr.app_add(Application::new("doc", 5678));
+
+        let area = Rect::new(0, 0, 100, 100);
+        let doc = Window::new(&doc_key, "", area.clone(), None, true, false, 5);
+        mgr.window_add(doc)?;
+        mgr.app_set_always_on_top(&palette_key, true)?;
+        // Windows added after the flag is set are on top too
+        let palette = Window::new(&palette_key, "", area, None, true, false, 0);
+        let palette = mgr.window_add(palette)?;
+
+        let hints = |mgr: &PixelManager| -> Result<Vec<Hint>, PixelManagerError> {
+            let hints = mgr.compute_hints()?.rect_hints;
+            Ok(hints.iter().map(|rh| rh.hint).collect())
+        };
+        assert_eq!(vec![Y1], hints(&mgr)?);
+        assert_eq!(0, mgr.window(&palette)?.data.z_index);
+        assert!(mgr.export_layout().apps.iter().any(|app| app.always_on_top));
+
+        mgr.app_set_always_on_top(&palette_key, false)?;
+        assert_eq!(vec![Y4DITHER_REDRAW], hints(&mgr)?);
+
+        assert_eq!(
+            Err(PixelManagerError::UnknownApp("unknown:0".into())),
+            mgr.app_set_always_on_top(&"unknown:0".to_string(), true)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn window_stats_count_updates() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+
+        let area = Rect::new(0, 0, 100, 100);
+        let bottom = Window::new(&app_key, "", area.clone(), None, true, false, 0);
+        let bottom = mgr.window_add(bottom)?;
+        let top = Window::new(&app_key, "", Rect::new(0, 0, 50, 100), None, true, false, 1);
+        mgr.window_add(top)?;
+
+        let stats = mgr.window_stats(&bottom)?;
+        assert_eq!(0, stats.updates);
+        assert_eq!(50 * 100, stats.visible_area);
+
+        for _ in 0..2 {
+            let data = mgr.window(&bottom)?.data.clone();
+            mgr.window_update(&bottom, data)?;
+        }
+        assert_eq!(2, mgr.window_stats(&bottom)?.updates);
+
+        assert!(matches!(
+            mgr.window_stats(&"unknown".into()),
+            Err(PixelManagerError::UnknownWindow(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn window_at_picks_top_window() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+        let mut mgr = setup_manager();
+        let default = mgr.default_hint;
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let win = |area, hint, visible, z_index| {
+            Window::new(&app_key, "", area, hint, visible, false, z_index)
+        };
+
+        let bottom = mgr.window_add(win(Rect::new(0, 0, 200, 200), Some(Y1), true, 0))?;
+        let top = mgr.window_add(win(Rect::new(100, 100, 300, 300), None, true, 1))?;
+        let hidden = win(Rect::new(0, 0, 400, 400), Some(Y1), false, 2);
+        mgr.window_add(hidden)?;
+
+        assert_eq!(Some((bottom.clone(), Y1)), mgr.window_at(50, 50));
+        // Overlap goes to the highest z-index, falling back to the default hint
+        assert_eq!(Some((top.clone(), default)), mgr.window_at(150, 150));
+        // x2 and y2 are excluded
+        assert_eq!(Some((top.clone(), default)), mgr.window_at(299, 299));
+        assert_eq!(None, mgr.window_at(300, 300));
+        assert_eq!(None, mgr.window_at(-1, 0));
+
+        // Raising the bottom window hands it the overlap
+        let mut data = mgr.window(&bottom)?.data.clone();
+        data.z_index = 2;
+        mgr.window_update(&bottom, data)?;
+        assert_eq!(Some((bottom.clone(), Y1)), mgr.window_at(150, 150));
+        assert_eq!(Some((top, default)), mgr.window_at(250, 250));
+
+        mgr.forced_bit_depth = Some(BitDepth::Y4);
+        let forced = Y1.with_bit_depth(BitDepth::Y4);
+        assert_eq!(Some((bottom, forced)), mgr.window_at(150, 150));
+
+        Ok(())
+    }
+
+    #[test]
+    fn window_at_margins_and_fullscreen() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+        let mut mgr = setup_manager();
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+
+        let area = Rect::new(0, 0, 200, 200);
+        let mut win = Window::new(&app_key, "", area, Some(Y1), true, false, 0);
+        win.data.content_inset = Inset {
+            top: 20,
+            ..Default::default()
+        };
+        let win = mgr.window_add(win)?;
+
+        assert_eq!(Some((win.clone(), Y1)), mgr.window_at(50, 50));
+        assert_eq!(Some((win, Y4DITHER_REDRAW)), mgr.window_at(50, 10));
+
+        let full = Window::new(&app_key, "", Rect::new(0, 0, 10, 10), None, true, true, -1);
+        let full = mgr.window_add(full)?;
+        assert_eq!(Some((full, Y4DITHER_REDRAW)), mgr.window_at(50, 50));
+
+        Ok(())
+    }
+
+    #[test]
+    fn layout_svg_draws_visible_windows() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+        let mut mgr = setup_manager();
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let win = |title, area, visible, z_index| {
+            Window::new(&app_key, title, area, Some(Y1), visible, false, z_index)
+        };
+
+        mgr.window_add(win("<editor>", Rect::new(10, 20, 110, 70), true, 1))?;
+        mgr.window_add(win("top", Rect::new(0, 0, 50, 50), true, 2))?;
+        mgr.window_add(win("hidden", Rect::new(0, 0, 30, 30), false, 3))?;
+
+        let svg = mgr.layout_svg();
+
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(r#"viewBox="0 0 1872 1404""#));
+        // The screen, then a rectangle per visible window
+        assert_eq!(3, svg.matches("<rect").count());
+        let position = |rect: &str| svg.find(rect).unwrap();
+        let editor = position(r#"<rect x="10" y="20" width="100" height="50""#);
+        let top = position(r#"<rect x="0" y="0" width="50" height="50""#);
+        assert!(editor < top);
+        assert!(svg.contains("&lt;editor&gt; (z 1, Y1|T|r)"));
+        assert!(!svg.contains("hidden"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn default_bit_depth_cap_only_lowers() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+        let mut mgr = setup_manager();
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+        let (left, right) = (Rect::new(0, 0, 100, 100), Rect::new(100, 0, 200, 100));
+        let hinted = mgr.window_add(Window::new(&app_key, "", left, Some(Y1), true, false, 0))?;
+        let unhinted = mgr.window_add(Window::new(&app_key, "", right, None, true, false, 0))?;
+
+        mgr.default_bit_depth_cap = Some(BitDepth::Y2);
+        let capped = Y4DITHER_REDRAW.with_bit_depth(BitDepth::Y2);
+        assert_eq!(Some(capped), mgr.compute_hints()?.default_hint);
+        assert_eq!(Some((unhinted, capped)), mgr.window_at(150, 50));
+        // The window hints and the default hint itself are left alone
+        assert_eq!(Some((hinted, Y1)), mgr.window_at(50, 50));
+        assert_eq!(Y4DITHER_REDRAW, mgr.default_hint);
+
+        mgr.default_hint = Y1;
+        assert_eq!(Some(Y1), mgr.compute_hints()?.default_hint);
+
+        Ok(())
+    }
+
+    #[test]
+    fn forced_bit_depth_keeps_convert_and_redraw() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+        const Y2: Hint = Hint::new(BitDepth::Y2, HintConvertMode::Dither, true);
+        let mut mgr = setup_manager();
+        let app_key = mgr.app_add(Application::new("testapp", 1234));
+
+        let (left, right) = (Rect::new(0, 0, 100, 100), Rect::new(100, 0, 200, 100));
+        mgr.window_add(Window::new(&app_key, "", left, Some(Y1), true, false, 0))?;
+        mgr.window_add(Window::new(&app_key, "", right, Some(Y2), true, false, 0))?;
+        let before = mgr.compute_hints()?;
+
+        mgr.forced_bit_depth = Some(BitDepth::Y4);
+        let forced = mgr.compute_hints()?;
+        assert_eq!(before.rect_hints.len(), forced.rect_hints.len());
+        for (rh, forced_rh) in before.rect_hints.iter().zip(&forced.rect_hints) {
+            assert_eq!(rh.rect, forced_rh.rect);
+            assert_eq!(BitDepth::Y4, forced_rh.hint.bit_depth());
+            assert_eq!(rh.hint.with_bit_depth(BitDepth::Y4), forced_rh.hint);
+        }
+
+        mgr.forced_bit_depth = None;
+        assert_eq!(before, mgr.compute_hints()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn output_default_hints() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+        let mut mgr = setup_manager();
+        let external = Output {
+            area: Rect::new(0, 0, 1920, 1080),
+            default_hint: Y1,
+        };
+        assert!(mgr.output_add("HDMI-A-1", external.clone()));
+        assert!(!mgr.output_add(PixelManager::PANEL_OUTPUT, external.clone()));
+
+        let panel = mgr.output(PixelManager::PANEL_OUTPUT)?;
+        assert_eq!(SCREEN_RECT, panel.area);
+        assert_eq!(Y4DITHER_REDRAW, panel.default_hint);
+        assert_eq!(external, mgr.output("HDMI-A-1")?);
+
+        // Each output keeps its own default
+        mgr.set_output_default_hint("HDMI-A-1", Y4DITHER)?;
+        assert_eq!(Y4DITHER, mgr.output("HDMI-A-1")?.default_hint);
+        assert_eq!(Y4DITHER_REDRAW, mgr.default_hint);
+
+        mgr.set_output_default_hint(PixelManager::PANEL_OUTPUT, Y1)?;
+        assert_eq!(Y4DITHER, mgr.output("HDMI-A-1")?.default_hint);
+        assert_eq!(ComputedHints::with_hint(Y1), mgr.compute_hints()?);
+
+        assert_eq!(
+            Err(PixelManagerError::UnknownOutput("DP-1".into())),
+            mgr.set_output_default_hint("DP-1", Y1)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignored_apps_left_to_default() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+        let mut mgr = setup_manager();
+        mgr.ignored_apps.insert("mpv".into());
+        let player = mgr.app_add(Application::new("mpv", 1234));
+        let editor = mgr.app_add(Application::new("editor", 1235));
+
+        // The video overlaps the top of the text window
+        let (top, bottom) = (Rect::new(0, 0, 100, 150), Rect::new(0, 100, 100, 200));
+        let video = Window::new(&player, "", top.clone(), Some(Y1), true, false, 1);
+        let video = mgr.window_add(video)?;
+        let text = Window::new(&editor, "", bottom, Some(Y1), true, false, 0);
+        mgr.window_add(text)?;
+
+        assert_eq!(Some(Y1), mgr.window_hint(&video)?);
+        assert_eq!(Y4DITHER_REDRAW, mgr.window_hint_fallback(&video)?);
+        assert_eq!(2, mgr.window_count());
+
+        let mut hints = mgr.compute_hints()?.rect_hints;
+        hints.sort_by_key(|rh| rh.rect.y1);
+        assert_eq!(
+            vec![
+                RectHint {
+                    rect: top,
+                    hint: Y4DITHER_REDRAW
+                },
+                RectHint {
+                    rect: Rect::new(0, 150, 100, 200),
+                    hint: Y1
+                }
+            ],
+            hints
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn window_count_skips_regions() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+
+        let app = mgr.app_add(Application::new("app", 1234));
+        let area = Rect::new(0, 0, 100, 100);
+        mgr.window_add(Window::new(&app, "", area.clone(), None, true, false, 0))?;
+        mgr.region_add(area, Y4DITHER, 0);
+        mgr.background_set_hint(Y4DITHER);
+
+        assert_eq!(1, mgr.window_count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn process_alive_self() {
+        assert!(process_alive(std::process::id() as pid_t));
+        assert!(!process_alive(pid_t::MAX));
+    }
+
+    #[test]
+    fn merged_app_windows_take_one_rect() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+
+        let mut mgr = setup_manager();
+        let app_key = mgr.app_add(Application::with_hint("term", 1234, Some(Y2DITHER)));
+
+        // Tiled panes, with gaps between them
+        for (area, z_index) in [
+            (Rect::new(0, 0, 400, 300), 1),
+            (Rect::new(410, 0, 800, 300), 2),
+            (Rect::new(0, 310, 400, 600), 3),
+            (Rect::new(410, 310, 800, 600), 4),
+        ] {
+            let win = Window::new(&app_key, "", area, Some(Y1), true, false, z_index);
+            mgr.window_add(win)?;
+        }
+
+        let unmerged = mgr.compute_hints()?.rect_hints;
+        mgr.app_set_merge_windows(&app_key, true)?;
+        let merged = mgr.compute_hints()?.rect_hints;
+
+        assert_eq!(4, unmerged.len());
+        assert_eq!(
+            vec![RectHint {
+                rect: Rect::new(0, 0, 800, 600),
+                hint: Y2DITHER,
+            }],
+            merged
+        );
+
+        mgr.app_set_merge_windows(&app_key, false)?;
+        assert_eq!(4, mgr.compute_hints()?.rect_hints.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn merged_app_windows_use_max_z_index() -> Result<(), PixelManagerError> {
+        let mut mgr = setup_manager();
+        let merged_key = mgr.app_add(Application::new("term", 1234));
+        let other_key = mgr.app_add(Application::new("other", 5678));
+
+        let mut merged = Vec::new();
+        for (area, z_index) in [
+            (Rect::new(0, 0, 100, 100), 0),
+            (Rect::new(200, 0, 300, 100), 5),
+        ] {
+            let win = Window::new(&merged_key, "", area, None, true, false, z_index);
+            merged.push(mgr.window_add(win)?);
+        }
+        // Between the merged windows, in z-index and on screen
+        let area = Rect::new(120, 0, 180, 100);
+        let win = Window::new(&other_key, "", area, None, true, false, 2);
+        let other = mgr.window_add(win)?;
+        mgr.app_set_merge_windows(&merged_key, true)?;
+
+        let hints = mgr.compute_hints()?.rect_hints;
+
+        assert_eq!(
+            vec![Rect::new(0, 0, 300, 100)],
+            hints.iter().map(|rh| rh.rect.clone()).collect::<Vec<_>>()
+        );
+        // Inspecting the layout agrees with the hints
+        let references: Vec<_> = mgr.ztree().leaves().map(|(_, r, _)| r.to_owned()).collect();
+        assert_eq!(vec![merged_key.clone()], references);
+        assert_eq!(
+            Some((merged_key.clone(), Y4DITHER_REDRAW)),
+            mgr.window_at(150, 50)
+        );
+        assert_eq!(0, mgr.window_stats(&other)?.visible_area);
+        assert_eq!(100 * 100, mgr.window_stats(&merged[0])?.visible_area);
+        assert!(matches!(
+            mgr.app_set_merge_windows(&"unknown:0".to_string(), true),
+            Err(PixelManagerError::UnknownApp(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn layout_round_trip() -> Result<(), PixelManagerError> {
+        const Y1: Hint = Hint::new(BitDepth::Y1, HintConvertMode::Threshold, false);
+
+        let mut mgr = setup_manager();
+        let term_key = mgr.app_add(Application::with_hint("term", 1234, Some(Y2DITHER)));
+        let viewer_key = mgr.app_add(Application::new("viewer", 5678));
+
+        for (area, z_index) in [
+            (Rect::new(0, 0, 400, 300), 1),
+            (Rect::new(0, 310, 400, 600), 2),
+        ] {
+            mgr.window_add(Window::new(&term_key, "", area, None, true, false, z_index))?;
+        }
+        mgr.app_set_merge_windows(&term_key, true)?;
+
+        let area = Rect::new(300, 200, 1200, 1000);
+        let win = Window::new(&viewer_key, "doc <1>", area, Some(Y1), true, false, 3)
+            .with_external_id("42")
+            .with_focus(true);
+        let win_key = mgr.window_add(win)?;
+        let mut data = mgr.window(&win_key)?.data.clone();
+        data.content_inset = Inset {
+            top: 20,
+            ..Default::default()
+        };
+        mgr.window_update(&win_key, data)?;
+
+        mgr.region_add(Rect::new(0, 0, 100, 100), Y1, 10);
+        mgr.background_set_hint(Y2DITHER);
+
+        // Through JSON, as captured from a live session
+        let json = serde_json::to_string(&mgr.export_layout()).unwrap();
+        let snapshot: LayoutSnapshot = serde_json::from_str(&json).unwrap();
+
+        let mut replay = PixelManager::new(Y2DITHER, SCREEN_RECT.clone());
+        let (removed, added) = replay.import_layout(snapshot.clone());
+
+        let sorted = |mgr: &PixelManager| -> Result<_, PixelManagerError> {
+            let mut hints = mgr.compute_hints()?;
+            hints.rect_hints.sort_by_key(|rh| {
+                let Rect { x1, y1, x2, y2 } = rh.rect;
+                (x1, y1, x2, y2)
+            });
+            Ok(hints)
+        };
+
+        assert!(removed.is_empty());
+        // The background is restored as such, not as a window
+        assert_eq!(4, added.len());
+        assert_eq!(sorted(&mgr)?, sorted(&replay)?);
+        assert_eq!(snapshot, replay.export_layout());
+        let focused = replay.window_by_external_id(&viewer_key, "42");
+        assert!(focused.is_some_and(Window::is_focused));
+
+        // Importing again replaces everything
+        let (removed, added) = replay.import_layout(snapshot);
+        assert_eq!(5, removed.len());
+        assert_eq!(4, added.len());
+        assert_eq!(sorted(&mgr)?, sorted(&replay)?);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,447 @@
+//! Battery and temperature monitor
+//!
+//! Opt-in: it only runs when a policy is configured. It polls the battery and a thermal zone from
+//! sysfs, and adjusts the display through core commands: on low battery the bit depth of the
+//! default hint is capped, in the cold the driver temperature is forced. Both are reverted once
+//! the condition clears, and when the service stops.
+
+use std::{path::Path, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use pinenote_service::{
+    sysfs::attribute::{AttributeBase, RGeneric, RInt32, TypedRead},
+    types::{ParseError, rockchip_ebc::HintBitDepth},
+};
+use tokio::{sync::oneshot, task::JoinHandle, time::MissedTickBehavior};
+
+use crate::{config::Config, ebc, shared_state::Shared};
+
+/// Cap the bit depth of the default hint while the battery discharges below a level.
+///
+/// Written as `<percent>,<bit depth>`, e.g. `15,Y2`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LowBatteryPolicy {
+    /// Battery level, in percent, below which the cap applies.
+    pub below: u8,
+    pub bit_depth: HintBitDepth,
+}
+
+impl FromStr for LowBatteryPolicy {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [below, bit_depth] = split_policy(s)?;
+
+        let below = match below.parse()? {
+            below @ 0..=100 => below,
+            _ => Err(ParseError::OutOfRange(below.into()))?,
+        };
+
+        Ok(Self {
+            below,
+            bit_depth: bit_depth.parse()?,
+        })
+    }
+}
+
+/// Force the temperature the driver picks waveforms for while the panel is cold.
+///
+/// Written as `<degrees>,<temp_override>`, e.g. `10,10`, both in degrees Celsius.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColdPolicy {
+    /// Temperature below which the override applies.
+    pub below: i32,
+    /// Value written to the `temp_override` driver parameter.
+    pub temp_override: i32,
+}
+
+impl FromStr for ColdPolicy {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [below, temp_override] = split_policy(s)?;
+
+        Ok(Self {
+            below: below.parse()?,
+            temp_override: temp_override.parse()?,
+        })
+    }
+}
+
+/// Split a policy written as `<threshold>,<value>`.
+fn split_policy(s: &str) -> Result<[&str; 2], ParseError> {
+    s.split(',')
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .try_into()
+        .map_err(|_| ParseError::Fields(2))
+}
+
+/// State of the battery and the panel surroundings. Either may be unavailable.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Reading {
+    /// Battery level in percent, and whether the battery is discharging.
+    pub battery: Option<(u8, bool)>,
+    /// Temperature in degrees Celsius.
+    pub temperature: Option<i32>,
+}
+
+/// Display adjustments called for by the policies.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Adjustments {
+    /// Highest bit depth the default hint may use.
+    pub bit_depth_cap: Option<HintBitDepth>,
+    /// Forced driver temperature.
+    pub temp_override: Option<i32>,
+}
+
+/// Policies applied by the monitor.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Policies {
+    pub low_battery: Option<LowBatteryPolicy>,
+    pub cold: Option<ColdPolicy>,
+}
+
+impl Policies {
+    /// Battery percents an active cap waits past its threshold before lifting, so that it
+    /// doesn't flip at every percent around the threshold.
+    const BATTERY_MARGIN: u8 = 5;
+    /// Degrees an active override waits past its threshold before lifting.
+    const TEMPERATURE_MARGIN: i32 = 2;
+
+    /// Whether any policy is set, the monitor has nothing to do otherwise.
+    pub fn enabled(&self) -> bool {
+        self.low_battery.is_some() || self.cold.is_some()
+    }
+
+    /// Adjustments called for by `reading`, given the `active` ones.
+    ///
+    /// An unavailable reading keeps the active adjustment as-is.
+    pub fn decide(&self, reading: &Reading, active: &Adjustments) -> Adjustments {
+        let bit_depth_cap = self.low_battery.and_then(|policy| {
+            let Some((level, discharging)) = reading.battery else {
+                return active.bit_depth_cap;
+            };
+            let margin = active.bit_depth_cap.map_or(0, |_| Self::BATTERY_MARGIN);
+
+            (discharging && level < policy.below.saturating_add(margin)).then_some(policy.bit_depth)
+        });
+
+        let temp_override = self.cold.and_then(|policy| {
+            let Some(temperature) = reading.temperature else {
+                return active.temp_override;
+            };
+            let margin = active.temp_override.map_or(0, |_| Self::TEMPERATURE_MARGIN);
+
+            (temperature < policy.below + margin).then_some(policy.temp_override)
+        });
+
+        Adjustments {
+            bit_depth_cap,
+            temp_override,
+        }
+    }
+}
+
+/// Sysfs attributes the readings come from.
+struct Sensors {
+    capacity: RInt32,
+    status: RGeneric<String>,
+    temperature: RInt32,
+}
+
+impl Sensors {
+    fn new(battery: &Path, thermal_zone: &Path) -> Self {
+        let path = |dir: &Path, name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        Self {
+            capacity: RInt32::from_path(path(battery, "capacity")),
+            status: RGeneric::from_path(path(battery, "status")),
+            temperature: RInt32::from_path(path(thermal_zone, "temp")),
+        }
+    }
+
+    /// Read the sensors. A battery whose status can't be read is assumed to discharge.
+    fn read(&self) -> Reading {
+        let battery = self.capacity.read().ok().map(|capacity| {
+            let discharging = self
+                .status
+                .read()
+                .map_or(true, |status| status == "Discharging");
+
+            (capacity.clamp(0, 100) as u8, discharging)
+        });
+
+        Reading {
+            battery,
+            // Thermal zones report millidegrees
+            temperature: self.temperature.read().ok().map(|t| t.div_euclid(1000)),
+        }
+    }
+}
+
+/// Applies the policies to the readings, remembering what it changed to revert it.
+struct Monitor {
+    tx: ebc::CommandSender,
+    sensors: Arc<Sensors>,
+    policies: Policies,
+    active: Adjustments,
+    /// Value of `temp_override` before it was forced.
+    saved_temp_override: Option<String>,
+}
+
+impl Monitor {
+    const TEMP_OVERRIDE: &str = "temp_override";
+
+    /// Read the sensors, and apply the adjustments the policies call for.
+    async fn poll(&mut self) -> Result<()> {
+        let sensors = self.sensors.clone();
+        let reading = tokio::task::spawn_blocking(move || sensors.read())
+            .await
+            .context("Reading sensors panicked")?;
+        debug!("Power reading: {reading:?}");
+
+        let wanted = self.policies.decide(&reading, &self.active);
+        self.apply(wanted).await
+    }
+
+    async fn apply(&mut self, wanted: Adjustments) -> Result<()> {
+        if wanted.bit_depth_cap != self.active.bit_depth_cap {
+            self.apply_bit_depth_cap(wanted.bit_depth_cap).await?;
+            self.active.bit_depth_cap = wanted.bit_depth_cap;
+        }
+
+        if wanted.temp_override != self.active.temp_override {
+            self.apply_temp_override(wanted.temp_override).await?;
+            self.active.temp_override = wanted.temp_override;
+        }
+
+        Ok(())
+    }
+
+    /// Cap the bit depth of the default hint, or lift the cap.
+    ///
+    /// The cap is a runtime overlay in the core: the default hint the user set is kept, and is
+    /// the one saved with the state.
+    async fn apply_bit_depth_cap(&mut self, cap: Option<HintBitDepth>) -> Result<()> {
+        info!("Battery policy: default hint bit depth cap {cap:?}");
+        self.tx
+            .send(ebc::Property::SetDefaultBitDepthCap(cap))
+            .await
+    }
+
+    /// Force the driver temperature, or restore the previous value once lifted.
+    async fn apply_temp_override(&mut self, temp_override: Option<i32>) -> Result<()> {
+        let value = match temp_override {
+            Some(value) => {
+                if self.saved_temp_override.is_none() {
+                    let (tx, rx) = oneshot::channel();
+                    let cmd = ebc::Command::GetParam(Self::TEMP_OVERRIDE.into(), tx);
+                    self.saved_temp_override = Some(self.tx.with_reply(cmd, rx).await?);
+                }
+
+                value.to_string()
+            }
+            None => match &self.saved_temp_override {
+                Some(saved) => saved.clone(),
+                None => return Ok(()),
+            },
+        };
+
+        info!("Cold policy: {} = {value}", Self::TEMP_OVERRIDE);
+        let (tx, rx) = oneshot::channel();
+        let cmd = ebc::Command::SetParam(Self::TEMP_OVERRIDE.into(), value, tx);
+        self.tx.with_reply(cmd, rx).await?;
+
+        // Only forget the saved value once restored, a failed restore is retried on next poll
+        if temp_override.is_none() {
+            self.saved_temp_override = None;
+        }
+
+        Ok(())
+    }
+
+    /// Poll every `interval` until a shutdown is requested, then revert the adjustments so that
+    /// the driver isn't left with them.
+    async fn run(mut self, shared: Shared, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        shared
+            .until_shutdown(async {
+                loop {
+                    ticks.tick().await;
+                    if let Err(e) = self.poll().await {
+                        error!("{e:?}");
+                    }
+                }
+            })
+            .await;
+
+        if let Err(e) = self.apply(Adjustments::default()).await {
+            error!("{e:?}");
+        }
+    }
+}
+
+/// Start the monitor if any policy is configured.
+///
+/// The returned task ends once a shutdown is requested and the adjustments are reverted, which
+/// must happen before the core stops.
+pub fn start(tx: ebc::CommandSender, shared: Shared, config: &Config) -> Option<JoinHandle<()>> {
+    let policies = Policies {
+        low_battery: config.low_battery_policy,
+        cold: config.cold_policy,
+    };
+    if !policies.enabled() {
+        return None;
+    }
+
+    info!("Power monitor started: {policies:?}");
+    let monitor = Monitor {
+        tx,
+        sensors: Arc::new(Sensors::new(&config.battery, &config.thermal_zone)),
+        policies,
+        active: Adjustments::default(),
+        saved_temp_override: None,
+    };
+
+    let interval = config.power_poll_interval;
+    Some(tokio::spawn(monitor.run(shared, interval)))
+}
+
+/// Default battery of the PineNote, driven by its power management chip.
+pub const DEFAULT_BATTERY: &str = "/sys/class/power_supply/rk817-battery";
+/// Default thermal zone, the SoC one.
+pub const DEFAULT_THERMAL_ZONE: &str = "/sys/class/thermal/thermal_zone0";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    const POLICIES: Policies = Policies {
+        low_battery: Some(LowBatteryPolicy {
+            below: 15,
+            bit_depth: HintBitDepth::Y2,
+        }),
+        cold: Some(ColdPolicy {
+            below: 10,
+            temp_override: 10,
+        }),
+    };
+
+    fn reading(battery: Option<(u8, bool)>, temperature: Option<i32>) -> Reading {
+        Reading {
+            battery,
+            temperature,
+        }
+    }
+
+    #[test]
+    fn low_battery_caps_bit_depth() {
+        let none = Adjustments::default();
+
+        let wanted = POLICIES.decide(&reading(Some((14, true)), Some(20)), &none);
+        assert_eq!(Some(HintBitDepth::Y2), wanted.bit_depth_cap);
+        assert_eq!(None, wanted.temp_override);
+
+        // Charging, or above the threshold
+        let wanted = POLICIES.decide(&reading(Some((14, false)), Some(20)), &none);
+        assert_eq!(none, wanted);
+        let wanted = POLICIES.decide(&reading(Some((15, true)), Some(20)), &none);
+        assert_eq!(none, wanted);
+    }
+
+    #[test]
+    fn cold_sets_temp_override() {
+        let none = Adjustments::default();
+
+        let wanted = POLICIES.decide(&reading(Some((80, true)), Some(9)), &none);
+        assert_eq!(Some(10), wanted.temp_override);
+        assert_eq!(None, wanted.bit_depth_cap);
+
+        let wanted = POLICIES.decide(&reading(Some((80, true)), Some(10)), &none);
+        assert_eq!(none, wanted);
+    }
+
+    #[test]
+    fn active_adjustments_lift_past_margin() {
+        let active = POLICIES.decide(&reading(Some((10, true)), Some(0)), &Adjustments::default());
+
+        // Back at the thresholds, still within the margins
+        let wanted = POLICIES.decide(&reading(Some((19, true)), Some(11)), &active);
+        assert_eq!(active, wanted);
+
+        let wanted = POLICIES.decide(&reading(Some((20, true)), Some(12)), &active);
+        assert_eq!(Adjustments::default(), wanted);
+
+        // Plugging in lifts the cap right away
+        let wanted = POLICIES.decide(&reading(Some((10, false)), Some(0)), &active);
+        assert_eq!(None, wanted.bit_depth_cap);
+    }
+
+    #[test]
+    fn unavailable_reading_keeps_active() {
+        let active = POLICIES.decide(&reading(Some((10, true)), Some(0)), &Adjustments::default());
+
+        assert_eq!(active, POLICIES.decide(&Reading::default(), &active));
+        assert_eq!(
+            Adjustments::default(),
+            POLICIES.decide(&Reading::default(), &Adjustments::default())
+        );
+    }
+
+    #[test]
+    fn policies_disabled() {
+        let policies = Policies::default();
+        let none = Adjustments::default();
+
+        assert!(!policies.enabled());
+        let wanted = policies.decide(&reading(Some((1, true)), Some(-20)), &none);
+        assert_eq!(none, wanted);
+    }
+
+    #[test]
+    fn policies_from_str() {
+        let low_battery: LowBatteryPolicy = "15, Y1".parse().unwrap();
+        assert_eq!(15, low_battery.below);
+        assert_eq!(HintBitDepth::Y1, low_battery.bit_depth);
+
+        let cold: ColdPolicy = "-5,0".parse().unwrap();
+        assert_eq!((-5, 0), (cold.below, cold.temp_override));
+
+        for invalid in ["15", "15,Y3", "101,Y2", "15,Y2,1"] {
+            assert!(invalid.parse::<LowBatteryPolicy>().is_err(), "{invalid}");
+        }
+        assert!(matches!(
+            "101,Y2".parse::<LowBatteryPolicy>(),
+            Err(ParseError::OutOfRange(_))
+        ));
+        assert!(matches!(
+            "cold,10".parse::<ColdPolicy>(),
+            Err(ParseError::Int(_))
+        ));
+    }
+
+    #[test]
+    fn sensors_read_sysfs() {
+        let dir = TempDir::new("power");
+        let base = dir.path();
+        let (battery, thermal_zone) = (base.join("battery"), base.join("thermal_zone0"));
+        std::fs::create_dir_all(&battery).unwrap();
+        std::fs::create_dir_all(&thermal_zone).unwrap();
+        std::fs::write(battery.join("capacity"), "42\n").unwrap();
+        std::fs::write(battery.join("status"), "Charging\n").unwrap();
+        std::fs::write(thermal_zone.join("temp"), "-1500\n").unwrap();
+
+        let sensors = Sensors::new(&battery, &thermal_zone);
+        assert_eq!(reading(Some((42, false)), Some(-2)), sensors.read());
+
+        // Without status the battery is assumed to discharge, without thermal zone no temperature
+        std::fs::remove_file(battery.join("status")).unwrap();
+        let sensors = Sensors::new(&battery, &base.join("missing"));
+        assert_eq!(reading(Some((42, true)), None), sensors.read());
+    }
+}
@@ -0,0 +1,187 @@
+//! State shared between the compositor bridge and the DBus interfaces
+//!
+//! It is created once in `main`, and every task holding a [Shared] sees the same state. Keep it to
+//! what needs coordinating across tasks: everything else belongs to the core, behind its commands.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use tokio::sync::watch;
+
+pub type Shared = Arc<SharedState>;
+
+#[derive(Default)]
+pub struct SharedState {
+    /// Whether the bridge is paused, watched by bridges to resync when resumed.
+    bridge_paused: watch::Sender<bool>,
+    bridge_connected: AtomicBool,
+    /// Set once a shutdown was requested, never unset.
+    shutdown: watch::Sender<bool>,
+    /// Settings of the quill niri bridge, reloaded outside of the bridge task.
+    #[cfg(all(feature = "bridges", feature = "quill-niri"))]
+    pub quill_niri: crate::bridge::quill_niri::SharedSettings,
+}
+
+impl SharedState {
+    pub fn new() -> Shared {
+        Arc::default()
+    }
+
+    /// Whether the bridge should keep its updates to itself.
+    pub fn bridge_paused(&self) -> bool {
+        *self.bridge_paused.borrow()
+    }
+
+    pub fn set_bridge_paused(&self, paused: bool) {
+        self.bridge_paused.send_if_modified(|current| {
+            let modified = *current != paused;
+            *current = paused;
+            modified
+        });
+    }
+
+    /// Watch the bridge getting paused or resumed, only notified on actual changes.
+    pub fn watch_bridge_paused(&self) -> watch::Receiver<bool> {
+        self.bridge_paused.subscribe()
+    }
+
+    /// Whether the bridge is connected to its compositor and following its events.
+    pub fn bridge_connected(&self) -> bool {
+        self.bridge_connected.load(Ordering::Relaxed)
+    }
+
+    pub fn set_bridge_connected(&self, connected: bool) {
+        self.bridge_connected.store(connected, Ordering::Relaxed);
+    }
+
+    /// Ask every task to stop. Returns false if a shutdown was already requested.
+    pub fn request_shutdown(&self) -> bool {
+        !self.shutdown.send_replace(true)
+    }
+
+    pub fn shutdown_requested(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Wait until a shutdown is requested, returning right away if it already was.
+    pub async fn wait_shutdown(&self) {
+        let mut rx = self.shutdown.subscribe();
+
+        // The sender lives as long as self, waiting can't fail
+        let _ = rx.wait_for(|&requested| requested).await;
+    }
+
+    /// Run `task` until it completes, or a shutdown is requested and it is dropped.
+    pub async fn until_shutdown<F: Future>(&self, task: F) -> Option<F::Output> {
+        tokio::select! {
+            output = task => Some(output),
+            _ = self.wait_shutdown() => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn bridge_resyncs_on_resume() {
+        let shared = SharedState::new();
+        let (resynced_tx, mut resynced) = mpsc::unbounded_channel();
+
+        // A bridge stand in, resyncing whenever it is resumed
+        let mut paused = shared.watch_bridge_paused();
+        let bridge = tokio::spawn({
+            let shared = shared.clone();
+            async move {
+                shared
+                    .until_shutdown(async {
+                        while paused.changed().await.is_ok() {
+                            if !*paused.borrow_and_update() {
+                                resynced_tx.send(()).unwrap();
+                            }
+                        }
+                    })
+                    .await
+            }
+        });
+
+        // Resuming a running bridge is a no-op
+        shared.set_bridge_paused(false);
+        shared.set_bridge_paused(true);
+        shared.set_bridge_paused(false);
+        resynced.recv().await.unwrap();
+
+        shared.set_bridge_paused(true);
+        shared.set_bridge_paused(true);
+        tokio::task::yield_now().await;
+        assert!(resynced.try_recv().is_err());
+
+        shared.set_bridge_paused(false);
+        resynced.recv().await.unwrap();
+
+        shared.request_shutdown();
+        bridge.await.unwrap();
+        assert!(resynced.try_recv().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_updates() {
+        let shared = SharedState::new();
+        let mut paused = shared.watch_bridge_paused();
+
+        for value in [true, false] {
+            shared.set_bridge_paused(value);
+            assert!(paused.has_changed().unwrap());
+            assert_eq!(value, *paused.borrow_and_update());
+
+            // Tasks racing to write the value it already has, and reading it back
+            let tasks: Vec<_> = (0..8)
+                .map(|_| {
+                    let shared = shared.clone();
+                    tokio::spawn(async move {
+                        for _ in 0..100 {
+                            shared.set_bridge_paused(value);
+                            shared.set_bridge_connected(value);
+                            assert_eq!(value, shared.bridge_paused());
+                            assert_eq!(value, shared.bridge_connected());
+                            tokio::task::yield_now().await;
+                        }
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+
+            // None of the writes was an actual change
+            assert!(!paused.has_changed().unwrap());
+            assert_eq!(value, shared.bridge_paused());
+            assert_eq!(value, shared.bridge_connected());
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_tasks() {
+        let shared = SharedState::new();
+
+        // A bridge stand in, running until cancelled
+        let bridge = tokio::spawn({
+            let shared = shared.clone();
+            async move { shared.until_shutdown(std::future::pending::<()>()).await }
+        });
+        assert_eq!(Some(1), shared.until_shutdown(async { 1 }).await);
+
+        assert!(shared.request_shutdown());
+        assert!(!shared.request_shutdown());
+        assert!(shared.shutdown_requested());
+
+        assert_eq!(None, bridge.await.unwrap());
+        // Tasks started after the request stop right away
+        shared.wait_shutdown().await;
+    }
+}
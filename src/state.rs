@@ -0,0 +1,175 @@
+//! Service state persisted across restarts
+//!
+//! Only the global display settings are kept: per-window state is repopulated by the bridges.
+
+use std::{fmt::Display, fs, io, path::Path, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use thiserror::Error;
+
+use crate::types::rockchip_ebc::{DitherMode, DriverMode, Hint};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Malformed state file")]
+    Format(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    #[serde(with = "human_readable_hint")]
+    pub default_hint: Hint,
+    #[serde(with = "named")]
+    pub driver_mode: DriverMode,
+    #[serde(with = "named")]
+    pub dither_mode: DitherMode,
+    pub redraw_delay: u16,
+}
+
+impl State {
+    /// Load the state from `path`. A missing file is not an error, there is just nothing to
+    /// restore.
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>, Error> {
+        match fs::read(path) {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    /// Save the state to `path`.
+    ///
+    /// The state is written next to the destination, then renamed over it, so that a crash never
+    /// leaves a truncated file behind.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+}
+
+/// (De)serialize a hint using its [human readable](Hint::try_from_human_readable) form.
+pub(crate) mod human_readable_hint {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(hint: &Hint, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(hint)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Hint, D::Error> {
+        let value = String::deserialize(deserializer)?;
+
+        Hint::try_from_human_readable(&value).map_err(de::Error::custom)
+    }
+
+    /// Same for an optional hint, `None` being `null`.
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            hint: &Option<Hint>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match hint {
+                Some(hint) => serializer.collect_str(hint),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Hint>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|value| Hint::try_from_human_readable(&value).map_err(de::Error::custom))
+                .transpose()
+        }
+    }
+}
+
+/// (De)serialize a mode using its name.
+mod named {
+    use super::*;
+
+    pub fn serialize<T: Display, S: Serializer>(
+        mode: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(mode)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr<Err: Display>,
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    fn state() -> State {
+        State {
+            default_hint: Hint::try_from_human_readable("Y2|D|R").unwrap(),
+            driver_mode: DriverMode::Fast,
+            dither_mode: DitherMode::BlueNoise32,
+            redraw_delay: 150,
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new("state");
+        let path = dir.join("state.json");
+
+        state().save(&path).unwrap();
+        assert_eq!(Some(state()), State::load(&path).unwrap());
+
+        let mut changed = state();
+        changed.default_hint = Hint::try_from_human_readable("Y1").unwrap();
+        changed.save(&path).unwrap();
+        assert_eq!(Some(changed), State::load(&path).unwrap());
+    }
+
+    #[test]
+    fn load_missing() {
+        let dir = TempDir::new("state");
+        let path = dir.join("state.json");
+
+        assert!(State::load(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn load_malformed() {
+        let dir = TempDir::new("state");
+        let path = dir.join("state.json");
+        fs::create_dir_all(dir.path()).unwrap();
+
+        fs::write(&path, "{").unwrap();
+        assert!(matches!(State::load(&path), Err(Error::Format(_))));
+
+        let invalid_hint = r#"{
+            "default_hint": "Y3",
+            "driver_mode": "fast",
+            "dither_mode": "bayer",
+            "redraw_delay": 100
+        }"#;
+        fs::write(&path, invalid_hint).unwrap();
+        assert!(matches!(State::load(&path), Err(Error::Format(_))));
+    }
+}
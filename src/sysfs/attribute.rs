@@ -5,8 +5,11 @@ use std::{
     io::{self, Read, Write},
     marker::PhantomData,
     str::FromStr,
+    thread,
+    time::Duration,
 };
 
+use nix::libc;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,6 +20,37 @@ pub enum Error {
     ConvError,
 }
 
+/// Retry `op` a few times, with a short backoff, while it fails with an error deemed `transient`.
+///
+/// The driver refuses some accesses while its refresh thread is busy, those go through a few
+/// milliseconds later. Other errors are returned right away.
+fn retry_transient<T>(
+    transient: impl Fn(&io::Error) -> bool,
+    mut op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    const RETRIES: u32 = 3;
+    const BACKOFF: Duration = Duration::from_millis(2);
+
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < RETRIES && transient(&e) => {
+                attempt += 1;
+                thread::sleep(BACKOFF * attempt);
+            }
+            res => return res,
+        }
+    }
+}
+
+fn is_interrupted(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Interrupted
+}
+
+fn is_busy_or_interrupted(e: &io::Error) -> bool {
+    is_interrupted(e) || e.raw_os_error() == Some(libc::EBUSY)
+}
+
 /// Base trait for attribues
 pub trait AttributeBase {
     fn path(&self) -> &str;
@@ -28,10 +62,13 @@ pub trait AttributeBase {
 pub trait RawRead: AttributeBase {
     fn read_raw(&self) -> Result<String, Error> {
         let path = self.path();
-        let mut file = OpenOptions::new().read(true).open(path)?;
-        let mut str = String::new();
+        let str = retry_transient(is_interrupted, || {
+            let mut file = OpenOptions::new().read(true).open(path)?;
+            let mut str = String::new();
 
-        file.read_to_string(&mut str)?;
+            file.read_to_string(&mut str)?;
+            Ok(str)
+        })?;
 
         Ok(str.trim().to_string())
     }
@@ -48,11 +85,14 @@ pub trait TypedRead: RawRead {
 pub trait RawWrite: AttributeBase {
     fn write_raw(&self, value: impl Into<String>) -> Result<(), Error> {
         let path = self.path();
+        let value = value.into();
 
-        OpenOptions::new()
-            .write(true)
-            .open(path)
-            .and_then(|mut f| write!(f, "{}", value.into()))?;
+        retry_transient(is_busy_or_interrupted, || {
+            OpenOptions::new()
+                .write(true)
+                .open(path)
+                .and_then(|mut f| write!(f, "{value}"))
+        })?;
 
         Ok(())
     }
@@ -241,3 +281,72 @@ pub type RGeneric<T> = ReadOnly<Generic<T>>;
 
 pub type Int32 = Generic<i32>;
 pub type RInt32 = ReadOnly<Generic<i32>>;
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+
+    /// In memory attribute, failing its first writes with `errno`.
+    struct Flaky {
+        errno: i32,
+        failures: Cell<u32>,
+        writes: Cell<u32>,
+        value: RefCell<String>,
+    }
+
+    impl Flaky {
+        fn new(errno: i32, failures: u32) -> Self {
+            Self {
+                errno,
+                failures: Cell::new(failures),
+                writes: Cell::new(0),
+                value: RefCell::default(),
+            }
+        }
+
+        fn write(&self, value: &str) -> io::Result<()> {
+            self.writes.set(self.writes.get() + 1);
+
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(io::Error::from_raw_os_error(self.errno));
+            }
+
+            *self.value.borrow_mut() = value.into();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn retry_busy_writes() {
+        let attr = Flaky::new(libc::EBUSY, 2);
+
+        retry_transient(is_busy_or_interrupted, || attr.write("1")).unwrap();
+        assert_eq!(3, attr.writes.get());
+        assert_eq!("1", *attr.value.borrow());
+    }
+
+    #[test]
+    fn retry_bounded() {
+        let attr = Flaky::new(libc::EINTR, 10);
+
+        let err = retry_transient(is_busy_or_interrupted, || attr.write("1")).unwrap_err();
+        assert_eq!(io::ErrorKind::Interrupted, err.kind());
+        assert_eq!(4, attr.writes.get());
+        assert!(attr.value.borrow().is_empty());
+    }
+
+    #[test]
+    fn retry_skips_other_errors() {
+        let attr = Flaky::new(libc::EINVAL, 1);
+        assert!(retry_transient(is_busy_or_interrupted, || attr.write("1")).is_err());
+        assert_eq!(1, attr.writes.get());
+
+        // Reads only retry interrupted calls
+        let attr = Flaky::new(libc::EBUSY, 1);
+        assert!(retry_transient(is_interrupted, || attr.write("1")).is_err());
+        assert_eq!(1, attr.writes.get());
+    }
+}
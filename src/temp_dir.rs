@@ -0,0 +1,40 @@
+//! Fresh directories for tests, removed once done with.
+//!
+//! Declared by both the library and the service, so that every test can use it.
+
+use std::path::{Path, PathBuf};
+
+/// A directory in the system temporary directory, removed with its content on drop, even when a
+/// test fails. It isn't created, for tests checking the code under test does it.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// A directory named after `prefix`, unique to the caller.
+    pub fn new(prefix: &str) -> Self {
+        let name = format!("pinenote-{prefix}-{}", uuid::Uuid::new_v4());
+
+        Self(std::env::temp_dir().join(name))
+    }
+
+    /// Create the directory, returning its path.
+    pub fn create(&self) -> &Path {
+        std::fs::create_dir_all(&self.0).expect("The temporary directory should be writable");
+
+        &self.0
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Path of `name` in the directory.
+    pub fn join(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,122 @@
+use std::str::FromStr;
+
+use super::{ParseError, Rect};
+
+/// Physical mounting of the panel, as a clockwise rotation from the orientation assumed by
+/// default.
+///
+/// Compositors handle their own rotation, this only applies to what the service draws or
+/// addresses directly, e.g. the off screen image and the HintMgr1 regions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MountOrientation {
+    #[default]
+    Normal,
+    Rotated90,
+    Rotated180,
+    Rotated270,
+}
+
+impl MountOrientation {
+    /// Dimensions of the mounted frame, for a panel of `size` in the default orientation.
+    pub fn mounted_size(self, (width, height): (i32, i32)) -> (i32, i32) {
+        match self {
+            Self::Normal | Self::Rotated180 => (width, height),
+            Self::Rotated90 | Self::Rotated270 => (height, width),
+        }
+    }
+
+    /// Map a point of the mounted frame to the default orientation, for a panel of `size` in the
+    /// default orientation.
+    ///
+    /// Points are pixel corners rather than pixels, so that the far corner of the mounted frame
+    /// maps to a corner of the panel.
+    pub fn map_point(self, (x, y): (i32, i32), (width, height): (i32, i32)) -> (i32, i32) {
+        match self {
+            Self::Normal => (x, y),
+            Self::Rotated90 => (y, height - x),
+            Self::Rotated180 => (width - x, height - y),
+            Self::Rotated270 => (width - y, x),
+        }
+    }
+
+    /// Map a rectangle of the mounted frame to the default orientation, see [Self::map_point].
+    pub fn map_rect(self, rect: &Rect, size: (i32, i32)) -> Rect {
+        Rect::from_points(
+            self.map_point((rect.x1, rect.y1), size),
+            self.map_point((rect.x2, rect.y2), size),
+        )
+    }
+}
+
+impl FromStr for MountOrientation {
+    type Err = ParseError;
+
+    /// Parse a rotation in degrees.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Self::Normal),
+            "90" => Ok(Self::Rotated90),
+            "180" => Ok(Self::Rotated180),
+            "270" => Ok(Self::Rotated270),
+            _ => Err(ParseError::Unknown(s.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: (i32, i32) = (1872, 1404);
+
+    #[test]
+    fn map_origin() {
+        let cases = [
+            (MountOrientation::Normal, (0, 0)),
+            (MountOrientation::Rotated90, (0, 1404)),
+            (MountOrientation::Rotated180, (1872, 1404)),
+            (MountOrientation::Rotated270, (1872, 0)),
+        ];
+
+        for (mount, expected) in cases {
+            assert_eq!(expected, mount.map_point((0, 0), SIZE), "{mount:?}");
+        }
+    }
+
+    #[test]
+    fn map_full_frame() {
+        let panel = Rect::new(0, 0, SIZE.0, SIZE.1);
+
+        for mount in [
+            MountOrientation::Normal,
+            MountOrientation::Rotated90,
+            MountOrientation::Rotated180,
+            MountOrientation::Rotated270,
+        ] {
+            let (w, h) = mount.mounted_size(SIZE);
+            let frame = Rect::new(0, 0, w, h);
+
+            assert_eq!(panel, mount.map_rect(&frame, SIZE), "{mount:?}");
+        }
+    }
+
+    #[test]
+    fn map_rect_rotated90() {
+        // Top left corner of the mounted frame lands on the bottom left of the panel
+        let rect = Rect::new(0, 0, 10, 20);
+
+        assert_eq!(
+            Rect::new(0, 1394, 20, 1404),
+            MountOrientation::Rotated90.map_rect(&rect, SIZE)
+        );
+    }
+
+    #[test]
+    fn parse_degrees() {
+        assert_eq!(MountOrientation::Rotated270, "270".parse().unwrap());
+        assert!(matches!(
+            "45".parse::<MountOrientation>(),
+            Err(ParseError::Unknown(_))
+        ));
+    }
+}
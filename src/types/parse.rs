@@ -0,0 +1,23 @@
+//! Error of the settings parsed from text, e.g. read from the environment
+
+use std::num::ParseIntError;
+
+use thiserror::Error;
+
+use super::rockchip_ebc;
+
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("Unknown value '{0}'")]
+    Unknown(String),
+    #[error("Unsupported value '{0}'")]
+    Unsupported(String),
+    #[error("Value '{0}' is out of range")]
+    OutOfRange(String),
+    #[error("Expected {0} comma separated fields")]
+    Fields(usize),
+    #[error(transparent)]
+    Int(#[from] ParseIntError),
+    #[error(transparent)]
+    Ebc(#[from] rockchip_ebc::Error),
+}
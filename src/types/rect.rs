@@ -1,9 +1,56 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, str::FromStr};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use zbus::zvariant::{Type, Value};
 
-#[derive(Debug, PartialEq, Clone, Deserialize, Type, Value)]
+use super::ParseError;
+
+/// Rounding of the fractional coordinates of a [scaled](Rect::scaled_with) rectangle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rounding {
+    /// Drop the fractional part, towards zero.
+    Truncate,
+    /// Round to the nearest, half up.
+    #[default]
+    Round,
+    /// Floor the origin and ceil the extent, so that the result covers every pixel the
+    /// rectangle touches. Adjacent rectangles may then overlap by a pixel, but never leave a gap.
+    Cover,
+}
+
+impl Rounding {
+    /// Round the scaled `x1` or `y1` coordinate.
+    fn origin(self, v: f64) -> i32 {
+        match self {
+            Self::Truncate => v.trunc() as i32,
+            Self::Round => (v + 0.5).floor() as i32,
+            Self::Cover => v.floor() as i32,
+        }
+    }
+
+    /// Round the scaled `x2` or `y2` coordinate.
+    fn extent(self, v: f64) -> i32 {
+        match self {
+            Self::Cover => v.ceil() as i32,
+            _ => self.origin(v),
+        }
+    }
+}
+
+impl FromStr for Rounding {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(Self::Truncate),
+            "round" => Ok(Self::Round),
+            "cover" => Ok(Self::Cover),
+            _ => Err(ParseError::Unknown(s.into())),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Deserialize, Serialize, Type, Value)]
 pub struct Rect {
     pub x1: i32,
     pub y1: i32,
@@ -16,14 +63,61 @@ impl Rect {
         Self { x1, y1, x2, y2 }
     }
 
+    /// Build a rectangle from two opposite corners, in any order.
+    pub const fn from_points((x1, y1): (i32, i32), (x2, y2): (i32, i32)) -> Self {
+        Self::new(x1, y1, x2, y2).normalized()
+    }
+
+    /// Build a rectangle from its origin and dimensions.
+    ///
+    /// A negative width (resp. height) extends the rectangle to the left (resp. top) of the
+    /// origin.
     pub const fn from_xywh(x: i32, y: i32, w: i32, h: i32) -> Self {
-        Self::new(x, y, x + w, y + h)
+        Self::from_points((x, y), (x + w, y + h))
+    }
+
+    /// Resolve a rectangle given as fractions of `screen`, from 0.0 (its top left corner) to 1.0
+    /// (its bottom right corner), e.g. `(0.0, 0.0, 1.0, 0.1)` for a top bar of a tenth of the
+    /// screen height.
+    ///
+    /// Fractions are clamped to `[0.0, 1.0]` and corners are rounded half up like
+    /// [Rect::scaled], so that adjacent fractions give adjacent rectangles. The result is
+    /// [normalized](Rect::normalized).
+    pub fn from_fractions(x1f: f64, y1f: f64, x2f: f64, y2f: f64, screen: &Rect) -> Self {
+        let Rect { x1, y1, x2, y2 } = screen.clone().normalized();
+        let resolve = |from: i32, to: i32, f: f64| {
+            let f = f.clamp(0.0, 1.0);
+            from + ((to - from) as f64 * f + 0.5).floor() as i32
+        };
+
+        Self::from_points(
+            (resolve(x1, x2, x1f), resolve(y1, y2, y1f)),
+            (resolve(x1, x2, x2f), resolve(y1, y2, y2f)),
+        )
+    }
+
+    /// Whether the pixel at (`x`, `y`) is inside the rectangle, `x2` and `y2` being excluded.
+    pub fn contains_point(&self, x: i32, y: i32) -> bool {
+        self.x1 <= x && x < self.x2 && self.y1 <= y && y < self.y2
     }
 
     pub fn intersect(&self, rhs: &Self) -> bool {
         self.x1 <= rhs.x2 && self.x2 >= rhs.x1 && self.y1 <= rhs.y2 && self.y2 >= rhs.y1
     }
 
+    /// Swap corners if needed, so that `x1 <= x2` and `y1 <= y2`.
+    pub const fn normalized(self) -> Rect {
+        let Self { x1, y1, x2, y2 } = self;
+        let (x1, x2) = if x1 <= x2 { (x1, x2) } else { (x2, x1) };
+        let (y1, y2) = if y1 <= y2 { (y1, y2) } else { (y2, y1) };
+
+        Rect::new(x1, y1, x2, y2)
+    }
+
+    pub fn area(&self) -> i64 {
+        (self.x2 - self.x1) as i64 * (self.y2 - self.y1) as i64
+    }
+
     pub fn cover(&self, other: &Self) -> bool {
         self.x1 <= other.x1 && self.y1 <= other.y1 && self.x2 >= other.x2 && self.y2 >= other.y2
     }
@@ -42,6 +136,59 @@ impl Rect {
             Some(inter)
         }
     }
+
+    /// Number of pixels covered by both rectangles, 0 if they don't overlap.
+    pub fn overlap_area(&self, other: &Self) -> i64 {
+        self.intersection(other).map_or(0, |inter| inter.area())
+    }
+
+    /// Scale all coordinates by `factor`, rounding half up (i.e. towards positive infinity).
+    ///
+    /// Corners are scaled independently so that adjacent rectangles stay adjacent, and the result
+    /// is [normalized](Rect::normalized). A factor that isn't strictly positive and finite leaves
+    /// the rectangle unchanged.
+    pub fn scaled(&self, factor: f64) -> Rect {
+        self.scaled_with(factor, Rounding::Round)
+    }
+
+    /// Same as [Rect::scaled], rounding the coordinates according to `rounding`.
+    pub fn scaled_with(&self, factor: f64, rounding: Rounding) -> Rect {
+        if !(factor.is_finite() && factor > 0.0) {
+            return self.clone();
+        }
+
+        let Rect { x1, y1, x2, y2 } = self.clone().normalized();
+        let scale = |v: i32| v as f64 * factor;
+
+        Rect::new(
+            rounding.origin(scale(x1)),
+            rounding.origin(scale(y1)),
+            rounding.extent(scale(x2)),
+            rounding.extent(scale(y2)),
+        )
+    }
+
+    /// Split the rectangle in `tile_w` x `tile_h` tiles, row by row.
+    ///
+    /// Tiles on the right and bottom edges are clipped to the rectangle. A zero tile dimension
+    /// yields no tile.
+    pub fn tiles(&self, tile_w: u32, tile_h: u32) -> impl Iterator<Item = Rect> + use<> {
+        let Rect { x1, y1, x2, y2 } = self.clone().normalized();
+        let valid = tile_w > 0 && tile_h > 0;
+        let (tile_w, tile_h) = (tile_w.max(1) as usize, tile_h.max(1) as usize);
+
+        (y1..y2)
+            .step_by(tile_h)
+            .filter(move |_| valid)
+            .flat_map(move |y| {
+                (x1..x2).step_by(tile_w).map(move |x| {
+                    let right = x.saturating_add_unsigned(tile_w as u32).min(x2);
+                    let bottom = y.saturating_add_unsigned(tile_h as u32).min(y2);
+
+                    Rect::new(x, y, right, bottom)
+                })
+            })
+    }
 }
 
 /// Rectangle, possibly split to mask part of it.
@@ -53,6 +200,16 @@ impl SplitRect {
         self.0.is_empty()
     }
 
+    /// Iterate over the pieces of the split rectangle
+    pub fn iter(&self) -> std::slice::Iter<'_, Rect> {
+        self.0.iter()
+    }
+
+    /// Return the total area covered by the split rectangle
+    pub fn area(&self) -> i64 {
+        self.0.iter().map(Rect::area).sum()
+    }
+
     /// Return the split rectangle bounding box
     pub fn bounds(&self) -> Option<Rect> {
         if self.is_empty() {
@@ -124,7 +281,203 @@ impl From<Rect> for SplitRect {
 
 #[cfg(test)]
 pub mod tests {
-    use super::{Rect, SplitRect};
+    use super::{Rect, Rounding, SplitRect};
+
+    #[test]
+    fn tiles() {
+        let tiles: Vec<_> = Rect::new(0, 0, 250, 150).tiles(100, 100).collect();
+
+        assert_eq!(
+            vec![
+                Rect::new(0, 0, 100, 100),
+                Rect::new(100, 0, 200, 100),
+                Rect::new(200, 0, 250, 100),
+                Rect::new(0, 100, 100, 150),
+                Rect::new(100, 100, 200, 150),
+                Rect::new(200, 100, 250, 150),
+            ],
+            tiles
+        );
+
+        let screen = Rect::new(0, 0, 1872, 1404);
+        assert_eq!(
+            screen.area(),
+            screen.tiles(500, 300).map(|t| t.area()).sum::<i64>()
+        );
+        assert_eq!(1, screen.tiles(2000, 2000).count());
+        assert_eq!(0, screen.tiles(0, 100).count());
+    }
+
+    #[test]
+    fn scaled_identity() {
+        let r = Rect::new(-10, 20, 30, 45);
+
+        assert_eq!(r, r.scaled(1.0));
+    }
+
+    #[test]
+    fn scaled_double() {
+        let r = Rect::new(-10, 20, 30, 45);
+
+        assert_eq!(Rect::new(-20, 40, 60, 90), r.scaled(2.0));
+    }
+
+    #[test]
+    fn scaled_fractional_rounding() {
+        // 1.5 * [-1, -3, 1, 3] = [-1.5, -4.5, 1.5, 4.5], all exactly on a rounding boundary.
+        let r = Rect::new(-1, -3, 1, 3);
+        assert_eq!(Rect::new(-1, -4, 2, 5), r.scaled(1.5));
+
+        // 1.25 * [1, 2, 3, 5] = [1.25, 2.5, 3.75, 6.25]
+        let r = Rect::new(1, 2, 3, 5);
+        assert_eq!(Rect::new(1, 3, 4, 6), r.scaled(1.25));
+    }
+
+    #[test]
+    fn from_fractions_full_screen() {
+        let screen = Rect::new(0, 0, 1872, 1404);
+
+        assert_eq!(screen, Rect::from_fractions(0.0, 0.0, 1.0, 1.0, &screen));
+        assert_eq!(screen, Rect::from_fractions(-0.5, 0.0, 1.0, 2.0, &screen));
+    }
+
+    #[test]
+    fn from_fractions_quadrant() {
+        let screen = Rect::new(0, 0, 1872, 1404);
+        assert_eq!(
+            Rect::new(936, 702, 1872, 1404),
+            Rect::from_fractions(0.5, 0.5, 1.0, 1.0, &screen)
+        );
+
+        // Relative to the screen origin
+        let screen = Rect::new(100, 200, 300, 400);
+        assert_eq!(
+            Rect::new(100, 200, 200, 300),
+            Rect::from_fractions(0.0, 0.0, 0.5, 0.5, &screen)
+        );
+    }
+
+    #[test]
+    fn from_fractions_rounding() {
+        // 0.1 * 1404 = 140.4, 0.25 * 10 = 2.5, 0.75 * 10 = 7.5
+        let screen = Rect::new(0, 0, 10, 1404);
+        assert_eq!(
+            Rect::new(3, 0, 8, 140),
+            Rect::from_fractions(0.25, 0.0, 0.75, 0.1, &screen)
+        );
+
+        // Adjacent fractions stay adjacent
+        let top = Rect::from_fractions(0.0, 0.0, 1.0, 1.0 / 3.0, &screen);
+        let rest = Rect::from_fractions(0.0, 1.0 / 3.0, 1.0, 1.0, &screen);
+        assert_eq!(top.y2, rest.y1);
+        assert_eq!(screen.area(), top.area() + rest.area());
+    }
+
+    #[test]
+    fn scaled_rounding_policies() {
+        // 1.5 * [1, 1, 3, 3] = [1.5, 1.5, 4.5, 4.5]
+        let scaled = |rounding| Rect::new(1, 1, 3, 3).scaled_with(1.5, rounding);
+
+        assert_eq!(Rect::new(1, 1, 4, 4), scaled(Rounding::Truncate));
+        assert_eq!(Rect::new(2, 2, 5, 5), scaled(Rounding::Round));
+        assert_eq!(Rect::new(1, 1, 5, 5), scaled(Rounding::Cover));
+
+        // Negative coordinates truncate towards zero
+        let r = Rect::new(-1, -1, 1, 1).scaled_with(1.5, Rounding::Truncate);
+        assert_eq!(Rect::new(-1, -1, 1, 1), r);
+    }
+
+    #[test]
+    fn scaled_cover_leaves_no_gap() {
+        let left = Rect::new(0, 0, 3, 10);
+        let right = Rect::new(3, 0, 7, 10);
+
+        let left = left.scaled_with(1.5, Rounding::Cover);
+        let right = right.scaled_with(1.5, Rounding::Cover);
+
+        // Both cover the pixel the shared edge falls in
+        assert_eq!(Rect::new(0, 0, 5, 15), left);
+        assert_eq!(Rect::new(4, 0, 11, 15), right);
+        assert!(left.x2 >= right.x1);
+        // Every pixel of the scaled span is covered
+        assert!((0..11).all(|x| left.contains_point(x, 0) || right.contains_point(x, 0)));
+    }
+
+    #[test]
+    fn rounding_from_str() {
+        assert_eq!(Rounding::Cover, "cover".parse().unwrap());
+        assert_eq!(Rounding::Truncate, "truncate".parse().unwrap());
+        assert!("ceil".parse::<Rounding>().is_err());
+    }
+
+    #[test]
+    fn scaled_inverted() {
+        assert_eq!(Rect::new(0, 0, 20, 40), Rect::new(10, 20, 0, 0).scaled(2.0));
+    }
+
+    #[test]
+    fn normalized_inverted() {
+        assert_eq!(
+            Rect::new(-10, 5, 30, 40),
+            Rect::new(30, 40, -10, 5).normalized()
+        );
+        assert_eq!(
+            Rect::new(10, 5, 30, 40),
+            Rect::new(30, 5, 10, 40).normalized()
+        );
+        assert_eq!(
+            Rect::new(10, 5, 30, 40),
+            Rect::new(10, 40, 30, 5).normalized()
+        );
+    }
+
+    #[test]
+    fn from_xywh_positive() {
+        assert_eq!(Rect::new(10, 20, 110, 70), Rect::from_xywh(10, 20, 100, 50));
+        assert_eq!(Rect::new(10, 20, 10, 20), Rect::from_xywh(10, 20, 0, 0));
+    }
+
+    #[test]
+    fn from_xywh_negative() {
+        assert_eq!(
+            Rect::new(-90, 20, 10, 70),
+            Rect::from_xywh(10, 20, -100, 50)
+        );
+        assert_eq!(
+            Rect::new(10, -30, 110, 20),
+            Rect::from_xywh(10, 20, 100, -50)
+        );
+        assert_eq!(
+            Rect::new(-90, -30, 10, 20),
+            Rect::from_xywh(10, 20, -100, -50)
+        );
+    }
+
+    #[test]
+    fn from_points() {
+        let expected = Rect::new(10, 20, 30, 40);
+
+        assert_eq!(expected, Rect::from_points((10, 20), (30, 40)));
+        assert_eq!(expected, Rect::from_points((30, 40), (10, 20)));
+        assert_eq!(expected, Rect::from_points((10, 40), (30, 20)));
+        assert_eq!(Rect::new(0, 0, 0, 0), Rect::default());
+    }
+
+    #[test]
+    fn normalized_noop() {
+        let r = Rect::new(10, 20, 30, 40);
+
+        assert_eq!(r.clone(), r.normalized());
+    }
+
+    #[test]
+    fn scaled_invalid_factor() {
+        let r = Rect::new(10, 20, 30, 40);
+
+        assert_eq!(r, r.scaled(0.0));
+        assert_eq!(r, r.scaled(-2.0));
+        assert_eq!(r, r.scaled(f64::NAN));
+    }
 
     #[test]
     fn no_inter() {
@@ -138,6 +491,72 @@ pub mod tests {
         assert_eq!(expected, res);
     }
 
+    #[test]
+    fn single_pixel_intersection() {
+        let pixel = Rect::new(5, 5, 6, 6);
+
+        assert_eq!(
+            Some(pixel.clone()),
+            pixel.intersection(&Rect::new(0, 0, 10, 10))
+        );
+        assert_eq!(
+            Some(pixel.clone()),
+            Rect::new(5, 0, 6, 10).intersection(&pixel)
+        );
+        // Touching edges share no pixel
+        assert_eq!(None, pixel.intersection(&Rect::new(6, 5, 7, 6)));
+    }
+
+    #[test]
+    fn overlap_area_disjoint() {
+        let rect = Rect::new(0, 0, 10, 10);
+
+        assert_eq!(0, rect.overlap_area(&Rect::new(20, 20, 30, 30)));
+        // Touching edges share no pixel
+        assert_eq!(0, rect.overlap_area(&Rect::new(10, 0, 20, 10)));
+    }
+
+    #[test]
+    fn overlap_area_partial() {
+        let a = Rect::new(0, 0, 10, 10);
+        let b = Rect::new(5, 8, 15, 20);
+
+        assert_eq!(10, a.overlap_area(&b));
+        assert_eq!(a.overlap_area(&b), b.overlap_area(&a));
+    }
+
+    #[test]
+    fn overlap_area_contained() {
+        let outer = Rect::new(0, 0, 100, 100);
+        let inner = Rect::new(10, 10, 30, 20);
+
+        assert_eq!(inner.area(), outer.overlap_area(&inner));
+        assert_eq!(inner.area(), inner.overlap_area(&outer));
+        assert_eq!(outer.area(), outer.overlap_area(&outer));
+    }
+
+    #[test]
+    fn single_pixel_mask() {
+        // Punching a pixel out of a 3x3 square keeps the 8 others
+        let sr = SplitRect::from(Rect::new(0, 0, 3, 3));
+        let res = sr.mask_with(&Rect::new(1, 1, 2, 2));
+        assert_eq!(8, res.area());
+        assert!(res.iter().all(|r| r.area() > 0));
+
+        // A 1px wide strip survives its neighbors being masked
+        let sr = SplitRect::from(Rect::new(0, 0, 3, 1));
+        let res = sr
+            .mask_with(&Rect::new(0, 0, 1, 1))
+            .mask_with(&Rect::new(2, 0, 3, 1));
+        assert_eq!(
+            vec![Rect::new(1, 0, 2, 1)],
+            res.iter().cloned().collect::<Vec<_>>()
+        );
+
+        let res = SplitRect::from(Rect::new(1, 0, 2, 1)).mask_with(&Rect::new(1, 0, 2, 1));
+        assert!(res.is_empty());
+    }
+
     #[test]
     fn full_cover() {
         let sr = SplitRect::from(Rect::new(100, 100, 150, 150));
@@ -426,4 +845,72 @@ pub mod tests {
         assert_eq!(expected_bounds, res.bounds());
         assert_eq!(expected, res);
     }
+
+    /// Every rectangle with coordinates in `0..=max`, on a grid of the given step.
+    fn all_rects(max: i32, step: usize) -> Vec<Rect> {
+        let coords: Vec<i32> = (0..=max).step_by(step).collect();
+        let spans: Vec<(i32, i32)> = coords
+            .iter()
+            .flat_map(|&a| coords.iter().filter(move |&&b| b > a).map(move |&b| (a, b)))
+            .collect();
+
+        spans
+            .iter()
+            .flat_map(|&(x1, x2)| spans.iter().map(move |&(y1, y2)| Rect::new(x1, y1, x2, y2)))
+            .collect()
+    }
+
+    fn contains(r: &Rect, x: i32, y: i32) -> bool {
+        r.x1 <= x && x < r.x2 && r.y1 <= y && y < r.y2
+    }
+
+    /// Check that masking `rect` with `masks` covers exactly the pixels of `rect` outside of the
+    /// masks, each of them once. This implies the pieces don't overlap, stay within the original
+    /// bounds, and never cover more area than the original.
+    fn assert_mask_invariants(rect: &Rect, masks: &[&Rect]) {
+        let res = masks
+            .iter()
+            .fold(SplitRect::from(rect.clone()), |sr, m| sr.mask_with(m));
+
+        assert!(
+            res.0.iter().all(|p| p.x1 < p.x2 && p.y1 < p.y2),
+            "Empty piece masking {rect:?} with {masks:?}: {res:?}"
+        );
+
+        for x in -1..=7 {
+            for y in -1..=7 {
+                let expected = contains(rect, x, y) && !masks.iter().any(|m| contains(m, x, y));
+                let count = res.0.iter().filter(|p| contains(p, x, y)).count();
+
+                assert_eq!(
+                    expected as usize, count,
+                    "Pixel ({x}, {y}) masking {rect:?} with {masks:?}: {res:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mask_invariants_exhaustive() {
+        let rects = all_rects(5, 1);
+
+        for rect in &rects {
+            for mask in &rects {
+                assert_mask_invariants(rect, &[mask]);
+            }
+        }
+    }
+
+    #[test]
+    fn mask_invariants_chained() {
+        let rects = all_rects(6, 2);
+
+        for rect in &rects {
+            for m1 in &rects {
+                for m2 in &rects {
+                    assert_mask_invariants(rect, &[m1, m2]);
+                }
+            }
+        }
+    }
 }
@@ -7,6 +7,7 @@ use std::{
 };
 
 use num_enum::{IntoPrimitive, TryFromPrimitive, TryFromPrimitiveError};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use zbus::zvariant::{Type, Value};
 use log::warn;
@@ -15,7 +16,7 @@ use crate::ioctls::{self, drm};
 
 use super::Rect;
 
-#[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy, Type, Value)]
+#[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy, PartialEq, Eq, Debug, Type, Value)]
 #[repr(u8)]
 pub enum HintBitDepth {
     Y1 = 0,
@@ -23,6 +24,31 @@ pub enum HintBitDepth {
     Y4 = 2,
 }
 
+impl HintBitDepth {
+    pub const ALL: [Self; 3] = [Self::Y1, Self::Y2, Self::Y4];
+
+    /// Name of the bit depth, as used in human readable hints.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Y1 => "Y1",
+            Self::Y2 => "Y2",
+            Self::Y4 => "Y4",
+        }
+    }
+}
+
+/// Parse the [name](HintBitDepth::name) of a bit depth.
+impl FromStr for HintBitDepth {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|d| d.name() == s)
+            .ok_or(Error::Invalid)
+    }
+}
+
 #[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy, Type, Value)]
 #[repr(u8)]
 pub enum HintConvertMode {
@@ -40,13 +66,15 @@ pub enum Error {
     ConvertMode(#[from] TryFromPrimitiveError<HintConvertMode>),
     #[error("Unsupported dithering method")]
     Method(#[from] TryFromPrimitiveError<DitherMode>),
+    #[error("Unsupported driver mode")]
+    DriverMode(#[from] TryFromPrimitiveError<DriverMode>),
     #[error("Unsupported value")]
     DclkSelect(#[from] TryFromPrimitiveError<DclkSelect>),
     #[error("Invalid value.")]
     Invalid,
 }
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub struct Hint {
     repr: u8,
 }
@@ -123,6 +151,29 @@ impl Hint {
     pub fn redraw(&self) -> bool {
         Self::extract_redraw(self.repr)
     }
+
+    /// The same hint, using `bit_depth` instead.
+    pub fn with_bit_depth(self, bit_depth: HintBitDepth) -> Self {
+        Self::new(bit_depth, self.convert_mode(), self.redraw())
+    }
+
+    /// Explain why the hint combination is questionable, if it is.
+    ///
+    /// This is only advisory, such hints are still valid.
+    pub fn is_wasteful(&self) -> Option<&'static str> {
+        use HintBitDepth::*;
+        use HintConvertMode::*;
+
+        match (self.bit_depth(), self.convert_mode(), self.redraw()) {
+            (Y1, Threshold, true) => {
+                Some("redraw gains nothing on thresholded 1bpp content, it is already final")
+            }
+            (Y1, Dither, _) => {
+                Some("dithering at 1bpp only helps images, it wastes cycles on text")
+            }
+            _ => None,
+        }
+    }
 }
 
 impl FromStr for Hint {
@@ -152,11 +203,7 @@ impl From<Hint> for u8 {
 
 impl Display for Hint {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let depth = match self.bit_depth() {
-            HintBitDepth::Y4 => "Y4",
-            HintBitDepth::Y2 => "Y2",
-            HintBitDepth::Y1 => "Y1",
-        };
+        let depth = self.bit_depth().name();
 
         let convert = match self.convert_mode() {
             HintConvertMode::Threshold => "T",
@@ -177,7 +224,7 @@ impl Debug for Hint {
     }
 }
 
-#[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy, PartialEq, Eq, Type, Value)]
+#[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy, PartialEq, Eq, Debug, Type, Value)]
 #[repr(u8)]
 pub enum DitherMode {
     Bayer = 0,
@@ -186,6 +233,8 @@ pub enum DitherMode {
 }
 
 impl DitherMode {
+    pub const ALL: [Self; 3] = [Self::Bayer, Self::BlueNoise16, Self::BlueNoise32];
+
     pub fn cycle_next(&self) -> Self {
         match self {
             Self::Bayer => Self::BlueNoise16,
@@ -193,18 +242,40 @@ impl DitherMode {
             Self::BlueNoise32 => Self::Bayer,
         }
     }
+
+    /// Human readable name of the mode.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bayer => "bayer",
+            Self::BlueNoise16 => "blue-noise-16",
+            Self::BlueNoise32 => "blue-noise-32",
+        }
+    }
 }
 
+/// Parse either the numeric value used by the driver, or the [name](DitherMode::name).
 impl FromStr for DitherMode {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let repr: u8 = s.parse()?;
-        Self::try_from_primitive(repr).map_err(Error::from)
+        if let Ok(repr) = s.parse::<u8>() {
+            return Self::try_from_primitive(repr).map_err(Error::from);
+        }
+
+        Self::ALL
+            .into_iter()
+            .find(|m| m.name() == s)
+            .ok_or(Error::Invalid)
+    }
+}
+
+impl Display for DitherMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
     }
 }
 
-#[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy, PartialEq, Eq, Type, Value)]
+#[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy, PartialEq, Eq, Debug, Type, Value)]
 #[repr(u8)]
 pub enum DriverMode {
     Normal = 0,
@@ -220,6 +291,37 @@ impl DriverMode {
             _ => *self,
         }
     }
+
+    /// Human readable name of the mode.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Fast => "fast",
+            Self::ZeroWaveform => "zero-waveform",
+        }
+    }
+}
+
+/// Parse either the numeric value used by the driver, or the [name](DriverMode::name).
+impl FromStr for DriverMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(repr) = s.parse::<u8>() {
+            return Self::try_from_primitive(repr).map_err(Error::from);
+        }
+
+        [Self::Normal, Self::Fast, Self::ZeroWaveform]
+            .into_iter()
+            .find(|m| m.name() == s)
+            .ok_or(Error::Invalid)
+    }
+}
+
+impl Display for DriverMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
 #[derive(Default)]
@@ -229,6 +331,10 @@ pub struct Mode {
     pub redraw_delay: Option<u16>,
 }
 
+/// Read back the mode reported by the driver.
+///
+/// The `set_*` flags are only meaningful when writing, they are ignored: every field is read.
+/// Values the driver reports but that aren't known are logged and left to `None`.
 impl From<ioctls::rockchip_ebc::Mode> for Mode {
     fn from(value: ioctls::rockchip_ebc::Mode) -> Self {
         let driver_mode = match DriverMode::try_from_primitive(value.driver_mode) {
@@ -257,6 +363,9 @@ impl From<ioctls::rockchip_ebc::Mode> for Mode {
     }
 }
 
+/// Build the ioctl changing the `Some` fields of the mode.
+///
+/// The driver only applies the fields whose `set_*` flag is set, the `None` ones are left as is.
 impl From<Mode> for ioctls::rockchip_ebc::Mode {
     fn from(value: Mode) -> Self {
         let mut ret = Self::new();
@@ -280,6 +389,26 @@ impl From<Mode> for ioctls::rockchip_ebc::Mode {
     }
 }
 
+/// Fast drawing parameters, used by the hints with the redraw bit set.
+///
+/// Pixels drawn with a fast waveform are redrawn with the regular one once the delay elapsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, Type, Value)]
+pub struct RedrawOptions {
+    /// Frames to wait before redrawing the pixels, set through the mode ioctl.
+    pub redraw_delay: u16,
+    /// Frames added to the early cancellation of a redraw, set through sysfs.
+    pub early_cancellation_addition: i32,
+}
+
+impl From<RedrawOptions> for Mode {
+    fn from(value: RedrawOptions) -> Self {
+        Self {
+            redraw_delay: Some(value.redraw_delay),
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(TryFromPrimitive, IntoPrimitive, Clone, Copy)]
 #[repr(i32)]
 pub enum DclkSelect {
@@ -297,7 +426,7 @@ impl FromStr for DclkSelect {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct RectHint {
     pub rect: Rect,
     pub hint: Hint,
@@ -377,3 +506,138 @@ impl From<&mut FrameBuffers> for ioctls::rockchip_ebc::ExtractFBs {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dither_mode_names() {
+        for mode in [
+            DitherMode::Bayer,
+            DitherMode::BlueNoise16,
+            DitherMode::BlueNoise32,
+        ] {
+            assert_eq!(mode, mode.to_string().parse().unwrap());
+            assert_eq!(mode, u8::from(mode).to_string().parse().unwrap());
+        }
+
+        assert!("blue-noise-8".parse::<DitherMode>().is_err());
+        assert!("3".parse::<DitherMode>().is_err());
+    }
+
+    #[test]
+    fn driver_mode_names() {
+        for mode in [
+            DriverMode::Normal,
+            DriverMode::Fast,
+            DriverMode::ZeroWaveform,
+        ] {
+            assert_eq!(mode, mode.to_string().parse().unwrap());
+            assert_eq!(mode, u8::from(mode).to_string().parse().unwrap());
+        }
+
+        assert_eq!("zero-waveform", DriverMode::ZeroWaveform.to_string());
+        assert!("slow".parse::<DriverMode>().is_err());
+        assert!("Fast".parse::<DriverMode>().is_err());
+        assert!("2".parse::<DriverMode>().is_err());
+    }
+
+    #[test]
+    fn wasteful_hints() {
+        for hint in ["Y1|T|R", "Y1|D", "Y1|D|R"] {
+            let hint = Hint::try_from_human_readable(hint).unwrap();
+            assert!(hint.is_wasteful().is_some(), "{hint}");
+        }
+
+        for hint in ["Y1|T", "Y2|D|R", "Y4|T|R", "Y4"] {
+            let hint = Hint::try_from_human_readable(hint).unwrap();
+            assert_eq!(None, hint.is_wasteful(), "{hint}");
+        }
+    }
+
+    #[test]
+    fn rect_hint_set_dedup() {
+        let rect_hint = |rect: Rect, hint: &str| RectHint {
+            rect,
+            hint: Hint::try_from_human_readable(hint).unwrap(),
+        };
+
+        let set: std::collections::HashSet<_> = [
+            rect_hint(Rect::new(0, 0, 100, 100), "Y1|T"),
+            rect_hint(Rect::new(0, 0, 100, 100), "Y1|T"),
+            rect_hint(Rect::new(0, 0, 100, 100), "Y4|T"),
+            rect_hint(Rect::new(0, 0, 100, 200), "Y1|T"),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(3, set.len());
+        assert!(set.contains(&rect_hint(Rect::new(0, 0, 100, 100), "Y4|T")));
+    }
+
+    #[test]
+    fn redraw_options_ioctl() {
+        let options = RedrawOptions {
+            redraw_delay: 150,
+            early_cancellation_addition: 2,
+        };
+
+        let mode = ioctls::rockchip_ebc::Mode::from(Mode::from(options));
+        assert_eq!(1, mode.set_redraw_delay);
+        assert_eq!(150, mode.redraw_delay);
+        // Only the delay is set, the driver and dither modes are left alone
+        assert_eq!(0, mode.set_driver_mode);
+        assert_eq!(0, mode.set_dither_mode);
+    }
+
+    #[test]
+    fn mode_read_ignores_set_flags() {
+        let ioctl = ioctls::rockchip_ebc::Mode {
+            driver_mode: DriverMode::Fast.into(),
+            dither_mode: DitherMode::BlueNoise16.into(),
+            redraw_delay: 120,
+            ..Default::default()
+        };
+
+        let mode = Mode::from(ioctl);
+        assert_eq!(Some(DriverMode::Fast), mode.driver_mode);
+        assert_eq!(Some(DitherMode::BlueNoise16), mode.dither_mode);
+        assert_eq!(Some(120), mode.redraw_delay);
+
+        let unknown = ioctls::rockchip_ebc::Mode {
+            driver_mode: 42,
+            dither_mode: 42,
+            ..Default::default()
+        };
+        let mode = Mode::from(unknown);
+        assert_eq!(None, mode.driver_mode);
+        assert_eq!(None, mode.dither_mode);
+    }
+
+    #[test]
+    fn mode_partial_write_flags() {
+        let flags = |m: &ioctls::rockchip_ebc::Mode| {
+            (m.set_driver_mode, m.set_dither_mode, m.set_redraw_delay)
+        };
+
+        let ioctl = ioctls::rockchip_ebc::Mode::from(Mode {
+            dither_mode: Some(DitherMode::BlueNoise32),
+            ..Default::default()
+        });
+        assert_eq!((0, 1, 0), flags(&ioctl));
+        assert_eq!(u8::from(DitherMode::BlueNoise32), ioctl.dither_mode);
+
+        let ioctl = ioctls::rockchip_ebc::Mode::from(Mode {
+            driver_mode: Some(DriverMode::Normal),
+            dither_mode: None,
+            redraw_delay: Some(0),
+        });
+        assert_eq!((1, 0, 1), flags(&ioctl));
+        assert_eq!(u8::from(DriverMode::Normal), ioctl.driver_mode);
+        assert_eq!(0, ioctl.redraw_delay);
+
+        let ioctl = ioctls::rockchip_ebc::Mode::from(Mode::default());
+        assert_eq!((0, 0, 0), flags(&ioctl));
+    }
+}
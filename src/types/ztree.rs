@@ -1,4 +1,4 @@
-use std::{collections::BTreeMap, ops::Bound};
+use std::{collections::BTreeMap, fmt::Write, ops::Bound};
 
 use super::{Rect, rect::SplitRect};
 
@@ -97,6 +97,11 @@ impl ZTree {
             reference,
             area,
         } = surface;
+
+        // A surface covering no pixel can't be masked nor mask anything
+        if area.x2 <= area.x1 || area.y2 <= area.y1 {
+            return false;
+        }
         let Some(new_leaf) = self
             .nodes
             .range((Bound::Excluded(z_index), Bound::Unbounded))
@@ -130,10 +135,89 @@ impl ZTree {
         true
     }
 
+    /// Return the total visible area of the ZTree, without flattening it
+    ///
+    /// This sums the exact visible parts of each surface, so it is smaller than (or equal to) the
+    /// sum of the flattened bounding boxes. Overlapping surfaces on the same layer don't mask each
+    /// other, and are counted twice.
+    pub fn visible_area(&self) -> i64 {
+        self.nodes
+            .values()
+            .flat_map(|n| &n.leaves)
+            .map(|l| l.area.area())
+            .sum()
+    }
+
+    /// Iterate over the visible part of every surface, from the lowest layer to the highest
+    ///
+    /// Items are the surface z-index, reference, and visible area.
+    pub fn leaves(&self) -> impl Iterator<Item = (i32, &str, &SplitRect)> {
+        self.nodes.iter().flat_map(|(&z_index, n)| {
+            n.leaves
+                .iter()
+                .map(move |l| (z_index, l.reference.as_str(), &l.area))
+        })
+    }
+
+    /// Render the ZTree as a Graphviz DOT graph, for debugging
+    ///
+    /// Layers are chained from the highest to the lowest. Each surface is linked to its layer, and
+    /// labelled with its reference, the bounding box of its visible area, and the pieces it is
+    /// made of.
+    pub fn to_dot(&self) -> String {
+        let quote = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let rect = |r: &Rect| format!("({}, {}, {}, {})", r.x1, r.y1, r.x2, r.y2);
+
+        let mut dot = String::from("digraph ztree {\n    node [shape=box];\n");
+
+        let mut upper: Option<i32> = None;
+        for &z_index in self.nodes.keys().rev() {
+            let _ = writeln!(dot, "    \"z{z_index}\" [label=\"z-index {z_index}\"];");
+            if let Some(upper) = upper {
+                let _ = writeln!(dot, "    \"z{upper}\" -> \"z{z_index}\" [style=dashed];");
+            }
+            upper = Some(z_index);
+        }
+
+        for (i, (z_index, reference, area)) in self.leaves().enumerate() {
+            let bounds = area.bounds().as_ref().map(rect).unwrap_or_default();
+            let pieces: Vec<_> = area.iter().map(rect).collect();
+
+            let _ = writeln!(
+                dot,
+                "    leaf{i} [label=\"{}\\nbounds: {bounds}\\npieces: {}\"];",
+                quote(reference),
+                pieces.join(" ")
+            );
+            let _ = writeln!(dot, "    \"z{z_index}\" -> leaf{i};");
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
     /// Flatten the ZTree into a vector of ZSurfaces
     pub fn flatten(self) -> Vec<ZSurface> {
         self.into()
     }
+
+    /// Flatten the ZTree into a vector of ZSurfaces, one per visible piece of each surface
+    ///
+    /// Unlike [ZTree::flatten], a partially covered surface produces several ZSurfaces sharing
+    /// the same reference, which together cover exactly its visible area.
+    pub fn flatten_precise(self) -> Vec<ZSurface> {
+        self.nodes
+            .into_iter()
+            .flat_map(|(z_index, n)| {
+                n.leaves.into_iter().flat_map(move |l| {
+                    let reference = l.reference;
+                    l.area
+                        .into_iter()
+                        .map(move |area| ZSurface::new(z_index, reference.clone(), area))
+                })
+            })
+            .collect()
+    }
 }
 
 impl From<ZTree> for Vec<ZSurface> {
@@ -180,6 +264,19 @@ mod tests {
         assert_eq!(expected, tree.flatten())
     }
 
+    #[test]
+    fn empty_surface_skipped() {
+        let mut tree = ZTree::new();
+        let s = ZSurface::new(0, "test_surface", Rect::new(0, 0, 100, 100));
+        tree.insert(s.clone());
+
+        for area in [Rect::new(50, 50, 50, 150), Rect::new(60, 60, 10, 10)] {
+            assert!(!tree.insert(ZSurface::new(1, "empty", area)));
+        }
+
+        assert_eq!(vec![s], tree.flatten())
+    }
+
     #[test]
     fn sanity_one_layer() {
         let mut tree = ZTree::new();
@@ -227,6 +324,41 @@ mod tests {
         assert_eq!(expected, tree.flatten());
     }
 
+    #[test]
+    fn single_pixel_surface_visible() {
+        let mut tree = ZTree::new();
+        let pixel = ZSurface::new(0, "pixel", Rect::new(10, 10, 11, 11));
+        // Covers everything around the pixel, but not the pixel itself
+        let left = ZSurface::new(1, "left", Rect::new(0, 0, 10, 20));
+        let right = ZSurface::new(1, "right", Rect::new(11, 0, 20, 20));
+
+        assert!(tree.insert(pixel.clone()));
+        assert!(tree.insert(left.clone()));
+        assert!(tree.insert(right.clone()));
+
+        assert_eq!(vec![pixel.clone(), left, right], tree.flatten());
+
+        let mut tree = ZTree::new();
+        tree.insert(pixel.clone());
+        assert_eq!(vec![pixel], tree.flatten_precise());
+    }
+
+    #[test]
+    fn single_pixel_surface_covered() {
+        let mut tree = ZTree::new();
+        let pixel = ZSurface::new(0, "pixel", Rect::new(10, 10, 11, 11));
+        let cover = ZSurface::new(1, "cover", Rect::new(10, 10, 11, 11));
+
+        assert!(tree.insert(pixel.clone()));
+        assert!(tree.insert(cover.clone()));
+        assert_eq!(vec![cover.clone()], tree.flatten());
+
+        // Inserting it below an existing cover is refused outright
+        let mut tree = ZTree::new();
+        assert!(tree.insert(cover));
+        assert!(!tree.insert(pixel));
+    }
+
     #[test]
     fn multi_layers_no_overlap() {
         let mut tree = ZTree::new();
@@ -247,15 +379,19 @@ mod tests {
         assert_eq!(expected, tree.flatten())
     }
 
+    fn partial_overlap_surfaces() -> Vec<ZSurface> {
+        vec![
+            ZSurface::new(3, "surface1", Rect::new(0, 0, 100, 100)),
+            ZSurface::new(1, "surface2", Rect::new(50, 0, 200, 200)),
+            ZSurface::new(2, "surface3", Rect::new(0, 100, 150, 200)),
+        ]
+    }
+
     #[test]
     fn multi_layers_partial_overlap() {
         let mut tree = ZTree::new();
 
-        let mut surfaces = vec![
-            ZSurface::new(3, "surface1", Rect::new(0, 0, 100, 100)),
-            ZSurface::new(1, "surface2", Rect::new(50, 0, 200, 200)),
-            ZSurface::new(2, "surface3", Rect::new(0, 100, 150, 200)),
-        ];
+        let mut surfaces = partial_overlap_surfaces();
 
         for s in surfaces.iter().cloned() {
             tree.insert(s);
@@ -292,4 +428,143 @@ mod tests {
 
         assert_eq!(expected, tree.flatten())
     }
+
+    #[test]
+    fn visible_area_empty() {
+        assert_eq!(0, ZTree::new().visible_area());
+    }
+
+    #[test]
+    fn visible_area_partial_overlap() {
+        let mut tree = ZTree::new();
+
+        for s in partial_overlap_surfaces() {
+            tree.insert(s);
+        }
+
+        // surface1 and surface3 are fully visible, surface2 is L-shaped: (100, 0, 200, 100) and
+        // (150, 100, 200, 200).
+        let expected = 100 * 100 + 150 * 100 + (100 * 100 + 50 * 100);
+        assert_eq!(expected, tree.visible_area());
+
+        // The bounding box of surface2 covers the whole (100, 0, 200, 200) area.
+        let bounding: i64 = tree.flatten().iter().map(|s| s.area.area()).sum();
+        assert_eq!(expected + 50 * 100, bounding);
+    }
+
+    /// Minimal xorshift generator, to keep randomized tests reproducible without a dependency.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: i32) -> i32 {
+            (self.next() % n as u64) as i32
+        }
+
+        fn rect(&mut self, max: i32) -> Rect {
+            let (x1, y1) = (self.below(max), self.below(max));
+            let (w, h) = (1 + self.below(max - x1), 1 + self.below(max - y1));
+
+            Rect::from_xywh(x1, y1, w, h)
+        }
+    }
+
+    fn covers(area: &Rect, x: i32, y: i32) -> bool {
+        Rect::new(x, y, x + 1, y + 1).intersection(area).is_some()
+    }
+
+    #[test]
+    fn random_surfaces_occlusion() {
+        const SIZE: i32 = 20;
+        let mut rng = XorShift(0x5eed_0f21_71ee);
+
+        for _ in 0..500 {
+            // Distinct z-indices, inserted in random order.
+            let count = 1 + rng.below(6);
+            let mut surfaces: Vec<_> = (0..count)
+                .map(|z| ZSurface::new(z, format!("s{z}"), rng.rect(SIZE)))
+                .collect();
+            for i in (1..surfaces.len()).rev() {
+                surfaces.swap(i, rng.below(i as i32 + 1) as usize);
+            }
+
+            let build = || {
+                surfaces.iter().cloned().fold(ZTree::new(), |mut tree, s| {
+                    tree.insert(s);
+                    tree
+                })
+            };
+            let pieces = build().flatten_precise();
+            let boxes = build().flatten();
+
+            // Top-most input surface covering a pixel, if any.
+            let top = |x: i32, y: i32| {
+                surfaces
+                    .iter()
+                    .filter(|s| covers(&s.area, x, y))
+                    .max_by_key(|s| s.z_index)
+            };
+
+            for x in 0..SIZE {
+                for y in 0..SIZE {
+                    let covering: Vec<_> =
+                        pieces.iter().filter(|p| covers(&p.area, x, y)).collect();
+
+                    match top(x, y) {
+                        // Visible pixels are kept exactly once, by the top-most surface.
+                        Some(s) => {
+                            assert_eq!(1, covering.len(), "{surfaces:?} at ({x}, {y})");
+                            assert_eq!(s.reference, covering[0].reference);
+                            assert!(
+                                boxes
+                                    .iter()
+                                    .any(|b| b.reference == s.reference && covers(&b.area, x, y))
+                            );
+                        }
+                        None => assert!(covering.is_empty(), "{surfaces:?} at ({x}, {y})"),
+                    }
+                }
+            }
+
+            // Fully occluded surfaces don't produce anything.
+            for b in &boxes {
+                assert!(pieces.iter().any(|p| p.reference == b.reference));
+            }
+        }
+    }
+
+    #[test]
+    fn dot_dump() {
+        let mut tree = ZTree::new();
+        for surface in partial_overlap_surfaces() {
+            tree.insert(surface);
+        }
+        tree.insert(ZSurface::new(-1, "background", Rect::new(0, 0, 300, 300)));
+
+        let dot = tree.to_dot();
+
+        assert!(dot.starts_with("digraph ztree {"));
+        assert!(dot.contains("surface1\\nbounds: (0, 0, 100, 100)"));
+        assert!(dot.contains("\"z3\" -> \"z2\" [style=dashed];"));
+        assert!(dot.contains("\"z1\" -> \"z-1\" [style=dashed];"));
+
+        for (z_index, reference, area) in tree.leaves() {
+            let bounds = area.bounds().unwrap();
+            assert!(dot.contains(&format!("\"z{z_index}\" [label=\"z-index {z_index}\"];")));
+            assert!(dot.contains(&format!(
+                "{reference}\\nbounds: ({}, {}, {}, {})",
+                bounds.x1, bounds.y1, bounds.x2, bounds.y2
+            )));
+        }
+
+        let layers: Vec<_> = tree.leaves().map(|(z_index, ..)| z_index).collect();
+        assert_eq!(4, layers.len());
+        assert_eq!(Some(&-1), layers.first());
+    }
 }